@@ -0,0 +1,258 @@
+// Benchmarks of the client hot paths, run with
+//   cargo bench --features test-util
+// They always run on the in-memory FakeBackend, and additionally on redis when
+// RDCACHE_BENCH_REDIS is set to its address, e.g. 127.0.0.1:6379.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::future::join_all;
+use rdcache::{test_util::FakeBackend, Client, Options};
+use serde::{Deserialize, Serialize};
+use std::{
+    hint::black_box,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+
+const EXPIRE: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct User {
+    id: u64,
+    name: String,
+    email: String,
+    tags: Vec<String>,
+}
+
+fn user(id: u64) -> User {
+    User {
+        id,
+        name: format!("user {}", id),
+        email: format!("user{}@example.com", id),
+        tags: vec!["admin".to_string(), "beta".to_string()],
+    }
+}
+
+fn clients(rt: &Runtime) -> Vec<(&'static str, Client)> {
+    let mut clients = vec![(
+        "fake",
+        Client::with_backend(FakeBackend::new(), Options::default()),
+    )];
+    if let Ok(addr) = std::env::var("RDCACHE_BENCH_REDIS") {
+        let rdb = rt
+            .block_on(rustis::client::Client::connect(addr))
+            .expect("connect to RDCACHE_BENCH_REDIS");
+        let options = Options {
+            common_prefix: format!("bench:{}:", uuid::Uuid::new_v4().simple()),
+            ..Default::default()
+        };
+        clients.push(("redis", Client::new(rdb, options)));
+    }
+    clients
+}
+
+fn bench_fetch(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let clients = clients(&rt);
+
+    let mut group = c.benchmark_group("fetch_hit");
+    for (name, client) in &clients {
+        rt.block_on(client.fetch("hit", EXPIRE, || async { Ok(Some(user(1))) }))
+            .unwrap();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.to_async(&rt).iter(|| async {
+                let v: Option<User> = client
+                    .fetch("hit", EXPIRE, || async { unreachable!() })
+                    .await
+                    .unwrap();
+                black_box(v)
+            })
+        });
+    }
+    group.finish();
+
+    // every iteration takes the lock on a new key, loads and writes the value
+    let mut group = c.benchmark_group("fetch_miss_with_lock");
+    let next = AtomicU64::new(0);
+    for (name, client) in &clients {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.to_async(&rt).iter(|| async {
+                let id = next.fetch_add(1, Ordering::Relaxed);
+                let key = format!("miss:{}", id);
+                let v = client
+                    .fetch(key, EXPIRE, || async { Ok(Some(user(id))) })
+                    .await
+                    .unwrap();
+                black_box(v)
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("fetch_batch_16");
+    for (name, client) in &clients {
+        for id in 0..16 {
+            rt.block_on(client.fetch(format!("batch:{}", id), EXPIRE, || async {
+                Ok(Some(user(id)))
+            }))
+            .unwrap();
+        }
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.to_async(&rt).iter(|| async {
+                let fetches = (0..16).map(|id| async move {
+                    let v: Option<User> = client
+                        .fetch(format!("batch:{}", id), EXPIRE, || async { unreachable!() })
+                        .await
+                        .unwrap();
+                    v
+                });
+                black_box(join_all(fetches).await)
+            })
+        });
+    }
+    group.finish();
+}
+
+// fetch_large measures the copies of the value on the way to and from the backend,
+// each iteration writes a new key and reads it back.
+fn bench_large(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let clients = clients(&rt);
+    let next = AtomicU64::new(0);
+    let mut group = c.benchmark_group("fetch_large");
+    for size in [1 << 10, 64 << 10, 1 << 20] {
+        let value = vec![7u8; size];
+        for (name, client) in &clients {
+            group.bench_function(BenchmarkId::new(*name, size), |b| {
+                b.to_async(&rt).iter(|| async {
+                    let key = format!("large:{}", next.fetch_add(1, Ordering::Relaxed));
+                    for _ in 0..2 {
+                        let v = client
+                            .fetch(key.as_str(), EXPIRE, || async { Ok(Some(value.clone())) })
+                            .await
+                            .unwrap();
+                        black_box(v);
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+// fetch_hit_concurrent runs hits on 1024 keys from many tasks at once, with the
+// per key state of the client (hot key counters and sliding expiration touches) enabled.
+// On the fake backend, its own lock bounds the scaling.
+fn bench_concurrent(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let options = Options {
+        hot_key_capacity: 1024,
+        sliding_expiration: true,
+        ..Default::default()
+    };
+    let client = Client::with_backend(FakeBackend::new(), options);
+    for id in 0..1024 {
+        rt.block_on(client.fetch(format!("hit:{}", id), EXPIRE, || async {
+            Ok(Some(user(id)))
+        }))
+        .unwrap();
+    }
+    let mut group = c.benchmark_group("fetch_hit_concurrent");
+    for tasks in [1u64, 16, 64, 128] {
+        group.bench_function(BenchmarkId::from_parameter(tasks), |b| {
+            b.to_async(&rt).iter(|| {
+                let client = client.clone();
+                async move {
+                    let handles = (0..tasks).map(|task| {
+                        let client = client.clone();
+                        tokio::spawn(async move {
+                            for i in 0..16 {
+                                let id = (task * 16 + i) % 1024;
+                                let v: Option<User> = client
+                                    .fetch(format!("hit:{}", id), EXPIRE, || async {
+                                        unreachable!()
+                                    })
+                                    .await
+                                    .unwrap();
+                                black_box(v);
+                            }
+                        })
+                    });
+                    join_all(handles).await
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// executor_latency measures how long a task waits for a worker while two hits decode
+// a 4MB value, with the decoding inline or with Options::blocking_threshold.
+fn bench_blocking(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    let value = vec![7u8; 4 << 20];
+    let mut group = c.benchmark_group("executor_latency");
+    for (name, threshold) in [("inline", 0), ("block_in_place", 64 << 10)] {
+        let options = Options {
+            blocking_threshold: threshold,
+            ..Default::default()
+        };
+        let client = Client::with_backend(FakeBackend::new(), options);
+        rt.block_on(client.fetch("large", EXPIRE, || async { Ok(Some(value.clone())) }))
+            .unwrap();
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let client = client.clone();
+                async move {
+                    let mut waited = Duration::ZERO;
+                    for _ in 0..iters {
+                        let hits: Vec<_> = (0..2)
+                            .map(|_| {
+                                let client = client.clone();
+                                tokio::spawn(async move {
+                                    let v: Option<Vec<u8>> = client
+                                        .fetch("large", EXPIRE, || async { unreachable!() })
+                                        .await
+                                        .unwrap();
+                                    black_box(v)
+                                })
+                            })
+                            .collect();
+                        let start = Instant::now();
+                        tokio::spawn(async {}).await.unwrap();
+                        waited += start.elapsed();
+                        join_all(hits).await;
+                    }
+                    waited
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+    let value = Some(user(1));
+    let bytes = rmp_serde::to_vec(&value).unwrap();
+    group.bench_function("msgpack_encode", |b| {
+        b.iter(|| rmp_serde::to_vec(black_box(&value)).unwrap())
+    });
+    group.bench_function("msgpack_decode", |b| {
+        b.iter(|| rmp_serde::from_slice::<Option<User>>(black_box(&bytes)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_fetch,
+    bench_large,
+    bench_concurrent,
+    bench_blocking,
+    bench_codec
+);
+criterion_main!(benches);
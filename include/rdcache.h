@@ -0,0 +1,46 @@
+/* The C ABI of rdcache, built with `cargo rustc --release --features ffi --crate-type cdylib`
+ * (or staticlib). It runs the same lock protocol as the rust Client, so services in other
+ * languages can share keys with it. All the calls block. */
+#ifndef RDCACHE_H
+#define RDCACHE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct RdcacheClient RdcacheClient;
+
+/* rdcache_loader loads the value of key as json into a string it keeps ownership of,
+ * valid until it is called again. It returns NULL if the load failed. */
+typedef const char *(*rdcache_loader)(void *ctx, const char *key);
+
+/* rdcache_new connects to the redis server at addr, e.g. "127.0.0.1:6379", with the
+ * default options. It returns NULL if the connection failed. */
+RdcacheClient *rdcache_new(const char *addr);
+
+/* rdcache_free shuts the client down, waiting for its background writes. client must
+ * come from rdcache_new and not be used afterwards, it may be NULL. */
+void rdcache_free(RdcacheClient *client);
+
+/* rdcache_fetch_json returns the cached value of key as json, loading it with loader
+ * and caching it for expire_ms on a miss. The json null is the empty result. The
+ * returned string must be freed with rdcache_string_free, it is NULL on error.
+ * client must be a live client, key a NUL terminated UTF-8 string, and loader is
+ * called on the calling thread with ctx. */
+char *rdcache_fetch_json(const RdcacheClient *client, const char *key, uint64_t expire_ms,
+                         rdcache_loader loader, void *ctx);
+
+/* rdcache_invalidate tag deletes key, its value is reloaded by the next fetch. It
+ * returns 0 on success and -1 on error. */
+int rdcache_invalidate(const RdcacheClient *client, const char *key);
+
+/* rdcache_string_free frees a string returned by rdcache, it may be NULL. */
+void rdcache_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
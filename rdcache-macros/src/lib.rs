@@ -0,0 +1,276 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    meta::ParseNestedMeta, parse_macro_input, Data, DeriveInput, Expr, Fields, ItemFn, LitStr,
+    ReturnType,
+};
+
+// Derive `rdcache::CacheKey` for a struct of id fields.
+//
+// The generated key is `prefix:field=value:field=value`, with fields sorted by
+// name so that reordering the struct does not change the keys already in redis.
+// The prefix defaults to the snake_case struct name and can be changed with
+// `#[cache_key(prefix = "...")]`. Fields accept `#[cache_key(rename = "...")]`
+// and `#[cache_key(skip)]`.
+#[proc_macro_derive(CacheKey, attributes(cache_key))]
+pub fn derive_cache_key(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_cache_key(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_cache_key(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    let mut prefix = to_snake_case(&ident.to_string());
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("cache_key"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("prefix") {
+                prefix = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("unsupported cache_key attribute, expected `prefix`"))
+            }
+        })?;
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "CacheKey can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "CacheKey can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut parts = Vec::new();
+    for field in fields {
+        let field_ident = field.ident.as_ref().unwrap();
+        let mut name = field_ident.to_string();
+        let mut skip = false;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|a| a.path().is_ident("cache_key"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                    Ok(())
+                } else if meta.path.is_ident("rename") {
+                    name = meta.value()?.parse::<LitStr>()?.value();
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported cache_key attribute, expected `skip` or `rename`"))
+                }
+            })?;
+        }
+        if !skip {
+            parts.push((name, field_ident.clone()));
+        }
+    }
+    parts.sort_by(|a, b| a.0.cmp(&b.0));
+    for pair in parts.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            return Err(syn::Error::new_spanned(
+                &pair[1].1,
+                format!("duplicate cache key field name `{}`", pair[1].0),
+            ));
+        }
+    }
+
+    let pushes = parts.iter().map(|(name, field)| {
+        quote! {
+            ::rdcache::key::push_key_part(&mut key, #name, &self.#field);
+        }
+    });
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::rdcache::CacheKey for #ident #ty_generics #where_clause {
+            fn cache_key(&self) -> ::std::string::String {
+                let mut key = ::std::string::String::from(#prefix);
+                #(#pushes)*
+                key
+            }
+        }
+    })
+}
+
+// Cache the result of an async fn with `rdcache::Client::fetch`.
+//
+// `#[cached(key = "user:{id}", ttl = "300s")]` formats the key from the arguments
+// with `format!`, and runs the body as the loader on a miss. The fn must return
+// `rdcache::Result<Option<V>>`. The client is the `client` argument by default,
+// `client = "expr"` names another expression, e.g. `client = "self.cache"`.
+// The ttl accepts the `ms`, `s`, `m`, `h` and `d` units.
+#[proc_macro_attribute]
+pub fn cached(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = CachedArgs::default();
+    let parser = syn::meta::parser(|meta| args.parse(meta));
+    parse_macro_input!(attr with parser);
+    let item = parse_macro_input!(item as ItemFn);
+    match expand_cached(args, item) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct CachedArgs {
+    key: Option<LitStr>,
+    ttl: Option<LitStr>,
+    client: Option<Expr>,
+}
+
+impl CachedArgs {
+    fn parse(&mut self, meta: ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("key") {
+            self.key = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("ttl") {
+            self.ttl = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("client") {
+            self.client = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+        } else {
+            return Err(
+                meta.error("unsupported cached attribute, expected `key`, `ttl` or `client`")
+            );
+        }
+        Ok(())
+    }
+}
+
+fn expand_cached(args: CachedArgs, item: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let span = item.sig.ident.span();
+    let key = args
+        .key
+        .ok_or_else(|| syn::Error::new(span, "cached needs a `key`"))?;
+    let ttl = args
+        .ttl
+        .ok_or_else(|| syn::Error::new(span, "cached needs a `ttl`"))?;
+    let millis = parse_ttl(&ttl.value()).map_err(|e| syn::Error::new_spanned(&ttl, e))?;
+    if item.sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            item.sig.fn_token,
+            "cached can only be used on async fns",
+        ));
+    }
+    if let ReturnType::Default = item.sig.output {
+        return Err(syn::Error::new_spanned(
+            &item.sig,
+            "cached fns must return rdcache::Result<Option<V>>",
+        ));
+    }
+    let client = args.client.unwrap_or_else(|| syn::parse_quote!(client));
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = item;
+    Ok(quote! {
+        #(#attrs)*
+        #vis #sig {
+            let __rdcache_key = ::std::format!(#key);
+            (#client)
+                .fetch(
+                    __rdcache_key,
+                    ::std::time::Duration::from_millis(#millis),
+                    || async move #block,
+                )
+                .await
+        }
+    })
+}
+
+// parse_ttl parses a duration like `300s` into milliseconds.
+fn parse_ttl(ttl: &str) -> Result<u64, String> {
+    let split = ttl.find(|c: char| !c.is_ascii_digit()).unwrap_or(ttl.len());
+    let (n, unit) = ttl.split_at(split);
+    let n: u64 = n
+        .parse()
+        .map_err(|_| format!("invalid ttl `{}`, expected e.g. `300s`", ttl))?;
+    let unit = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 3600 * 1000,
+        "d" => 86400 * 1000,
+        _ => {
+            return Err(format!(
+                "invalid ttl unit in `{}`, expected ms, s, m, h or d",
+                ttl
+            ))
+        }
+    };
+    n.checked_mul(unit)
+        .ok_or_else(|| format!("ttl `{}` is too large", ttl))
+}
+
+fn to_snake_case(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 4);
+    for (i, c) in s.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_snake_case() {
+        assert_eq!(to_snake_case("User"), "user");
+        assert_eq!(to_snake_case("OrderItem"), "order_item");
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("300s"), Ok(300_000));
+        assert_eq!(parse_ttl("250ms"), Ok(250));
+        assert_eq!(parse_ttl("2h"), Ok(7_200_000));
+        assert!(parse_ttl("5").is_err());
+        assert!(parse_ttl("s").is_err());
+        assert!(parse_ttl("5w").is_err());
+    }
+
+    #[test]
+    fn test_expand_rejects_sync_fn() {
+        let args = CachedArgs {
+            key: Some(syn::parse_quote!("k")),
+            ttl: Some(syn::parse_quote!("1s")),
+            client: None,
+        };
+        let item: ItemFn = syn::parse_quote! { fn load() -> Result<Option<u64>> { Ok(None) } };
+        assert!(expand_cached(args, item).is_err());
+    }
+
+    #[test]
+    fn test_expand_rejects_tuple_struct() {
+        let input: DeriveInput = syn::parse_quote! { struct Id(u64); };
+        assert!(expand_cache_key(input).is_err());
+    }
+}
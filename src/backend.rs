@@ -0,0 +1,237 @@
+use crate::{
+    error::{new_backend_error, new_unexpected_reply_error},
+    pool,
+    script::{Script, HMGET_SCRIPT},
+    Result,
+};
+use futures::stream::BoxStream;
+use std::{any::Any, future::Future, io::Write, pin::Pin};
+
+#[cfg(feature = "rustis")]
+mod rustis_backend;
+
+#[cfg(feature = "rustis")]
+pub(crate) use rustis_backend::as_rustis;
+#[cfg(feature = "rustis")]
+pub use rustis_backend::RustisBackend;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// CacheBackend is the port between Client and the redis server.
+// The rustis client implements it through RustisBackend, with the rustis feature, other
+// redis clients or connection pools can implement it, returning Error::BackendError
+// when the server can't be reached or fails a call. Tests can implement it with a mock
+// that checks which script is called and returns canned replies.
+pub trait CacheBackend: Any + Send + Sync {
+    // eval runs one of the rdcache lua scripts, identified by Script::name.
+    fn eval<'a>(
+        &'a self,
+        script: &'a Script,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+    ) -> BoxFuture<'a, Result<Reply>>;
+
+    // eval_pipeline runs independent script calls, in one round trip if the backend
+    // supports it, and returns their replies in order. A failed call doesn't stop the
+    // others.
+    fn eval_pipeline<'a>(
+        &'a self,
+        calls: Vec<ScriptCall<'a>>,
+    ) -> BoxFuture<'a, Vec<Result<Reply>>> {
+        Box::pin(async move {
+            let mut replies = Vec::with_capacity(calls.len());
+            for call in calls {
+                replies.push(self.eval(call.script, call.keys, call.args).await);
+            }
+            replies
+        })
+    }
+
+    // hmget reads fields of the hash at key without writing anything, so that it may be
+    // served by a replica. The default runs HMGET in a script.
+    fn hmget<'a>(
+        &'a self,
+        key: &'a str,
+        fields: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Vec<Option<Vec<u8>>>>> {
+        Box::pin(async move {
+            let args = fields.iter().map(|f| f.as_bytes().to_vec()).collect();
+            let reply = self
+                .eval(&HMGET_SCRIPT, vec![key.to_string()], args)
+                .await?;
+            reply
+                .into_array()?
+                .into_iter()
+                .map(Reply::into_bytes)
+                .collect()
+        })
+    }
+
+    // load_scripts loads scripts into the script cache of the server, so that their
+    // first calls don't send their source. The default does nothing.
+    fn load_scripts<'a>(&'a self, scripts: &'a [&'a Script]) -> BoxFuture<'a, Result<()>> {
+        _ = scripts;
+        Box::pin(async { Ok(()) })
+    }
+
+    // subscribe returns the messages published on channel, until the stream is dropped
+    // or the connection is lost. The default fails, the backend having no subscriptions.
+    fn subscribe<'a>(
+        &'a self,
+        channel: &'a str,
+    ) -> BoxFuture<'a, Result<BoxStream<'static, String>>> {
+        Box::pin(async move {
+            Err(new_backend_error(format!(
+                "the backend can't subscribe to {}",
+                channel
+            )))
+        })
+    }
+
+    // del removes keys and returns the number of keys removed.
+    fn del(&self, keys: Vec<String>) -> BoxFuture<'_, Result<u64>>;
+
+    // scan runs one SCAN iteration over the keys matching pattern.
+    fn scan<'a>(
+        &'a self,
+        cursor: u64,
+        pattern: &'a str,
+        count: usize,
+    ) -> BoxFuture<'a, Result<(u64, Vec<String>)>>;
+}
+
+// ScriptCall is one script call of a pipeline.
+#[derive(Debug, Clone)]
+pub struct ScriptCall<'a> {
+    pub script: &'a Script,
+    pub keys: Vec<String>,
+    pub args: Vec<Vec<u8>>,
+}
+
+// Reply is a script reply, with lua false and redis nil both mapped to Nil.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Nil,
+    Int(i64),
+    Bulk(Vec<u8>),
+    Status(String),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn into_array(self) -> Result<Vec<Reply>> {
+        match self {
+            Reply::Array(items) => Ok(items),
+            Reply::Nil => Ok(Vec::new()),
+            r => Err(new_unexpected_reply_error(r)),
+        }
+    }
+
+    pub fn into_bytes(self) -> Result<Option<Vec<u8>>> {
+        match self {
+            Reply::Nil => Ok(None),
+            Reply::Bulk(b) => Ok(Some(b)),
+            Reply::Status(s) => Ok(Some(s.into_bytes())),
+            Reply::Int(i) => Ok(Some(i.to_string().into_bytes())),
+            r => Err(new_unexpected_reply_error(r)),
+        }
+    }
+
+    pub fn into_string(self) -> Result<Option<String>> {
+        match self.into_bytes()? {
+            Some(b) => String::from_utf8(b)
+                .map(Some)
+                .map_err(|e| new_unexpected_reply_error(Reply::Bulk(e.into_bytes()))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn as_int(&self) -> Result<i64> {
+        match self {
+            Reply::Int(i) => Ok(*i),
+            Reply::Bulk(b) => std::str::from_utf8(b)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| new_unexpected_reply_error(self.clone())),
+            r => Err(new_unexpected_reply_error(r.clone())),
+        }
+    }
+}
+
+// Args collects script arguments the way CommandArgs does for rustis commands.
+#[derive(Debug, Default)]
+pub(crate) struct Args(Vec<Vec<u8>>);
+
+impl Args {
+    pub(crate) fn arg(mut self, arg: impl ToArg) -> Self {
+        self.0.push(arg.to_arg());
+        self
+    }
+
+    pub(crate) fn push(&mut self, arg: impl ToArg) {
+        self.0.push(arg.to_arg());
+    }
+
+    pub(crate) fn build(self) -> Vec<Vec<u8>> {
+        self.0
+    }
+}
+
+pub(crate) trait ToArg {
+    fn to_arg(self) -> Vec<u8>;
+}
+
+macro_rules! impl_to_arg_display {
+    ($($t:ty),*) => {
+        $(impl ToArg for $t {
+            fn to_arg(self) -> Vec<u8> {
+                let mut buf = pool::take();
+                _ = write!(buf, "{}", self);
+                buf
+            }
+        })*
+    };
+}
+
+impl_to_arg_display!(u64, i64, u128, usize, &str, &String, String);
+
+impl ToArg for Vec<u8> {
+    fn to_arg(self) -> Vec<u8> {
+        self
+    }
+}
+
+impl ToArg for &[u8] {
+    fn to_arg(self) -> Vec<u8> {
+        let mut buf = pool::take();
+        buf.extend_from_slice(self);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let args = Args::default()
+            .arg(3u64)
+            .arg("owner")
+            .arg(vec![0xc0])
+            .build();
+        assert_eq!(args, vec![b"3".to_vec(), b"owner".to_vec(), vec![0xc0]]);
+    }
+
+    #[test]
+    fn test_reply_conversions() {
+        assert_eq!(Reply::Nil.into_bytes().unwrap(), None);
+        assert_eq!(Reply::Int(7).as_int().unwrap(), 7);
+        assert_eq!(Reply::Bulk(b"12".to_vec()).as_int().unwrap(), 12);
+        assert_eq!(
+            Reply::Bulk(b"x".to_vec()).into_string().unwrap(),
+            Some("x".to_string())
+        );
+        assert!(Reply::Int(1).into_array().is_err());
+    }
+}
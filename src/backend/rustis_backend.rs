@@ -0,0 +1,274 @@
+use super::{BoxFuture, CacheBackend, Reply, ScriptCall};
+use crate::{error::new_redis_error, pool, script::Script, Error, Result};
+use futures::{future, stream::BoxStream, StreamExt};
+use rustis::{
+    commands::{
+        CallBuilder, GenericCommands, HashCommands, PubSubCommands, ScanOptions, ScriptingCommands,
+    },
+    resp::{Command, Value},
+    RedisErrorKind,
+};
+use std::{any::Any, collections::HashSet, sync::Mutex};
+
+// RustisBackend runs the scripts on a rustis client: with EVAL the first time, which
+// loads them into the script cache of the server, and with EVALSHA afterwards. A script
+// missing from the cache again, e.g. after SCRIPT FLUSH or a failover, gets NOSCRIPT
+// once and is run again with EVAL.
+// The argument buffers go back to the pool once they are copied into the command.
+pub struct RustisBackend {
+    rdb: rustis::client::Client,
+    // loaded holds the hashes of the scripts known to be in the script cache.
+    loaded: Mutex<HashSet<String>>,
+}
+
+impl RustisBackend {
+    pub fn new(rdb: rustis::client::Client) -> Self {
+        Self {
+            rdb,
+            loaded: Mutex::default(),
+        }
+    }
+
+    pub fn client(&self) -> &rustis::client::Client {
+        &self.rdb
+    }
+
+    async fn eval_script(
+        &self,
+        script: &Script,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+    ) -> Result<Reply> {
+        let mut reply = self.send_call(script, &keys, &args).await;
+        if is_no_script(&reply) {
+            self.set_loaded(script, false);
+            reply = self.send_call(script, &keys, &args).await;
+        }
+        pool::recycle(args);
+        reply.and_then(to_reply)
+    }
+
+    // eval_batch sends the calls as one batch, running those that got NOSCRIPT again
+    // one by one with EVAL.
+    async fn eval_batch(&self, calls: Vec<ScriptCall<'_>>) -> Vec<Result<Reply>> {
+        let commands = calls
+            .iter()
+            .map(|call| self.call_command(call.script, &call.keys, &call.args))
+            .collect();
+        let replies: Vec<Result<Value>> = match self.rdb.send_batch(commands, None).await {
+            Ok(replies) => replies
+                .iter()
+                .map(|v| v.to::<Value>().map_err(new_redis_error))
+                .collect(),
+            Err(e) => calls
+                .iter()
+                .map(|_| Err(new_redis_error(e.clone())))
+                .collect(),
+        };
+        let mut results = Vec::with_capacity(calls.len());
+        for (call, reply) in calls.into_iter().zip(replies) {
+            results.push(if is_no_script(&reply) {
+                self.set_loaded(call.script, false);
+                self.eval_script(call.script, call.keys, call.args).await
+            } else {
+                if reply.is_ok() {
+                    self.set_loaded(call.script, true);
+                }
+                pool::recycle(call.args);
+                reply.and_then(to_reply)
+            });
+        }
+        results
+    }
+
+    // send_call sends the command of call_command, marking the script loaded once the
+    // server ran it.
+    async fn send_call(&self, script: &Script, keys: &[String], args: &[Vec<u8>]) -> Result<Value> {
+        let command = self.call_command(script, keys, args);
+        let v = self
+            .rdb
+            .send(command, None)
+            .await
+            .map_err(new_redis_error)?;
+        let reply = v.to::<Value>().map_err(new_redis_error);
+        if reply.is_ok() && !is_no_script(&reply) {
+            self.set_loaded(script, true);
+        }
+        reply
+    }
+
+    // call_command builds EVALSHA, or EVAL if the script is not known to be loaded, from
+    // the borrowed keys and args, which are copied once into the command buffer, so that
+    // they can be sent again after a NOSCRIPT.
+    fn call_command(&self, script: &Script, keys: &[String], args: &[Vec<u8>]) -> Command {
+        if self.loaded.lock().unwrap().contains(script.hash()) {
+            let call = CallBuilder::sha1(script.hash()).keys(keys).args(args);
+            self.rdb.evalsha::<String>(call).command
+        } else {
+            let call = CallBuilder::script(script.src()).keys(keys).args(args);
+            self.rdb.eval::<String>(call).command
+        }
+    }
+
+    fn set_loaded(&self, script: &Script, loaded: bool) {
+        let mut scripts = self.loaded.lock().unwrap();
+        if loaded {
+            if !scripts.contains(script.hash()) {
+                scripts.insert(script.hash().to_string());
+            }
+        } else {
+            scripts.remove(script.hash());
+        }
+    }
+}
+
+impl CacheBackend for RustisBackend {
+    fn eval<'a>(
+        &'a self,
+        script: &'a Script,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+    ) -> BoxFuture<'a, Result<Reply>> {
+        Box::pin(self.eval_script(script, keys, args))
+    }
+
+    fn eval_pipeline<'a>(
+        &'a self,
+        calls: Vec<ScriptCall<'a>>,
+    ) -> BoxFuture<'a, Vec<Result<Reply>>> {
+        Box::pin(self.eval_batch(calls))
+    }
+
+    fn load_scripts<'a>(&'a self, scripts: &'a [&'a Script]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for script in scripts {
+                let command = self.rdb.script_load::<&str, String>(script.src());
+                self.rdb
+                    .send(command.command, None)
+                    .await
+                    .map_err(new_redis_error)?;
+                self.set_loaded(script, true);
+            }
+            Ok(())
+        })
+    }
+
+    fn hmget<'a>(
+        &'a self,
+        key: &'a str,
+        fields: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Vec<Option<Vec<u8>>>>> {
+        Box::pin(async move {
+            let values: Vec<Value> = self
+                .rdb
+                .hmget::<_, _, Value, _, _>(key, fields)
+                .await
+                .map_err(new_redis_error)?;
+            values
+                .into_iter()
+                .map(|v| to_reply(v)?.into_bytes())
+                .collect()
+        })
+    }
+
+    fn subscribe<'a>(
+        &'a self,
+        channel: &'a str,
+    ) -> BoxFuture<'a, Result<BoxStream<'static, String>>> {
+        Box::pin(async move {
+            let messages = self.rdb.subscribe(channel).await.map_err(new_redis_error)?;
+            Ok(messages
+                .filter_map(|m| {
+                    future::ready(
+                        m.ok()
+                            .map(|m| String::from_utf8_lossy(&m.payload).into_owned()),
+                    )
+                })
+                .boxed())
+        })
+    }
+
+    fn del(&self, keys: Vec<String>) -> BoxFuture<'_, Result<u64>> {
+        Box::pin(async move {
+            let n: usize = self.rdb.del(keys).await.map_err(new_redis_error)?;
+            Ok(n as u64)
+        })
+    }
+
+    fn scan<'a>(
+        &'a self,
+        cursor: u64,
+        pattern: &'a str,
+        count: usize,
+    ) -> BoxFuture<'a, Result<(u64, Vec<String>)>> {
+        Box::pin(async move {
+            self.rdb
+                .scan(
+                    cursor,
+                    ScanOptions::default().match_pattern(pattern).count(count),
+                )
+                .await
+                .map_err(new_redis_error)
+        })
+    }
+}
+
+fn is_no_script(reply: &Result<Value>) -> bool {
+    match reply {
+        Ok(Value::Error(e)) | Err(Error::RedisError(rustis::Error::Redis(e))) => {
+            e.kind == RedisErrorKind::NoScript
+        }
+        _ => false,
+    }
+}
+
+fn to_reply(value: Value) -> Result<Reply> {
+    Ok(match value {
+        Value::Nil => Reply::Nil,
+        Value::Integer(i) => Reply::Int(i),
+        Value::Boolean(b) => Reply::Int(b as i64),
+        Value::Double(d) => Reply::Bulk(d.to_string().into_bytes()),
+        Value::BulkString(b) => Reply::Bulk(b),
+        Value::SimpleString(s) => Reply::Status(s),
+        Value::Array(items) | Value::Set(items) | Value::Push(items) => {
+            Reply::Array(items.into_iter().map(to_reply).collect::<Result<_>>()?)
+        }
+        Value::Map(map) => Reply::Array(
+            map.into_iter()
+                .flat_map(|(k, v)| [to_reply(k), to_reply(v)])
+                .collect::<Result<_>>()?,
+        ),
+        Value::Error(e) => return Err(new_redis_error(rustis::Error::Redis(e))),
+    })
+}
+
+pub(crate) fn as_rustis(backend: &dyn CacheBackend) -> Option<&rustis::client::Client> {
+    (backend as &dyn Any)
+        .downcast_ref::<RustisBackend>()
+        .map(RustisBackend::client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_reply() {
+        let value = Value::Array(vec![Value::Nil, Value::BulkString(b"LOCKED".to_vec())]);
+        assert_eq!(
+            to_reply(value).unwrap(),
+            Reply::Array(vec![Reply::Nil, Reply::Bulk(b"LOCKED".to_vec())])
+        );
+    }
+    #[test]
+    fn test_is_no_script() {
+        let no_script: rustis::RedisError = "NOSCRIPT No matching script".parse().unwrap();
+        assert!(is_no_script(&Ok(Value::Error(no_script.clone()))));
+        assert!(is_no_script(&Err(new_redis_error(rustis::Error::Redis(
+            no_script
+        )))));
+        let other: rustis::RedisError = "ERR unknown".parse().unwrap();
+        assert!(!is_no_script(&Ok(Value::Error(other))));
+        assert!(!is_no_script(&Ok(Value::Nil)));
+    }
+}
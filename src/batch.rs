@@ -0,0 +1,468 @@
+use crate::{
+    backend::{Args, Reply},
+    client::UnlockOnDrop,
+    error::new_unexpected_reply_error,
+    script::{GET_BATCH_SCRIPT, SET_BATCH_SCRIPT},
+    wait::parse_get,
+    Client, Error, Result,
+};
+use futures::{future::join_all, stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, fmt::Debug, future::Future, time::Duration};
+
+impl Client {
+    // fetch_batch is fetch for a batch of keys. The locks of all the keys are taken in
+    // one round trip, f is called once with the indexes in keys of the values missing
+    // from the cache, and the values it returns are written in one more round trip. The
+    // indexes it doesn't return are cached as empty results. The keys locked by another
+    // fetch are waited for like with fetch, calling f again with their index alone if
+    // the lock expires.
+    // It returns the values by index in keys, without the empty results. The scripts are
+    // multi key, so on a cluster either all keys must be in one slot or Options::cluster
+    // must be set.
+    // Options::lock_renewal, Options::recover_on_decode_error and
+    // Options::fallback_to_source_on_redis_error apply like with fetch. The local tier,
+    // Options::read_only_hits and Options::coalesce_fetches don't, and with
+    // NegativeCachePolicy::Suffix the empty results are not cached.
+    pub async fn fetch_batch<F, Fut, V>(
+        &self,
+        keys: Vec<String>,
+        expire: Duration,
+        f: F,
+    ) -> Result<HashMap<usize, V>>
+    where
+        F: Fn(Vec<usize>) -> Fut,
+        Fut: Future<Output = Result<HashMap<usize, V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let keys: Vec<String> = keys.into_iter().map(|k| self.prefixed_key(k)).collect();
+        for key in &keys {
+            self.hot_keys.record(key);
+        }
+        if keys.is_empty() || self.cache_bypassed() {
+            return f((0..keys.len()).collect()).await;
+        }
+        let ex = self.value_expire(expire)?;
+        let owner = (self.owner_id)();
+        let args = Args::default()
+            .arg(self.lock_span(self.options().lock_expire))
+            .arg(owner.as_str())
+            .arg(self.lock_unit())
+            .build();
+        // on a cluster, the groups of the slots whose GET_BATCH succeeds hold the locks of
+        // their keys even if another group fails: the keys locked by owner are released.
+        let cluster_keys = if self.options().cluster {
+            keys.iter().map(String::as_str).collect()
+        } else {
+            Vec::new()
+        };
+        let unlock_groups = UnlockOnDrop::new(self, cluster_keys, &owner);
+        let groups = match self
+            .call_lua_by_slot(&GET_BATCH_SCRIPT, &keys, |_| args.clone())
+            .await
+        {
+            Err(e) if e.is_redis_error() && self.options().fallback_to_source_on_redis_error => {
+                for _ in &keys {
+                    self.stats.miss();
+                }
+                return f((0..keys.len()).collect()).await;
+            }
+            groups => groups?,
+        };
+        let mut replies = vec![Reply::Nil; keys.len()];
+        for (group, reply) in groups {
+            let reply = reply.into_array()?;
+            if reply.len() != group.len() {
+                return Err(new_unexpected_reply_error(Reply::Array(reply)));
+            }
+            for (i, reply) in group.into_iter().zip(reply) {
+                replies[i] = reply;
+            }
+        }
+        unlock_groups.disarm();
+
+        // the locks taken are released if the fetch fails or is dropped before writing,
+        // like with fetch.
+        let replies: Vec<_> = replies.into_iter().map(parse_get).collect();
+        let locked: Vec<usize> = (0..keys.len())
+            .filter(|&i| matches!(&replies[i], Ok((_, Some(l), _)) if l == "LOCKED"))
+            .collect();
+        let locked_keys = locked.iter().map(|&i| keys[i].as_str()).collect();
+        let unlock = UnlockOnDrop::new(self, locked_keys, &owner);
+
+        let mut values = HashMap::new();
+        let mut waiting = Vec::new();
+        let recover = self.options().recover_on_decode_error;
+        for (i, reply) in replies.into_iter().enumerate() {
+            let (value, lock_until, _) = reply?;
+            match lock_until.as_deref() {
+                Some("LOCKED") => {}
+                Some(_) => waiting.push(i),
+                None => {
+                    let Some(s) = value else {
+                        return Err(new_unexpected_reply_error(Reply::Nil));
+                    };
+                    let value: Option<V> = match self.decode_value(&keys[i], &s) {
+                        Err(e) if recover && e.is_decode_error() => {
+                            // the value is tag deleted and fetched again like a locked key.
+                            self.delete_key(&keys[i]).await?;
+                            waiting.push(i);
+                            continue;
+                        }
+                        value => value?,
+                    };
+                    self.stats.hit();
+                    if let Some(value) = value {
+                        if self.options().sliding_expiration {
+                            self.touch(&keys[i], ex).await;
+                        }
+                        values.insert(i, value);
+                    }
+                }
+            }
+        }
+        if !locked.is_empty() {
+            for _ in &locked {
+                self.stats.miss();
+            }
+            let loaded = self.fetch_new_batch(&keys, &locked, ex, &owner, &f).await;
+            unlock.disarm();
+            values.extend(loaded?);
+        }
+
+        let waited = join_all(waiting.into_iter().map(|i| {
+            let (key, f) = (&keys[i], &f);
+            async move {
+                // like with fetch, the loader is called directly if redis fails before.
+                let mut source = Some(move || async move { Ok(f(vec![i]).await?.remove(&i)) });
+                let load = || source.take().expect("the loader is called once")();
+                let value = match (self.strong_fetch(key, ex, &[], load).await, source) {
+                    (Err(e), Some(load))
+                        if e.is_redis_error()
+                            && self.options().fallback_to_source_on_redis_error =>
+                    {
+                        self.stats.miss();
+                        load().await?
+                    }
+                    (value, _) => value?,
+                };
+                Ok::<_, Error>((i, value))
+            }
+        }))
+        .await;
+        for result in waited {
+            if let (i, Some(value)) = result? {
+                values.insert(i, value);
+            }
+        }
+        Ok(values)
+    }
+
+    // fetch_many is fetch for each of keys with its own loader call, f(key), running up
+    // to concurrency fetches at once. Unlike fetch_batch, each key takes its own round
+    // trips, for loaders that load one key per call, e.g. from different sources. It
+    // returns the result of each key in the order of keys, a failed key doesn't fail the
+    // others.
+    pub async fn fetch_many<F, Fut, V>(
+        &self,
+        keys: Vec<String>,
+        expire: Duration,
+        concurrency: usize,
+        f: F,
+    ) -> Vec<(String, Result<Option<V>>)>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let f = &f;
+        stream::iter(keys)
+            .map(|key| async move {
+                let result = self.fetch(&key, expire, || f(key.clone())).await;
+                (key, result)
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    // fetch_new_batch loads the values of the keys at indexes locked by owner and writes
+    // them with SET_BATCH, unlocking the keys if f fails.
+    async fn fetch_new_batch<F, Fut, V>(
+        &self,
+        keys: &[String],
+        locked: &[usize],
+        expire: Duration,
+        owner: &str,
+        f: &F,
+    ) -> Result<HashMap<usize, V>>
+    where
+        F: Fn(Vec<usize>) -> Fut,
+        Fut: Future<Output = Result<HashMap<usize, V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let locked_keys: Vec<&str> = locked.iter().map(|&i| keys[i].as_str()).collect();
+        let load = f(locked.to_vec());
+        let mut loaded = match self.load_locked(&locked_keys, owner, load).await {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                for &i in locked {
+                    _ = self.unlock_for_update(&keys[i], owner).await;
+                }
+                return Err(e);
+            }
+        };
+
+        let mut values = HashMap::new();
+        let mut set_keys = Vec::new();
+        let mut encoded = Vec::new();
+        let mut expires = Vec::new();
+        let mut deleted = Vec::new();
+        for &i in locked {
+            let value = loaded.remove(&i);
+            let mut expire = expire;
+            if value.is_none() {
+                expire = self.empty_expire();
+                if expire.is_zero() {
+                    // the lock is gone with the key, SET_BATCH would not write anything.
+                    deleted.push(keys[i].clone());
+                    continue;
+                }
+            }
+            encoded.push(self.encode_value(&keys[i], &value)?);
+            expires.push(expire.as_millis());
+            set_keys.push(keys[i].clone());
+            if let Some(value) = value {
+                values.insert(i, value);
+            }
+        }
+        if !deleted.is_empty() {
+            _ = self.del_by_slot(deleted).await;
+        }
+        if set_keys.is_empty() {
+            return Ok(values);
+        }
+
+        // the metadata pairs are the ARGV of SET after value, owner and expire.
+        let metadata = self.set_args(Vec::new(), owner, expire, &[]).split_off(3);
+        let written = self.call_lua_by_slot(&SET_BATCH_SCRIPT, &set_keys, |group| {
+            let mut args = Args::default().arg(owner);
+            for &i in group {
+                args.push(encoded[i].as_slice());
+            }
+            for &i in group {
+                args.push(expires[i]);
+            }
+            for pair in &metadata {
+                args.push(pair.as_slice());
+            }
+            args.build()
+        });
+        match written.await {
+            Err(e) if e.is_redis_error() && self.options().fallback_to_source_on_redis_error => {}
+            written => _ = written?,
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::FakeBackend, Client, Options};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_fetch_batch() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            empty_expire: Duration::from_secs(60),
+            metadata: vec![("version".to_string(), "1".to_string())],
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let expire = Duration::from_secs(600);
+        let loads = Arc::new(Mutex::new(Vec::new()));
+        let fetch = |ids: Vec<u64>| {
+            let loads = loads.clone();
+            let keys = ids.iter().map(|id| format!("user:{}", id)).collect();
+            let client = &client;
+            async move {
+                client
+                    .fetch_batch(keys, expire, |idxs: Vec<usize>| {
+                        let loaded: Vec<u64> = idxs.iter().map(|&i| ids[i]).collect();
+                        loads.lock().unwrap().push(loaded);
+                        let values: HashMap<usize, String> = idxs
+                            .into_iter()
+                            .filter(|&i| ids[i] != 0)
+                            .map(|i| (i, format!("user {}", ids[i])))
+                            .collect();
+                        async move { Ok(values) }
+                    })
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let values = fetch(vec![1, 2, 0]).await;
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[&0], "user 1");
+        assert_eq!(values[&1], "user 2");
+        assert_eq!(fake.calls("get_batch"), 1);
+        assert_eq!(fake.calls("set_batch"), 1);
+        assert_eq!(fake.hget("user:1", "meta:version"), Some(b"1".to_vec()));
+        assert!(fake.pttl("user:0").unwrap() <= Duration::from_secs(60));
+
+        let values = fetch(vec![0, 3, 2]).await;
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[&1], "user 3");
+        assert_eq!(values[&2], "user 2");
+        assert_eq!(*loads.lock().unwrap(), vec![vec![1, 2, 0], vec![3]]);
+
+        let values = fetch(vec![1, 3]).await;
+        assert_eq!(values.len(), 2);
+        assert_eq!(fake.calls("get_batch"), 3);
+        assert_eq!(fake.calls("set_batch"), 2);
+        assert_eq!(loads.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_batch_options() {
+        let fake = FakeBackend::new();
+        let keys = || vec!["a".to_string(), "b".to_string()];
+        let expire = Duration::from_secs(600);
+        let load = |idxs: Vec<usize>| async move { Ok(idxs.into_iter().map(|i| (i, i)).collect()) };
+
+        // a value that doesn't decode is loaded again.
+        fake.hset("a", "value", rmp_serde::to_vec(&Some("v1")).unwrap());
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let values = client.fetch_batch(keys(), expire, load).await;
+        assert!(values.unwrap_err().is_decode_error());
+        tokio::task::yield_now().await;
+        assert_eq!(fake.hget("b", "lockOwner"), None);
+        let options = Options {
+            recover_on_decode_error: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let values = client.fetch_batch(keys(), expire, load).await.unwrap();
+        assert_eq!(values, HashMap::from([(0, 0), (1, 1)]));
+        assert_eq!(
+            fake.hget("a", "value"),
+            Some(rmp_serde::to_vec(&Some(0)).unwrap())
+        );
+
+        // the loader is called directly if GET_BATCH or SET_BATCH fail.
+        let options = Options {
+            fallback_to_source_on_redis_error: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let keys = || vec!["c".to_string(), "d".to_string()];
+        fake.fail_next(1);
+        let values = client.fetch_batch(keys(), expire, load).await.unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(fake.hget("c", "lockOwner"), None);
+        let values = client
+            .fetch_batch(keys(), expire, |idxs: Vec<usize>| {
+                fake.fail_next(1);
+                load(idxs)
+            })
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(fake.hget("c", "value"), None);
+
+        // the locks of all the keys are renewed while the loader runs.
+        let options = Options {
+            lock_renewal: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let keys = vec!["e".to_string(), "f".to_string()];
+        let values = client
+            .fetch_batch(keys, expire, |idxs: Vec<usize>| async move {
+                tokio::time::sleep(Duration::from_millis(1500)).await;
+                load(idxs).await
+            })
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(fake.calls("extend"), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_many() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        let keys = || ["user:1", "order:2", "user:3"].map(String::from).to_vec();
+        let started = tokio::time::Instant::now();
+        let results = client
+            .fetch_many(keys(), expire, 2, |key| async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                match key.split_once(':') {
+                    Some(("user", id)) => Ok(Some(format!("user {}", id))),
+                    _ => Err(crate::error::new_config_error("no orders".to_string())),
+                }
+            })
+            .await;
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        let keys_in_order: Vec<&str> = results.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys_in_order, ["user:1", "order:2", "user:3"]);
+        assert_eq!(results[0].1.as_ref().unwrap().as_deref(), Some("user 1"));
+        assert!(results[1].1.is_err());
+        assert_eq!(results[2].1.as_ref().unwrap().as_deref(), Some("user 3"));
+
+        let results = client
+            .fetch_many(keys(), expire, 8, |key| async move { Ok(Some(key)) })
+            .await;
+        assert_eq!(results[0].1.as_ref().unwrap().as_deref(), Some("user 1"));
+        assert_eq!(results[1].1.as_ref().unwrap().as_deref(), Some("order:2"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_batch_cluster() {
+        let fake = FakeBackend::cluster();
+        let keys = || {
+            ["{user}:1", "{user}:2", "order:1", "order:2"]
+                .map(String::from)
+                .to_vec()
+        };
+        let load = |idxs: Vec<usize>| async move {
+            Ok(idxs
+                .into_iter()
+                .filter(|&i| i != 3)
+                .map(|i| (i, i))
+                .collect())
+        };
+        let expire = Duration::from_secs(600);
+        let client = Client::with_backend(fake.clone(), Options::default());
+        assert!(client.fetch_batch(keys(), expire, load).await.is_err());
+
+        let options = Options {
+            cluster: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let values = client.fetch_batch(keys(), expire, load).await.unwrap();
+        assert_eq!(values, HashMap::from([(0, 0), (1, 1), (2, 2)]));
+        assert_eq!(fake.calls("get_batch"), 1 + 3);
+        assert_eq!(fake.calls("set_batch"), 3);
+        let values = client.fetch_batch(keys(), expire, load).await.unwrap();
+        assert_eq!(values.len(), 3);
+
+        // the keys locked by the other slots are released when one fails.
+        let keys = || ["{user}:3", "order:3"].map(String::from).to_vec();
+        fake.fail_next(1);
+        assert!(client.fetch_batch(keys(), expire, load).await.is_err());
+        tokio::task::yield_now().await;
+        assert_eq!(fake.hget("{user}:3", "lockOwner"), None);
+        assert_eq!(fake.hget("order:3", "lockOwner"), None);
+        let values = client.fetch_batch(keys(), expire, load).await.unwrap();
+        assert_eq!(values, HashMap::from([(0, 0), (1, 1)]));
+    }
+}
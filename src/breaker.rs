@@ -0,0 +1,172 @@
+use crate::{Client, Error, Result};
+use std::{sync::Mutex, time::Instant};
+
+// CircuitState is the state of the circuit breaker of Options::circuit_breaker_threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CircuitState {
+    // Closed is the normal state, fetches read and write the cache.
+    #[default]
+    Closed,
+    // Open bypasses the cache: fetches call their loader without reading or writing redis.
+    Open,
+    // HalfOpen lets one fetch probe redis, its first redis call closing the breaker
+    // again or opening it for another Options::circuit_breaker_open.
+    HalfOpen,
+}
+
+// Health is the state of the connection of a client to redis, from Client::health.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Health {
+    // State is the state of the circuit breaker, always Closed if it is disabled.
+    pub state: CircuitState,
+    // ConsecutiveFailures is the number of redis calls that failed or were slow in a
+    // row since the last successful one.
+    pub consecutive_failures: u32,
+    // Opened is the number of times the breaker opened.
+    pub opened: u64,
+}
+
+// Breaker is the circuit breaker of a client and the clients detached from it.
+#[derive(Debug, Default)]
+pub(crate) struct Breaker {
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    health: Health,
+    // until is when an open breaker lets a probe through, or when a probe that never
+    // reported is replaced by another one.
+    until: Option<Instant>,
+}
+
+impl Client {
+    // health returns the state of the circuit breaker of the client.
+    pub fn health(&self) -> Health {
+        self.breaker.state.lock().unwrap().health
+    }
+
+    // cache_bypassed reports whether a fetch must call its loader without the cache,
+    // with Options::disable_cache_read or while the circuit breaker is open.
+    pub(crate) fn cache_bypassed(&self) -> bool {
+        let options = self.options();
+        if options.disable_cache_read {
+            return true;
+        }
+        if options.circuit_breaker_threshold == 0 {
+            return false;
+        }
+        let now = self.executor.now();
+        let mut state = self.breaker.state.lock().unwrap();
+        match state.health.state {
+            CircuitState::Closed => false,
+            _ if state.until.is_some_and(|until| now < until) => true,
+            _ => {
+                // this fetch is the probe, the next one is let through after another
+                // period if it never reports.
+                state.until = Some(now + options.circuit_breaker_open);
+                self.set_circuit_state(&mut state, CircuitState::HalfOpen);
+                false
+            }
+        }
+    }
+
+    // record_redis_call reports a redis call started at started to the circuit breaker.
+    // Calls failing with a redis error, see Error::is_redis_error, or slower than
+    // Options::circuit_breaker_slow_call are failures.
+    pub(crate) fn record_redis_call<T>(&self, started: Instant, result: &Result<T>) {
+        let options = self.options();
+        if options.circuit_breaker_threshold == 0 {
+            return;
+        }
+        let now = self.executor.now();
+        let slow = options.circuit_breaker_slow_call;
+        let failed = result.as_ref().is_err_and(Error::is_redis_error)
+            || (!slow.is_zero() && now.duration_since(started) > slow);
+        let mut state = self.breaker.state.lock().unwrap();
+        if !failed {
+            state.health.consecutive_failures = 0;
+            state.until = None;
+            self.set_circuit_state(&mut state, CircuitState::Closed);
+            return;
+        }
+        state.health.consecutive_failures = state.health.consecutive_failures.saturating_add(1);
+        let open = match state.health.state {
+            CircuitState::Closed => {
+                state.health.consecutive_failures >= options.circuit_breaker_threshold
+            }
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if open {
+            state.health.opened += 1;
+            state.until = Some(now + options.circuit_breaker_open);
+            self.set_circuit_state(&mut state, CircuitState::Open);
+        }
+    }
+
+    fn set_circuit_state(&self, state: &mut BreakerState, circuit: CircuitState) {
+        if state.health.state != circuit {
+            state.health.state = circuit;
+            self.stats.circuit_state(circuit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            circuit_breaker_threshold: 2,
+            circuit_breaker_open: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let expire = Duration::from_secs(600);
+        let fetch = |v| {
+            let client = &client;
+            async move {
+                client
+                    .fetch("k", expire, || async move { Ok(Some(v)) })
+                    .await
+            }
+        };
+        assert_eq!(fetch(1).await.unwrap(), Some(1));
+
+        fake.fail_next(usize::MAX);
+        assert!(fetch(2).await.is_err());
+        assert_eq!(client.health().state, CircuitState::Closed);
+        assert!(fetch(2).await.is_err());
+        assert_eq!(client.health().state, CircuitState::Open);
+        assert_eq!(fetch(3).await.unwrap(), Some(3), "the loader is called");
+        assert_eq!(client.health().consecutive_failures, 2);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(fetch(4).await.is_err(), "the probe fails");
+        assert_eq!(client.health().state, CircuitState::Open);
+        assert_eq!(client.health().opened, 2);
+
+        fake.fail_next(0);
+        assert_eq!(fetch(5).await.unwrap(), Some(5));
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(
+            fetch(6).await.unwrap(),
+            Some(1),
+            "the probe reads the cache"
+        );
+        assert_eq!(
+            client.health(),
+            Health {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened: 2,
+            }
+        );
+    }
+}
@@ -1,33 +1,106 @@
 use crate::{
-    error::{new_decode_error, new_encode_error, new_redis_error},
-    script::Script,
+    backend::{Args, CacheBackend, Reply, ScriptCall},
+    breaker::Breaker,
+    clock::{Clock, SystemClock},
+    coalesce::{FetchFlights, InvalidationFlights},
+    codec::Codec,
+    compression::{decompress, Compression},
+    encrypt::Encryptor,
+    error::{
+        new_config_error, new_decode_error, new_encode_error, new_key_error,
+        new_unexpected_reply_error,
+    },
+    executor::Executor,
+    hot_keys::HotKeySketch,
+    jitter::random_jitter,
+    journal::Journal,
+    limit::LoadLimits,
+    negative::NegativeCachePolicy,
+    pool,
+    retry::RetryPolicy,
+    runtime::{default_runtime, Runtime},
+    schedule::{RefreshRegistry, TaskSlot},
+    script::{all_scripts, Script},
+    stats::StatsCounters,
+    touch::TouchBatch,
+    wait::{LockWaitStrategy, LockWaits},
+    write_retry::{DroppedFn, Write, WriteRetry},
     Error, Result,
 };
-use chrono::Local;
-use rustis::{
-    commands::{CallBuilder, GenericCommands, ScriptingCommands},
-    resp::{CommandArgs, SingleArg, SingleArgCollection, Value},
+use futures::future::{self, join_all, Either};
+#[cfg(feature = "rustis")]
+use rustis::client::{ClusterConfig, Config, IntoConfig, SentinelConfig, ServerConfig};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Debug,
+    future::Future,
+    pin::pin,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
-use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt::Debug, future::Future, time::Duration};
 use uuid::Uuid;
 
-use crate::script::{DELETE_SCRIPT, GET_SCRIPT, SET_SCRIPT, UNLOCK_SCRIPT};
+use crate::script::{
+    DELETE_SCRIPT, EXISTS_SCRIPT, EXTEND_SCRIPT, INSPECT_SCRIPT, PUBLISH_SCRIPT,
+    REFRESH_LOCK_SCRIPT, REFRESH_SET_SCRIPT, REFRESH_UNLOCK_SCRIPT, SET_SCRIPT, TTL_SCRIPT,
+    UNLOCK_SCRIPT,
+};
 
-#[derive(Debug)]
+// Options can be loaded from a configuration file with serde, durations being written
+// like 300s and the missing options taking their default. Call validate on the loaded
+// options, or build the client with Options::from_env or Client::from_url instead.
+// The options of the local-cache feature are accepted and ignored without it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "local-cache"), serde(remote = "Self"))]
 pub struct Options {
     // Delay is the delay delete time for keys that are tag deleted. default is 10s
+    #[serde(with = "crate::config::duration")]
     pub delay: Duration,
     // EmptyExpire is the expire time for empty result. default is 60s
+    #[serde(with = "crate::config::duration")]
     pub empty_expire: Duration,
+    // NegativeCache is how empty results are cached, in their key for EmptyExpire, not
+    // at all or under a separate key. default is NegativeCachePolicy::Expire
+    pub negative_cache: NegativeCachePolicy,
     // LockExpire is the expire time for the lock which is allocated when updating cache. default is 3s
     // should be set to the max of the underling data calculating time.
+    // Lock timestamps are taken from the redis server time, in milliseconds, in seconds
+    // with GoCompat like the Go client, so clients of versions writing seconds must not
+    // share keys with this one outside of GoCompat. The Go client uses its own clock.
+    // Redis 5 or later is needed for the scripts to write after reading the time.
+    #[serde(with = "crate::config::duration")]
     pub lock_expire: Duration,
+    // LockRenewal extends the lock of a fetch every third of LockExpire while its loader
+    // runs, so that a loader running longer than LockExpire keeps the lock instead of
+    // another fetch taking it and both writing. The renewals stop once the loader
+    // returns, the fetch is dropped or the lock is lost to a tag_as_deleted. default is false
+    pub lock_renewal: bool,
+    // LoaderTimeout is how long a loader may run before the fetch releases the lock and
+    // fails with Error::LoaderTimeout, instead of holding the lock for LockExpire while
+    // e.g. a database query hangs. default is 0, unlimited
+    #[serde(with = "crate::config::duration")]
+    pub loader_timeout: Duration,
     // LockSleep is the sleep interval time if try lock failed. default is 100ms
+    #[serde(with = "crate::config::duration")]
     pub lock_sleep: Duration,
+    // LockWaitStrategy is how often a fetch polls a lock held by another fetch. default is
+    // LockWaitStrategy::Fixed, every LockSleep
+    pub lock_wait_strategy: LockWaitStrategy,
+    // LockWaitMaxAttempts is the number of polls after which a fetch waiting for a lock
+    // fails with Error::LockWaitTimeout. default is 0, unlimited
+    pub lock_wait_max_attempts: u32,
+    // LockWaitTimeout is how long a fetch waits for a lock before failing with
+    // Error::LockWaitTimeout. default is 0, unlimited
+    #[serde(with = "crate::config::duration")]
+    pub lock_wait_timeout: Duration,
     // RandomExpireAdjustment is the random adjustment for the expire time. default 0.1
     // if the expire time is set to 600s, and this value is set to 0.1, then the actual expire time will be 540s - 600s
-    // solve the problem of cache avalanche.
+    // solve the problem of cache avalanche. The adjustment is drawn for each write from
+    // the jitter of Client::with_jitter.
     pub random_expire_adjustment: f64,
     // CacheReadDisabled is the flag to disable read cache. default is false
     // when redis is down, set this flat to downgrade.
@@ -35,8 +108,149 @@ pub struct Options {
     // CacheDeleteDisabled is the flag to disable delete cache. default is false
     // when redis is down, set this flat to downgrade.
     pub disable_cache_delete: bool,
+    // FallbackToSourceOnRedisError makes fetch call the loader directly when a redis
+    // call fails, instead of failing, and return the loaded value without caching it.
+    // default is false
+    // The fetches falling back don't take the lock, so they all call their loader.
+    pub fallback_to_source_on_redis_error: bool,
     // CommonPrefix is the common prefix for all keys. default is ""
     pub common_prefix: String,
+    // Metadata is written next to every cached value as `meta:<name>` hash fields,
+    // e.g. the deploy version that produced the value. default is empty
+    pub metadata: Vec<(String, String)>,
+    // RefreshAhead is the fraction of the expire time below which a hit served by
+    // fetch_with_refresh reloads the value in the background. default is 0, disabled
+    // with 0.2 and an expire time of 600s, hits in the last 120s trigger a reload.
+    pub refresh_ahead: f64,
+    // InvalidationRetryMaxAge is how long a tag_as_deleted or a detached write failed on a
+    // redis error keeps being retried in the background. default is 60s, 0 disables the retries
+    #[serde(with = "crate::config::duration")]
+    pub invalidation_retry_max_age: Duration,
+    // DetachedWrite makes a fetch return the loaded value without waiting for it to be
+    // written to redis, saving a round trip on misses. default is false
+    // The lock is held until the write completes, so other fetches still wait for it.
+    pub detached_write: bool,
+    // SlidingExpiration extends the expire time of a value back to the full expire time
+    // each time fetch serves it from the cache, so hot keys only expire once they go
+    // cold. default is false
+    // Empty results and tag deleted values are not extended.
+    pub sliding_expiration: bool,
+    // TouchFlushInterval is how often the keys read with sliding expiration are
+    // extended, as one batch. default is 100ms, 0 extends each key on every read
+    #[serde(with = "crate::config::duration")]
+    pub touch_flush_interval: Duration,
+    // HotKeyCapacity is the number of keys tracked to find the most fetched ones,
+    // see Client::hot_keys. default is 0, disabled
+    pub hot_key_capacity: usize,
+    // GoCompat lets the client share keys with services running the Go rockscache
+    // client, e.g. during a migration. default is false
+    // Values are written without metadata, empty results are stored as an empty string
+    // like Go does, and refreshes reload the value through the lock instead of the
+    // refresh fields, which the Go client would not clear.
+    pub go_compat: bool,
+    // CoalesceLockWaits makes the fetches waiting for a lock held by another fetch poll
+    // it together, every LockSleep in one round trip, instead of each on its own timer.
+    // default is false
+    // A fetch starting to wait just before the shared tick polls sooner than LockSleep.
+    pub coalesce_lock_waits: bool,
+    // ReadOnlyHits makes fetch read the value with a plain HMGET first, and only run the
+    // locking GET script if it is missing, tag deleted or being reloaded. default is false
+    // Fresh hits then cost no write capable command, so they can be served by replicas,
+    // at the price of a second round trip on misses. fetch_with_refresh, which needs the
+    // ttl left, always runs the GET script.
+    pub read_only_hits: bool,
+    // CoalesceInvalidations makes the tag_as_deleted calls of a key made while one is
+    // running share the next DELETE, so a burst of writes to one row costs two redis
+    // calls. default is false
+    // A call is only covered by a DELETE sent after it was made, which keeps the
+    // guarantee of the delay window.
+    pub coalesce_invalidations: bool,
+    // CoalesceFetches makes the concurrent fetches of a key in this process share one
+    // fetch: the first one talks to redis and runs the loader, the others wait for its
+    // value. default is false
+    // The waiting fetches don't see an error of the running one, they fetch on their own.
+    pub coalesce_fetches: bool,
+    // BlockingThreshold is the size in bytes above which values are encoded and decoded
+    // with Runtime::block_in_place, so that large values don't stall the other tasks of
+    // the worker thread. On tokio that needs the multi thread runtime. default is 0, disabled
+    pub blocking_threshold: usize,
+    // RecoverOnDecodeError makes fetch reload a cached value it fails to decode, e.g.
+    // one written before a change of its type, instead of failing until it expires. The
+    // value is tag deleted and loaded again under its lock, once per fetch, and the
+    // loaded value overwrites it. default is false
+    pub recover_on_decode_error: bool,
+    // Compression is the algorithm compressing the values encoded in more than
+    // CompressionThreshold bytes, see Compression. default is Compression::None
+    // Values are only compressed if it makes them smaller, and never with go_compat.
+    pub compression: Compression,
+    // CompressionThreshold is the size in bytes above which values are compressed.
+    // default is 1024
+    pub compression_threshold: usize,
+    // InvalidationChannel is the pub/sub channel tag_as_deleted publishes the keys it tag
+    // deletes on, for the local tiers of the other instances to evict them, see
+    // Client::start_invalidation_subscriber. default is "", disabled
+    // The key is published in the round trip of the DELETE. Keys deleted by a flush, a
+    // journal replay or a retry are not published.
+    pub invalidation_channel: String,
+    // LocalTtl is how long the values fetched are kept in the in-process tier in front of
+    // redis, which fetch reads first. default is 0, disabled
+    // A value changed by another instance is served from the tier until it expires, and
+    // tag_as_deleted only evicts it from the tier of its own client.
+    #[cfg(feature = "local-cache")]
+    #[serde(with = "crate::config::duration")]
+    pub local_ttl: Duration,
+    // LocalCapacity is the number of values the local tier holds at most, the least
+    // recently used being evicted. default is 10000
+    #[cfg(feature = "local-cache")]
+    pub local_capacity: usize,
+    // LocalMemoryBudget is the approximate size in bytes of the keys and encoded values
+    // the local tier holds at most. default is 64MiB
+    // The tier is split in up to 16 shards, a value larger than the budget of a shard
+    // is not cached locally.
+    #[cfg(feature = "local-cache")]
+    pub local_memory_budget: usize,
+    // LocalMinFetches promotes to the local tier only the keys counted at least that
+    // many fetches by the hot key sketch of Options::hot_key_capacity, which must be
+    // set, so that the tier holds the hot keys. default is 0, all keys
+    // The counts are halved by the hot key report, see Client::start_hot_key_report.
+    #[cfg(feature = "local-cache")]
+    pub local_min_fetches: u32,
+    // MaxBackgroundTasks caps the background refreshes and writes running at once,
+    // the others wait for one to finish. default is 64
+    pub max_background_tasks: usize,
+    // MaxConcurrentLoads caps the loaders running at once, the others wait for one to
+    // finish, so that a cold cache doesn't send every miss to the data source at once.
+    // default is 0, unlimited
+    // A fetch waiting for a permit holds the lock of its key, which expires after
+    // lock_expire unless lock_renewal is set.
+    pub max_concurrent_loads: usize,
+    // MaxConcurrentLoadsByPrefix caps the loaders running at once for the keys starting
+    // with a prefix, without common_prefix, the longest prefix of a key applying. They
+    // also count in max_concurrent_loads. default is empty
+    pub max_concurrent_loads_by_prefix: Vec<(String, usize)>,
+    // Cluster splits the multi key scripts and commands of fetch_batch and of the
+    // sliding expiration touches into one call per hash slot, for redis cluster, which
+    // rejects the keys of several slots with CROSSSLOT. default is false
+    // Keys sharing a `{hash_tag}` are in one slot, so they still take one call.
+    pub cluster: bool,
+    // RedisRetry retries the redis calls failing with a transient error, e.g. a dropped
+    // connection or a failover, so that it doesn't fail the fetch. default is
+    // RetryPolicy::default(), no retry
+    // The scripts can be run twice by a retry, they are written to allow it.
+    pub redis_retry: RetryPolicy,
+    // CircuitBreakerThreshold is the number of redis script calls failing in a row that
+    // opens the circuit breaker: fetches then call their loader without the cache, like
+    // with disable_cache_read, see Client::health. default is 0, disabled
+    // Invalidations still go to redis, failing ones are retried like when it is closed.
+    pub circuit_breaker_threshold: u32,
+    // CircuitBreakerOpen is how long the breaker stays open before a fetch probes redis,
+    // closing it if its first call succeeds. default is 5s
+    #[serde(with = "crate::config::duration")]
+    pub circuit_breaker_open: Duration,
+    // CircuitBreakerSlowCall counts the redis calls slower than it as failures, a
+    // degraded redis being bypassed like a down one. default is 0, disabled
+    #[serde(with = "crate::config::duration")]
+    pub circuit_breaker_slow_call: Duration,
 }
 
 impl Default for Options {
@@ -44,32 +258,488 @@ impl Default for Options {
         Self {
             delay: Duration::from_secs(10),
             empty_expire: Duration::from_secs(60),
+            negative_cache: NegativeCachePolicy::Expire,
             lock_expire: Duration::from_secs(3),
+            lock_renewal: false,
+            loader_timeout: Duration::ZERO,
             lock_sleep: Duration::from_millis(100),
+            lock_wait_strategy: LockWaitStrategy::Fixed,
+            lock_wait_max_attempts: 0,
+            lock_wait_timeout: Duration::ZERO,
             random_expire_adjustment: 0.1,
             disable_cache_read: false,
             disable_cache_delete: false,
+            fallback_to_source_on_redis_error: false,
             common_prefix: "".to_string(),
+            metadata: Vec::new(),
+            refresh_ahead: 0.0,
+            invalidation_retry_max_age: Duration::from_secs(60),
+            detached_write: false,
+            sliding_expiration: false,
+            touch_flush_interval: Duration::from_millis(100),
+            hot_key_capacity: 0,
+            go_compat: false,
+            coalesce_lock_waits: false,
+            read_only_hits: false,
+            coalesce_invalidations: false,
+            coalesce_fetches: false,
+            invalidation_channel: "".to_string(),
+            blocking_threshold: 0,
+            recover_on_decode_error: false,
+            compression: Compression::None,
+            compression_threshold: 1024,
+            #[cfg(feature = "local-cache")]
+            local_ttl: Duration::ZERO,
+            #[cfg(feature = "local-cache")]
+            local_capacity: 10_000,
+            #[cfg(feature = "local-cache")]
+            local_memory_budget: 64 << 20,
+            #[cfg(feature = "local-cache")]
+            local_min_fetches: 0,
+            max_background_tasks: 64,
+            max_concurrent_loads: 0,
+            max_concurrent_loads_by_prefix: Vec::new(),
+            cluster: false,
+            redis_retry: RetryPolicy::default(),
+            circuit_breaker_threshold: 0,
+            circuit_breaker_open: Duration::from_secs(5),
+            circuit_breaker_slow_call: Duration::ZERO,
+        }
+    }
+}
+
+impl Options {
+    // builder starts from the default options, build validates them.
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder {
+            options: Options::default(),
+        }
+    }
+
+    // validate checks the options, returning Error::ConfigError for the first invalid
+    // one. The expire time of a fetch must also be over Delay plus the random
+    // adjustment, which is checked by the fetch.
+    pub fn validate(&self) -> Result<()> {
+        if self.delay.is_zero() {
+            return Err(new_config_error("delay must be over 0".to_string()));
+        }
+        if !(0.0..1.0).contains(&self.random_expire_adjustment) {
+            return Err(new_config_error(format!(
+                "random_expire_adjustment {} is not in [0, 1)",
+                self.random_expire_adjustment
+            )));
+        }
+        if self.lock_expire <= self.lock_sleep {
+            return Err(new_config_error(format!(
+                "lock_expire {:?} must be over lock_sleep {:?}",
+                self.lock_expire, self.lock_sleep
+            )));
+        }
+        if let Some((prefix, _)) = self
+            .max_concurrent_loads_by_prefix
+            .iter()
+            .find(|(_, max)| *max == 0)
+        {
+            return Err(new_config_error(format!(
+                "max_concurrent_loads of prefix {} must be over 0",
+                prefix
+            )));
+        }
+        if self.redis_retry.attempts == 0 {
+            return Err(new_config_error(
+                "redis_retry attempts must be over 0".to_string(),
+            ));
+        }
+        if self.negative_cache == NegativeCachePolicy::Suffix(String::new()) {
+            return Err(new_config_error(
+                "negative cache suffix must not be empty".to_string(),
+            ));
+        }
+        #[cfg(feature = "local-cache")]
+        if self.local_min_fetches > 0 && self.hot_key_capacity == 0 {
+            return Err(new_config_error(
+                "local_min_fetches needs hot_key_capacity".to_string(),
+            ));
+        }
+        if let LockWaitStrategy::Exponential { jitter, .. } = self.lock_wait_strategy {
+            if !(0.0..=1.0).contains(&jitter) {
+                return Err(new_config_error(format!(
+                    "lock wait jitter {} is not in [0, 1]",
+                    jitter
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// OptionsBuilder builds validated Options, from Options::builder. Each option is set
+// by the method of the same name.
+#[derive(Debug, Clone)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+macro_rules! options_setters {
+    ($($(#[$attr:meta])* $field:ident: $t:ty),* $(,)?) => {
+        $($(#[$attr])*
+        pub fn $field(mut self, $field: impl Into<$t>) -> Self {
+            self.options.$field = $field.into();
+            self
+        })*
+    };
+}
+
+impl OptionsBuilder {
+    options_setters!(
+        delay: Duration,
+        empty_expire: Duration,
+        negative_cache: NegativeCachePolicy,
+        lock_expire: Duration,
+        lock_renewal: bool,
+        loader_timeout: Duration,
+        lock_sleep: Duration,
+        lock_wait_strategy: LockWaitStrategy,
+        lock_wait_max_attempts: u32,
+        lock_wait_timeout: Duration,
+        random_expire_adjustment: f64,
+        disable_cache_read: bool,
+        disable_cache_delete: bool,
+        fallback_to_source_on_redis_error: bool,
+        common_prefix: String,
+        metadata: Vec<(String, String)>,
+        refresh_ahead: f64,
+        invalidation_retry_max_age: Duration,
+        detached_write: bool,
+        sliding_expiration: bool,
+        touch_flush_interval: Duration,
+        hot_key_capacity: usize,
+        go_compat: bool,
+        coalesce_lock_waits: bool,
+        read_only_hits: bool,
+        coalesce_invalidations: bool,
+        coalesce_fetches: bool,
+        blocking_threshold: usize,
+        recover_on_decode_error: bool,
+        compression: Compression,
+        compression_threshold: usize,
+        invalidation_channel: String,
+        #[cfg(feature = "local-cache")]
+        local_ttl: Duration,
+        #[cfg(feature = "local-cache")]
+        local_capacity: usize,
+        #[cfg(feature = "local-cache")]
+        local_memory_budget: usize,
+        #[cfg(feature = "local-cache")]
+        local_min_fetches: u32,
+        max_background_tasks: usize,
+        max_concurrent_loads: usize,
+        max_concurrent_loads_by_prefix: Vec<(String, usize)>,
+        cluster: bool,
+        redis_retry: RetryPolicy,
+        circuit_breaker_threshold: u32,
+        circuit_breaker_open: Duration,
+        circuit_breaker_slow_call: Duration,
+    );
+
+    // build returns the options, or Error::ConfigError if they are invalid.
+    pub fn build(self) -> Result<Options> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+// KeyInfo is a snapshot of the cache entry stored under a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyInfo {
+    // Exists is false if there is no entry for the key at all.
+    pub exists: bool,
+    // Ttl is the remaining time to live, None if the key doesn't exist or has no expire.
+    pub ttl: Option<Duration>,
+    // HasValue is true if a value (including the empty result) has been written.
+    pub has_value: bool,
+    // Locked is true if an owner currently holds the update lock.
+    pub locked: bool,
+    // LockUntil is the unix time in milliseconds the lock is held until, in seconds with
+    // Options::go_compat, 0 if the key is tag deleted.
+    pub lock_until: Option<u64>,
+    // LockOwner is the id of the fetch holding the lock.
+    pub lock_owner: Option<String>,
+    // Metadata holds the metadata fields written with the value.
+    pub metadata: BTreeMap<String, String>,
+}
+
+// FetchOptions overrides options of the client for one call of Client::fetch_with.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    // EmptyExpire overrides Options::empty_expire, Some(Duration::ZERO) doesn't cache
+    // empty results. default is None
+    pub empty_expire: Option<Duration>,
+    // NegativeCache overrides Options::negative_cache. default is None
+    pub negative_cache: Option<NegativeCachePolicy>,
+    // LockExpire overrides Options::lock_expire. default is None
+    pub lock_expire: Option<Duration>,
+    // LoaderTimeout overrides Options::loader_timeout. default is None
+    pub loader_timeout: Option<Duration>,
+    // Strong fetches with the strong consistency of fetch, false with the weak one of
+    // fetch_weak. default is true
+    pub strong: bool,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            empty_expire: None,
+            negative_cache: None,
+            lock_expire: None,
+            loader_timeout: None,
+            strong: true,
         }
     }
 }
 
+type OwnerIdFn = dyn Fn() -> String + Send + Sync;
+
+// LoadedTtl is where the loader of fetch_with_ttl leaves the ttl of its value.
+type LoadedTtl = std::sync::Mutex<Option<Duration>>;
+
+type JitterFn = dyn Fn() -> f64 + Send + Sync;
+
+// LocalWrite is the encoded value a SET caches in the local tier once it is confirmed,
+// with the invalidation generation of its key, see Client::local_write.
+type LocalWrite = Option<(u64, Vec<u8>)>;
+
+// Client is cheap to clone: clones share the connection, the options, the background
+// tasks, the stats and everything else, so it can be moved into tasks without an Arc.
+// The background tasks stop when the last clone is dropped.
+#[derive(Clone)]
 pub struct Client {
-    rdb: rustis::client::Client,
-    pub options: Options,
+    pub(crate) backend: Arc<dyn CacheBackend>,
+    pub(crate) options: Arc<RwLock<Arc<Options>>>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) owner_id: Arc<OwnerIdFn>,
+    pub(crate) jitter: Arc<JitterFn>,
+    pub(crate) refreshes: Arc<RefreshRegistry>,
+    pub(crate) flushes: Arc<RefreshRegistry>,
+    pub(crate) write_retry: Arc<WriteRetry>,
+    pub(crate) on_invalidation_dropped: Option<Arc<DroppedFn>>,
+    pub(crate) janitor: Arc<TaskSlot>,
+    pub(crate) executor: Arc<Executor>,
+    pub(crate) stats: Arc<StatsCounters>,
+    pub(crate) breaker: Arc<Breaker>,
+    pub(crate) load_limits: Arc<LoadLimits>,
+    pub(crate) stats_report: Arc<TaskSlot>,
+    pub(crate) touches: Arc<TouchBatch>,
+    pub(crate) lock_waits: Arc<LockWaits>,
+    pub(crate) invalidations: Arc<InvalidationFlights>,
+    pub(crate) fetches: Arc<FetchFlights>,
+    pub(crate) codec: Option<Arc<dyn Codec>>,
+    pub(crate) encryptor: Option<Arc<dyn Encryptor>>,
+    #[cfg(feature = "zstd")]
+    pub(crate) dictionaries: Arc<crate::compress::Dictionaries>,
+    #[cfg(feature = "local-cache")]
+    pub(crate) local: Option<Arc<crate::local::LocalCache>>,
+    #[cfg(feature = "local-cache")]
+    pub(crate) subscriber: Arc<TaskSlot>,
+    pub(crate) journal: Option<Arc<Journal>>,
+    pub(crate) hot_keys: Arc<HotKeySketch>,
+    pub(crate) hot_key_report: Arc<TaskSlot>,
 }
 
 impl Client {
+    // new creates a client on the rustis client rdb, with the rustis feature.
+    #[cfg(feature = "rustis")]
     pub fn new(rdb: rustis::client::Client, options: Options) -> Self {
-        Self { rdb, options }
+        Self::with_backend(crate::RustisBackend::new(rdb), options)
+    }
+
+    // connect connects to redis with config, e.g. a url: redis://host:6379 for a single
+    // server, redis+cluster://host1:6379,host2:6379 for a cluster, which also sets
+    // Options::cluster, or redis+sentinel://host1:26379,host2:26379/master for the master
+    // of sentinels. rustis routes the scripts of a key to the node of its slot.
+    #[cfg(feature = "rustis")]
+    pub async fn connect(config: impl IntoConfig, mut options: Options) -> Result<Self> {
+        let config = config
+            .into_config()
+            .map_err(|e| new_config_error(e.to_string()))?;
+        options.cluster |= matches!(config.server, ServerConfig::Cluster(_));
+        let rdb = rustis::client::Client::connect(config)
+            .await
+            .map_err(crate::error::new_redis_error)?;
+        Ok(Self::new(rdb, options))
+    }
+
+    // connect_cluster connects to the redis cluster of nodes, see connect.
+    #[cfg(feature = "rustis")]
+    pub async fn connect_cluster(nodes: Vec<(String, u16)>, options: Options) -> Result<Self> {
+        let config = Config {
+            server: ServerConfig::Cluster(ClusterConfig { nodes }),
+            ..Default::default()
+        };
+        Self::connect(config, options).await
+    }
+
+    // connect_sentinel connects to the master named master_name, found through the
+    // sentinels, see connect.
+    #[cfg(feature = "rustis")]
+    pub async fn connect_sentinel(
+        sentinels: Vec<(String, u16)>,
+        master_name: impl Into<String>,
+        options: Options,
+    ) -> Result<Self> {
+        let config = Config {
+            server: ServerConfig::Sentinel(SentinelConfig {
+                instances: sentinels,
+                service_name: master_name.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        Self::connect(config, options).await
+    }
+
+    // with_backend creates a client talking to redis through backend instead of rustis,
+    // the only way to create one without the rustis feature.
+    pub fn with_backend(backend: impl CacheBackend, options: Options) -> Self {
+        let executor = Arc::new(Executor::new(
+            options.max_background_tasks,
+            default_runtime(),
+        ));
+        let hot_keys = Arc::new(HotKeySketch::new(options.hot_key_capacity));
+        let load_limits = Arc::new(LoadLimits::new(&options));
+        #[cfg(feature = "local-cache")]
+        let local = (!options.local_ttl.is_zero()).then(|| {
+            Arc::new(crate::local::LocalCache::new(
+                options.local_ttl,
+                options.local_capacity,
+                options.local_memory_budget,
+            ))
+        });
+        let options = Arc::new(RwLock::new(Arc::new(options)));
+        let lock_waits = Arc::new(LockWaits::new(options.clone()));
+        Self {
+            backend: Arc::new(backend),
+            options,
+            clock: Arc::new(SystemClock),
+            owner_id: Arc::new(|| Uuid::new_v4().simple().to_string()),
+            jitter: Arc::new(random_jitter),
+            refreshes: Arc::default(),
+            flushes: Arc::default(),
+            write_retry: Arc::default(),
+            janitor: Arc::default(),
+            executor,
+            stats: Arc::default(),
+            breaker: Arc::default(),
+            load_limits,
+            stats_report: Arc::default(),
+            touches: Arc::default(),
+            lock_waits,
+            invalidations: Arc::default(),
+            fetches: Arc::default(),
+            codec: None,
+            encryptor: None,
+            #[cfg(feature = "zstd")]
+            dictionaries: Arc::default(),
+            #[cfg(feature = "local-cache")]
+            local,
+            #[cfg(feature = "local-cache")]
+            subscriber: Arc::default(),
+            journal: None,
+            hot_keys,
+            hot_key_report: Arc::default(),
+            on_invalidation_dropped: None,
+        }
+    }
+
+    // options returns the current options of the client.
+    pub fn options(&self) -> Arc<Options> {
+        self.options.read().unwrap().clone()
+    }
+
+    // update_options changes the options of the client and its clones while it runs,
+    // e.g. from an admin endpoint setting disable_cache_read or changing lock_sleep or
+    // empty_expire. The fetches running read the options anew at each step, e.g. a
+    // loader running when empty_expire changes stores its empty result for the updated
+    // empty_expire. It fails with Error::ConfigError, changing nothing, if the updated
+    // options are invalid. The options sizing what the client creates, like
+    // max_background_tasks, max_concurrent_loads, hot_key_capacity and the local cache
+    // ones, keep the values the client was created with.
+    pub fn update_options(&self, update: impl FnOnce(&mut Options)) -> Result<()> {
+        let mut options = self.options.write().unwrap();
+        let mut updated = Options::clone(&options);
+        update(&mut updated);
+        updated.validate()?;
+        *options = Arc::new(updated);
+        Ok(())
+    }
+
+    // with_clock replaces the clock of the application server, see Clock.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // with_runtime runs the timers and background tasks of the client on runtime
+    // instead of the default one. It must be called before the client is used.
+    pub fn with_runtime(mut self, runtime: impl Runtime) -> Self {
+        self.executor = Arc::new(Executor::new(
+            self.options().max_background_tasks,
+            Arc::new(runtime),
+        ));
+        self
+    }
+
+    // with_owner_id replaces the generator of lock owner ids, random uuids by default.
+    pub fn with_owner_id(mut self, owner_id: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        self.owner_id = Arc::new(owner_id);
+        self
+    }
+
+    // with_jitter replaces the source of the random numbers in [0, 1) the expire time
+    // adjustment and the lock wait jitter are drawn from, random by default. See
+    // seeded_jitter for a reproducible one.
+    pub fn with_jitter(mut self, jitter: impl Fn() -> f64 + Send + Sync + 'static) -> Self {
+        self.jitter = Arc::new(jitter);
+        self
+    }
+
+    // with_codec encodes the values with codec instead of MessagePack, e.g. to share
+    // them with services in other languages. Readers of the same keys must use the same
+    // codec.
+    pub fn with_codec(mut self, codec: impl Codec) -> Self {
+        self.codec = Some(Arc::new(codec));
+        self
     }
+
+    // preload_scripts loads the rdcache scripts into the script cache of redis, e.g. at
+    // startup, so that the first fetches don't send their source. A script missing from
+    // the cache later is still run with EVAL once.
+    pub async fn preload_scripts(&self) -> Result<()> {
+        let result = self.backend.load_scripts(&all_scripts()).await;
+        if result.as_ref().is_err_and(Error::is_redis_error) {
+            self.stats.redis_error();
+        }
+        result
+    }
+
+    // raw_client returns the rustis client. It panics if the client runs on another
+    // backend, see try_raw_client.
+    #[cfg(feature = "rustis")]
     pub fn raw_client(&self) -> &rustis::client::Client {
-        &self.rdb
+        self.try_raw_client()
+            .expect("the client runs on a backend other than rustis")
+    }
+
+    // try_raw_client returns the rustis client, None if the client runs on another
+    // backend.
+    #[cfg(feature = "rustis")]
+    pub fn try_raw_client(&self) -> Option<&rustis::client::Client> {
+        crate::backend::as_rustis(self.backend.as_ref())
     }
 
     pub async fn fetch<F, Fut, V>(
         &self,
-        key: impl Into<String>,
+        key: impl AsRef<str>,
         expire: Duration,
         f: F,
     ) -> Result<Option<V>>
@@ -78,88 +748,655 @@ impl Client {
         Fut: Future<Output = Result<Option<V>>>,
         V: DeserializeOwned + Serialize + Debug,
     {
-        let mut key = key.into();
-        if !self.options.common_prefix.is_empty() {
-            key = format!("{}{}", self.options.common_prefix, key);
-        }
-        let ex = expire
-            - self.options.delay
-            - Duration::from_secs(
-                (self.options.random_expire_adjustment * expire.as_secs() as f64) as u64,
-            );
-        if self.options.disable_cache_read {
-            f().await
+        self.fetch_with_metadata(key, expire, &[], f).await
+    }
+
+    // fetch_with_metadata is fetch, additionally writing metadata as `meta:<name>`
+    // fields if the value is recomputed. It is merged with Options::metadata.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "fetch", skip_all, fields(key = key.as_ref()), err)
+    )]
+    pub async fn fetch_with_metadata<F, Fut, V>(
+        &self,
+        key: impl AsRef<str>,
+        expire: Duration,
+        metadata: &[(&str, &str)],
+        f: F,
+    ) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let key = self.borrowed_key(key.as_ref());
+        let ex = self.value_expire(expire)?;
+        self.hot_keys.record(&key);
+        if self.cache_bypassed() {
+            return f().await;
+        }
+        // the loader is kept to call it directly if redis fails before it is called.
+        let mut source = Some(f);
+        let f = || source.take().expect("the loader is called once")();
+        let result = if self.options().coalesce_fetches {
+            self.coalesced_fetch(&key, ex, metadata, f).await
+        } else {
+            self.strong_fetch(&key, ex, metadata, f).await
+        };
+        match (result, source) {
+            (Err(e), Some(f))
+                if e.is_redis_error() && self.options().fallback_to_source_on_redis_error =>
+            {
+                self.stats.miss();
+                f().await
+            }
+            (result, _) => result,
+        }
+    }
+
+    // fetch_with_ttl is fetch with the expire time of the value chosen by its loader,
+    // e.g. the expiry of a session: f returns the value with its ttl. Options::delay is
+    // taken off the ttl like off the expire of fetch, and a value whose ttl is not over
+    // it is returned without being cached. Empty results are cached for
+    // Options::empty_expire. Options::sliding_expiration doesn't apply.
+    pub async fn fetch_with_ttl<F, Fut, V>(&self, key: impl AsRef<str>, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<(V, Duration)>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let key = self.borrowed_key(key.as_ref());
+        self.hot_keys.record(&key);
+        if self.cache_bypassed() {
+            return Ok(f().await?.map(|(value, _)| value));
+        }
+        #[cfg(feature = "local-cache")]
+        if let Some(value) = self.local_get(&key)? {
+            return Ok(value);
+        }
+        let loaded_ttl = LoadedTtl::default();
+        let load = || async {
+            let loaded = f().await?;
+            Ok(loaded.map(|(value, ttl)| {
+                *loaded_ttl.lock().unwrap() = Some(ttl);
+                value
+            }))
+        };
+        // a zero expire never touches the value, the ttl is the one of the loader.
+        let (value, _) = self
+            .strong_fetch_hit(&key, Duration::ZERO, &[], Some(&loaded_ttl), load)
+            .await?;
+        Ok(value)
+    }
+
+    // fetch_weak is fetch in the weak consistency mode of rockscache: a tag deleted value
+    // is returned right away, stale, and reloaded in the background by the fetch that
+    // takes its lock, readers only wait if there is no value at all. Values are served
+    // stale for up to the reload time after tag_as_deleted.
+    pub async fn fetch_weak<F, Fut, V>(
+        &self,
+        key: impl AsRef<str>,
+        expire: Duration,
+        f: F,
+    ) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Option<V>>> + Send + 'static,
+        V: DeserializeOwned + Serialize + Debug + Send + 'static,
+    {
+        let key = self.borrowed_key(key.as_ref());
+        let ex = self.value_expire(expire)?;
+        self.hot_keys.record(&key);
+        if self.cache_bypassed() {
+            return f().await;
+        }
+        let owner = (self.owner_id)();
+        let (mut value, mut lock_until, _) = self.lua_get(&key, &owner).await?;
+        let mut wait = self.lock_wait();
+        while value.is_none() && lock_until.as_deref() != Some("LOCKED") {
+            (value, lock_until, _) = self.wait_get(&key, &owner, &mut wait).await?;
+        }
+        self.end_lock_wait(&wait);
+        let Some(s) = value else {
+            if self.hit_marked_empty(&key, &owner).await? {
+                return Ok(None);
+            }
+            self.stats.miss();
+            return self.fetch_new(&key, ex, &owner, &[], None, f).await;
+        };
+        if lock_until.as_deref() == Some("LOCKED") {
+            if self.executor.is_closed() {
+                self.stats.miss();
+                return self.fetch_new(&key, ex, &owner, &[], None, f).await;
+            }
+            let client = self.detach();
+            let key = key.clone().into_owned();
+            self.executor.spawn(async move {
+                _ = client.fetch_new(&key, ex, &owner, &[], None, f).await;
+            });
+        }
+        let value: Option<V> = self.decode_value(&key, &s)?;
+        self.stats.hit();
+        if self.options().sliding_expiration && lock_until.is_none() && value.is_some() {
+            self.touch(&key, ex).await;
+        }
+        Ok(value)
+    }
+
+    // fetch_with is fetch, or fetch_weak, with options overridden for this call by
+    // options, so that call sites don't need a client of their own. It is an
+    // Error::ConfigError if the overridden options are invalid.
+    pub async fn fetch_with<F, Fut, V>(
+        &self,
+        key: impl AsRef<str>,
+        expire: Duration,
+        options: FetchOptions,
+        f: F,
+    ) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Option<V>>> + Send + 'static,
+        V: DeserializeOwned + Serialize + Debug + Send + 'static,
+    {
+        let overridden = self.with_fetch_options(&options)?;
+        let client = overridden.as_ref().unwrap_or(self);
+        if options.strong {
+            client.fetch(key, expire, f).await
         } else {
-            self.strong_fetch(&key, ex, f).await
+            client.fetch_weak(key, expire, f).await
+        }
+    }
+
+    // with_fetch_options returns a client sharing the state of self with the overrides
+    // of options applied, None if there are none. Waits for a lock expiring at another time than the
+    // one of the client are not coalesced.
+    fn with_fetch_options(&self, options: &FetchOptions) -> Result<Option<Client>> {
+        if options.empty_expire.is_none()
+            && options.negative_cache.is_none()
+            && options.lock_expire.is_none()
+            && options.loader_timeout.is_none()
+        {
+            return Ok(None);
+        }
+        let mut overridden = Options::clone(&self.options());
+        if let Some(empty_expire) = options.empty_expire {
+            overridden.empty_expire = empty_expire;
+        }
+        if let Some(negative_cache) = &options.negative_cache {
+            overridden.negative_cache = negative_cache.clone();
+        }
+        if let Some(lock_expire) = options.lock_expire {
+            overridden.lock_expire = lock_expire;
+            overridden.coalesce_lock_waits &= lock_expire == self.options().lock_expire;
+        }
+        if let Some(loader_timeout) = options.loader_timeout {
+            overridden.loader_timeout = loader_timeout;
+        }
+        overridden.validate()?;
+        Ok(Some(Client {
+            options: Arc::new(RwLock::new(Arc::new(overridden))),
+            write_retry: self.write_retry.clone(),
+            touches: self.touches.clone(),
+            lock_waits: self.lock_waits.clone(),
+            ..self.detach()
+        }))
+    }
+
+    // fetch_with_refresh is fetch, additionally reloading the value in the background
+    // when a hit finds less than Options::refresh_ahead of the expire time left, so that
+    // hot keys are reloaded before they expire. Readers keep getting the cached value
+    // during the reload, and a value tag deleted meanwhile is not overwritten.
+    pub async fn fetch_with_refresh<F, Fut, V>(
+        &self,
+        key: impl AsRef<str>,
+        expire: Duration,
+        f: F,
+    ) -> Result<Option<V>>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Option<V>>> + Send + 'static,
+        V: DeserializeOwned + Serialize + Debug + Send + 'static,
+    {
+        let key = self.prefixed_key(key.as_ref());
+        let ex = self.value_expire(expire)?;
+        self.hot_keys.record(&key);
+        if self.cache_bypassed() {
+            return f().await;
+        }
+        let (value, ttl) = self.strong_fetch_hit(&key, ex, &[], None, &f).await?;
+        let threshold = ex.mul_f64(self.options().refresh_ahead.clamp(0.0, 1.0));
+        if ttl.is_some_and(|ttl| ttl < threshold) {
+            let client = self.detach();
+            self.executor.spawn(async move {
+                _ = client.refresh(&key, ex, f).await;
+            });
         }
+        Ok(value)
     }
 
-    pub async fn tag_as_deleted(&self, key: impl Into<String>) -> Result<()> {
-        if self.options.disable_cache_delete {
+    // tag_as_deleted marks the value of key as deleted, it is reloaded by the next fetch
+    // and removed after Options::delay. If redis fails, the invalidation is also retried
+    // in the background for up to Options::invalidation_retry_max_age, and journaled
+    // if the client has an invalidation journal.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(key = tracing::field::Empty), err)
+    )]
+    pub async fn tag_as_deleted(&self, key: impl AsRef<str>) -> Result<()> {
+        if self.options().disable_cache_delete {
             return Ok(());
         }
-        let mut key = key.into();
-        if !self.options.common_prefix.is_empty() {
-            key = format!("{}{}", self.options.common_prefix, key);
+        let key = self.prefixed_key(key.as_ref());
+        record_span("key", &key);
+        self.journal_begin(&key)?;
+        if self.options().coalesce_invalidations {
+            return self.coalesced_invalidate(key).await;
         }
-        self.call_lua(
-            &DELETE_SCRIPT,
-            CommandArgs::default().arg(key).build(),
-            CommandArgs::default()
-                .arg(self.options.delay.as_secs())
-                .build(),
-        )
-        .await?;
+        self.invalidate(key).await
+    }
+
+    // invalidate tag deletes key, queueing it for a retry if redis fails.
+    pub(crate) async fn invalidate(&self, key: String) -> Result<()> {
+        match self.delete_key(&key).await {
+            Ok(()) => {
+                self.journal_done(&key);
+                Ok(())
+            }
+            Err(e)
+                if e.is_redis_error() && !self.options().invalidation_retry_max_age.is_zero() =>
+            {
+                self.retry_write(key, Write::Delete);
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    pub(crate) async fn delete_key(&self, key: &str) -> Result<()> {
+        #[cfg(feature = "local-cache")]
+        self.local_remove(key);
+        if let Some(marker) = self.empty_marker(key) {
+            self.with_redis_retry(|| self.backend.del(vec![marker.clone()]))
+                .await?;
+        }
+        let call = self.delete_call(key.to_string());
+        if self.options().invalidation_channel.is_empty() {
+            self.call_lua(call.script, call.keys, call.args).await?;
+            return Ok(());
+        }
+        // a failed PUBLISH only leaves the value in the other local tiers until it expires.
+        let replies = self
+            .call_lua_pipeline(vec![call, self.publish_call(key)])
+            .await;
+        replies.into_iter().next().unwrap_or(Ok(Reply::Nil))?;
         Ok(())
     }
 
-    async fn strong_fetch<F, Fut, V>(&self, key: &str, expire: Duration, f: F) -> Result<Option<V>>
+    // delete removes key right away with DEL, unlike tag_as_deleted, for values that
+    // must not be served again, not even stale, e.g. on an erasure request. A fetch
+    // loading key meanwhile doesn't write it back. It returns whether key existed, and
+    // does nothing with Options::disable_cache_delete.
+    pub async fn delete(&self, key: impl AsRef<str>) -> Result<bool> {
+        Ok(self.delete_batch(&[key]).await? == 1)
+    }
+
+    // delete_batch is delete for keys, with one DEL per slot with Options::cluster. It
+    // returns the number of keys that existed. The keys are published on
+    // Options::invalidation_channel, a failed PUBLISH is ignored.
+    pub async fn delete_batch(&self, keys: &[impl AsRef<str>]) -> Result<u64> {
+        if self.options().disable_cache_delete || keys.is_empty() {
+            return Ok(0);
+        }
+        let keys: Vec<String> = keys
+            .iter()
+            .map(|key| self.prefixed_key(key.as_ref()))
+            .collect();
+        #[cfg(feature = "local-cache")]
+        for key in &keys {
+            self.local_remove(key);
+        }
+        let markers: Vec<String> = keys.iter().filter_map(|k| self.empty_marker(k)).collect();
+        if !markers.is_empty() {
+            self.del_by_slot(markers).await?;
+        }
+        let deleted = self.del_by_slot(keys.clone()).await;
+        if deleted.as_ref().is_err_and(Error::is_redis_error) {
+            self.stats.redis_error();
+        }
+        if !self.options().invalidation_channel.is_empty() {
+            let calls = keys.iter().map(|key| self.publish_call(key)).collect();
+            _ = self.call_lua_pipeline(calls).await;
+        }
+        deleted
+    }
+
+    // inspect returns a snapshot of the entry of key: whether it exists and has a value,
+    // its ttl, its lock and its metadata, for operators and health checks. It neither
+    // takes the lock nor decodes the value.
+    pub async fn inspect(&self, key: impl AsRef<str>) -> Result<KeyInfo> {
+        let key = self.prefixed_key(key.as_ref());
+        let reply = self
+            .call_lua(
+                &INSPECT_SCRIPT,
+                vec![key],
+                Args::default().arg(self.lock_unit()).build(),
+            )
+            .await?;
+        let [pttl, lock_until, lock_owner, has_value, locked, metadata] =
+            <[Reply; 6]>::try_from(reply.into_array()?)
+                .map_err(|r| new_unexpected_reply_error(Reply::Array(r)))?;
+        let pttl = pttl.as_int()?;
+        let metadata = metadata
+            .into_array()?
+            .into_iter()
+            .map(|r| r.into_string().map(Option::unwrap_or_default))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(KeyInfo {
+            exists: pttl != -2,
+            ttl: (pttl >= 0).then(|| Duration::from_millis(pttl as u64)),
+            has_value: has_value.as_int()? == 1,
+            locked: locked.as_int()? == 1,
+            lock_until: lock_until.into_string()?.and_then(|lu| lu.parse().ok()),
+            lock_owner: lock_owner.into_string()?,
+            metadata: metadata
+                .chunks_exact(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect(),
+        })
+    }
+
+    // exists reports whether a fresh, non-empty value is cached under key.
+    // It neither takes the lock nor decodes the value, and returns false for
+    // cached empty results, tag deleted values and keys that only hold a lock.
+    pub async fn exists(&self, key: impl AsRef<str>) -> Result<bool> {
+        let key = self.prefixed_key(key.as_ref());
+        let empty = self.encode_value(&key, &None::<()>)?;
+        let exists = self
+            .call_lua(
+                &EXISTS_SCRIPT,
+                vec![key],
+                Args::default().arg(empty).build(),
+            )
+            .await?;
+        Ok(exists.as_int()? == 1)
+    }
+
+    // ttl returns the time to live left of key, None if there is no key or it has no
+    // expire. A tag deleted key is still there until Options::delay has passed.
+    pub async fn ttl(&self, key: impl AsRef<str>) -> Result<Option<Duration>> {
+        let key = self.prefixed_key(key.as_ref());
+        let pttl = self
+            .call_lua(&TTL_SCRIPT, vec![key], Vec::new())
+            .await?
+            .as_int()?;
+        Ok((pttl >= 0).then(|| Duration::from_millis(pttl as u64)))
+    }
+
+    // value_expire is the ttl written for a value fetched with expire: expire shortened
+    // by a random fraction of up to Options::random_expire_adjustment, so that the keys
+    // fetched together don't expire together, minus Options::delay. It is an
+    // Error::ConfigError if expire is not over the delay plus the largest adjustment.
+    pub(crate) fn value_expire(&self, expire: Duration) -> Result<Duration> {
+        let options = self.options();
+        let max_adjustment = options.random_expire_adjustment * expire.as_millis() as f64;
+        let Some(ex) = expire
+            .checked_sub(options.delay)
+            .and_then(|ex| ex.checked_sub(Duration::from_millis(max_adjustment as u64)))
+        else {
+            return Err(new_config_error(format!(
+                "expire {:?} must be over delay {:?} plus the random adjustment {}ms",
+                expire, options.delay, max_adjustment as u64
+            )));
+        };
+        let jitter = (self.jitter)().clamp(0.0, 1.0);
+        Ok(ex + Duration::from_millis((max_adjustment * (1.0 - jitter)) as u64))
+    }
+
+    // lock_unit is the unit of the lock timestamps in milliseconds, which the scripts
+    // read from the redis server time: 1, or 1000 with Options::go_compat for the
+    // seconds of the Go client.
+    pub(crate) fn lock_unit(&self) -> u64 {
+        if self.options().go_compat {
+            1000
+        } else {
+            1
+        }
+    }
+
+    // lock_span is d in the unit of the lock timestamps, rounded up so that a sub-second
+    // lock is still taken with Options::go_compat.
+    pub(crate) fn lock_span(&self, d: Duration) -> u64 {
+        (d.as_millis() as u64).div_ceil(self.lock_unit())
+    }
+
+    // detach returns a client sharing the backend, clock, options and executor of self,
+    // for background tasks that outlive the borrow of self. It has no scheduled
+    // refreshes of its own, so the tasks don't keep the ones of self alive.
+    pub(crate) fn detach(&self) -> Client {
+        Client {
+            backend: self.backend.clone(),
+            options: self.options.clone(),
+            clock: self.clock.clone(),
+            owner_id: self.owner_id.clone(),
+            jitter: self.jitter.clone(),
+            refreshes: Arc::default(),
+            flushes: Arc::default(),
+            write_retry: Arc::default(),
+            janitor: Arc::default(),
+            executor: self.executor.clone(),
+            stats: self.stats.clone(),
+            breaker: self.breaker.clone(),
+            load_limits: self.load_limits.clone(),
+            stats_report: Arc::default(),
+            touches: Arc::default(),
+            lock_waits: Arc::new(LockWaits::new(self.options.clone())),
+            invalidations: self.invalidations.clone(),
+            fetches: self.fetches.clone(),
+            codec: self.codec.clone(),
+            encryptor: self.encryptor.clone(),
+            #[cfg(feature = "zstd")]
+            dictionaries: self.dictionaries.clone(),
+            #[cfg(feature = "local-cache")]
+            local: self.local.clone(),
+            #[cfg(feature = "local-cache")]
+            subscriber: Arc::default(),
+            journal: self.journal.clone(),
+            hot_keys: self.hot_keys.clone(),
+            hot_key_report: Arc::default(),
+            on_invalidation_dropped: self.on_invalidation_dropped.clone(),
+        }
+    }
+
+    // borrowed_key is prefixed_key for reads, it only allocates if there is a common prefix.
+    pub(crate) fn borrowed_key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        let prefix = &self.options().common_prefix;
+        if prefix.is_empty() {
+            Cow::Borrowed(key)
+        } else {
+            Cow::Owned(format!("{}{}", prefix, key))
+        }
+    }
+
+    pub(crate) fn prefixed_key(&self, key: impl Into<String>) -> String {
+        let key = key.into();
+        let prefix = &self.options().common_prefix;
+        if prefix.is_empty() {
+            key
+        } else {
+            format!("{}{}", prefix, key)
+        }
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(key = key, owner = tracing::field::Empty, outcome = tracing::field::Empty),
+            err
+        )
+    )]
+    pub(crate) async fn strong_fetch<F, Fut, V>(
+        &self,
+        key: &str,
+        expire: Duration,
+        metadata: &[(&str, &str)],
+        f: F,
+    ) -> Result<Option<V>>
     where
         F: FnOnce() -> Fut,
         Fut: Future<Output = Result<Option<V>>>,
         V: DeserializeOwned + Serialize + Debug,
     {
-        let owner = Uuid::new_v4().simple().to_string();
-        let now = Local::now().timestamp() as u64;
-        let (mut value, mut lock_until): (Value, Value) = self
-            .call_lua(
-                &GET_SCRIPT,
-                CommandArgs::default().arg(key).build(),
-                CommandArgs::default()
-                    .arg(now)
-                    .arg(now + self.options.lock_expire.as_secs())
-                    .arg(&owner)
-                    .build(),
-            )
+        #[cfg(feature = "local-cache")]
+        if let Some(value) = self.local_get(key)? {
+            record_span("outcome", "local_hit");
+            return Ok(value);
+        }
+        if self.options().read_only_hits {
+            if let Some(value) = self.read_only_hit(key, expire).await? {
+                record_span("outcome", "hit");
+                return Ok(value);
+            }
+        }
+        let (value, _) = self
+            .strong_fetch_hit(key, expire, metadata, None, f)
             .await?;
-        while lock_until != Value::Nil && lock_until.to_string() != "LOCKED" {
-            tokio::time::sleep(self.options.lock_sleep).await;
-            (value, lock_until) = self
-                .call_lua(
-                    &GET_SCRIPT,
-                    CommandArgs::default().arg(key).build(),
-                    CommandArgs::default()
-                        .arg(now)
-                        .arg(now + self.options.lock_expire.as_secs())
-                        .arg(&owner)
-                        .build(),
-                )
-                .await?;
+        Ok(value)
+    }
+
+    // read_only_hit reads the value of key with HMGET, returning it if it is fresh and
+    // None if the locking GET must run: no value, tag deleted or being reloaded.
+    async fn read_only_hit<V: DeserializeOwned>(
+        &self,
+        key: &str,
+        expire: Duration,
+    ) -> Result<Option<Option<V>>> {
+        #[cfg(feature = "local-cache")]
+        let generation = self.local_generation(key);
+        let fields = self
+            .with_redis_retry(|| self.backend.hmget(key, &["value", "lockUntil"]))
+            .await;
+        if fields.as_ref().is_err_and(Error::is_redis_error) {
+            self.stats.redis_error();
         }
-        if lock_until.to_string() != "LOCKED" {
-            let Value::BulkString(s) = value else {
-                return Err(Error::RedisError(rustis::Error::Aborted));
+        let [value, lock_until] = <[Option<Vec<u8>>; 2]>::try_from(fields?)
+            .map_err(|_| new_unexpected_reply_error(Reply::Nil))?;
+        let (Some(s), None) = (value, lock_until) else {
+            return Ok(None);
+        };
+        let value: Option<V> = match self.decode_value(key, &s) {
+            Err(e) if self.options().recover_on_decode_error && e.is_decode_error() => {
+                return Ok(None);
+            }
+            value => value?,
+        };
+        self.stats.hit();
+        #[cfg(feature = "local-cache")]
+        self.local_insert(key, &s, generation);
+        if self.options().sliding_expiration && value.is_some() {
+            self.touch(key, expire).await;
+        }
+        Ok(Some(value))
+    }
+
+    // strong_fetch_hit is strong_fetch, also returning the ttl left if the value was
+    // served from the cache. A value loaded with a ttl in loaded_ttl is written with it,
+    // see fetch_with_ttl.
+    async fn strong_fetch_hit<F, Fut, V>(
+        &self,
+        key: &str,
+        expire: Duration,
+        metadata: &[(&str, &str)],
+        loaded_ttl: Option<&LoadedTtl>,
+        f: F,
+    ) -> Result<(Option<V>, Option<Duration>)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let owner = (self.owner_id)();
+        record_span("owner", &owner);
+        let mut recover = self.options().recover_on_decode_error;
+        loop {
+            #[cfg(feature = "local-cache")]
+            let generation = self.local_generation(key);
+            let (mut value, mut lock_until, mut ttl) = self.lua_get(key, &owner).await?;
+            let mut wait = self.lock_wait();
+            while lock_until.is_some() && lock_until.as_deref() != Some("LOCKED") {
+                (value, lock_until, ttl) = self.wait_get(key, &owner, &mut wait).await?;
+            }
+            self.end_lock_wait(&wait);
+            if lock_until.as_deref() == Some("LOCKED") {
+                break;
+            }
+            let Some(s) = value else {
+                return Err(new_unexpected_reply_error(Reply::Nil));
+            };
+            let value: Option<V> = match self.decode_value(key, &s) {
+                Err(e) if recover && e.is_decode_error() => {
+                    // the value is tag deleted, so that the next GET takes its lock and
+                    // loads it again, unless another fetch did.
+                    recover = false;
+                    self.delete_key(key).await?;
+                    continue;
+                }
+                value => value?,
             };
-            return rmp_serde::from_slice(&s).map_err(new_decode_error);
+            self.stats.hit();
+            record_span("outcome", "hit");
+            #[cfg(feature = "local-cache")]
+            self.local_insert(key, &s, generation);
+            if self.options().sliding_expiration && value.is_some() {
+                self.touch(key, expire).await;
+            }
+            return Ok((value, ttl));
         }
-        self.fetch_new(key, expire, &owner, f).await
+        if self.hit_marked_empty(key, &owner).await? {
+            record_span("outcome", "hit");
+            return Ok((None, None));
+        }
+        self.stats.miss();
+        record_span("outcome", "miss");
+        let value = self
+            .fetch_new(key, expire, &owner, metadata, loaded_ttl, f)
+            .await?;
+        Ok((value, None))
     }
 
+    // fetch_new loads the value of key locked by owner and writes it. The lock is
+    // released in the background if the fetch is dropped before, e.g. by a timeout
+    // around it, instead of being held until Options::lock_expire.
     async fn fetch_new<F, Fut, V>(
         &self,
         key: &str,
         expire: Duration,
         owner: &str,
+        metadata: &[(&str, &str)],
+        loaded_ttl: Option<&LoadedTtl>,
+        f: F,
+    ) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let unlock = UnlockOnDrop::new(self, vec![key], owner);
+        let result = self
+            .load_and_set(key, expire, owner, metadata, loaded_ttl, f)
+            .await;
+        unlock.disarm();
+        result
+    }
+
+    async fn load_and_set<F, Fut, V>(
+        &self,
+        key: &str,
+        expire: Duration,
+        owner: &str,
+        metadata: &[(&str, &str)],
+        loaded_ttl: Option<&LoadedTtl>,
         f: F,
     ) -> Result<Option<V>>
     where
@@ -167,29 +1404,51 @@ impl Client {
         Fut: Future<Output = Result<Option<V>>>,
         V: DeserializeOwned + Serialize + Debug,
     {
-        let result = f().await;
+        let result = self.load_locked(&[key], owner, f()).await;
         let mut expire = expire;
 
         match result {
             Ok(result) => {
+                if let Some(ttl) = loaded_ttl.and_then(|ttl| ttl.lock().unwrap().take()) {
+                    expire = ttl.saturating_sub(self.options().delay);
+                }
                 if result.is_none() {
-                    expire = self.options.empty_expire;
-                    if self.options.empty_expire.as_secs() == 0 {
-                        _ = self.rdb.del(key).await.map_err(new_redis_error);
+                    match self.mark_empty(key).await {
+                        Err(e)
+                            if e.is_redis_error()
+                                && self.options().fallback_to_source_on_redis_error =>
+                        {
+                            return Ok(result);
+                        }
+                        marked => marked?,
                     }
+                    expire = self.empty_expire();
+                }
+                if expire.is_zero() {
+                    // not cached, the lock is gone with the key, SET would not write anything.
+                    _ = self
+                        .with_redis_retry(|| self.backend.del(vec![key.to_string()]))
+                        .await;
+                    return Ok(result);
                 }
 
-                let result_bytes = rmp_serde::to_vec(&result).map_err(new_encode_error)?;
-                self.call_lua(
-                    &SET_SCRIPT,
-                    CommandArgs::default().arg(key).build(),
-                    CommandArgs::default()
-                        .arg(result_bytes)
-                        .arg(owner)
-                        .arg(expire.as_secs())
-                        .build(),
-                )
-                .await?;
+                // the value is encoded once, the buffer moves into the SET arguments.
+                let encoded = self.encode_value(key, &result)?;
+                let local = self.local_write(key, &encoded);
+                let args = self.set_args(encoded, owner, expire, metadata);
+                if self.options().detached_write && !self.executor.is_closed() {
+                    self.write_detached(key.to_string(), args, local);
+                } else {
+                    match self
+                        .call_lua(&SET_SCRIPT, vec![key.to_string()], args)
+                        .await
+                    {
+                        Err(e)
+                            if e.is_redis_error()
+                                && self.options().fallback_to_source_on_redis_error => {}
+                        reply => self.written(key, &reply?, local),
+                    }
+                }
                 Ok(result)
             }
             Err(e) => {
@@ -199,77 +1458,874 @@ impl Client {
         }
     }
 
-    async fn unlock_for_update(&self, key: &str, owner: &str) -> Result<()> {
-        let _: Vec<Value> = self
-            .call_lua(
-                &UNLOCK_SCRIPT,
-                CommandArgs::default().arg(key).build(),
-                CommandArgs::default()
-                    .arg(owner)
-                    .arg(self.options.lock_expire.as_secs())
-                    .build(),
-            )
-            .await?;
-        Ok(())
+    // write_detached runs SET in the background, queueing it for a retry if redis fails.
+    // The arguments are only copied when retries are enabled.
+    fn write_detached(&self, key: String, args: Vec<Vec<u8>>, local: LocalWrite) {
+        let client = self.detach();
+        let write_retry = self.write_retry.clone();
+        let retry = (!self.options().invalidation_retry_max_age.is_zero()).then(|| args.clone());
+        self.executor.spawn(async move {
+            match (
+                client.call_lua(&SET_SCRIPT, vec![key.clone()], args).await,
+                retry,
+            ) {
+                (Ok(reply), _) => client.written(&key, &reply, local),
+                (Err(e), Some(args)) if e.is_redis_error() => {
+                    write_retry.push(&client, key, Write::Set(args));
+                }
+                _ => {}
+            }
+        });
     }
 
-    async fn call_lua<K, C, V>(&self, script: &Script, keys: C, args: C) -> Result<V>
+    // local_write is what a SET of the encoded value of key caches in the local tier once
+    // it is confirmed: the value and the invalidation generation of key before the SET.
+    #[cfg_attr(not(feature = "local-cache"), allow(unused_variables))]
+    fn local_write(&self, key: &str, encoded: &[u8]) -> LocalWrite {
+        #[cfg(feature = "local-cache")]
+        if self.local.is_some() {
+            return Some((self.local_generation(key), encoded.to_vec()));
+        }
+        None
+    }
+
+    // written caches the value of a SET of key in the local tier if its reply says it
+    // wrote it. SET doesn't write once the lock was lost, e.g. to a tag_as_deleted.
+    #[cfg_attr(not(feature = "local-cache"), allow(unused_variables))]
+    fn written(&self, key: &str, reply: &Reply, local: LocalWrite) {
+        #[cfg(feature = "local-cache")]
+        if let (Some((generation, encoded)), Ok(1)) = (local, reply.as_int()) {
+            self.local_insert(key, &encoded, generation);
+        }
+    }
+
+    // set_args builds the ARGV of SET and REFRESH_SET from the encoded value: value, owner,
+    // expire and metadata pairs.
+    pub(crate) fn set_args(
+        &self,
+        encoded: Vec<u8>,
+        owner: &str,
+        expire: Duration,
+        metadata: &[(&str, &str)],
+    ) -> Vec<Vec<u8>> {
+        let mut args = Args::default()
+            .arg(encoded)
+            .arg(owner)
+            .arg(expire.as_millis());
+        let options = self.options();
+        if options.go_compat {
+            return args.build();
+        }
+        let defaults = options.metadata.iter();
+        for (name, value) in defaults
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .chain(metadata.iter().copied())
+        {
+            args.push(format!("meta:{}", name));
+            args.push(value);
+        }
+        args.build()
+    }
+
+    // encode_value encodes the value of key as stored in redis, its errors carry key.
+    pub(crate) fn encode_value<V: Serialize>(
+        &self,
+        key: &str,
+        value: &Option<V>,
+    ) -> Result<Vec<u8>> {
+        self.encode_stored(key, value)
+            .map_err(|e| new_key_error(key, e))
+    }
+
+    // decode_value decodes the value of key as stored in redis, its errors carry key.
+    pub(crate) fn decode_value<V: DeserializeOwned>(
+        &self,
+        key: &str,
+        s: &[u8],
+    ) -> Result<Option<V>> {
+        self.decode_stored(key, s)
+            .map_err(|e| new_key_error(key, e))
+    }
+
+    // encode_stored encodes value as stored in redis. With Options::go_compat the empty
+    // result is the empty string, which no encoded value can be.
+    // With the zstd feature, it is compressed if key has a dictionary, and otherwise with
+    // Options::compression over Options::compression_threshold. It is encrypted last,
+    // with the encryptor of Client::with_encryptor.
+    fn encode_stored<V: Serialize>(&self, key: &str, value: &Option<V>) -> Result<Vec<u8>> {
+        let options = self.options();
+        if options.go_compat && value.is_none() {
+            return Ok(Vec::new());
+        }
+        let mut buf = pool::take();
+        let threshold = options.blocking_threshold;
+        let mut limited = Limited {
+            buf: &mut buf,
+            limit: if threshold == 0 {
+                usize::MAX
+            } else {
+                threshold
+            },
+        };
+        if let Err(e) = self.encode_to(&mut limited, value) {
+            if threshold == 0 || buf.len() <= threshold {
+                return Err(e);
+            }
+            // over the threshold, the value is encoded again off the worker thread.
+            buf.clear();
+            self.executor
+                .block_in_place(|| self.encode_to(&mut buf, value))?;
+        }
+        if !options.go_compat && value.is_some() {
+            #[cfg(feature = "zstd")]
+            {
+                buf = self.dictionaries.compress(key, buf)?;
+            }
+            buf = self.compress(buf)?;
+        }
+        let stored = self.encrypt(key, buf)?;
+        if value.is_some() {
+            self.stats.payload(stored.len());
+        }
+        Ok(stored)
+    }
+
+    fn decode_stored<V: DeserializeOwned>(&self, key: &str, s: &[u8]) -> Result<Option<V>> {
+        if self.options().go_compat && s.is_empty() {
+            return Ok(None);
+        }
+        let s = &*self.decrypt(key, s)?;
+        let s = &*decompress(s)?;
+        #[cfg(feature = "zstd")]
+        let s = &*self.dictionaries.decompress(key, s)?;
+        let threshold = self.options().blocking_threshold;
+        if threshold > 0 && s.len() > threshold {
+            return self.executor.block_in_place(|| self.decode_from(s));
+        }
+        self.decode_from(s)
+    }
+
+    // encode_to writes value with the codec of the client, MessagePack by default.
+    fn encode_to<V: Serialize>(
+        &self,
+        w: &mut impl std::io::Write,
+        value: &Option<V>,
+    ) -> Result<()> {
+        match &self.codec {
+            Some(codec) => codec.encode(value, w),
+            None => rmp_serde::encode::write(w, value).map_err(new_encode_error),
+        }
+    }
+
+    fn decode_from<V: DeserializeOwned>(&self, s: &[u8]) -> Result<Option<V>> {
+        match &self.codec {
+            Some(codec) => codec.decode(s),
+            None => rmp_serde::from_slice(s).map_err(new_decode_error),
+        }
+    }
+
+    // refresh reloads a cached value without blocking its readers. It only writes if
+    // no other refresh is running and the value has not been tag deleted meanwhile,
+    // and returns whether the value was replaced.
+    // With Options::go_compat it tag deletes the value and fetches it again instead,
+    // readers wait for the reload then.
+    pub(crate) async fn refresh<F, Fut, V>(&self, key: &str, expire: Duration, f: F) -> Result<bool>
     where
-        K: SingleArg,
-        C: SingleArgCollection<K> + Clone,
-        V: DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
     {
-        let command = self.rdb.evalsha::<String>(
-            CallBuilder::sha1(&script.hash)
-                .keys(keys.clone())
-                .args(args.clone()),
-        );
-        let r = self.rdb.send(command.command, None).await;
-        match r {
-            Ok(v) => {
-                let resp: String = v.to_string();
-                if resp.contains("kind: NoScript") {
-                    let command = self.rdb.script_load::<&str, String>(script.src);
-                    match self.rdb.send(command.command, None).await {
-                        Ok(_) => {
-                            let command = self.rdb.evalsha::<String>(
-                                CallBuilder::sha1(&script.hash).keys(keys).args(args),
-                            );
-
-                            let r = self.rdb.send(command.command, None).await;
-
-                            match r {
-                                Ok(v) => v.to().map_err(new_redis_error),
-                                Err(e) => Err(Error::RedisError(e)),
-                            }
-                        }
-                        Err(_) => {
-                            let command = self.rdb.evalsha::<String>(
-                                CallBuilder::sha1(&script.hash).keys(keys).args(args),
-                            );
-                            let r = self.rdb.send(command.command, None).await;
-                            match r {
-                                Ok(v) => v.to().map_err(new_redis_error),
-                                Err(e) => Err(Error::RedisError(e)),
-                            }
-                        }
-                    }
-                } else {
-                    v.to().map_err(new_redis_error)
-                }
+        if self.options().go_compat {
+            self.delete_key(key).await?;
+            self.strong_fetch::<_, _, V>(key, expire, &[], f).await?;
+            return Ok(true);
+        }
+        let owner = (self.owner_id)();
+        let locked = self
+            .call_lua(
+                &REFRESH_LOCK_SCRIPT,
+                vec![key.to_string()],
+                Args::default()
+                    .arg(self.lock_span(self.options().lock_expire))
+                    .arg(owner.as_str())
+                    .arg(self.lock_unit())
+                    .build(),
+            )
+            .await?;
+        if locked.as_int()? != 1 {
+            return Ok(false);
+        }
+        let result = match self.load(key, f()).await {
+            Ok(result) => result,
+            Err(e) => {
+                _ = self
+                    .call_lua(
+                        &REFRESH_UNLOCK_SCRIPT,
+                        vec![key.to_string()],
+                        Args::default().arg(owner).build(),
+                    )
+                    .await;
+                return Err(e);
+            }
+        };
+        let expire = if result.is_none() {
+            self.mark_empty(key).await?;
+            self.empty_expire()
+        } else {
+            expire
+        };
+        let args = self.set_args(self.encode_value(key, &result)?, &owner, expire, &[]);
+        let written = self
+            .call_lua(&REFRESH_SET_SCRIPT, vec![key.to_string()], args)
+            .await?
+            .as_int()?
+            == 1;
+        #[cfg(feature = "local-cache")]
+        if written {
+            self.local_remove(key);
+        }
+        Ok(written)
+    }
+
+    // load_locked is load for the loader of a fetch holding the locks of keys for owner,
+    // one key or those of a batch. With Options::lock_renewal, the locks are extended
+    // every third of Options::lock_expire while the loader runs, until they are lost.
+    pub(crate) async fn load_locked<T>(
+        &self,
+        keys: &[&str],
+        owner: &str,
+        load: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let load = pin!(self.load(keys[0], load));
+        if !self.options().lock_renewal {
+            return load.await;
+        }
+        let renew = pin!(async {
+            let mut held = keys.to_vec();
+            while !held.is_empty() {
+                self.executor.sleep(self.options().lock_expire / 3).await;
+                let args = Args::default()
+                    .arg(self.lock_span(self.options().lock_expire))
+                    .arg(owner)
+                    .arg(self.lock_unit())
+                    .build();
+                let extended =
+                    join_all(held.iter().map(|key| {
+                        self.call_lua(&EXTEND_SCRIPT, vec![key.to_string()], args.clone())
+                    }))
+                    .await;
+                // a redis error is retried on the next tick.
+                let mut extended = extended.into_iter();
+                held.retain(|_| !matches!(extended.next(), Some(Ok(Reply::Int(0)))));
             }
-            Err(e) => Err(Error::RedisError(e)),
+            future::pending::<Infallible>().await
+        });
+        match future::select(load, renew).await {
+            Either::Left((result, _)) => result,
+            Either::Right((never, _)) => match never {},
         }
     }
+
+    pub(crate) async fn unlock_for_update(&self, key: &str, owner: &str) -> Result<()> {
+        self.call_lua(
+            &UNLOCK_SCRIPT,
+            vec![key.to_string()],
+            Args::default()
+                .arg(owner)
+                .arg(self.options().lock_expire.as_millis())
+                .build(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(script = script.name(), key = keys.first()),
+            err
+        )
+    )]
+    pub(crate) async fn call_lua(
+        &self,
+        script: &Script,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+    ) -> Result<Reply> {
+        if self.options().redis_retry.attempts <= 1 {
+            return self.call_lua_once(script, keys, args).await;
+        }
+        self.with_redis_retry(|| self.call_lua_once(script, keys.clone(), args.clone()))
+            .await
+    }
+
+    async fn call_lua_once(
+        &self,
+        script: &Script,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+    ) -> Result<Reply> {
+        let started = self.executor.now();
+        let reply = self.backend.eval(script, keys, args).await;
+        self.record_redis_call(started, &reply);
+        if reply.as_ref().is_err_and(Error::is_redis_error) {
+            self.stats.redis_error();
+        }
+        reply
+    }
+
+    // call_lua_pipeline runs independent script calls in one round trip. The calls
+    // failing with an error Options::redis_retry retries are retried in one more round
+    // trip each time.
+    pub(crate) async fn call_lua_pipeline(&self, calls: Vec<ScriptCall<'_>>) -> Vec<Result<Reply>> {
+        if self.options().redis_retry.attempts <= 1 {
+            return self.call_lua_pipeline_once(calls).await;
+        }
+        let mut replies = self.call_lua_pipeline_once(calls.clone()).await;
+        let mut attempt = 1;
+        loop {
+            let failed: Vec<usize> = (0..replies.len())
+                .filter(|&i| self.should_retry(&replies[i], attempt))
+                .collect();
+            if failed.is_empty() {
+                return replies;
+            }
+            self.retry_sleep(attempt).await;
+            attempt += 1;
+            let retried = failed.iter().map(|&i| calls[i].clone()).collect();
+            let retried = self.call_lua_pipeline_once(retried).await;
+            for (i, reply) in failed.into_iter().zip(retried) {
+                replies[i] = reply;
+            }
+        }
+    }
+
+    async fn call_lua_pipeline_once(&self, calls: Vec<ScriptCall<'_>>) -> Vec<Result<Reply>> {
+        let started = self.executor.now();
+        let replies = self.backend.eval_pipeline(calls).await;
+        for reply in &replies {
+            self.record_redis_call(started, reply);
+            if reply.as_ref().is_err_and(Error::is_redis_error) {
+                self.stats.redis_error();
+            }
+        }
+        replies
+    }
+
+    // publish_call is the PUBLISH of key on Options::invalidation_channel.
+    pub(crate) fn publish_call(&self, key: &str) -> ScriptCall<'static> {
+        ScriptCall {
+            script: &PUBLISH_SCRIPT,
+            keys: Vec::new(),
+            args: Args::default()
+                .arg(self.options().invalidation_channel.as_str())
+                .arg(key)
+                .build(),
+        }
+    }
+
+    // delete_call is the DELETE script call tag deleting key.
+    pub(crate) fn delete_call(&self, key: String) -> ScriptCall<'static> {
+        ScriptCall {
+            script: &DELETE_SCRIPT,
+            keys: vec![key],
+            args: Args::default()
+                .arg(self.options().delay.as_millis())
+                .build(),
+        }
+    }
+}
+
+// Limited is a writer failing once more than limit bytes are written to buf.
+struct Limited<'a> {
+    buf: &'a mut Vec<u8>,
+    limit: usize,
+}
+
+impl std::io::Write for Limited<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() > self.limit {
+            return Err(std::io::ErrorKind::FileTooLarge.into());
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// record_span records field of the current span with the tracing feature.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn record_span(field: &str, value: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record(field, value);
+}
+
+// UnlockOnDrop unlocks keys for owner in the background when dropped, unless disarmed
+// once the fetch holding their locks wrote or unlocked them.
+pub(crate) struct UnlockOnDrop<'a> {
+    client: &'a Client,
+    keys: Vec<&'a str>,
+    owner: &'a str,
+}
+
+impl<'a> UnlockOnDrop<'a> {
+    pub(crate) fn new(client: &'a Client, keys: Vec<&'a str>, owner: &'a str) -> Self {
+        Self {
+            client,
+            keys,
+            owner,
+        }
+    }
+
+    pub(crate) fn disarm(mut self) {
+        self.keys.clear();
+    }
+}
+
+impl Drop for UnlockOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.keys.is_empty() {
+            return;
+        }
+        let client = self.client.detach();
+        let keys: Vec<String> = self.keys.iter().map(|k| k.to_string()).collect();
+        let owner = self.owner.to_string();
+        self.client.executor.spawn(async move {
+            for key in keys {
+                _ = client.unlock_for_update(&key, &owner).await;
+            }
+        });
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        backend::BoxFuture,
+        clock::{unix_millis, ManualClock},
+        seeded_jitter,
+        test_util::FakeBackend,
+    };
+    #[cfg(feature = "rustis")]
     use rustis::client::Client as RustisClient;
-    use std::time::Duration;
+    use std::{
+        collections::VecDeque,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    type Calls = Arc<Mutex<Vec<(&'static str, Vec<Vec<u8>>)>>>;
+
+    // MockBackend expects the scripts to be called in order, and answers with canned replies.
+    struct MockBackend {
+        expected: Mutex<VecDeque<(&'static str, Reply)>>,
+        calls: Calls,
+    }
+
+    impl MockBackend {
+        fn new(expected: Vec<(&'static str, Reply)>) -> (Self, Calls) {
+            let calls = Calls::default();
+            let backend = Self {
+                expected: Mutex::new(expected.into()),
+                calls: calls.clone(),
+            };
+            (backend, calls)
+        }
+    }
+
+    impl CacheBackend for MockBackend {
+        fn eval<'a>(
+            &'a self,
+            script: &'a Script,
+            _keys: Vec<String>,
+            args: Vec<Vec<u8>>,
+        ) -> BoxFuture<'a, Result<Reply>> {
+            let (name, reply) = self.expected.lock().unwrap().pop_front().unwrap();
+            assert_eq!(script.name(), name);
+            self.calls.lock().unwrap().push((script.name(), args));
+            Box::pin(async move { Ok(reply) })
+        }
+
+        fn del(&self, keys: Vec<String>) -> BoxFuture<'_, Result<u64>> {
+            Box::pin(async move { Ok(keys.len() as u64) })
+        }
+
+        fn scan<'a>(
+            &'a self,
+            _cursor: u64,
+            _pattern: &'a str,
+            _count: usize,
+        ) -> BoxFuture<'a, Result<(u64, Vec<String>)>> {
+            Box::pin(async { Ok((0, Vec::new())) })
+        }
+    }
+
+    fn bulk(v: &str) -> Reply {
+        Reply::Bulk(v.as_bytes().to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_hit_with_mock() {
+        let value = rmp_serde::to_vec(&Some("cached")).unwrap();
+        let (backend, _) = MockBackend::new(vec![(
+            "get",
+            Reply::Array(vec![Reply::Bulk(value), Reply::Nil]),
+        )]);
+        let client = Client::with_backend(backend, Options::default());
+        let result: Option<String> = client
+            .fetch("k", Duration::from_secs(600), || async {
+                panic!("loader must not run on a hit")
+            })
+            .await
+            .unwrap();
+        assert_eq!(result, Some("cached".to_string()));
+        #[cfg(feature = "rustis")]
+        assert!(client.try_raw_client().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_miss_with_mock() {
+        let (backend, calls) = MockBackend::new(vec![
+            ("get", Reply::Array(vec![Reply::Nil, bulk("LOCKED")])),
+            ("set", Reply::Nil),
+        ]);
+        let client = Client::with_backend(backend, Options::default());
+        let result = client
+            .fetch("k", Duration::from_secs(600), || async {
+                Ok(Some("fresh".to_string()))
+            })
+            .await
+            .unwrap();
+        assert_eq!(result, Some("fresh".to_string()));
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[1].1[0], rmp_serde::to_vec(&Some("fresh")).unwrap());
+        assert_eq!(calls[0].1[1], calls[1].1[1]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_script_args_with_injected_owner() {
+        let (backend, calls) = MockBackend::new(vec![
+            ("get", Reply::Array(vec![Reply::Nil, bulk("LOCKED")])),
+            ("set", Reply::Nil),
+        ]);
+        let client = Client::with_backend(backend, Options::default())
+            .with_owner_id(|| "owner-1".to_string())
+            .with_jitter(|| 0.5);
+        client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            calls[0].1,
+            vec![b"3000".to_vec(), b"owner-1".to_vec(), b"1".to_vec()]
+        );
+        assert_eq!(calls[1].1[1..], [b"owner-1".to_vec(), b"560000".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_random_expire_adjustment() {
+        let fake = FakeBackend::new();
+        let client =
+            Client::with_backend(fake.clone(), Options::default()).with_jitter(seeded_jitter(1));
+        let mut ttls = Vec::new();
+        for i in 0..20 {
+            let key = format!("k{}", i);
+            client
+                .fetch(&key, Duration::from_secs(600), || async { Ok(Some(i)) })
+                .await
+                .unwrap();
+            ttls.push(fake.pttl(&key).unwrap());
+        }
+        // 600s minus the 10s delay, minus up to 10% of 600s.
+        assert!(ttls
+            .iter()
+            .all(|ttl| *ttl > Duration::from_secs(530) && *ttl <= Duration::from_secs(590)));
+        ttls.sort();
+        ttls.dedup();
+        assert!(ttls.len() > 10);
+    }
+
+    #[tokio::test]
+    async fn test_sub_second_durations() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000));
+        let fake = FakeBackend::with_clock(clock.clone());
+        let options = Options {
+            delay: Duration::from_millis(100),
+            lock_expire: Duration::from_millis(500),
+            random_expire_adjustment: 0.0,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options).with_clock(clock.clone());
+        let (_, lock, _) = client.lua_get("locked", "holder").await.unwrap();
+        assert_eq!(lock.as_deref(), Some("LOCKED"));
+        clock.advance(Duration::from_millis(400));
+        let (_, lock, _) = client.lua_get("locked", "contender").await.unwrap();
+        assert_ne!(lock.as_deref(), Some("LOCKED"));
+        clock.advance(Duration::from_millis(200));
+        let (_, lock, _) = client.lua_get("locked", "contender").await.unwrap();
+        assert_eq!(lock.as_deref(), Some("LOCKED"));
+
+        client
+            .fetch("k", Duration::from_millis(1500), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        assert_eq!(fake.pttl("k"), Some(Duration::from_millis(1400)));
+        client.tag_as_deleted("k").await.unwrap();
+        assert_eq!(fake.pttl("k"), Some(Duration::from_millis(100)));
+
+        // the Go client reads lock timestamps in seconds.
+        let options = Options {
+            go_compat: true,
+            lock_expire: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options).with_clock(clock);
+        client.lua_get("go", "holder").await.unwrap();
+        assert_eq!(fake.hget("go", "lockUntil"), Some(b"1001".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_takes_over_lock_expiring_while_waiting() {
+        let clock = ManualClock::default();
+        let fake = FakeBackend::with_clock(clock.clone());
+        let now = unix_millis(&clock);
+        fake.hset("k", "lockUntil", (now + 3_000).to_string());
+        fake.hset("k", "lockOwner", "crashed");
+
+        let client = Client::with_backend(
+            fake.clone(),
+            Options {
+                lock_sleep: Duration::from_millis(1),
+                ..Default::default()
+            },
+        )
+        .with_clock(clock.clone());
+        let expire_lock = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            clock.advance(Duration::from_secs(4));
+        };
+        let (v, _) = tokio::join!(
+            client.fetch("k", Duration::from_secs(600), || async { Ok(Some(2)) }),
+            expire_lock
+        );
+        assert_eq!(v.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_waits_for_lock_with_mock() {
+        let value = rmp_serde::to_vec(&Some("other")).unwrap();
+        let (backend, _) = MockBackend::new(vec![
+            ("get", Reply::Array(vec![Reply::Nil, bulk("1700000000")])),
+            ("get", Reply::Array(vec![Reply::Bulk(value), Reply::Nil])),
+        ]);
+        let client = Client::with_backend(
+            backend,
+            Options {
+                lock_sleep: Duration::from_millis(1),
+                ..Default::default()
+            },
+        );
+        let result: Option<String> = client
+            .fetch("k", Duration::from_secs(600), || async { Ok(None) })
+            .await
+            .unwrap();
+        assert_eq!(result, Some("other".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with_refresh_reloads_before_expiry() {
+        let clock = ManualClock::default();
+        let fake = FakeBackend::with_clock(clock.clone());
+        let options = Options {
+            delay: Duration::ZERO,
+            random_expire_adjustment: 0.0,
+            refresh_ahead: 0.5,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options).with_clock(clock);
+        let version = Arc::new(AtomicU64::new(0));
+        let loader = move || {
+            let version = version.clone();
+            async move { Ok(Some(version.fetch_add(1, Ordering::SeqCst) + 1)) }
+        };
+        let expire = Duration::from_secs(600);
+
+        let v = client
+            .fetch_with_refresh("k", expire, loader.clone())
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+        fake.advance(Duration::from_secs(200));
+        let v = client
+            .fetch_with_refresh("k", expire, loader.clone())
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+        assert_eq!(
+            fake.hget("k", "value"),
+            Some(rmp_serde::to_vec(&Some(1)).unwrap())
+        );
+
+        fake.advance(Duration::from_secs(200));
+        let v = client
+            .fetch_with_refresh("k", expire, loader.clone())
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1), "the stale hit is served while refreshing");
+        let refreshed = rmp_serde::to_vec(&Some(2)).unwrap();
+        for _ in 0..100 {
+            if fake.hget("k", "value") == Some(refreshed.clone()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(fake.hget("k", "value"), Some(refreshed));
+        assert_eq!(fake.pttl("k"), Some(Duration::from_secs(600)));
+        assert_eq!(fake.hget("k", "refreshOwner"), None);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_does_not_overwrite_deleted_or_locked() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        client
+            .fetch("k", expire, || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        client.tag_as_deleted("k").await.unwrap();
+        let written = client
+            .refresh("k", expire, || async { Ok(Some(2)) })
+            .await
+            .unwrap();
+        assert!(!written);
+        assert_eq!(
+            fake.hget("k", "value"),
+            Some(rmp_serde::to_vec(&Some(1)).unwrap())
+        );
+
+        client.lua_get("locked", "owner").await.unwrap();
+        let written = client
+            .refresh("locked", expire, || async { Ok(Some(2)) })
+            .await
+            .unwrap();
+        assert!(!written);
+        assert_eq!(fake.hget("locked", "value"), None);
+    }
+
+    fn hex(b: &[u8]) -> String {
+        b.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn fixture(src: &str) -> Vec<Vec<&str>> {
+        src.lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.split(' ').collect())
+            .collect()
+    }
+
+    #[derive(Serialize)]
+    struct User {
+        id: u64,
+        name: String,
+    }
+
+    #[test]
+    fn test_wire_format_values() {
+        let encoded = [
+            ("empty", rmp_serde::to_vec(&None::<String>)),
+            ("string", rmp_serde::to_vec(&Some("test"))),
+            ("u64", rmp_serde::to_vec(&Some(42u64))),
+            ("i64", rmp_serde::to_vec(&Some(-1i64))),
+            ("bool", rmp_serde::to_vec(&Some(true))),
+            ("f64", rmp_serde::to_vec(&Some(1.5f64))),
+            ("bytes", rmp_serde::to_vec(&Some(vec![1u8, 2, 3]))),
+            (
+                "struct",
+                rmp_serde::to_vec(&Some(User {
+                    id: 1,
+                    name: "a".to_string(),
+                })),
+            ),
+            (
+                "map",
+                rmp_serde::to_vec(&Some(BTreeMap::from([("k", "v")]))),
+            ),
+        ];
+        let golden = fixture(include_str!("../tests/fixtures/wire/values.txt"));
+        assert_eq!(golden.len(), encoded.len());
+        for (line, (name, bytes)) in golden.iter().zip(encoded) {
+            assert_eq!(line[0], name);
+            assert_eq!(
+                hex(&bytes.unwrap()),
+                line[1],
+                "encoding of {} changed",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wire_format_hash_layout() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let fake = FakeBackend::with_clock(clock.clone());
+        let client = Client::with_backend(fake.clone(), Options::default())
+            .with_clock(clock)
+            .with_owner_id(|| "owner-1".to_string());
+        let expire = Duration::from_secs(600);
+        let mut layout = Vec::new();
+        let mut dump = |state: &str, key: &str| {
+            for (field, value) in fake.hgetall(key) {
+                let value = if field == "value" {
+                    hex(&value)
+                } else {
+                    String::from_utf8(value).unwrap()
+                };
+                layout.push(format!("{} {} {}", state, field, value));
+            }
+        };
 
+        client.lua_get("locked", "owner-1").await.unwrap();
+        dump("locked", "locked");
+        client
+            .fetch_with_metadata("fetched", expire, &[("version", "v1")], || async {
+                Ok(Some("test".to_string()))
+            })
+            .await
+            .unwrap();
+        dump("fetched", "fetched");
+        client
+            .fetch_with_metadata("deleted", expire, &[("version", "v1")], || async {
+                Ok(Some("test".to_string()))
+            })
+            .await
+            .unwrap();
+        client.tag_as_deleted("deleted").await.unwrap();
+        dump("deleted", "deleted");
+        client
+            .fetch("empty", expire, || async { Ok(None::<String>) })
+            .await
+            .unwrap();
+        dump("empty", "empty");
+
+        let golden = fixture(include_str!("../tests/fixtures/wire/hash_layout.txt"));
+        let golden: Vec<_> = golden.iter().map(|l| l.join(" ")).collect();
+        assert_eq!(layout, golden);
+    }
+
+    #[cfg(feature = "rustis")]
     #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
     async fn test_fetch() {
         let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
         let client = Client::new(rdb, Options::default());
@@ -280,7 +2336,220 @@ mod tests {
         assert_eq!(result.unwrap(), Some("test".to_string()));
     }
 
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
+    async fn test_script_cache_on_redis() {
+        use rustis::commands::{FlushingMode, ScriptingCommands};
+        let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
+        let client = Client::new(rdb.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        let fetch = || client.fetch("test_script_cache", expire, || async { Ok(Some(1)) });
+        assert_eq!(fetch().await.unwrap(), Some(1));
+        // the scripts are run with EVAL again after they are flushed.
+        rdb.script_flush(FlushingMode::Sync).await.unwrap();
+        assert_eq!(fetch().await.unwrap(), Some(1));
+
+        rdb.script_flush(FlushingMode::Sync).await.unwrap();
+        client.preload_scripts().await.unwrap();
+        let hashes: Vec<&str> = all_scripts().iter().map(|s| s.hash()).collect();
+        let exists: Vec<bool> = rdb.script_exists(hashes).await.unwrap();
+        assert!(exists.into_iter().all(|e| e));
+        assert_eq!(fetch().await.unwrap(), Some(1));
+        client.tag_as_deleted("test_script_cache").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_decode_error_has_key() {
+        let fake = FakeBackend::new();
+        fake.hset("app:k", "value", vec![0x92]);
+        let options = Options {
+            common_prefix: "app:".to_string(),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake, options);
+        let error = client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, Error::KeyError(key, e) if key == "app:k" && matches!(**e, Error::DecodeError(_)))
+        );
+        assert_eq!(error.key(), Some("app:k"));
+    }
+
+    #[tokio::test]
+    async fn test_recover_on_decode_error() {
+        let fake = FakeBackend::new();
+        let old = rmp_serde::to_vec(&Some("v1")).unwrap();
+        fake.hset("k", "value", old.clone());
+        let expire = Duration::from_secs(600);
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let v = client.fetch("k", expire, || async { Ok(Some(2u64)) }).await;
+        assert!(v.unwrap_err().is_decode_error());
+        assert_eq!(fake.hget("k", "value"), Some(old));
+
+        let options = Options {
+            recover_on_decode_error: true,
+            read_only_hits: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let v = client.fetch("k", expire, || async { Ok(Some(2u64)) }).await;
+        assert_eq!(v.unwrap(), Some(2));
+        let v = client.fetch("k", expire, || async { Ok(Some(3u64)) }).await;
+        assert_eq!(v.unwrap(), Some(2));
+        assert_eq!(client.stats().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_clone() {
+        fn shareable<T: Clone + Send + Sync + 'static>() {}
+        shareable::<Client>();
+
+        let client = Client::in_memory(Options::default());
+        let expire = Duration::from_secs(600);
+        let fetches: Vec<_> = (0..4)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    client
+                        .fetch("k", expire, || async move { Ok(Some(i)) })
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut values = Vec::new();
+        for fetch in fetches {
+            values.push(fetch.await.unwrap());
+        }
+        assert!(values.iter().all(|v| *v == values[0]));
+        assert_eq!(client.stats().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_options() {
+        let client = Client::in_memory(Options::default());
+        let clone = client.clone();
+        let expire = Duration::from_secs(600);
+        let v = client.fetch("k", expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+
+        clone
+            .update_options(|o| o.disable_cache_read = true)
+            .unwrap();
+        assert!(client.options().disable_cache_read);
+        let v = client.fetch("k", expire, || async { Ok(Some(2)) }).await;
+        assert_eq!(v.unwrap(), Some(2));
+
+        let invalid = client.update_options(|o| {
+            o.disable_cache_read = false;
+            o.delay = Duration::ZERO;
+        });
+        assert!(matches!(invalid, Err(Error::ConfigError(_))));
+        assert!(client.options().disable_cache_read);
+        client
+            .update_options(|o| o.disable_cache_read = false)
+            .unwrap();
+        let v = client.fetch("k", expire, || async { Ok(Some(3)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_update_options_during_load() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        let v = client.fetch("k", expire, || async {
+            client.update_options(|o| o.empty_expire = Duration::from_secs(5))?;
+            Ok(None::<u64>)
+        });
+        assert_eq!(v.await.unwrap(), None);
+        assert!(fake.pttl("k").unwrap() <= Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_source_on_redis_error() {
+        let fake = FakeBackend::new();
+        let expire = Duration::from_secs(600);
+        let client = Client::with_backend(fake.clone(), Options::default());
+        fake.fail_next(1);
+        let v = client.fetch("k", expire, || async { Ok(Some(1)) }).await;
+        assert!(v.as_ref().is_err_and(Error::is_redis_error));
+
+        let options = Options {
+            fallback_to_source_on_redis_error: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        fake.fail_next(1);
+        let v = client.fetch("k", expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+        assert_eq!(fake.hget("k", "value"), None);
+
+        // the write of SET fails once the loader ran.
+        let v = client
+            .fetch("k", expire, || async {
+                fake.fail_next(1);
+                Ok(Some(2))
+            })
+            .await;
+        assert_eq!(v.unwrap(), Some(2));
+        assert_eq!(fake.hget("k", "value"), None);
+        assert_eq!(client.stats().misses, 2);
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let options = Options::builder()
+            .delay(Duration::from_secs(5))
+            .common_prefix("app:")
+            .build()
+            .unwrap();
+        assert_eq!(options.delay, Duration::from_secs(5));
+        assert_eq!(options.common_prefix, "app:");
+
+        let invalid = [
+            Options::builder().delay(Duration::ZERO),
+            Options::builder().random_expire_adjustment(1.0),
+            Options::builder().random_expire_adjustment(-0.1),
+            Options::builder().lock_sleep(Duration::from_secs(3)),
+            Options::builder().lock_wait_strategy(LockWaitStrategy::Exponential {
+                max: Duration::from_secs(1),
+                jitter: 2.0,
+            }),
+        ];
+        for builder in invalid {
+            assert!(matches!(builder.build(), Err(Error::ConfigError(_))));
+        }
+    }
+
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    async fn test_connect_errors() {
+        let invalid = Client::connect("http://127.0.0.1:6379", Options::default()).await;
+        assert!(matches!(invalid, Err(Error::ConfigError(_))));
+        let refused = Client::connect("redis://127.0.0.1:1", Options::default()).await;
+        assert!(matches!(refused, Err(Error::RedisError(_))));
+    }
+
     #[tokio::test]
+    async fn test_fetch_expire_under_delay() {
+        let client = Client::with_backend(FakeBackend::new(), Options::default());
+        let fetched = client
+            .fetch("k", Duration::from_secs(10), || async { Ok(Some(1)) })
+            .await;
+        assert!(matches!(fetched, Err(Error::ConfigError(_))));
+        let fetched = client
+            .fetch("k", Duration::from_secs(12), || async { Ok(Some(1)) })
+            .await;
+        assert_eq!(fetched.unwrap(), Some(1));
+    }
+
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
     async fn test_tag_as_deleted() {
         let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
         let client = Client::new(rdb, Options::default());
@@ -288,4 +2557,619 @@ mod tests {
         let result = client.tag_as_deleted(key).await;
         assert!(result.is_ok());
     }
+
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
+    async fn test_inspect() {
+        let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
+        let client = Client::new(rdb, Options::default());
+        let key = "test_inspect";
+        let expire = Duration::from_secs(600);
+        let f = async { Ok(Some("test".to_string())) };
+        client.tag_as_deleted(key).await.unwrap();
+        client.fetch(key, expire, || f).await.unwrap();
+        let info = client.inspect(key).await.unwrap();
+        assert!(info.exists && info.has_value && !info.locked);
+        assert!(info.ttl.is_some());
+        assert_eq!(info.lock_owner, None);
+
+        let missing = client.inspect("test_inspect_missing").await.unwrap();
+        assert!(!missing.exists && !missing.has_value);
+        assert_eq!(missing.ttl, None);
+    }
+
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
+    async fn test_fetch_with_metadata() {
+        let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
+        let client = Client::new(
+            rdb,
+            Options {
+                metadata: vec![("version".to_string(), "1.2.0".to_string())],
+                ..Default::default()
+            },
+        );
+        let key = "test_fetch_with_metadata";
+        client.tag_as_deleted(key).await.unwrap();
+        let f = async { Ok(Some("test".to_string())) };
+        client
+            .fetch_with_metadata(key, Duration::from_secs(600), &[("by", "job")], || f)
+            .await
+            .unwrap();
+        let info = client.inspect(key).await.unwrap();
+        assert_eq!(info.metadata.get("version").unwrap(), "1.2.0");
+        assert_eq!(info.metadata.get("by").unwrap(), "job");
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        for key in ["a", "b", "c"] {
+            client
+                .fetch(key, expire, || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+        }
+        assert!(client.delete("a").await.unwrap());
+        assert!(fake.hgetall("a").is_empty());
+        assert!(!client.delete("a").await.unwrap());
+        assert_eq!(client.delete_batch(&["a", "b", "c"]).await.unwrap(), 2);
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let fetch = client.fetch("k", expire, || async move {
+            _ = rx.await;
+            Ok(Some(2))
+        });
+        let delete = async {
+            assert!(client.delete("k").await.unwrap());
+            tx.send(()).unwrap();
+        };
+        let (fetched, ()) = tokio::join!(fetch, delete);
+        assert_eq!(fetched.unwrap(), Some(2));
+        assert!(
+            fake.hgetall("k").is_empty(),
+            "the loaded value is not written"
+        );
+
+        let options = Options {
+            disable_cache_delete: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        client
+            .fetch("d", expire, || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        assert!(!client.delete("d").await.unwrap());
+        assert!(fake.hget("d", "value").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with_ttl() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            empty_expire: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let session = |ttl| async move { Ok(Some(("session".to_string(), ttl))) };
+        let v = client
+            .fetch_with_ttl("s", || session(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        assert_eq!(v.as_deref(), Some("session"));
+        let delay = client.options().delay;
+        assert_eq!(fake.pttl("s"), Some(Duration::from_secs(3600) - delay));
+        let v = client
+            .fetch_with_ttl("s", || session(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        assert_eq!(v.as_deref(), Some("session"));
+        assert_eq!(fake.calls("set"), 1);
+
+        let v = client
+            .fetch_with_ttl("short", || session(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(v.as_deref(), Some("session"));
+        assert!(
+            fake.hgetall("short").is_empty(),
+            "a ttl under the delay isn't cached"
+        );
+
+        let v = client
+            .fetch_with_ttl("none", || async { Ok(None::<(u64, Duration)>) })
+            .await
+            .unwrap();
+        assert_eq!(v, None);
+        assert_eq!(fake.pttl("none"), Some(Duration::from_secs(30)));
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            random_expire_adjustment: 0.0,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        assert_eq!(client.ttl("k").await.unwrap(), None);
+        client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        let ttl = client.ttl("k").await.unwrap();
+        assert_eq!(ttl, Some(Duration::from_secs(600) - client.options().delay));
+        client.raw_set("k", b"raw", Duration::ZERO).await.unwrap();
+        assert_eq!(client.ttl("k").await.unwrap(), None);
+    }
+
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
+    async fn test_exists() {
+        let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
+        let client = Client::new(rdb, Options::default());
+        let key = "test_exists";
+        let expire = Duration::from_secs(600);
+        client.tag_as_deleted(key).await.unwrap();
+        assert!(!client.exists(key).await.unwrap());
+        let f = async { Ok(Some("test".to_string())) };
+        client.fetch(key, expire, || f).await.unwrap();
+        assert!(client.exists(key).await.unwrap());
+
+        let empty_key = "test_exists_empty";
+        client.tag_as_deleted(empty_key).await.unwrap();
+        let f = async { Ok(None::<String>) };
+        client.fetch(empty_key, expire, || f).await.unwrap();
+        assert!(!client.exists(empty_key).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_call_lua_pipeline() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        for key in ["a", "b"] {
+            client
+                .fetch(key, Duration::from_secs(600), || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+        }
+        fake.fail_next(1);
+        let calls = ["a", "b"]
+            .iter()
+            .map(|key| client.delete_call(key.to_string()))
+            .collect();
+        let replies = client.call_lua_pipeline(calls).await;
+        assert!(replies[0].is_err());
+        assert!(replies[1].is_ok());
+        assert_eq!(fake.hget("a", "lockUntil"), None);
+        assert_eq!(fake.hget("b", "lockUntil"), Some(b"0".to_vec()));
+        assert_eq!(client.stats().redis_errors, 1);
+    }
+
+    #[tokio::test]
+    async fn test_borrowed_key() {
+        let client = Client::with_backend(FakeBackend::new(), Options::default());
+        assert!(matches!(client.borrowed_key("k"), Cow::Borrowed("k")));
+        let options = Options {
+            common_prefix: "app:".to_string(),
+            ..Default::default()
+        };
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), options);
+        assert_eq!(client.borrowed_key("k"), "app:k");
+        let key = String::from("k");
+        client
+            .fetch(&key, Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        assert_eq!(fake.keys(), vec!["app:k".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_hits() {
+        let value = rmp_serde::to_vec(&Some("cached")).unwrap();
+        let (backend, calls) = MockBackend::new(vec![
+            (
+                "hmget",
+                Reply::Array(vec![Reply::Bulk(value.clone()), Reply::Nil]),
+            ),
+            ("hmget", Reply::Array(vec![Reply::Bulk(value), bulk("0")])),
+            ("get", Reply::Array(vec![Reply::Nil, bulk("LOCKED")])),
+            ("set", Reply::Nil),
+        ]);
+        let options = Options {
+            read_only_hits: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(backend, options);
+        let fetch = || {
+            client.fetch("k", Duration::from_secs(600), || async {
+                Ok(Some("fresh".to_string()))
+            })
+        };
+        assert_eq!(fetch().await.unwrap().as_deref(), Some("cached"));
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        assert_eq!(
+            calls.lock().unwrap()[0].1,
+            vec![b"value".to_vec(), b"lockUntil".to_vec()]
+        );
+
+        // tag deleted, the locking script runs
+        assert_eq!(fetch().await.unwrap().as_deref(), Some("fresh"));
+        assert_eq!(calls.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_blocking_threshold() {
+        struct Blocking(Arc<AtomicU64>);
+
+        impl Runtime for Blocking {
+            fn spawn(&self, task: BoxFuture<'static, ()>) {
+                tokio::spawn(task);
+            }
+
+            fn sleep(&self, d: Duration) -> BoxFuture<'static, ()> {
+                Box::pin(tokio::time::sleep(d))
+            }
+
+            fn block_in_place(&self, f: &mut dyn FnMut()) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                tokio::task::block_in_place(f)
+            }
+        }
+
+        let blocked = Arc::new(AtomicU64::new(0));
+        let fake = FakeBackend::new();
+        let options = Options {
+            blocking_threshold: 1024,
+            ..Default::default()
+        };
+        let client =
+            Client::with_backend(fake.clone(), options).with_runtime(Blocking(blocked.clone()));
+        let expire = Duration::from_secs(600);
+        for _ in 0..2 {
+            let small: Option<Vec<u8>> = client
+                .fetch("small", expire, || async { Ok(Some(vec![1; 16])) })
+                .await
+                .unwrap();
+            assert_eq!(small, Some(vec![1; 16]));
+        }
+        assert_eq!(blocked.load(Ordering::SeqCst), 0);
+
+        let large = vec![7u8; 4096];
+        for _ in 0..2 {
+            let cached: Option<Vec<u8>> = client
+                .fetch("large", expire, || async { Ok(Some(large.clone())) })
+                .await
+                .unwrap();
+            assert_eq!(cached.as_ref(), Some(&large));
+        }
+        // encoded on the miss, decoded on the hit
+        assert_eq!(blocked.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            fake.hget("large", "value").unwrap(),
+            rmp_serde::to_vec(&Some(large)).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_weak() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        let v = client
+            .fetch_weak("k", expire, || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+
+        client.tag_as_deleted("k").await.unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let v = client
+            .fetch_weak("k", expire, || async move {
+                _ = rx.await;
+                Ok(Some(2))
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1), "the stale value is served during the reload");
+        let v = client
+            .fetch_weak("k", expire, || async { Ok(Some(3)) })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1), "the reload holds the lock");
+
+        tx.send(()).unwrap();
+        client.shutdown().await;
+        let v: Option<u64> = client
+            .fetch_weak("k", expire, || async { panic!("cached") })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with() {
+        let clock = ManualClock::default();
+        let fake = FakeBackend::with_clock(clock.clone());
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        let empty = |empty_expire| FetchOptions {
+            empty_expire: Some(empty_expire),
+            ..Default::default()
+        };
+
+        let v = client
+            .fetch_with("none", expire, empty(Duration::ZERO), || async {
+                Ok(None::<u64>)
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, None);
+        assert!(
+            fake.hgetall("none").is_empty(),
+            "empty results are not cached"
+        );
+        client
+            .fetch_with("empty", expire, empty(Duration::from_secs(5)), || async {
+                Ok(None::<u64>)
+            })
+            .await
+            .unwrap();
+        assert!(fake.pttl("empty").unwrap() <= Duration::from_secs(5));
+
+        let now = unix_millis(&clock);
+        let holder = fake.clone();
+        let options = FetchOptions {
+            lock_expire: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        client
+            .fetch_with("k", expire, options, move || async move {
+                let lock_until = holder.hget("k", "lockUntil").unwrap();
+                assert_eq!(lock_until, (now + 30_000).to_string().into_bytes());
+                Ok(Some(1))
+            })
+            .await
+            .unwrap();
+
+        client.tag_as_deleted("k").await.unwrap();
+        let weak = FetchOptions {
+            strong: false,
+            ..Default::default()
+        };
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let v = client
+            .fetch_with("k", expire, weak, || async move {
+                _ = rx.await;
+                Ok(Some(2))
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1), "the stale value is served during the reload");
+        tx.send(()).unwrap();
+
+        let invalid = FetchOptions {
+            lock_expire: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let fetched = client
+            .fetch_with("k", expire, invalid, || async { Ok(Some(3)) })
+            .await;
+        assert!(matches!(fetched, Err(Error::ConfigError(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lock_renewal() {
+        let clock = ManualClock::default();
+        let fake = FakeBackend::with_clock(clock.clone());
+        let options = Options {
+            lock_renewal: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let now = unix_millis(&clock);
+        let (holder, server) = (fake.clone(), clock.clone());
+        let v = client
+            .fetch("k", Duration::from_secs(600), move || async move {
+                // the loader runs for 6s of the 3s lock, renewed every second.
+                for _ in 0..3 {
+                    server.advance(Duration::from_secs(2));
+                    tokio::time::sleep(Duration::from_millis(1400)).await;
+                }
+                let lock_until = holder.hget("k", "lockUntil").unwrap();
+                assert_eq!(lock_until, (now + 9_000).to_string().into_bytes());
+                Ok(Some(1))
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+        assert_eq!(fake.calls("extend"), 4);
+        assert!(fake.hget("k", "value").is_some());
+        assert!(fake.hget("k", "lockUntil").is_none());
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(fake.calls("extend"), 4, "the renewals stop with the loader");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dropped_fetch_unlocks() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        let fetch = client.fetch("k", expire, future::pending::<Result<Option<u64>>>);
+        let timeout = tokio::time::timeout(Duration::from_millis(100), fetch).await;
+        assert!(timeout.is_err());
+        assert!(fake.hget("k", "lockOwner").is_some());
+        tokio::task::yield_now().await;
+        assert!(fake.hget("k", "lockOwner").is_none());
+        assert_eq!(fake.hget("k", "lockUntil"), Some(b"0".to_vec()));
+
+        let start = tokio::time::Instant::now();
+        let v = client
+            .fetch("k", expire, || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+        assert_eq!(
+            start.elapsed(),
+            Duration::ZERO,
+            "no wait for the dropped lock"
+        );
+
+        let keys = vec!["a".to_string(), "b".to_string()];
+        let batch = client.fetch_batch(keys, expire, |_| {
+            future::pending::<Result<std::collections::HashMap<usize, u64>>>()
+        });
+        let timeout = tokio::time::timeout(Duration::from_millis(100), batch).await;
+        assert!(timeout.is_err());
+        tokio::task::yield_now().await;
+        assert!(fake.hget("a", "lockOwner").is_none());
+        assert!(fake.hget("b", "lockOwner").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loader_timeout() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            loader_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let expire = Duration::from_secs(600);
+        let start = tokio::time::Instant::now();
+        let fetched = client
+            .fetch("k", expire, future::pending::<Result<Option<u64>>>)
+            .await;
+        assert!(matches!(fetched, Err(Error::LoaderTimeout(key)) if key == "k"));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert!(fake.hget("k", "lockOwner").is_none());
+        assert_eq!(client.stats().load_errors, 1);
+        let v = client
+            .fetch("k", expire, || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+
+        let options = FetchOptions {
+            loader_timeout: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        client.tag_as_deleted("k").await.unwrap();
+        let v = client
+            .fetch_with("k", expire, options, || async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(Some(2))
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(2));
+    }
+
+    // Spans records the spans created with their fields, as `name field=value ...`.
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Spans {
+        spans: std::sync::Mutex<Vec<(&'static tracing::Metadata<'static>, String)>>,
+        entered: std::sync::Mutex<Vec<tracing::span::Id>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct Fields<'a>(&'a mut String);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.push_str(&format!(" {}={}", field.name(), value));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Spans {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            let mut fields = span.metadata().name().to_string();
+            span.record(&mut Fields(&mut fields));
+            spans.push((span.metadata(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1].1));
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+
+        fn current_span(&self) -> tracing_core::span::Current {
+            match self.entered.lock().unwrap().last() {
+                Some(id) => {
+                    let metadata = self.spans.lock().unwrap()[id.into_u64() as usize - 1].0;
+                    tracing_core::span::Current::new(id.clone(), metadata)
+                }
+                None => tracing_core::span::Current::none(),
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_tracing_spans() {
+        let spans = Arc::new(Spans::default());
+        let _guard = tracing::subscriber::set_default(spans.clone());
+        let client = Client::with_backend(FakeBackend::new(), Options::default())
+            .with_owner_id(|| "owner-1".to_string());
+        for _ in 0..2 {
+            client
+                .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+        }
+        client.tag_as_deleted("k").await.unwrap();
+        let spans: Vec<String> = spans
+            .spans
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| s.1.clone())
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                "fetch key=k",
+                "strong_fetch key=k owner=owner-1 outcome=miss",
+                "call_lua script=get key=k",
+                "call_lua script=set key=k",
+                "fetch key=k",
+                "strong_fetch key=k owner=owner-1 outcome=hit",
+                "call_lua script=get key=k",
+                "tag_as_deleted key=k",
+                "call_lua script=delete key=k",
+            ]
+        );
+    }
 }
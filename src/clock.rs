@@ -0,0 +1,146 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Clock is the time of the application server, used by the local tier and the flush
+// schedules. The lock protocol reads the time of the redis server instead, so that the
+// clocks of the application servers don't need to agree.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+// SystemClock reads the system wall clock, it is the default clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// ManualClock only moves when told to, with millisecond precision.
+// Clones share the same time, so a test can keep one and hand another to a Client.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    // new creates a clock stopped at now.
+    pub fn new(now: SystemTime) -> Self {
+        let clock = Self {
+            millis: Arc::new(AtomicU64::new(0)),
+        };
+        clock.set(now);
+        clock
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.millis.store(millis as u64, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.millis
+            .fetch_add(d.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.millis.load(Ordering::SeqCst))
+    }
+}
+
+// SkewedClock is another clock shifted by a fixed offset, to simulate an
+// application server whose wall clock is ahead of or behind the others.
+#[derive(Clone)]
+pub struct SkewedClock {
+    base: Arc<dyn Clock>,
+    offset_millis: i64,
+}
+
+impl SkewedClock {
+    // new creates a clock offset_millis ahead of base, behind it if negative.
+    pub fn new(base: impl Clock + 'static, offset_millis: i64) -> Self {
+        Self {
+            base: Arc::new(base),
+            offset_millis,
+        }
+    }
+
+    pub fn offset_millis(&self) -> i64 {
+        self.offset_millis
+    }
+}
+
+impl Clock for SkewedClock {
+    fn now(&self) -> SystemTime {
+        let now = self.base.now();
+        let offset = Duration::from_millis(self.offset_millis.unsigned_abs());
+        if self.offset_millis >= 0 {
+            now + offset
+        } else {
+            now - offset
+        }
+    }
+}
+
+// unix_secs and unix_millis read clock as unix time. The lock protocol uses the time
+// of the redis server, the test backends read their server time with these.
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn unix_secs(clock: &dyn Clock) -> u64 {
+    unix_millis(clock) / 1000
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn unix_millis(clock: &dyn Clock) -> u64 {
+    clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(100));
+        let shared = clock.clone();
+        shared.advance(Duration::from_millis(1500));
+        assert_eq!(unix_millis(&clock), 101_500);
+        assert_eq!(unix_secs(&clock), 101);
+    }
+
+    #[test]
+    fn test_skewed_clock() {
+        let base = ManualClock::new(UNIX_EPOCH + Duration::from_secs(100));
+        let ahead = SkewedClock::new(base.clone(), 1_500);
+        let behind = SkewedClock::new(base.clone(), -2_000);
+        base.advance(Duration::from_secs(1));
+        assert_eq!(unix_millis(&ahead), 102_500);
+        assert_eq!(unix_millis(&behind), 99_000);
+    }
+
+    #[test]
+    fn test_system_clock() {
+        assert!(unix_secs(&SystemClock) > 1_700_000_000);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{Client, Result};
+use futures::channel::oneshot;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+// Flight is the invalidations of one key. They run one at a time, numbered in the
+// order they start.
+#[derive(Default)]
+struct Flight {
+    started: AtomicU64,
+    // succeeded is the number of the last invalidation that succeeded.
+    succeeded: tokio::sync::Mutex<u64>,
+}
+
+// InvalidationFlights coalesces the concurrent tag_as_deleted calls of a key with
+// Options::coalesce_invalidations. A call is covered by any invalidation of the key
+// that started after it was made, since its database write was committed by then: the
+// calls queued behind a running invalidation share the next one, so a burst of calls
+// costs two DELETE calls instead of one per call.
+#[derive(Default)]
+pub(crate) struct InvalidationFlights {
+    keys: Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+impl Client {
+    // coalesced_invalidate is invalidate, sharing the invalidation of key started after
+    // it was called if there is one.
+    pub(crate) async fn coalesced_invalidate(&self, key: String) -> Result<()> {
+        let flights = &self.invalidations;
+        let flight = flights
+            .keys
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let called = flight.started.load(Ordering::SeqCst);
+        let result = {
+            let mut succeeded = flight.succeeded.lock().await;
+            if *succeeded > called {
+                self.journal_done(&key);
+                Ok(())
+            } else {
+                let n = flight.started.fetch_add(1, Ordering::SeqCst) + 1;
+                let result = self.invalidate(key.clone()).await;
+                if result.is_ok() {
+                    *succeeded = n;
+                }
+                result
+            }
+        };
+        let mut keys = flights.keys.lock().unwrap();
+        // the map and this call hold the last references, nobody is waiting
+        if Arc::strong_count(&flight) == 2 {
+            keys.remove(&key);
+        }
+        result
+    }
+}
+
+// FetchFlights coalesces the concurrent fetches of a key with Options::coalesce_fetches:
+// the first one runs, the others wait for it and decode the value it got, so a burst of
+// fetches of one key costs one GET and at most one load. The value is shared encoded,
+// so that V needs no Clone. If the running fetch fails or is cancelled, the waiting ones
+// fetch on their own.
+#[derive(Default)]
+pub(crate) struct FetchFlights {
+    keys: Mutex<HashMap<String, Vec<FetchWaiter>>>,
+}
+
+// FetchWaiter receives the encoded value, it is dropped if the running fetch fails.
+type FetchWaiter = oneshot::Sender<Vec<u8>>;
+
+// Leading is the fetch running for key, removing it from the flights when done or
+// cancelled. Dropping the waiters lets them know it failed.
+struct Leading<'a> {
+    flights: &'a FetchFlights,
+    key: &'a str,
+}
+
+impl Leading<'_> {
+    fn waiters(&self) -> Vec<FetchWaiter> {
+        self.flights
+            .keys
+            .lock()
+            .unwrap()
+            .remove(self.key)
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Leading<'_> {
+    fn drop(&mut self) {
+        self.waiters();
+    }
+}
+
+impl Client {
+    // coalesced_fetch is strong_fetch, sharing the fetch of key running in this process
+    // if there is one.
+    pub(crate) async fn coalesced_fetch<F, Fut, V>(
+        &self,
+        key: &str,
+        expire: Duration,
+        metadata: &[(&str, &str)],
+        f: F,
+    ) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let waiting = {
+            let mut keys = self.fetches.keys.lock().unwrap();
+            match keys.get_mut(key) {
+                Some(waiters) => {
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(tx);
+                    Some(rx)
+                }
+                None => {
+                    keys.insert(key.to_string(), Vec::new());
+                    None
+                }
+            }
+        };
+        if let Some(rx) = waiting {
+            if let Ok(s) = rx.await {
+                return self.decode_value(key, &s);
+            }
+            return self.strong_fetch(key, expire, metadata, f).await;
+        }
+        let leading = Leading {
+            flights: &self.fetches,
+            key,
+        };
+        let value = self.strong_fetch(key, expire, metadata, f).await?;
+        let waiters = leading.waiters();
+        if !waiters.is_empty() {
+            let s = self.encode_value(key, &value)?;
+            for waiter in waiters {
+                _ = waiter.send(s.clone());
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::FakeBackend, Client, Options};
+    use futures::future::join_all;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce_invalidations() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            coalesce_invalidations: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        // the first call runs, the others wait for it and share the next one
+        fake.set_latency(Duration::from_millis(10));
+        let calls = join_all((0..20).map(|_| client.tag_as_deleted("k"))).await;
+        assert!(calls.iter().all(|r| r.is_ok()));
+        assert_eq!(fake.calls("delete"), 2);
+        assert_eq!(fake.hget("k", "lockUntil"), Some(b"0".to_vec()));
+        assert!(client.invalidations.keys.lock().unwrap().is_empty());
+
+        // a failed invalidation doesn't cover the calls waiting for it
+        fake.fail_next(1);
+        let calls = join_all((0..3).map(|_| client.tag_as_deleted("k"))).await;
+        assert_eq!(calls.iter().filter(|r| r.is_err()).count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce_fetches() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            coalesce_fetches: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let loads = AtomicUsize::new(0);
+        let fetch = || {
+            client.fetch("k", Duration::from_secs(600), || async {
+                loads.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(Some("v".to_string()))
+            })
+        };
+        let values = join_all((0..20).map(|_| fetch())).await;
+        assert!(values
+            .iter()
+            .all(|v| v.as_ref().unwrap().as_deref() == Some("v")));
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(fake.calls("get"), 1);
+        assert!(client.fetches.keys.lock().unwrap().is_empty());
+
+        // the waiting fetches fetch on their own if the running one fails
+        client.tag_as_deleted("k").await.unwrap();
+        fake.fail_next(1);
+        let values = join_all((0..3).map(|_| fetch())).await;
+        assert_eq!(values.iter().filter(|v| v.is_err()).count(), 1);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+}
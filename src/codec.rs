@@ -0,0 +1,164 @@
+#[cfg(any(feature = "json", feature = "bincode"))]
+use crate::error::new_codec_error;
+use crate::{
+    error::{new_decode_error, new_encode_error},
+    Result,
+};
+use serde::de::{DeserializeOwned, Error as _};
+use std::io::Write;
+
+// DeserializeFn is the deserialization of a value by a Codec, it takes the deserializer
+// of the codec.
+pub type DeserializeFn<'a> =
+    dyn FnMut(&mut dyn erased_serde::Deserializer<'_>) -> ErasedResult + 'a;
+
+type ErasedResult = std::result::Result<(), erased_serde::Error>;
+
+// Codec encodes the values stored in redis, set with Client::with_codec. Values are
+// type erased so that a client can hold any codec: encode takes any Serialize value,
+// and the typed decode of dyn Codec reads one back.
+// The value of a fetch is encoded as an Option, None being the empty result. The Go
+// rockscache client and Options::go_compat need MessagePack.
+pub trait Codec: Send + Sync + 'static {
+    // encode writes the encoding of value to w.
+    fn encode(&self, value: &dyn erased_serde::Serialize, w: &mut dyn Write) -> Result<()>;
+
+    // deserialize runs deserialize on a deserializer of bytes.
+    fn deserialize(&self, bytes: &[u8], deserialize: &mut DeserializeFn<'_>) -> Result<()>;
+}
+
+impl dyn Codec {
+    // decode decodes the value encoded in bytes.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        let mut value = None;
+        self.deserialize(bytes, &mut |d| {
+            value = Some(erased_serde::deserialize(d)?);
+            Ok(())
+        })?;
+        value.ok_or_else(|| new_decode_error(rmp_serde::decode::Error::custom("no value")))
+    }
+}
+
+// MessagePack is the default codec, rmp_serde with structs as arrays like the Go
+// rockscache client. Setting it is the same as not setting a codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode(&self, value: &dyn erased_serde::Serialize, w: &mut dyn Write) -> Result<()> {
+        rmp_serde::encode::write(w, value).map_err(new_encode_error)
+    }
+
+    fn deserialize(&self, bytes: &[u8], deserialize: &mut DeserializeFn<'_>) -> Result<()> {
+        let mut d = rmp_serde::Deserializer::from_read_ref(bytes);
+        deserialize(&mut <dyn erased_serde::Deserializer>::erase(&mut d))
+            .map_err(|e| new_decode_error(rmp_serde::decode::Error::custom(e)))
+    }
+}
+
+// Json encodes values as json, the empty result being null. Values compressed with a
+// dictionary still start with its marker, readers in other languages must not use
+// dictionaries.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode(&self, value: &dyn erased_serde::Serialize, w: &mut dyn Write) -> Result<()> {
+        serde_json::to_writer(w, value).map_err(new_codec_error)
+    }
+
+    fn deserialize(&self, bytes: &[u8], deserialize: &mut DeserializeFn<'_>) -> Result<()> {
+        let mut d = serde_json::Deserializer::from_slice(bytes);
+        deserialize(&mut <dyn erased_serde::Deserializer>::erase(&mut d))
+            .map_err(new_codec_error)?;
+        d.end().map_err(new_codec_error)
+    }
+}
+
+// Bincode encodes values with the default options of bincode 1. Its encoding may start
+// with the marker of compressed values, so it must not be used with dictionaries.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode(&self, value: &dyn erased_serde::Serialize, w: &mut dyn Write) -> Result<()> {
+        bincode::serialize_into(w, value).map_err(new_codec_error)
+    }
+
+    fn deserialize(&self, bytes: &[u8], deserialize: &mut DeserializeFn<'_>) -> Result<()> {
+        use bincode::Options;
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+        let mut d = bincode::Deserializer::from_slice(bytes, options);
+        deserialize(&mut <dyn erased_serde::Deserializer>::erase(&mut d)).map_err(new_codec_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Client, Options};
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        id: u64,
+        name: String,
+    }
+
+    async fn round_trip(codec: impl Codec) -> Vec<u8> {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default()).with_codec(codec);
+        let user = User {
+            id: 1,
+            name: "ann".to_string(),
+        };
+        for _ in 0..2 {
+            let fetched = client
+                .fetch("user:1", Duration::from_secs(600), || async {
+                    Ok(Some(user.clone()))
+                })
+                .await
+                .unwrap();
+            assert_eq!(fetched, Some(user.clone()));
+        }
+        let empty = client
+            .fetch("user:0", Duration::from_secs(600), || async {
+                Ok(None::<User>)
+            })
+            .await
+            .unwrap();
+        assert_eq!(empty, None);
+        fake.hget("user:1", "value").unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_codecs() {
+        let bytes = round_trip(MessagePack).await;
+        assert_eq!(
+            bytes,
+            rmp_serde::to_vec(&Some(User {
+                id: 1,
+                name: "ann".to_string()
+            }))
+            .unwrap()
+        );
+        let codec: &dyn Codec = &MessagePack;
+        assert!(codec.decode::<Option<String>>(&bytes).is_err());
+
+        #[cfg(feature = "json")]
+        assert_eq!(round_trip(Json).await, br#"{"id":1,"name":"ann"}"#);
+        #[cfg(feature = "bincode")]
+        {
+            let bytes = round_trip(Bincode).await;
+            let codec: &dyn Codec = &Bincode;
+            assert_eq!(codec.decode::<Option<User>>(&bytes).unwrap().unwrap().id, 1);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{
+    compression::{ENCRYPTED, MARKER},
+    error::{new_config_error, new_io_error},
+    Client, Result,
+};
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+// LEVEL is the zstd compression level.
+const LEVEL: i32 = 3;
+
+// RESERVED_VERSIONS is the first version reserved for Compression and Encryptor, whose
+// ids follow MARKER like the first byte of a dictionary version.
+const RESERVED_VERSIONS: u32 = (ENCRYPTED as u32) << 24;
+
+// ZstdDictionary is a zstd dictionary for the values of a namespace, identified by its
+// version. A compressed value records the version of its dictionary, so readers need
+// every version that may still be cached: register the new version next to the old
+// ones, it is used for the writes as the highest one. The versions from 0xfd000000
+// are reserved for Compression and Encryptor, new fails with them.
+pub struct ZstdDictionary {
+    version: u32,
+    bytes: Vec<u8>,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl ZstdDictionary {
+    pub fn new(version: u32, bytes: Vec<u8>) -> Result<Self> {
+        if version >= RESERVED_VERSIONS {
+            return Err(new_config_error(format!(
+                "zstd dictionary version {:#x} is reserved",
+                version
+            )));
+        }
+        Ok(Self {
+            version,
+            encoder: EncoderDictionary::copy(&bytes, LEVEL),
+            decoder: DecoderDictionary::copy(&bytes),
+            bytes,
+        })
+    }
+
+    // train builds a dictionary of at most max_size bytes from encoded values, e.g. the
+    // bytes of Client::payload for a sample of keys.
+    pub fn train(version: u32, samples: &[impl AsRef<[u8]>], max_size: usize) -> Result<Self> {
+        let bytes = zstd::dict::from_samples(samples, max_size).map_err(new_io_error)?;
+        Self::new(version, bytes)
+    }
+
+    // train_values is train on values, encoded like the client does.
+    pub fn train_values<V: Serialize>(version: u32, values: &[V], max_size: usize) -> Result<Self> {
+        let samples = values
+            .iter()
+            .map(|v| rmp_serde::to_vec(&Some(v)).map_err(crate::error::new_encode_error))
+            .collect::<Result<Vec<_>>>()?;
+        Self::train(version, &samples, max_size)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // bytes is the dictionary, to store it and create it again with new.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdDictionary")
+            .field("version", &self.version)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+// Dictionaries holds the dictionaries of the namespaces, by key prefix.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Dictionaries {
+    namespaces: Vec<(String, Vec<Arc<ZstdDictionary>>)>,
+}
+
+impl Dictionaries {
+    fn add(&mut self, prefix: String, dictionary: ZstdDictionary) {
+        let dictionary = Arc::new(dictionary);
+        match self.namespaces.iter_mut().find(|(p, _)| *p == prefix) {
+            Some((_, versions)) => {
+                versions.retain(|d| d.version != dictionary.version);
+                versions.push(dictionary);
+                versions.sort_by_key(|d| d.version);
+            }
+            None => self.namespaces.push((prefix, vec![dictionary])),
+        }
+        // the longest prefix of a key wins
+        self.namespaces
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    }
+
+    fn versions(&self, key: &str) -> Option<&[Arc<ZstdDictionary>]> {
+        self.namespaces
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map(|(_, versions)| versions.as_slice())
+    }
+
+    // compress compresses the encoded value of key with the latest dictionary of its
+    // namespace, keeping it as is if there is none or it doesn't get smaller.
+    pub(crate) fn compress(&self, key: &str, encoded: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(dictionary) = self.versions(key).and_then(|v| v.last()) else {
+            return Ok(encoded);
+        };
+        let mut compressed = vec![MARKER];
+        compressed.extend_from_slice(&dictionary.version.to_be_bytes());
+        let mut encoder =
+            zstd::stream::write::Encoder::with_prepared_dictionary(compressed, &dictionary.encoder)
+                .map_err(new_io_error)?;
+        encoder.write_all(&encoded).map_err(new_io_error)?;
+        let compressed = encoder.finish().map_err(new_io_error)?;
+        Ok(if compressed.len() < encoded.len() {
+            compressed
+        } else {
+            encoded
+        })
+    }
+
+    // decompress returns the encoded value of key, decompressing it with the dictionary
+    // version it records.
+    pub(crate) fn decompress<'a>(&self, key: &str, stored: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let Some((&MARKER, rest)) = stored.split_first() else {
+            return Ok(Cow::Borrowed(stored));
+        };
+        let invalid = |msg: &str| new_io_error(io::Error::new(io::ErrorKind::InvalidData, msg));
+        let (version, frame) = rest
+            .split_first_chunk::<4>()
+            .ok_or_else(|| invalid("truncated compressed value"))?;
+        let version = u32::from_be_bytes(*version);
+        let dictionary = self
+            .versions(key)
+            .and_then(|v| v.iter().find(|d| d.version == version))
+            .ok_or_else(|| invalid("unknown zstd dictionary version"))?;
+        let mut encoded = Vec::new();
+        zstd::stream::read::Decoder::with_prepared_dictionary(frame, &dictionary.decoder)
+            .map_err(new_io_error)?
+            .read_to_end(&mut encoded)
+            .map_err(new_io_error)?;
+        Ok(Cow::Owned(encoded))
+    }
+}
+
+impl Client {
+    // with_dictionary compresses the values of the keys starting with prefix with
+    // dictionary. The prefix is a full redis key prefix, common_prefix is not applied to
+    // it. Values are only compressed if it makes them smaller, and never with
+    // Options::go_compat. Payload::bytes are returned as stored, compressed or not.
+    pub fn with_dictionary(
+        mut self,
+        prefix: impl Into<String>,
+        dictionary: ZstdDictionary,
+    ) -> Self {
+        Arc::make_mut(&mut self.dictionaries).add(prefix.into(), dictionary);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use serde::Deserialize;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Event {
+        kind: String,
+        user: u64,
+        source: String,
+    }
+
+    fn event(i: u64) -> Event {
+        Event {
+            kind: ["signup", "login", "logout", "purchase"][i as usize % 4].to_string(),
+            user: i,
+            source: "https://www.example.com/landing-page".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dictionary() {
+        let samples: Vec<_> = (0..1000).map(event).collect();
+        let v1 = ZstdDictionary::train_values(1, &samples, 4096).unwrap();
+        let v2 = ZstdDictionary::new(2, v1.bytes().to_vec()).unwrap();
+        let fake = FakeBackend::new();
+        let expire = Duration::from_secs(600);
+        let old = Client::with_backend(fake.clone(), Options::default()).with_dictionary(
+            "event:",
+            ZstdDictionary::new(1, v1.bytes().to_vec()).unwrap(),
+        );
+        let new = Client::with_backend(fake.clone(), Options::default())
+            .with_dictionary("event:", v1)
+            .with_dictionary("event:", v2);
+
+        old.fetch("event:1", expire, || async { Ok(Some(event(1))) })
+            .await
+            .unwrap();
+        new.fetch("event:2", expire, || async { Ok(Some(event(2))) })
+            .await
+            .unwrap();
+        new.fetch("other:2", expire, || async { Ok(Some(event(2))) })
+            .await
+            .unwrap();
+        let stored = |key| fake.hget(key, "value").unwrap();
+        assert_eq!(stored("event:1")[..5], [MARKER, 0, 0, 0, 1]);
+        assert_eq!(stored("event:2")[..5], [MARKER, 0, 0, 0, 2]);
+        assert!(stored("event:2").len() < stored("other:2").len());
+        assert_eq!(
+            stored("other:2"),
+            rmp_serde::to_vec(&Some(event(2))).unwrap()
+        );
+
+        for key in ["event:1", "event:2", "other:2"] {
+            let cached: Option<Event> = new
+                .fetch(key, expire, || async { panic!("cached") })
+                .await
+                .unwrap();
+            assert!(cached.is_some());
+        }
+        // the old client doesn't know the version 2
+        let unknown: Result<Option<Event>> = old
+            .fetch("event:2", expire, || async { panic!("cached") })
+            .await;
+        assert!(matches!(
+            unknown,
+            Err(crate::Error::KeyError(_, e)) if matches!(*e, crate::Error::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_reserved_versions() {
+        let samples: Vec<_> = (0..1000).map(event).collect();
+        let bytes = ZstdDictionary::train_values(1, &samples, 4096)
+            .unwrap()
+            .bytes()
+            .to_vec();
+        assert!(ZstdDictionary::new(0xfcff_ffff, bytes.clone()).is_ok());
+        for version in [0xfd00_0000, 0xff00_0001, u32::MAX] {
+            let err = ZstdDictionary::new(version, bytes.clone()).unwrap_err();
+            assert!(matches!(err, crate::Error::ConfigError(_)));
+        }
+        assert!(ZstdDictionary::train_values(0xfe00_0000, &samples, 4096).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{error::new_io_error, Client, Result};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, io};
+
+// MARKER starts a compressed value. It is the one byte that never starts MessagePack,
+// so values written before compression was enabled keep being read. It is followed by
+// the id of the format, or by the version of the dictionary of ZstdDictionary.
+pub(crate) const MARKER: u8 = 0xc1;
+
+// ZSTD and LZ4 are the ids of the algorithms after MARKER, and ENCRYPTED that of the
+// values encrypted by an Encryptor. They are the high bytes of the dictionary versions
+// from 0xfd000000, which are not valid for dictionaries.
+const ZSTD: u8 = 0xff;
+const LZ4: u8 = 0xfe;
+pub(crate) const ENCRYPTED: u8 = 0xfd;
+
+// Compression is the algorithm compressing the values whose encoding is over
+// Options::compression_threshold bytes. Values are read whatever the algorithm they
+// were written with, as long as its feature is enabled, so it can be changed or
+// disabled while they are cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    // None doesn't compress values.
+    #[default]
+    None,
+    // Zstd compresses with zstd at its default level.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    // Lz4 compresses with the lz4 block format, faster but compressing less than zstd.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    // compress returns the encoded value compressed with the algorithm, after MARKER and
+    // its id, None with Compression::None.
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    fn compress(self, encoded: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(match self {
+            Compression::None => None,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut compressed = vec![MARKER, ZSTD];
+                let frame = zstd::bulk::compress(encoded, zstd::DEFAULT_COMPRESSION_LEVEL)
+                    .map_err(new_io_error)?;
+                compressed.extend_from_slice(&frame);
+                Some(compressed)
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let mut compressed = vec![MARKER, LZ4];
+                compressed.extend_from_slice(&lz4_flex::compress_prepend_size(encoded));
+                Some(compressed)
+            }
+        })
+    }
+}
+
+impl Client {
+    // compress compresses the encoded value with Options::compression if it is over
+    // Options::compression_threshold, keeping it as is if it doesn't get smaller. Values
+    // already compressed with a dictionary are kept too.
+    pub(crate) fn compress(&self, encoded: Vec<u8>) -> Result<Vec<u8>> {
+        if encoded.len() <= self.options().compression_threshold || encoded.first() == Some(&MARKER)
+        {
+            return Ok(encoded);
+        }
+        let Some(compressed) = self.options().compression.compress(&encoded)? else {
+            return Ok(encoded);
+        };
+        self.stats.compressed(encoded.len(), compressed.len());
+        Ok(if compressed.len() < encoded.len() {
+            compressed
+        } else {
+            encoded
+        })
+    }
+}
+
+// decompress returns the encoded value of a stored one, decompressing it if it was
+// compressed with a Compression. Values compressed with a dictionary are returned as is.
+pub(crate) fn decompress(stored: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let invalid = |msg: String| new_io_error(io::Error::new(io::ErrorKind::InvalidData, msg));
+    match stored {
+        #[cfg(feature = "zstd")]
+        [MARKER, ZSTD, frame @ ..] => zstd::stream::decode_all(frame)
+            .map(Cow::Owned)
+            .map_err(new_io_error),
+        #[cfg(feature = "lz4")]
+        [MARKER, LZ4, frame @ ..] => lz4_flex::decompress_size_prepended(frame)
+            .map(Cow::Owned)
+            .map_err(|e| invalid(e.to_string())),
+        #[allow(unreachable_patterns)]
+        [MARKER, id @ (ZSTD | LZ4), ..] => Err(invalid(format!(
+            "value compressed with the disabled algorithm {:#x}",
+            id
+        ))),
+        _ => Ok(Cow::Borrowed(stored)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, CacheMetrics, Options};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Default)]
+    struct Ratios(Mutex<Vec<(usize, usize)>>);
+
+    impl CacheMetrics for Arc<Ratios> {
+        fn on_compress(&self, encoded: usize, compressed: usize) {
+            self.0.lock().unwrap().push((encoded, compressed));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compression_threshold() {
+        let fake = FakeBackend::new();
+        let expire = Duration::from_secs(600);
+        let long = "a".repeat(4096);
+        let plain = Client::with_backend(fake.clone(), Options::default());
+        plain
+            .fetch("old", expire, || async { Ok(Some(long.clone())) })
+            .await
+            .unwrap();
+
+        let algorithms = [
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ];
+        for compression in algorithms {
+            let options = Options {
+                compression,
+                compression_threshold: 100,
+                ..Default::default()
+            };
+            let ratios = Arc::new(Ratios::default());
+            let client = Client::with_backend(fake.clone(), options).with_metrics(ratios.clone());
+            for (key, value) in [("short", "short".to_string()), ("long", long.clone())] {
+                client.delete(key).await.unwrap();
+                let v = client
+                    .fetch(key, expire, || async { Ok(Some(value)) })
+                    .await;
+                assert!(v.unwrap().is_some());
+            }
+            let stored = fake.hget("long", "value").unwrap();
+            assert_eq!(stored[0], MARKER);
+            assert!(stored.len() < 100);
+            assert_ne!(fake.hget("short", "value").unwrap()[0], MARKER);
+            assert_eq!(ratios.0.lock().unwrap()[0], (4099, stored.len()));
+
+            for key in ["short", "long", "old"] {
+                let v: Option<String> = plain
+                    .fetch(key, expire, || async { panic!("cached") })
+                    .await
+                    .unwrap();
+                assert!(v.is_some());
+            }
+        }
+        let stored = fake.hget("old", "value").unwrap();
+        assert_eq!(stored, rmp_serde::to_vec(&Some(&long)).unwrap());
+
+        assert!(decompress(&[MARKER, 0x80, 1]).is_ok());
+    }
+}
@@ -0,0 +1,428 @@
+use crate::{error::new_config_error, Client, Compression, Options, Result};
+#[cfg(not(feature = "local-cache"))]
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        DeserializeSeed, IgnoredAny, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::time::Duration;
+
+// ConfigValue is the type of an option that can be set from a string of the
+// environment or of a url.
+trait ConfigValue: Sized {
+    fn parse_config(s: &str) -> Option<Self>;
+}
+
+impl ConfigValue for Duration {
+    fn parse_config(s: &str) -> Option<Self> {
+        parse_duration(s)
+    }
+}
+
+impl ConfigValue for bool {
+    fn parse_config(s: &str) -> Option<Self> {
+        match s {
+            "true" | "1" => Some(true),
+            "false" | "0" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl ConfigValue for String {
+    fn parse_config(s: &str) -> Option<Self> {
+        Some(s.to_string())
+    }
+}
+
+impl ConfigValue for Compression {
+    fn parse_config(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(Compression::None),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Compression::Zstd),
+            #[cfg(feature = "lz4")]
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! from_str_values {
+    ($($t:ty),*) => {
+        $(impl ConfigValue for $t {
+            fn parse_config(s: &str) -> Option<Self> {
+                s.parse().ok()
+            }
+        })*
+    };
+}
+
+from_str_values!(u32, usize, f64);
+
+// parse_duration parses a duration like 300s, with the ms, s, m, h and d units. 0 needs
+// no unit.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().ok()?;
+    let millis = match unit {
+        "" if n == 0 => 0,
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return None,
+    };
+    n.checked_mul(millis).map(Duration::from_millis)
+}
+
+// format_duration formats d like parse_duration parses it, in the largest unit that
+// divides it. Durations under a millisecond are truncated.
+pub(crate) fn format_duration(d: Duration) -> String {
+    let millis = d.as_millis();
+    if millis == 0 {
+        return "0".to_string();
+    }
+    let units = [
+        ("d", 24 * 60 * 60 * 1000),
+        ("h", 60 * 60 * 1000),
+        ("m", 60 * 1000),
+        ("s", 1000),
+    ];
+    match units.iter().find(|(_, unit)| millis % unit == 0) {
+        Some((name, unit)) => format!("{}{}", millis / unit, name),
+        None => format!("{}ms", millis),
+    }
+}
+
+// duration serializes the durations of the options as strings like 300s, for
+// #[serde(with)].
+pub(crate) mod duration {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub(crate) fn serialize<S: Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::format_duration(*d))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Duration, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        super::parse_duration(&s).ok_or_else(|| {
+            D::Error::custom(format!("invalid duration {:?}, expected e.g. 300s", s))
+        })
+    }
+}
+
+// IGNORED_OPTIONS are the options of the local-cache feature, which Options accepts
+// without it so that a configuration file is shared by the builds with and without it.
+#[cfg(not(feature = "local-cache"))]
+const IGNORED_OPTIONS: &[&str] = &[
+    "local_ttl",
+    "local_capacity",
+    "local_memory_budget",
+    "local_min_fetches",
+];
+
+#[cfg(not(feature = "local-cache"))]
+impl Serialize for Options {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Options::serialize(self, serializer)
+    }
+}
+
+#[cfg(not(feature = "local-cache"))]
+impl<'de> Deserialize<'de> for Options {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_map(OptionsVisitor)
+    }
+}
+
+// OptionsVisitor deserializes the derived Options from a map without IGNORED_OPTIONS.
+#[cfg(not(feature = "local-cache"))]
+struct OptionsVisitor;
+
+#[cfg(not(feature = "local-cache"))]
+impl<'de> Visitor<'de> for OptionsVisitor {
+    type Value = Options;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("struct Options")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> std::result::Result<Options, A::Error> {
+        Options::deserialize(MapAccessDeserializer::new(SkipIgnored(map)))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> std::result::Result<Options, A::Error> {
+        Options::deserialize(SeqAccessDeserializer::new(seq))
+    }
+}
+
+// SkipIgnored is a map without the entries of IGNORED_OPTIONS.
+#[cfg(not(feature = "local-cache"))]
+struct SkipIgnored<A>(A);
+
+#[cfg(not(feature = "local-cache"))]
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for SkipIgnored<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> std::result::Result<Option<K::Value>, A::Error> {
+        while let Some(key) = self.0.next_key::<String>()? {
+            if IGNORED_OPTIONS.contains(&key.as_str()) {
+                self.0.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            return seed.deserialize(key.into_deserializer()).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> std::result::Result<V::Value, A::Error> {
+        self.0.next_value_seed(seed)
+    }
+}
+
+macro_rules! config_options {
+    ($($(#[$attr:meta])* $field:ident),* $(,)?) => {
+        // CONFIG_OPTIONS are the names of the options Options::set knows.
+        const CONFIG_OPTIONS: &[&str] = &[$($(#[$attr])* stringify!($field)),*];
+
+        impl Options {
+            // set sets the option called name, e.g. lock_expire, from value, e.g. 3s.
+            // Durations take the ms, s, m, h and d units, flags true, false, 1 or 0, and
+            // compression none, zstd or lz4. The options that are not a number, a flag, a
+            // duration, a string or the compression can't be set. It fails with
+            // Error::ConfigError for an unknown option or an invalid value.
+            pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+                match name {
+                    $($(#[$attr])* stringify!($field) => self.$field = parse(name, value)?,)*
+                    _ => return Err(new_config_error(format!("unknown option {}", name))),
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+config_options!(
+    delay,
+    empty_expire,
+    lock_expire,
+    lock_renewal,
+    loader_timeout,
+    lock_sleep,
+    lock_wait_max_attempts,
+    lock_wait_timeout,
+    random_expire_adjustment,
+    disable_cache_read,
+    disable_cache_delete,
+    fallback_to_source_on_redis_error,
+    common_prefix,
+    refresh_ahead,
+    invalidation_retry_max_age,
+    detached_write,
+    sliding_expiration,
+    touch_flush_interval,
+    hot_key_capacity,
+    go_compat,
+    coalesce_lock_waits,
+    read_only_hits,
+    coalesce_invalidations,
+    coalesce_fetches,
+    blocking_threshold,
+    recover_on_decode_error,
+    compression,
+    compression_threshold,
+    invalidation_channel,
+    #[cfg(feature = "local-cache")]
+    local_ttl,
+    #[cfg(feature = "local-cache")]
+    local_capacity,
+    #[cfg(feature = "local-cache")]
+    local_memory_budget,
+    #[cfg(feature = "local-cache")]
+    local_min_fetches,
+    max_background_tasks,
+    max_concurrent_loads,
+    cluster,
+    circuit_breaker_threshold,
+    circuit_breaker_open,
+    circuit_breaker_slow_call,
+);
+
+fn parse<T: ConfigValue>(name: &str, value: &str) -> Result<T> {
+    T::parse_config(value)
+        .ok_or_else(|| new_config_error(format!("invalid value {:?} of option {}", value, name)))
+}
+
+impl Options {
+    // from_env returns the default options with those set in environment variables named
+    // prefix followed by the option name in upper case, e.g. RDCACHE_LOCK_EXPIRE=3s with
+    // the prefix RDCACHE_, see set for the values. The options are validated.
+    pub fn from_env(prefix: &str) -> Result<Options> {
+        Self::from_vars(prefix, |var| std::env::var(var).ok())
+    }
+
+    // from_vars is from_env with the variables looked up by lookup instead of in the
+    // environment, e.g. in a map loaded from a file.
+    pub fn from_vars(prefix: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Options> {
+        let mut options = Options::default();
+        for name in CONFIG_OPTIONS {
+            let var = format!("{}{}", prefix, name.to_uppercase());
+            if let Some(value) = lookup(&var) {
+                options.set(name, &value)?;
+            }
+        }
+        options.validate()?;
+        Ok(options)
+    }
+}
+
+// split_url_options returns url without the query parameters naming options and the
+// default options with those set, see Options::set. The other parameters are left for
+// the redis connection.
+#[cfg_attr(not(feature = "rustis"), allow(dead_code))]
+pub(crate) fn split_url_options(url: &str) -> Result<(String, Options)> {
+    let mut options = Options::default();
+    let Some((base, query)) = url.split_once('?') else {
+        return Ok((url.to_string(), options));
+    };
+    let mut kept = Vec::new();
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        if CONFIG_OPTIONS.contains(&name) {
+            options.set(name, &percent_decode(value)?)?;
+        } else {
+            kept.push(param);
+        }
+    }
+    options.validate()?;
+    let url = if kept.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, kept.join("&"))
+    };
+    Ok((url, options))
+}
+
+#[cfg_attr(not(feature = "rustis"), allow(dead_code))]
+fn percent_decode(s: &str) -> Result<String> {
+    let invalid = || new_config_error(format!("invalid percent encoding in {}", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b != b'%' {
+            bytes.push(b);
+            rest = tail;
+            continue;
+        }
+        let hex = tail.get(..2).ok_or_else(invalid)?;
+        let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+        bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+        rest = &tail[2..];
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+impl Client {
+    // from_url connects like connect to a redis url whose query parameters may set
+    // options, e.g. redis://host:6379?delay=10s&lock_expire=3s&common_prefix=app%3A,
+    // see Options::set.
+    #[cfg(feature = "rustis")]
+    pub async fn from_url(url: &str) -> Result<Self> {
+        let (url, options) = split_url_options(url)?;
+        Self::connect(url, options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_options_from_env_and_url() {
+        let mut vars = HashMap::from([
+            ("RDCACHE_LOCK_EXPIRE", "5s"),
+            ("RDCACHE_DISABLE_CACHE_READ", "true"),
+            ("RDCACHE_RANDOM_EXPIRE_ADJUSTMENT", "0.2"),
+        ]);
+        let from_vars = |vars: &HashMap<&str, &str>| {
+            Options::from_vars("RDCACHE_", |var| vars.get(var).map(|v| v.to_string()))
+        };
+        let options = from_vars(&vars).unwrap();
+        assert_eq!(options.lock_expire, Duration::from_secs(5));
+        assert!(options.disable_cache_read);
+        assert_eq!(options.random_expire_adjustment, 0.2);
+        assert_eq!(options.delay, Options::default().delay);
+        vars.insert("RDCACHE_LOCK_EXPIRE", "5 seconds");
+        assert!(matches!(from_vars(&vars), Err(Error::ConfigError(_))));
+
+        let (url, options) = split_url_options(
+            "redis://host:6379/0?delay=1m&connect_timeout=1000&common_prefix=app%3A&lock_sleep=50ms",
+        )
+        .unwrap();
+        assert_eq!(url, "redis://host:6379/0?connect_timeout=1000");
+        assert_eq!(options.delay, Duration::from_secs(60));
+        assert_eq!(options.common_prefix, "app:");
+        assert_eq!(options.lock_sleep, Duration::from_millis(50));
+        assert!(split_url_options("redis://host?lock_expire=10ms").is_err());
+        assert!(Options::default().set("metadata", "v1").is_err());
+        assert_eq!(parse_duration("0"), Some(Duration::ZERO));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn test_options_serde() {
+        let options: Options = serde_json::from_str(
+            r#"{
+                "lock_expire": "5s",
+                "lock_sleep": "50ms",
+                "negative_cache": {"suffix": ":none"},
+                "lock_wait_strategy": {"exponential": {"max": "1s", "jitter": 0.5}},
+                "redis_retry": {"attempts": 3},
+                "metadata": [["deploy", "v2"]]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(options.lock_expire, Duration::from_secs(5));
+        assert_eq!(options.lock_sleep, Duration::from_millis(50));
+        assert_eq!(
+            options.negative_cache,
+            crate::NegativeCachePolicy::Suffix(":none".to_string())
+        );
+        assert_eq!(options.redis_retry.attempts, 3);
+        assert_eq!(options.redis_retry.backoff, Duration::from_millis(50));
+        assert_eq!(options.delay, Options::default().delay);
+        options.validate().unwrap();
+
+        let json = serde_json::to_value(&options).unwrap();
+        assert_eq!(json["lock_expire"], "5s");
+        assert_eq!(json["delay"], "10s");
+        assert_eq!(json["compression"], "none");
+        let back: Options = serde_json::from_value(json).unwrap();
+        assert_eq!(back.lock_wait_strategy, options.lock_wait_strategy);
+
+        assert!(serde_json::from_str::<Options>(r#"{"lock_expire": "5"}"#).is_err());
+        assert!(serde_json::from_str::<Options>(r#"{"lock_expir": "5s"}"#).is_err());
+        // the local-cache options are accepted with or without the feature.
+        let options: Options = serde_json::from_str(
+            r#"{"local_ttl": "5s", "local_capacity": 10, "local_min_fetches": 2, "delay": "5s"}"#,
+        )
+        .unwrap();
+        assert_eq!(options.delay, Duration::from_secs(5));
+    }
+}
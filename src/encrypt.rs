@@ -0,0 +1,297 @@
+use crate::{
+    compression::{ENCRYPTED, MARKER},
+    error::new_encryption_error,
+    Client, Result,
+};
+use std::{borrow::Cow, sync::Arc};
+
+// Encryptor encrypts the values stored in redis, set with Client::with_encryptor, e.g.
+// for personal data that must be encrypted at rest. Values are encrypted after they
+// are encoded and compressed. Each value records the id of the key it was encrypted
+// with, so keys can be rotated: new values are encrypted with the key of key_id, while
+// decrypt must still accept the keys of the values that may be cached.
+// Each value is bound to its redis key and key id by the additional authenticated data
+// aad, which an authenticated cipher like AES-GCM checks without storing it: a value
+// copied to another key, or whose key id is rewritten, doesn't decrypt.
+pub trait Encryptor: Send + Sync + 'static {
+    // key_id is the id of the key encrypt uses, at most 255 bytes.
+    fn key_id(&self) -> &str;
+
+    // encrypt encrypts plaintext with the key of key_id, authenticating aad.
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+
+    // decrypt decrypts ciphertext encrypted with the key of key_id and aad, failing with
+    // Error::EncryptionError if it doesn't know the key or the ciphertext or aad is
+    // invalid.
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl Client {
+    // with_encryptor encrypts the values of the client with encryptor. Readers of the
+    // same keys need an encryptor knowing the key, Go clients can't read them. Values
+    // stored before it was set are still read.
+    pub fn with_encryptor(mut self, encryptor: impl Encryptor) -> Self {
+        self.encryptor = Some(Arc::new(encryptor));
+        self
+    }
+
+    // encrypt encrypts the stored value of key with the encryptor, after MARKER,
+    // ENCRYPTED, the length of the key id and the key id.
+    pub(crate) fn encrypt(&self, key: &str, stored: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(encryptor) = &self.encryptor else {
+            return Ok(stored);
+        };
+        let key_id = encryptor.key_id();
+        let len = u8::try_from(key_id.len())
+            .map_err(|_| new_encryption_error(format!("key id {} is too long", key_id)))?;
+        let mut encrypted = vec![MARKER, ENCRYPTED, len];
+        encrypted.extend_from_slice(key_id.as_bytes());
+        let ciphertext = encryptor.encrypt(&stored, &aad(&encrypted, key))?;
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    // decrypt returns the stored value of key of an encrypted one, or the value as is if
+    // it is not encrypted.
+    pub(crate) fn decrypt<'a>(&self, key: &str, stored: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let [MARKER, ENCRYPTED, rest @ ..] = stored else {
+            return Ok(Cow::Borrowed(stored));
+        };
+        let Some(encryptor) = &self.encryptor else {
+            return Err(new_encryption_error(
+                "the value is encrypted and the client has no encryptor".to_string(),
+            ));
+        };
+        let (key_id, ciphertext) = rest
+            .split_first()
+            .and_then(|(&len, rest)| rest.split_at_checked(len as usize))
+            .ok_or_else(|| new_encryption_error("truncated encrypted value".to_string()))?;
+        let header = &stored[..stored.len() - ciphertext.len()];
+        let key_id = std::str::from_utf8(key_id)
+            .map_err(|_| new_encryption_error("invalid key id".to_string()))?;
+        let aad = aad(header, key);
+        Ok(Cow::Owned(encryptor.decrypt(key_id, ciphertext, &aad)?))
+    }
+
+    // reencrypt returns the stored value of key encrypted for new_key instead, as is if
+    // it is not encrypted.
+    pub(crate) fn reencrypt(&self, key: &str, new_key: &str, stored: Vec<u8>) -> Result<Vec<u8>> {
+        if !stored.starts_with(&[MARKER, ENCRYPTED]) {
+            return Ok(stored);
+        }
+        let plain = self.decrypt(key, &stored)?.into_owned();
+        self.encrypt(new_key, plain)
+    }
+}
+
+// aad is the additional data of the value of key encrypted after header, which holds
+// its key id.
+fn aad(header: &[u8], key: &str) -> Vec<u8> {
+    [header, key.as_bytes()].concat()
+}
+
+// AesGcmEncryptor encrypts with AES-256-GCM, a random nonce being stored before each
+// ciphertext. To rotate keys, create it with the new key and add the previous ones
+// with with_key until their values have expired.
+#[cfg(feature = "aes-gcm")]
+pub struct AesGcmEncryptor {
+    key_id: String,
+    keys: Vec<(String, aes_gcm::Aes256Gcm)>,
+}
+
+#[cfg(feature = "aes-gcm")]
+impl AesGcmEncryptor {
+    // NONCE_LEN is the length of the nonce before each ciphertext.
+    const NONCE_LEN: usize = 12;
+
+    // new encrypts with the 256 bit key called key_id.
+    pub fn new(key_id: impl Into<String>, key: &[u8; 32]) -> Self {
+        let key_id = key_id.into();
+        Self {
+            keys: Vec::new(),
+            key_id: key_id.clone(),
+        }
+        .with_key(key_id, key)
+    }
+
+    // with_key adds a key to decrypt the values encrypted with it, e.g. the previous key.
+    pub fn with_key(mut self, key_id: impl Into<String>, key: &[u8; 32]) -> Self {
+        use aes_gcm::KeyInit;
+        let key_id = key_id.into();
+        self.keys.retain(|(id, _)| *id != key_id);
+        self.keys
+            .push((key_id, aes_gcm::Aes256Gcm::new(key.into())));
+        self
+    }
+
+    fn cipher(&self, key_id: &str) -> Result<&aes_gcm::Aes256Gcm> {
+        self.keys
+            .iter()
+            .find(|(id, _)| id == key_id)
+            .map(|(_, cipher)| cipher)
+            .ok_or_else(|| new_encryption_error(format!("unknown key id {}", key_id)))
+    }
+}
+
+#[cfg(feature = "aes-gcm")]
+impl Encryptor for AesGcmEncryptor {
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        use aes_gcm::{
+            aead::{Aead, Payload},
+            AeadCore,
+        };
+        let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut aes_gcm::aead::OsRng);
+        let ciphertext = self
+            .cipher(&self.key_id)?
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|e| new_encryption_error(e.to_string()))?;
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        use aes_gcm::aead::{Aead, Payload};
+        let cipher = self.cipher(key_id)?;
+        if ciphertext.len() < Self::NONCE_LEN {
+            return Err(new_encryption_error("truncated ciphertext".to_string()));
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(Self::NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        cipher
+            .decrypt(nonce.into(), payload)
+            .map_err(|e| new_encryption_error(e.to_string()))
+    }
+}
+
+#[cfg(feature = "aes-gcm")]
+impl std::fmt::Debug for AesGcmEncryptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let key_ids: Vec<&str> = self.keys.iter().map(|(id, _)| id.as_str()).collect();
+        f.debug_struct("AesGcmEncryptor")
+            .field("key_id", &self.key_id)
+            .field("key_ids", &key_ids)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Error, Options};
+    use std::time::Duration;
+
+    // Xor "encrypts" with a one byte key per key id, enough to see what is stored.
+    struct Xor(&'static str, Vec<(&'static str, u8)>);
+
+    impl Encryptor for Xor {
+        fn key_id(&self) -> &str {
+            self.0
+        }
+
+        fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+            self.decrypt(self.0, plaintext, aad)
+        }
+
+        // the aad is not authenticated, see test_aes_gcm_encryptor.
+        fn decrypt(&self, key_id: &str, ciphertext: &[u8], _aad: &[u8]) -> Result<Vec<u8>> {
+            let (_, key) = self
+                .1
+                .iter()
+                .find(|(id, _)| *id == key_id)
+                .ok_or_else(|| new_encryption_error(format!("unknown key id {}", key_id)))?;
+            Ok(ciphertext.iter().map(|b| b ^ key).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encryptor() {
+        let fake = FakeBackend::new();
+        let expire = Duration::from_secs(600);
+        let plain = Client::with_backend(fake.clone(), Options::default());
+        let v = plain.fetch("plain", expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+
+        let old = Client::with_backend(fake.clone(), Options::default())
+            .with_encryptor(Xor("k1", vec![("k1", 0x55)]));
+        let v = old.fetch("old", expire, || async { Ok(Some(2)) }).await;
+        assert_eq!(v.unwrap(), Some(2));
+        assert_eq!(
+            fake.hget("old", "value").unwrap(),
+            [MARKER, ENCRYPTED, 2, b'k', b'1', 2 ^ 0x55]
+        );
+
+        let new = Client::with_backend(fake.clone(), Options::default())
+            .with_encryptor(Xor("k2", vec![("k1", 0x55), ("k2", 0x66)]));
+        let v = new.fetch("new", expire, || async { Ok(Some(3)) }).await;
+        assert_eq!(v.unwrap(), Some(3));
+        assert_eq!(fake.hget("new", "value").unwrap()[2..5], [2, b'k', b'2']);
+        for (key, value) in [("plain", 1), ("old", 2), ("new", 3)] {
+            let v: Option<u64> = new.get(key).await.unwrap();
+            assert_eq!(v, Some(value));
+        }
+
+        let unknown: Result<Option<u64>> = old.get("new").await;
+        assert!(matches!(
+            unknown,
+            Err(Error::KeyError(_, e)) if matches!(*e, Error::EncryptionError(_))
+        ));
+        assert!(plain.get::<u64>("old").await.is_err());
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[tokio::test]
+    async fn test_aes_gcm_encryptor() {
+        let fake = FakeBackend::new();
+        let expire = Duration::from_secs(600);
+        let old = Client::with_backend(fake.clone(), Options::default())
+            .with_encryptor(AesGcmEncryptor::new("k1", &[1; 32]));
+        let secret = "4111 1111 1111 1111".to_string();
+        let v = old
+            .fetch("card", expire, || async { Ok(Some(secret.clone())) })
+            .await;
+        assert_eq!(v.unwrap(), Some(secret.clone()));
+        let stored = fake.hget("card", "value").unwrap();
+        assert!(!stored.windows(4).any(|w| w == b"4111"));
+
+        let new = Client::with_backend(fake.clone(), Options::default())
+            .with_encryptor(AesGcmEncryptor::new("k2", &[2; 32]).with_key("k1", &[1; 32]));
+        assert_eq!(new.get("card").await.unwrap(), Some(secret.clone()));
+        let mut tampered = stored.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        fake.hset("card", "value", tampered);
+        assert!(new.get::<String>("card").await.is_err());
+
+        // the value is bound to its key and key id.
+        fake.hset("other", "value", stored.clone());
+        assert!(new.get::<String>("other").await.is_err());
+        let mut relabeled = stored.clone();
+        relabeled[3..5].copy_from_slice(b"k2");
+        fake.hset("card", "value", relabeled);
+        let new = Client::with_backend(fake.clone(), Options::default())
+            .with_encryptor(AesGcmEncryptor::new("k2", &[1; 32]).with_key("k1", &[1; 32]));
+        assert!(new.get::<String>("card").await.is_err());
+
+        // migrate_namespace encrypts the values it copies for their new key.
+        fake.hset("card", "value", stored);
+        let migrated = new
+            .migrate_namespace("card", "new:card", Default::default())
+            .await
+            .unwrap();
+        assert_eq!(migrated.copied, 1);
+        assert_eq!(new.get("new:card").await.unwrap(), Some(secret));
+    }
+}
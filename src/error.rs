@@ -1,16 +1,118 @@
+use crate::backend::Reply;
+use std::fmt;
+
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(feature = "rustis")]
     RedisError(rustis::Error),
+    // BackendError is a failed call of a CacheBackend other than RustisBackend, like a
+    // redis server that can't be reached or returns an error.
+    BackendError(Box<dyn std::error::Error + Send + Sync>),
     EncodeError(rmp_serde::encode::Error),
     DecodeError(rmp_serde::decode::Error),
+    UnexpectedReply(Reply),
+    IoError(std::io::Error),
+    CodecError(Box<dyn std::error::Error + Send + Sync>),
+    // LockWaitTimeout is the key whose lock a fetch gave up waiting for.
+    LockWaitTimeout(String),
+    // LoaderTimeout is the key whose loader ran longer than Options::loader_timeout.
+    LoaderTimeout(String),
+    // CacheMiss is the key of a fetch that found no value where one was required, like
+    // the empty result for the response of a CacheLayer.
+    CacheMiss(String),
+    // ConfigError describes invalid Options.
+    ConfigError(String),
+    // EncryptionError is a value that could not be encrypted or decrypted by the
+    // Encryptor of the client.
+    EncryptionError(String),
+    // KeyError is the error encoding or decoding the value of a key, with the key. The
+    // error itself is its source.
+    KeyError(String, Box<Error>),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "rustis")]
+            Error::RedisError(e) => write!(f, "redis error: {}", e),
+            Error::BackendError(e) => write!(f, "backend error: {}", e),
+            Error::EncodeError(e) => write!(f, "encode error: {}", e),
+            Error::DecodeError(e) => write!(f, "decode error: {}", e),
+            Error::UnexpectedReply(reply) => write!(f, "unexpected reply: {:?}", reply),
+            Error::IoError(e) => write!(f, "io error: {}", e),
+            Error::CodecError(e) => write!(f, "codec error: {}", e),
+            Error::LockWaitTimeout(key) => write!(f, "timed out waiting for the lock of {}", key),
+            Error::LoaderTimeout(key) => write!(f, "timed out loading {}", key),
+            Error::CacheMiss(key) => write!(f, "no value for {}", key),
+            Error::ConfigError(message) => write!(f, "invalid options: {}", message),
+            Error::EncryptionError(message) => write!(f, "encryption error: {}", message),
+            Error::KeyError(key, _) => {
+                write!(f, "error encoding or decoding the value of key {}", key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "rustis")]
+            Error::RedisError(e) => Some(e),
+            Error::BackendError(e) => Some(e.as_ref()),
+            Error::EncodeError(e) => Some(e),
+            Error::DecodeError(e) => Some(e),
+            Error::IoError(e) => Some(e),
+            Error::CodecError(e) => Some(e.as_ref()),
+            Error::KeyError(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    // key returns the key the error is about, if any.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Error::LockWaitTimeout(key)
+            | Error::LoaderTimeout(key)
+            | Error::CacheMiss(key)
+            | Error::KeyError(key, _) => Some(key),
+            _ => None,
+        }
+    }
+
+    // is_redis_error reports whether the error is a failed call to redis, of rustis or
+    // of another CacheBackend, the errors counted by CacheStats::redis_errors.
+    pub fn is_redis_error(&self) -> bool {
+        match self {
+            #[cfg(feature = "rustis")]
+            Error::RedisError(_) => true,
+            Error::BackendError(_) => true,
+            _ => false,
+        }
+    }
+
+    // is_decode_error reports whether the error is a value that could not be decoded.
+    pub(crate) fn is_decode_error(&self) -> bool {
+        match self {
+            Error::DecodeError(_) | Error::CodecError(_) => true,
+            Error::KeyError(_, e) => e.is_decode_error(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "rustis")]
 pub(crate) fn new_redis_error(err: rustis::Error) -> Error {
     Error::RedisError(err)
 }
 
+pub(crate) fn new_backend_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::BackendError(err.into())
+}
+
 pub(crate) fn new_encode_error(err: rmp_serde::encode::Error) -> Error {
     Error::EncodeError(err)
 }
@@ -19,14 +121,62 @@ pub(crate) fn new_decode_error(err: rmp_serde::decode::Error) -> Error {
     Error::DecodeError(err)
 }
 
+pub(crate) fn new_unexpected_reply_error(reply: Reply) -> Error {
+    Error::UnexpectedReply(reply)
+}
+
+pub(crate) fn new_io_error(err: std::io::Error) -> Error {
+    Error::IoError(err)
+}
+
+#[cfg_attr(not(any(feature = "json", feature = "bincode")), allow(dead_code))]
+pub(crate) fn new_codec_error(err: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::CodecError(Box::new(err))
+}
+
+pub(crate) fn new_lock_wait_timeout_error(key: &str) -> Error {
+    Error::LockWaitTimeout(key.to_string())
+}
+
+pub(crate) fn new_loader_timeout_error(key: &str) -> Error {
+    Error::LoaderTimeout(key.to_string())
+}
+
+#[cfg_attr(not(feature = "tower"), allow(dead_code))]
+pub(crate) fn new_cache_miss_error(key: &str) -> Error {
+    Error::CacheMiss(key.to_string())
+}
+
+pub(crate) fn new_encryption_error(message: String) -> Error {
+    Error::EncryptionError(message)
+}
+
+pub(crate) fn new_key_error(key: &str, err: Error) -> Error {
+    Error::KeyError(key.to_string(), Box::new(err))
+}
+
+pub(crate) fn new_config_error(message: String) -> Error {
+    Error::ConfigError(message)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(feature = "rustis")]
     #[test]
     fn test_new_redis_error() {
         let error = new_redis_error(rustis::Error::Aborted);
         assert!(matches!(error, Error::RedisError(_)));
+        assert!(error.is_redis_error());
+    }
+
+    #[test]
+    fn test_new_backend_error() {
+        let error = new_backend_error("connection refused");
+        assert!(matches!(&error, Error::BackendError(_)));
+        assert!(error.is_redis_error());
+        assert_eq!(error.to_string(), "backend error: connection refused");
     }
 
     #[test]
@@ -40,4 +190,75 @@ mod tests {
         let error = new_decode_error(rmp_serde::decode::Error::OutOfRange);
         assert!(matches!(error, Error::DecodeError(_)));
     }
+
+    #[test]
+    fn test_new_unexpected_reply_error() {
+        let error = new_unexpected_reply_error(Reply::Int(1));
+        assert!(matches!(error, Error::UnexpectedReply(Reply::Int(1))));
+    }
+
+    #[test]
+    fn test_new_io_error() {
+        let error = new_io_error(std::io::ErrorKind::NotFound.into());
+        assert!(matches!(error, Error::IoError(_)));
+    }
+
+    #[test]
+    fn test_new_codec_error() {
+        let error = new_codec_error(std::io::Error::from(std::io::ErrorKind::InvalidData));
+        assert!(matches!(error, Error::CodecError(_)));
+    }
+
+    #[test]
+    fn test_new_lock_wait_timeout_error() {
+        let error = new_lock_wait_timeout_error("k");
+        assert!(matches!(error, Error::LockWaitTimeout(key) if key == "k"));
+    }
+
+    #[test]
+    fn test_new_loader_timeout_error() {
+        let error = new_loader_timeout_error("k");
+        assert!(matches!(&error, Error::LoaderTimeout(key) if key == "k"));
+        assert_eq!(error.key(), Some("k"));
+    }
+
+    #[test]
+    fn test_new_cache_miss_error() {
+        let error = new_cache_miss_error("k");
+        assert!(matches!(&error, Error::CacheMiss(key) if key == "k"));
+        assert_eq!(error.key(), Some("k"));
+    }
+
+    #[test]
+    fn test_new_encryption_error() {
+        let error = new_encryption_error("unknown key id k2".to_string());
+        assert!(
+            matches!(&error, Error::EncryptionError(message) if message == "unknown key id k2")
+        );
+        assert_eq!(error.to_string(), "encryption error: unknown key id k2");
+    }
+
+    #[test]
+    fn test_new_key_error() {
+        let error = new_key_error(
+            "user:1",
+            new_decode_error(rmp_serde::decode::Error::OutOfRange),
+        );
+        assert!(
+            matches!(&error, Error::KeyError(key, e) if key == "user:1" && matches!(**e, Error::DecodeError(_)))
+        );
+        assert_eq!(
+            error.to_string(),
+            "error encoding or decoding the value of key user:1"
+        );
+        let source = std::error::Error::source(&error).unwrap();
+        assert!(source.to_string().starts_with("decode error: "));
+        assert!(source.downcast_ref::<Error>().is_some());
+    }
+
+    #[test]
+    fn test_new_config_error() {
+        let error = new_config_error("delay must be over 0".to_string());
+        assert_eq!(error.to_string(), "invalid options: delay must be over 0");
+    }
 }
@@ -0,0 +1,274 @@
+use crate::{
+    backend::BoxFuture,
+    runtime::{Runtime, Task},
+    Client,
+};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Notify, Semaphore};
+
+// BackgroundStats is a snapshot of the background work of a client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackgroundStats {
+    // Spawned is the number of background jobs started, e.g. refreshes and detached writes.
+    pub spawned: u64,
+    // Completed is the number of background jobs finished.
+    pub completed: u64,
+    // Running is the number of jobs holding one of the Options::max_background_tasks permits.
+    pub running: usize,
+    // Queued is the number of jobs waiting for a permit.
+    pub queued: usize,
+    // Services is the number of long running tasks, e.g. scheduled refreshes and the janitor.
+    pub services: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    spawned: AtomicU64,
+    completed: AtomicU64,
+    running: AtomicUsize,
+    pending: AtomicUsize,
+    idle: Notify,
+}
+
+// Executor runs the background work of a client and the clients detached from it.
+// Jobs share a bounded number of permits, services run until they are aborted.
+// After close, nothing new is started and shutdown waits for the running jobs.
+pub(crate) struct Executor {
+    runtime: Arc<dyn Runtime>,
+    permits: Arc<Semaphore>,
+    counters: Arc<Counters>,
+    closed: AtomicBool,
+    services: Mutex<Vec<Arc<Task>>>,
+}
+
+impl Executor {
+    pub(crate) fn new(max_tasks: usize, runtime: Arc<dyn Runtime>) -> Self {
+        Self {
+            runtime,
+            permits: Arc::new(Semaphore::new(max_tasks.max(1))),
+            counters: Arc::default(),
+            closed: AtomicBool::new(false),
+            services: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // spawn starts a job once a permit is free, returning false if the executor is closed.
+    // The job runs in the tracing span and the opentelemetry context of the caller, so a
+    // background refresh is traced with the fetch that started it.
+    pub(crate) fn spawn(&self, job: impl Future<Output = ()> + Send + 'static) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        #[cfg(feature = "tracing")]
+        let job = tracing::Instrument::in_current_span(job);
+        #[cfg(feature = "otel")]
+        let job = opentelemetry::context::FutureExt::with_current_context(job);
+        let counters = self.counters.clone();
+        counters.spawned.fetch_add(1, Ordering::SeqCst);
+        counters.pending.fetch_add(1, Ordering::SeqCst);
+        let permits = self.permits.clone();
+        self.runtime.spawn(Box::pin(async move {
+            if let Ok(_permit) = permits.acquire_owned().await {
+                counters.running.fetch_add(1, Ordering::SeqCst);
+                job.await;
+                counters.running.fetch_sub(1, Ordering::SeqCst);
+            }
+            counters.completed.fetch_add(1, Ordering::SeqCst);
+            if counters.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                counters.idle.notify_waiters();
+            }
+        }));
+        true
+    }
+
+    // spawn_service starts a long running task, None if the executor is closed.
+    // Services should run their work through run, so it counts against the permits.
+    pub(crate) fn spawn_service(
+        &self,
+        service: impl Future<Output = ()> + Send + 'static,
+    ) -> Option<Arc<Task>> {
+        if self.is_closed() {
+            return None;
+        }
+        let task = Arc::new(Task::spawn(self.runtime.as_ref(), service));
+        let mut services = self.services.lock().unwrap();
+        services.retain(|s| !s.is_finished());
+        services.push(task.clone());
+        Some(task)
+    }
+
+    // run runs a piece of service work once a permit is free.
+    pub(crate) async fn run<T>(&self, work: impl Future<Output = T>) -> T {
+        let _permit = self.permits.acquire().await;
+        self.counters.running.fetch_add(1, Ordering::SeqCst);
+        let result = work.await;
+        self.counters.running.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    pub(crate) fn sleep(&self, d: Duration) -> BoxFuture<'static, ()> {
+        self.runtime.sleep(d)
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.runtime.now()
+    }
+
+    // block_in_place runs f with Runtime::block_in_place.
+    pub(crate) fn block_in_place<T>(&self, f: impl FnOnce() -> T) -> T {
+        let mut f = Some(f);
+        let mut result = None;
+        self.runtime
+            .block_in_place(&mut || result = f.take().map(|f| f()));
+        result.expect("Runtime::block_in_place must run f")
+    }
+
+    pub(crate) fn stats(&self) -> BackgroundStats {
+        let counters = &self.counters;
+        let running = counters.running.load(Ordering::SeqCst);
+        let pending = counters.pending.load(Ordering::SeqCst);
+        let mut services = self.services.lock().unwrap();
+        services.retain(|s| !s.is_finished());
+        BackgroundStats {
+            spawned: counters.spawned.load(Ordering::SeqCst),
+            completed: counters.completed.load(Ordering::SeqCst),
+            running,
+            queued: pending.saturating_sub(running),
+            services: services.len(),
+        }
+    }
+
+    // close stops the services and waits for the running jobs, rejecting new ones.
+    async fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for service in self.services.lock().unwrap().drain(..) {
+            service.abort();
+        }
+        loop {
+            let idle = self.counters.idle.notified();
+            if self.counters.pending.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+impl Client {
+    // background_stats returns the state of the background refreshes, writes and services.
+    pub fn background_stats(&self) -> BackgroundStats {
+        self.executor.stats()
+    }
+
+    // shutdown stops the background work of the client: scheduled refreshes, the janitor
+    // and the retry of failed writes end, running refreshes and detached writes are
+    // waited for, and the failed writes and sliding expiration touches still queued
+    // get a last attempt.
+    // Afterwards no background work is started anymore, writes are done inline.
+    pub async fn shutdown(&self) {
+        self.executor.close().await;
+        self.flush_write_retries().await;
+        self.flush_touches().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_executor_caps_running_jobs() {
+        let executor = Executor::new(2, crate::runtime::default_runtime());
+        let release = Arc::new(Notify::new());
+        for _ in 0..5 {
+            let release = release.clone();
+            assert!(executor.spawn(async move { release.notified().await }));
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let stats = executor.stats();
+        assert_eq!((stats.spawned, stats.running, stats.queued), (5, 2, 3));
+
+        let service = executor.spawn_service(std::future::pending()).unwrap();
+        assert_eq!(executor.stats().services, 1);
+
+        let closing = async {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                release.notify_waiters();
+            }
+        };
+        tokio::join!(executor.close(), closing);
+        let stats = executor.stats();
+        assert_eq!((stats.completed, stats.running, stats.queued), (5, 0, 0));
+        assert_eq!(stats.services, 0);
+        assert!(service.is_finished());
+        assert!(!executor.spawn(async {}));
+        assert!(executor.spawn_service(async {}).is_none());
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn test_spawn_propagates_otel_context() {
+        #[derive(Debug, PartialEq)]
+        struct Trace(u64);
+
+        let executor = Executor::new(1, crate::runtime::default_runtime());
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        {
+            let _guard = opentelemetry::Context::current_with_value(Trace(7)).attach();
+            executor.spawn(async move {
+                let cx = opentelemetry::Context::current();
+                _ = tx.send(cx.get::<Trace>() == Some(&Trace(7)));
+            });
+        }
+        assert!(rx.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_shutdown() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            detached_write: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        client.schedule_refresh("config", Duration::from_secs(1), || async { Ok(Some(1)) });
+        client.start_janitor(Duration::from_secs(60), Duration::from_secs(60));
+        client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(2)) })
+            .await
+            .unwrap();
+        fake.fail_next(1);
+        _ = client.tag_as_deleted("deleted").await;
+        assert_eq!(client.background_stats().services, 3);
+
+        client.shutdown().await;
+        let stats = client.background_stats();
+        assert_eq!(stats.services, 0);
+        assert_eq!(stats.spawned, stats.completed);
+        assert!(fake.hget("k", "value").is_some());
+        assert_eq!(fake.hget("deleted", "lockUntil"), Some(b"0".to_vec()));
+        assert_eq!(client.pending_invalidations(), 0);
+
+        client.schedule_refresh("late", Duration::from_secs(1), || async { Ok(Some(1)) });
+        client
+            .fetch("inline", Duration::from_secs(600), || async { Ok(Some(3)) })
+            .await
+            .unwrap();
+        assert!(fake.hget("inline", "value").is_some());
+        assert_eq!(client.background_stats().services, 0);
+    }
+}
@@ -0,0 +1,181 @@
+// The C ABI of rdcache, declared in include/rdcache.h with the safety requirements of
+// each function.
+#![allow(clippy::missing_safety_doc)]
+
+use crate::{error::new_unexpected_reply_error, Client, Options, Reply};
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    ptr,
+    time::Duration,
+};
+
+// RdcacheLoader loads the value of key as json into a string it keeps ownership of,
+// valid until it is called again. It returns NULL if the load failed.
+pub type RdcacheLoader = extern "C" fn(ctx: *mut c_void, key: *const c_char) -> *const c_char;
+
+// RdcacheClient is a Client with the runtime its calls block on.
+pub struct RdcacheClient {
+    runtime: tokio::runtime::Runtime,
+    client: Client,
+}
+
+impl RdcacheClient {
+    fn new(runtime: tokio::runtime::Runtime, client: Client) -> *mut Self {
+        Box::into_raw(Box::new(Self { runtime, client }))
+    }
+}
+
+fn new_runtime() -> Option<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .ok()
+}
+
+// rdcache_new connects to the redis server at addr, e.g. "127.0.0.1:6379", with the
+// default options. It returns NULL if the connection failed.
+#[no_mangle]
+pub unsafe extern "C" fn rdcache_new(addr: *const c_char) -> *mut RdcacheClient {
+    let Ok(addr) = CStr::from_ptr(addr).to_str() else {
+        return ptr::null_mut();
+    };
+    let Some(runtime) = new_runtime() else {
+        return ptr::null_mut();
+    };
+    match runtime.block_on(rustis::client::Client::connect(addr)) {
+        Ok(rdb) => RdcacheClient::new(runtime, Client::new(rdb, Options::default())),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+// rdcache_free shuts the client down, waiting for its background writes.
+#[no_mangle]
+pub unsafe extern "C" fn rdcache_free(client: *mut RdcacheClient) {
+    if client.is_null() {
+        return;
+    }
+    let client = Box::from_raw(client);
+    client.runtime.block_on(client.client.shutdown());
+}
+
+// rdcache_fetch_json is Client::fetch for json values, loading them with loader.
+// The json null is the empty result. It returns the value as json, to free with
+// rdcache_string_free, or NULL on error.
+#[no_mangle]
+pub unsafe extern "C" fn rdcache_fetch_json(
+    client: *const RdcacheClient,
+    key: *const c_char,
+    expire_ms: u64,
+    loader: RdcacheLoader,
+    ctx: *mut c_void,
+) -> *mut c_char {
+    let client = &*client;
+    let Ok(key) = CStr::from_ptr(key).to_str() else {
+        return ptr::null_mut();
+    };
+    let load = || async {
+        let c_key = CString::new(key).map_err(|_| new_unexpected_reply_error(Reply::Nil))?;
+        let json = loader(ctx, c_key.as_ptr());
+        if json.is_null() {
+            return Err(new_unexpected_reply_error(Reply::Nil));
+        }
+        let value: serde_json::Value = serde_json::from_slice(CStr::from_ptr(json).to_bytes())
+            .map_err(|_| new_unexpected_reply_error(Reply::Nil))?;
+        Ok((!value.is_null()).then_some(value))
+    };
+    let fetched = client.runtime.block_on(client.client.fetch(
+        key,
+        Duration::from_millis(expire_ms),
+        load,
+    ));
+    match fetched {
+        Ok(value) => {
+            let json = value.unwrap_or_default().to_string();
+            CString::new(json).map_or(ptr::null_mut(), CString::into_raw)
+        }
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+// rdcache_invalidate is Client::tag_as_deleted, it returns 0 on success and -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn rdcache_invalidate(
+    client: *const RdcacheClient,
+    key: *const c_char,
+) -> c_int {
+    let client = &*client;
+    let Ok(key) = CStr::from_ptr(key).to_str() else {
+        return -1;
+    };
+    match client.runtime.block_on(client.client.tag_as_deleted(key)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+// rdcache_string_free frees a string returned by rdcache.
+#[no_mangle]
+pub unsafe extern "C" fn rdcache_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::FakeBackend;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    extern "C" fn load_user(ctx: *mut c_void, key: *const c_char) -> *const c_char {
+        let calls = unsafe { &*(ctx as *const AtomicUsize) };
+        calls.fetch_add(1, Ordering::SeqCst);
+        match unsafe { CStr::from_ptr(key) }.to_bytes() {
+            b"user:1" => c"{\"name\":\"ann\"}".as_ptr(),
+            b"user:0" => c"null".as_ptr(),
+            _ => ptr::null(),
+        }
+    }
+
+    unsafe fn fetch(
+        client: *const RdcacheClient,
+        key: &CStr,
+        calls: &AtomicUsize,
+    ) -> Option<String> {
+        let ctx = calls as *const AtomicUsize as *mut c_void;
+        let json = rdcache_fetch_json(client, key.as_ptr(), 600_000, load_user, ctx);
+        if json.is_null() {
+            return None;
+        }
+        let s = CStr::from_ptr(json).to_str().unwrap().to_string();
+        rdcache_string_free(json);
+        Some(s)
+    }
+
+    #[test]
+    fn test_ffi() {
+        let fake = FakeBackend::new();
+        let runtime = new_runtime().unwrap();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let client = RdcacheClient::new(runtime, client);
+        let calls = AtomicUsize::new(0);
+        unsafe {
+            for _ in 0..2 {
+                assert_eq!(
+                    fetch(client, c"user:1", &calls).as_deref(),
+                    Some(r#"{"name":"ann"}"#)
+                );
+                assert_eq!(fetch(client, c"user:0", &calls).as_deref(), Some("null"));
+            }
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+            assert_eq!(fetch(client, c"user:2", &calls), None);
+
+            assert_eq!(rdcache_invalidate(client, c"user:1".as_ptr()), 0);
+            assert_eq!(fake.hget("user:1", "lockUntil"), Some(b"0".to_vec()));
+            fetch(client, c"user:1", &calls);
+            assert_eq!(calls.load(Ordering::SeqCst), 4);
+            rdcache_free(client);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    migrate::escape_glob, namespace::NAMESPACE_VERSION_PREFIX, tags::TAG_INDEX_PREFIX, Client,
+    Result,
+};
+use std::{
+    future::Future,
+    time::{Duration, UNIX_EPOCH},
+};
+
+// FLUSH_MARKER_PREFIX prefixes the keys locking a flush window, so that only one
+// instance runs it. They are not flushed themselves.
+const FLUSH_MARKER_PREFIX: &str = "rdcache:flush:";
+// FLUSH_MARKER_EXPIRE is how long a done window is remembered, instances reaching
+// the window later than that run it again.
+const FLUSH_MARKER_EXPIRE: Duration = Duration::from_secs(3600);
+// FLUSH_SCAN_COUNT is the COUNT hint of the SCAN calls of flush_namespace.
+const FLUSH_SCAN_COUNT: usize = 100;
+
+// FlushSchedule is when a scheduled flush runs, in UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlushSchedule {
+    // DailyAt runs at each of the times of day, in seconds after midnight.
+    DailyAt(Vec<u32>),
+    // Every runs at every multiple of the period since the unix epoch.
+    Every(Duration),
+}
+
+impl FlushSchedule {
+    // daily_at runs once a day at hour:minute.
+    pub fn daily_at(hour: u32, minute: u32) -> Self {
+        Self::DailyAt(vec![hour * 3600 + minute * 60])
+    }
+
+    // next_after returns the first time after the unix time secs, None if there is none.
+    fn next_after(&self, secs: u64) -> Option<u64> {
+        match self {
+            Self::DailyAt(times) => {
+                let day = secs - secs % 86400;
+                times
+                    .iter()
+                    .map(|t| day + (*t as u64 % 86400))
+                    .map(|at| if at > secs { at } else { at + 86400 })
+                    .min()
+            }
+            Self::Every(period) => {
+                let period = period.as_secs();
+                (period > 0).then(|| secs - secs % period + period)
+            }
+        }
+    }
+}
+
+impl Client {
+    // flush_namespace tag deletes every key under prefix, so each is reloaded by its
+    // next fetch and removed after Options::delay. The prefix is a full redis key
+    // prefix, common_prefix is not applied to it. It returns the number of keys flushed.
+    pub async fn flush_namespace(&self, prefix: &str) -> Result<u64> {
+        let pattern = format!("{}*", escape_glob(prefix));
+        let mut flushed = 0;
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = self
+                .backend
+                .scan(cursor, &pattern, FLUSH_SCAN_COUNT)
+                .await?;
+            let calls: Vec<_> = keys
+                .into_iter()
+                .filter(|key| {
+                    ![
+                        FLUSH_MARKER_PREFIX,
+                        TAG_INDEX_PREFIX,
+                        NAMESPACE_VERSION_PREFIX,
+                    ]
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix))
+                })
+                .map(|key| self.delete_call(key))
+                .collect();
+            for reply in self.call_lua_pipeline(calls).await {
+                reply?;
+                flushed += 1;
+            }
+            if next == 0 {
+                return Ok(flushed);
+            }
+            cursor = next;
+        }
+    }
+
+    // schedule_flush runs flush_namespace on prefix at the times of schedule, e.g. after
+    // the nightly ETL. See schedule_rewarm.
+    pub fn schedule_flush(&self, prefix: impl Into<String>, schedule: FlushSchedule) {
+        let prefix = prefix.into();
+        let flushed = prefix.clone();
+        self.schedule_rewarm(prefix, schedule, move |client| {
+            let prefix = flushed.clone();
+            async move {
+                client.flush_namespace(&prefix).await?;
+                Ok(())
+            }
+        });
+    }
+
+    // schedule_rewarm runs f at the times of schedule, e.g. to flush prefix and fill it
+    // again from a snapshot with warm_from. f gets a client sharing the backend of self.
+    // Each time is run by one instance only: it takes the lock of a marker key through
+    // the lock protocol, and the others wait for it and skip the time. A run taking
+    // longer than Options::lock_expire may be started again by another instance.
+    // Times missed while the process wasn't running are not caught up.
+    // Scheduling a prefix again replaces its previous schedule, nothing is scheduled
+    // after shutdown. It must be called within a tokio runtime.
+    pub fn schedule_rewarm<F, Fut>(&self, prefix: impl Into<String>, schedule: FlushSchedule, f: F)
+    where
+        F: Fn(Client) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let prefix = prefix.into();
+        let client = self.detach();
+        let task_prefix = prefix.clone();
+        let Some(task) = self.executor.spawn_service(async move {
+            let mut last = client.now_millis() / 1000;
+            while let Some(at) = schedule.next_after(last) {
+                let delay = (at * 1000).saturating_sub(client.now_millis());
+                client.executor.sleep(Duration::from_millis(delay)).await;
+                _ = client
+                    .executor
+                    .run(client.run_flush_window(&task_prefix, at, &f))
+                    .await;
+                last = at.max(client.now_millis() / 1000);
+            }
+        }) else {
+            return;
+        };
+        if let Some(old) = self.flushes.tasks.lock().unwrap().insert(prefix, task) {
+            old.abort();
+        }
+    }
+
+    // cancel_flush stops the scheduled flush or rewarm of prefix, returning false if there was none.
+    pub fn cancel_flush(&self, prefix: &str) -> bool {
+        match self.flushes.tasks.lock().unwrap().remove(prefix) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // run_flush_window runs f for the time at of prefix unless another instance did.
+    async fn run_flush_window<F, Fut>(&self, prefix: &str, at: u64, f: &F) -> Result<()>
+    where
+        F: Fn(Client) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let marker = format!("{}{}:{}", FLUSH_MARKER_PREFIX, prefix, at);
+        self.strong_fetch(&marker, FLUSH_MARKER_EXPIRE, &[], || async {
+            f(self.detach()).await?;
+            Ok(Some(at))
+        })
+        .await?;
+        Ok(())
+    }
+
+    fn now_millis(&self) -> u64 {
+        self.clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::ManualClock, test_util::FakeBackend, Options};
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::SystemTime,
+    };
+
+    #[test]
+    fn test_next_after() {
+        let day = 19_000 * 86400;
+        let schedule = FlushSchedule::DailyAt(vec![2 * 3600, 14 * 3600]);
+        assert_eq!(schedule.next_after(day), Some(day + 2 * 3600));
+        assert_eq!(schedule.next_after(day + 2 * 3600), Some(day + 14 * 3600));
+        assert_eq!(
+            schedule.next_after(day + 15 * 3600),
+            Some(day + 86400 + 2 * 3600)
+        );
+        assert_eq!(FlushSchedule::DailyAt(Vec::new()).next_after(day), None);
+
+        let schedule = FlushSchedule::Every(Duration::from_secs(60));
+        assert_eq!(schedule.next_after(day + 59), Some(day + 60));
+        assert_eq!(schedule.next_after(day + 60), Some(day + 120));
+        assert_eq!(FlushSchedule::Every(Duration::ZERO).next_after(day), None);
+    }
+
+    #[tokio::test]
+    async fn test_flush_namespace() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        for key in ["app:a", "app:b", "other:a"] {
+            client
+                .fetch(key, Duration::from_secs(600), || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+        }
+        assert_eq!(client.flush_namespace("app:").await.unwrap(), 2);
+        assert_eq!(fake.hget("app:a", "lockUntil"), Some(b"0".to_vec()));
+        assert_eq!(fake.hget("other:a", "lockUntil"), None);
+
+        let reloaded: Option<u64> = client
+            .fetch("app:a", Duration::from_secs(600), || async { Ok(Some(2)) })
+            .await
+            .unwrap();
+        assert_eq!(reloaded, Some(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_schedule_rewarm_runs_once_per_time() {
+        let day = 19_000 * 86400;
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(day + 3590));
+        let fake = FakeBackend::with_clock(clock.clone());
+        let runs = Arc::new(AtomicU64::new(0));
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let client =
+                Client::with_backend(fake.clone(), Options::default()).with_clock(clock.clone());
+            let counted = runs.clone();
+            client.schedule_rewarm(
+                "app:",
+                FlushSchedule::Every(Duration::from_secs(3600)),
+                move |client| {
+                    let runs = counted.clone();
+                    async move {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        client.flush_namespace("app:").await?;
+                        Ok(())
+                    }
+                },
+            );
+            clients.push(client);
+        }
+        clients[0]
+            .fetch("app:a", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(fake.hget("app:a", "lockUntil"), Some(b"0".to_vec()));
+
+        assert!(clients[0].cancel_flush("app:"));
+        assert!(!clients[0].cancel_flush("app:"));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{error::new_unexpected_reply_error, Client, Error, Reply};
+use async_graphql::dataloader::Loader;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// CachedLoader is an async-graphql Loader caching the values of another one with
+// Client::fetch_batch, under the key its key function returns for a loader key. The
+// keys of a batch missing from the cache are loaded with one call to the inner loader,
+// and the keys it doesn't return are cached as empty results.
+pub struct CachedLoader<L, F> {
+    inner: L,
+    client: Client,
+    expire: Duration,
+    key: F,
+}
+
+impl<L, F> CachedLoader<L, F> {
+    // new caches the values of inner for expire, under the key returned by key.
+    pub fn new(inner: L, client: Client, expire: Duration, key: F) -> Self {
+        Self {
+            inner,
+            client,
+            expire,
+            key,
+        }
+    }
+}
+
+// CachedLoaderError is the error of a CachedLoader: the error of the inner loader,
+// or of the cache.
+#[derive(Debug, Clone)]
+pub enum CachedLoaderError<E> {
+    Inner(E),
+    Cache(Arc<Error>),
+}
+
+impl<K, L, F> Loader<K> for CachedLoader<L, F>
+where
+    K: Send + Sync + Hash + Eq + Clone + 'static,
+    L: Loader<K>,
+    L::Value: DeserializeOwned + Serialize + Debug,
+    F: Fn(&K) -> String + Send + Sync + 'static,
+{
+    type Value = L::Value;
+    type Error = CachedLoaderError<L::Error>;
+
+    async fn load(&self, keys: &[K]) -> Result<HashMap<K, Self::Value>, Self::Error> {
+        let failed = Mutex::new(None);
+        let cache_keys = keys.iter().map(|k| (self.key)(k)).collect();
+        let fetched = self
+            .client
+            .fetch_batch(cache_keys, self.expire, |idxs: Vec<usize>| {
+                let failed = &failed;
+                async move {
+                    let missing: Vec<K> = idxs.iter().map(|&i| keys[i].clone()).collect();
+                    match self.inner.load(&missing).await {
+                        Ok(mut values) => Ok(idxs
+                            .into_iter()
+                            .filter_map(|i| values.remove(&keys[i]).map(|value| (i, value)))
+                            .collect()),
+                        Err(e) => {
+                            *failed.lock().unwrap() = Some(e);
+                            // the error of the inner loader is returned instead.
+                            Err(new_unexpected_reply_error(Reply::Nil))
+                        }
+                    }
+                }
+            })
+            .await;
+        if let Some(e) = failed.lock().unwrap().take() {
+            return Err(CachedLoaderError::Inner(e));
+        }
+        let fetched = fetched.map_err(|e| CachedLoaderError::Cache(Arc::new(e)))?;
+        Ok(fetched
+            .into_iter()
+            .map(|(i, value)| (keys[i].clone(), value))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+
+    #[derive(Default)]
+    struct Users {
+        batches: Mutex<Vec<Vec<u64>>>,
+    }
+
+    impl Loader<u64> for Users {
+        type Value = String;
+        type Error = String;
+
+        async fn load(&self, keys: &[u64]) -> Result<HashMap<u64, String>, String> {
+            let mut keys = keys.to_vec();
+            keys.sort();
+            self.batches.lock().unwrap().push(keys.clone());
+            if keys.contains(&13) {
+                return Err("unlucky".to_string());
+            }
+            Ok(keys
+                .into_iter()
+                .filter(|id| *id != 0)
+                .map(|id| (id, format!("user {}", id)))
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_loader() {
+        let client = Client::with_backend(FakeBackend::new(), Options::default());
+        let loader = CachedLoader::new(
+            Users::default(),
+            client,
+            Duration::from_secs(600),
+            |id: &u64| format!("user:{}", id),
+        );
+
+        let values = loader.load(&[1, 2, 0]).await.unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[&1], "user 1");
+        let values = loader.load(&[2, 3, 0]).await.unwrap();
+        assert_eq!(values[&3], "user 3");
+        assert_eq!(
+            *loader.inner.batches.lock().unwrap(),
+            vec![vec![0, 1, 2], vec![3]]
+        );
+
+        let err = loader.load(&[4, 13]).await.unwrap_err();
+        assert!(matches!(err, CachedLoaderError::Inner(e) if e == "unlucky"));
+        let values = loader.load(&[1, 4]).await.unwrap();
+        assert_eq!(values[&4], "user 4");
+    }
+
+    #[tokio::test]
+    async fn test_cached_loader_overlapping_loads() {
+        let options = Options {
+            lock_renewal: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(FakeBackend::new(), options);
+        let loader = CachedLoader::new(
+            Users::default(),
+            client.clone(),
+            Duration::from_secs(600),
+            |id: &u64| format!("user:{}", id),
+        );
+
+        // a fetch holding the lock of user:2 loads user:1, while a load of both holds
+        // the lock of user:1 and waits for user:2.
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let holder = client.fetch("user:2", Duration::from_secs(600), || async {
+            tx.send(()).unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut values = loader.load(&[1]).await.unwrap();
+            Ok(values.remove(&1))
+        });
+        let load = async {
+            rx.await.unwrap();
+            loader.load(&[1, 2]).await
+        };
+        let loads = futures::future::join(holder, load);
+        let (held, loaded) = tokio::time::timeout(Duration::from_secs(5), loads)
+            .await
+            .expect("the loads don't wait for each other");
+        assert_eq!(held.unwrap(), Some("user 1".to_string()));
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded[&1], "user 1");
+        assert_eq!(loaded[&2], "user 1");
+    }
+}
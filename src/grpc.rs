@@ -0,0 +1,193 @@
+use crate::{Client, Result};
+use prost::Message;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha1::{Digest, Sha1};
+use std::{collections::HashMap, fmt, future::Future, sync::Mutex, time::Duration};
+use tonic::{Request, Response, Status};
+
+// GrpcCache caches the responses of tonic unary handlers with Client::fetch, keyed on
+// the method and the encoded request:
+//
+//     async fn get_user(&self, request: Request<GetUserRequest>) -> Result<Response<User>, Status> {
+//         self.cache.unary("/users.Users/GetUser", request, |r| self.load_user(r)).await
+//     }
+//
+// Only the response message is cached, not its metadata. Requests with map fields
+// may be encoded differently from one call to the other and miss the cache.
+pub struct GrpcCache {
+    client: Client,
+    expire: Duration,
+    methods: HashMap<String, Duration>,
+}
+
+impl GrpcCache {
+    // new caches the responses of every method for expire.
+    pub fn new(client: Client, expire: Duration) -> Self {
+        Self {
+            client,
+            expire,
+            methods: HashMap::new(),
+        }
+    }
+
+    // with_method_expire caches the responses of method for expire instead, 0 disables
+    // the cache for it.
+    pub fn with_method_expire(mut self, method: impl Into<String>, expire: Duration) -> Self {
+        self.methods.insert(method.into(), expire);
+        self
+    }
+
+    // unary returns the cached response of method to request, calling handler and
+    // caching its response on a miss. A status returned by handler is not cached.
+    pub async fn unary<Req, Resp, F, Fut>(
+        &self,
+        method: &str,
+        request: Request<Req>,
+        handler: F,
+    ) -> std::result::Result<Response<Resp>, Status>
+    where
+        Req: Message,
+        Resp: Message + Default,
+        F: FnOnce(Request<Req>) -> Fut,
+        Fut: Future<Output = std::result::Result<Response<Resp>, Status>>,
+    {
+        let expire = self.methods.get(method).copied().unwrap_or(self.expire);
+        if expire.is_zero() {
+            return handler(request).await;
+        }
+        let key = self.key(method, request.get_ref());
+        // the status of the handler is kept aside, fetch only sees that the load failed.
+        let failed = Mutex::new(None);
+        let fetched = self
+            .client
+            .fetch(key, expire, || async {
+                match handler(request).await {
+                    Ok(response) => Ok(Some(Encoded(response.into_inner().encode_to_vec()))),
+                    Err(status) => {
+                        *failed.lock().unwrap() = Some(status);
+                        Err(crate::error::new_unexpected_reply_error(crate::Reply::Nil))
+                    }
+                }
+            })
+            .await;
+        if let Some(status) = failed.lock().unwrap().take() {
+            return Err(status);
+        }
+        match fetched {
+            Ok(Some(Encoded(bytes))) => Resp::decode(bytes.as_slice())
+                .map(Response::new)
+                .map_err(|e| Status::internal(format!("cached response: {}", e))),
+            Ok(None) => Err(Status::not_found("no cached response")),
+            Err(e) => Err(Status::internal(format!("cache error: {}", e))),
+        }
+    }
+
+    // invalidate tag deletes the cached response of method to request, e.g. from the
+    // handler of a method changing it.
+    pub async fn invalidate<Req: Message>(&self, method: &str, request: &Req) -> Result<()> {
+        self.client.tag_as_deleted(self.key(method, request)).await
+    }
+
+    // key is the cache key of the response of method to request.
+    pub fn key<Req: Message>(&self, method: &str, request: &Req) -> String {
+        let hash = Sha1::digest(request.encode_to_vec());
+        format!("grpc:{}:{:x}", method, hash)
+    }
+}
+
+// Encoded is an encoded message, serialized as bytes rather than as a list of numbers.
+struct Encoded(Vec<u8>);
+
+impl fmt::Debug for Encoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Encoded({} bytes)", self.0.len())
+    }
+}
+
+impl Serialize for Encoded {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Encoded {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Encoded;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("bytes")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Encoded, E> {
+                Ok(Encoded(v.to_vec()))
+            }
+        }
+
+        deserializer.deserialize_bytes(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Echo {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    #[tokio::test]
+    async fn test_grpc_cache() {
+        let client = Client::with_backend(FakeBackend::new(), Options::default());
+        let cache = GrpcCache::new(client, Duration::from_secs(600))
+            .with_method_expire("/echo.Echo/Now", Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+        let echo = |request: Request<Echo>| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match request.into_inner().text.as_str() {
+                    "fail" => Err(Status::invalid_argument("fail")),
+                    text => Ok(Response::new(Echo {
+                        text: format!("echo {}", text),
+                    })),
+                }
+            }
+        };
+        let call = |method: &'static str, text: &str| {
+            let request = Request::new(Echo {
+                text: text.to_string(),
+            });
+            let cache = &cache;
+            async move {
+                cache
+                    .unary(method, request, echo)
+                    .await
+                    .map(|r| r.into_inner().text)
+            }
+        };
+
+        for _ in 0..2 {
+            assert_eq!(call("/echo.Echo/Echo", "a").await.unwrap(), "echo a");
+            assert_eq!(call("/echo.Echo/Now", "a").await.unwrap(), "echo a");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(call("/echo.Echo/Echo", "b").await.unwrap(), "echo b");
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let status = call("/echo.Echo/Echo", "fail").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let request = Echo {
+            text: "a".to_string(),
+        };
+        cache.invalidate("/echo.Echo/Echo", &request).await.unwrap();
+        call("/echo.Echo/Echo", "a").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+}
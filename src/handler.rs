@@ -0,0 +1,126 @@
+use crate::{Client, Error, Result};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, future::Future, sync::Arc, time::Duration};
+
+// CacheExt caches the results of axum handlers, on the Client held in the router state:
+//
+//     async fn user(State(cache): State<Client>, Path(id): Path<u64>)
+//         -> Result<Cached<User>, Error> {
+//         cache.cached(format!("user:{}", id), Duration::from_secs(60), || load_user(id)).await
+//     }
+pub trait CacheExt {
+    fn cache_client(&self) -> &Client;
+
+    // cached returns the value of key, loading it with f and caching it for expire.
+    // An empty result responds with 404 Not Found.
+    fn cached<T, F, Fut>(
+        &self,
+        key: impl AsRef<str> + Send,
+        expire: Duration,
+        f: F,
+    ) -> impl Future<Output = Result<Cached<T>>> + Send
+    where
+        Self: Sync,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<Option<T>>> + Send,
+        T: DeserializeOwned + Serialize + Debug + Send,
+    {
+        async move {
+            let value = self.cache_client().fetch(key, expire, f).await?;
+            Ok(Cached(value))
+        }
+    }
+}
+
+impl CacheExt for Client {
+    fn cache_client(&self) -> &Client {
+        self
+    }
+}
+
+impl CacheExt for Arc<Client> {
+    fn cache_client(&self) -> &Client {
+        self
+    }
+}
+
+// Cached is a cached handler result, responding with it as json, or 404 Not Found if
+// it is empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cached<T>(pub Option<T>);
+
+impl<T: Serialize> IntoResponse for Cached<T> {
+    fn into_response(self) -> Response {
+        match self.0 {
+            Some(value) => Json(value).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+}
+
+// A cache error responds with 500 Internal Server Error, without details of the
+// error, which is logged with the tracing feature.
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        #[cfg(feature = "tracing")]
+        tracing::error!(error = ?self, "cache error");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use axum::{
+        body,
+        extract::{Path, State},
+        routing::get,
+        Router,
+    };
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct User {
+        id: u64,
+    }
+
+    async fn user(State(cache): State<Client>, Path(id): Path<u64>) -> Result<Cached<User>> {
+        cache
+            .cached(
+                format!("user:{}", id),
+                Duration::from_secs(60),
+                || async move { Ok((id != 0).then_some(User { id })) },
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_cached_handler() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let _: Router = Router::new()
+            .route("/users/{id}", get(user))
+            .with_state(client.clone());
+
+        let response = user(State(client.clone()), Path(7)).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = body::to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(&bytes[..], br#"{"id":7}"#);
+        assert!(fake.hget("user:7", "value").is_some());
+
+        let response = user(State(client.clone()), Path(0)).await.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        fake.fail_next(1);
+        let response = user(State(client), Path(8)).await.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let bytes = body::to_bytes(response.into_body(), 1024).await.unwrap();
+        assert!(bytes.is_empty());
+    }
+}
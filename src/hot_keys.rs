@@ -0,0 +1,204 @@
+use crate::{shard::Sharded, Client};
+use std::{collections::HashMap, time::Duration};
+
+// HotKey is a key among the most fetched ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotKey {
+    // Key is the redis key, with common_prefix.
+    pub key: String,
+    // Count is the estimated number of fetches of the key, halved at every hot key report.
+    // It overestimates the real number by at most Error.
+    pub count: u64,
+    pub error: u64,
+}
+
+// HotKeySketch tracks the most fetched keys with the SpaceSaving algorithm: it holds
+// a bounded number of counters, and a key without one takes over the smallest, so
+// the keys fetched more often than 1/capacity of the time are never missed. It feeds
+// the hot key report and the promotion of Options::local_min_fetches.
+// Large sketches are sharded by key, each shard holding at least SHARD_CAPACITY
+// counters, so that concurrent fetches don't all wait for one lock.
+#[derive(Debug, Default)]
+pub(crate) struct HotKeySketch {
+    // capacity is the number of counters of each shard.
+    capacity: usize,
+    counters: Sharded<HashMap<String, (u64, u64)>>,
+}
+
+// SHARD_CAPACITY is the smallest number of counters of a shard.
+const SHARD_CAPACITY: usize = 64;
+
+impl HotKeySketch {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let counters = Sharded::new(capacity / SHARD_CAPACITY);
+        Self {
+            capacity: capacity.div_ceil(counters.len()),
+            counters,
+        }
+    }
+
+    pub(crate) fn record(&self, key: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut counters = self.counters.shard(key);
+        if let Some((count, _)) = counters.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        if counters.len() < self.capacity {
+            counters.insert(key.to_string(), (1, 0));
+            return;
+        }
+        let Some((min_key, (min, _))) = counters
+            .iter()
+            .min_by_key(|(_, (count, _))| *count)
+            .map(|(k, c)| (k.clone(), *c))
+        else {
+            return;
+        };
+        counters.remove(&min_key);
+        counters.insert(key.to_string(), (min + 1, min));
+    }
+
+    // count returns the estimated number of fetches of key, 0 if it is not tracked.
+    #[cfg_attr(not(feature = "local-cache"), allow(dead_code))]
+    pub(crate) fn count(&self, key: &str) -> u64 {
+        if self.capacity == 0 {
+            return 0;
+        }
+        self.counters
+            .shard(key)
+            .get(key)
+            .map_or(0, |(count, _)| *count)
+    }
+
+    fn top(&self, n: usize) -> Vec<HotKey> {
+        let mut keys: Vec<_> = self
+            .counters
+            .iter()
+            .flat_map(|counters| {
+                counters
+                    .iter()
+                    .map(|(key, (count, error))| HotKey {
+                        key: key.clone(),
+                        count: *count,
+                        error: *error,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        keys.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+        keys.truncate(n);
+        keys
+    }
+
+    // decay halves the counters, so the sketch follows the keys that are hot now.
+    fn decay(&self) {
+        for mut counters in self.counters.iter() {
+            counters.retain(|_, (count, error)| {
+                *count /= 2;
+                *error /= 2;
+                *count > 0
+            });
+        }
+    }
+}
+
+impl Client {
+    // hot_keys returns the n most fetched keys, most fetched first.
+    // It is empty unless Options::hot_key_capacity is set.
+    pub fn hot_keys(&self, n: usize) -> Vec<HotKey> {
+        self.hot_keys.top(n)
+    }
+
+    // start_hot_key_report calls report with the n hottest keys every interval, e.g. for
+    // capacity planning, and halves their counts afterwards. It runs until
+    // stop_hot_key_report is called or the client is dropped, replacing a running
+    // report, and is not started after shutdown. It must be called within a tokio runtime.
+    pub fn start_hot_key_report(
+        &self,
+        interval: Duration,
+        n: usize,
+        report: impl Fn(&[HotKey]) + Send + Sync + 'static,
+    ) {
+        let sketch = self.hot_keys.clone();
+        let sleep = self.detach();
+        let task = self.executor.spawn_service(async move {
+            loop {
+                sleep.executor.sleep(interval).await;
+                report(&sketch.top(n));
+                sketch.decay();
+            }
+        });
+        if let Some(task) = task {
+            self.hot_key_report.set(task);
+        }
+    }
+
+    // stop_hot_key_report stops the hot key report, returning false if none was running.
+    pub fn stop_hot_key_report(&self) -> bool {
+        self.hot_key_report.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_sketch_keeps_heavy_hitters() {
+        let sketch = HotKeySketch::new(8);
+        for i in 0..1000 {
+            sketch.record("hot");
+            if i % 3 == 0 {
+                sketch.record("warm");
+            }
+            sketch.record(&format!("cold{}", i));
+        }
+        let top = sketch.top(2);
+        assert_eq!(top[0].key, "hot");
+        assert!(top[0].count - top[0].error <= 1000 && top[0].count >= 1000);
+        assert_eq!(top[1].key, "warm");
+
+        sketch.decay();
+        assert!(sketch.top(1)[0].count >= 500);
+        assert!(HotKeySketch::new(0).top(1).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hot_key_report() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            hot_key_capacity: 16,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake, options);
+        for key in ["a", "b", "a", "a", "b", "c"] {
+            client
+                .fetch(key, Duration::from_secs(600), || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+        }
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reported = reports.clone();
+        client.start_hot_key_report(Duration::from_secs(10), 2, move |keys| {
+            reported.lock().unwrap().push(
+                keys.iter()
+                    .map(|k| (k.key.clone(), k.count))
+                    .collect::<Vec<_>>(),
+            )
+        });
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(
+            *reports.lock().unwrap(),
+            vec![
+                vec![("a".to_string(), 3), ("b".to_string(), 2)],
+                vec![("a".to_string(), 1), ("b".to_string(), 1)],
+            ]
+        );
+        assert!(client.stop_hot_key_report());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{backend::Args, migrate::escape_glob, script::CLEAN_LOCK_SCRIPT, Client, Result};
+use std::time::Duration;
+
+// JANITOR_SCAN_COUNT is the COUNT hint of the SCAN calls of a janitor pass.
+const JANITOR_SCAN_COUNT: usize = 100;
+
+impl Client {
+    // clean_orphaned_locks scans the keys under common_prefix for locks that expired more
+    // than grace ago on keys without a value, left behind by owners that crashed while
+    // loading, and clears their lock fields. It returns the number of keys cleaned.
+    // Locks on keys with a value and non hash keys are left alone.
+    pub async fn clean_orphaned_locks(&self, grace: Duration) -> Result<u64> {
+        let pattern = format!("{}*", escape_glob(&self.options().common_prefix));
+        let mut cleaned = 0;
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = self
+                .backend
+                .scan(cursor, &pattern, JANITOR_SCAN_COUNT)
+                .await?;
+            for key in keys {
+                let reply = self
+                    .call_lua(
+                        &CLEAN_LOCK_SCRIPT,
+                        vec![key],
+                        Args::default()
+                            .arg(self.lock_span(grace))
+                            .arg(self.lock_unit())
+                            .build(),
+                    )
+                    .await?;
+                cleaned += reply.as_int()? as u64;
+            }
+            if next == 0 {
+                return Ok(cleaned);
+            }
+            cursor = next;
+        }
+    }
+
+    // start_janitor runs clean_orphaned_locks every interval in the background until
+    // stop_janitor is called or the client is dropped, replacing a running janitor.
+    // It is not started after shutdown. It must be called within a tokio runtime.
+    pub fn start_janitor(&self, interval: Duration, grace: Duration) {
+        let client = self.detach();
+        let janitor = self.executor.spawn_service(async move {
+            loop {
+                _ = client
+                    .executor
+                    .run(client.clean_orphaned_locks(grace))
+                    .await;
+                client.executor.sleep(interval).await;
+            }
+        });
+        if let Some(janitor) = janitor {
+            self.janitor.set(janitor);
+        }
+    }
+
+    // stop_janitor stops the janitor, returning false if none was running.
+    pub fn stop_janitor(&self) -> bool {
+        self.janitor.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{unix_millis, ManualClock},
+        test_util::FakeBackend,
+        Options,
+    };
+
+    #[tokio::test]
+    async fn test_clean_orphaned_locks() {
+        let clock = ManualClock::default();
+        let fake = FakeBackend::with_clock(clock.clone());
+        let client = Client::with_backend(
+            fake.clone(),
+            Options {
+                common_prefix: "app:".to_string(),
+                ..Default::default()
+            },
+        )
+        .with_clock(clock.clone());
+        let now = unix_millis(&clock);
+        fake.hset("app:crashed", "lockUntil", (now - 120_000).to_string());
+        fake.hset("app:crashed", "lockOwner", "gone");
+        fake.hset("app:loading", "lockUntil", (now + 3_000).to_string());
+        fake.hset("app:recent", "lockUntil", (now - 10_000).to_string());
+        fake.hset("other:crashed", "lockUntil", (now - 120_000).to_string());
+        client
+            .fetch("cached", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+
+        let cleaned = client
+            .clean_orphaned_locks(Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(cleaned, 1);
+        assert_eq!(
+            fake.keys(),
+            vec!["app:cached", "app:loading", "app:recent", "other:crashed"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_janitor() {
+        let clock = ManualClock::default();
+        let fake = FakeBackend::with_clock(clock.clone());
+        let client =
+            Client::with_backend(fake.clone(), Options::default()).with_clock(clock.clone());
+        client.start_janitor(Duration::from_secs(10), Duration::ZERO);
+        fake.hset("crashed", "lockUntil", "1");
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(fake.keys().is_empty());
+
+        assert!(client.stop_janitor());
+        assert!(!client.stop_janitor());
+        fake.hset("crashed", "lockUntil", "1");
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(fake.keys(), vec!["crashed"]);
+    }
+}
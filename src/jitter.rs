@@ -0,0 +1,48 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// GOLDEN_GAMMA is the increment of the splitmix64 sequence.
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// random_jitter returns a random number in [0, 1), from the random keys std gives to
+// each RandomState. It is the default jitter of a Client.
+pub(crate) fn random_jitter() -> f64 {
+    fraction(RandomState::new().build_hasher().finish())
+}
+
+// seeded_jitter returns a jitter for Client::with_jitter drawing the same sequence of
+// numbers in [0, 1) for the same seed, for tests and simulations to be reproducible.
+pub fn seeded_jitter(seed: u64) -> impl Fn() -> f64 + Send + Sync + 'static {
+    let state = AtomicU64::new(seed);
+    move || {
+        let mut z = state
+            .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+            .wrapping_add(GOLDEN_GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        fraction(z ^ (z >> 31))
+    }
+}
+
+// fraction maps the 53 high bits of random to [0, 1).
+fn fraction(random: u64) -> f64 {
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_jitter() {
+        let (a, b) = (seeded_jitter(7), seeded_jitter(7));
+        let drawn: Vec<f64> = (0..100).map(|_| a()).collect();
+        assert_eq!(drawn, (0..100).map(|_| b()).collect::<Vec<_>>());
+        assert!(drawn.iter().all(|x| (0.0..1.0).contains(x)));
+        assert!(drawn.iter().any(|x| *x < 0.5) && drawn.iter().any(|x| *x >= 0.5));
+        assert_ne!(seeded_jitter(8)(), drawn[0]);
+    }
+}
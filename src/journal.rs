@@ -0,0 +1,316 @@
+use crate::{error::new_io_error, Client, Result};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+// JOURNAL_COMPACT_LINES is the number of lines above which the journal is rewritten
+// with only the pending keys, once it holds twice as many lines as pending keys.
+const JOURNAL_COMPACT_LINES: usize = 1024;
+
+// Journal is an append only file of the invalidations started but not yet done,
+// `+<key>` when one starts and `-<key>` when it is done. A start is synced to disk
+// before the invalidation runs, so it survives a crash of the process.
+pub(crate) struct Journal {
+    path: PathBuf,
+    state: Mutex<JournalState>,
+}
+
+struct JournalState {
+    file: File,
+    // pending counts the starts of each key not matched by a done.
+    pending: HashMap<String, usize>,
+    lines: usize,
+}
+
+impl Journal {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let mut pending = HashMap::new();
+        let mut lines = 0;
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                lines += 1;
+                match line.split_at_checked(1) {
+                    Some(("+", key)) => *pending.entry(unescape(key)).or_default() += 1,
+                    Some(("-", key)) => {
+                        let key = unescape(key);
+                        if let Some(n) = pending.get_mut(&key) {
+                            *n -= 1;
+                            if *n == 0 {
+                                pending.remove(&key);
+                            }
+                        }
+                    }
+                    // A line torn by a crash is ignored.
+                    _ => {}
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new(JournalState {
+                file,
+                pending,
+                lines,
+            }),
+        })
+    }
+
+    fn begin(&self, key: &str) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        writeln!(state.file, "+{}", escape(key))?;
+        state.file.sync_data()?;
+        state.lines += 1;
+        *state.pending.entry(key.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    // done records that the invalidation of key succeeded. It is not synced, if it is
+    // lost the key is only invalidated once more on replay.
+    fn done(&self, key: &str) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(n) = state.pending.get_mut(key) else {
+            return Ok(());
+        };
+        *n -= 1;
+        if *n == 0 {
+            state.pending.remove(key);
+        }
+        if state.lines > JOURNAL_COMPACT_LINES.max(state.pending.len() * 2) {
+            return self.compact(&mut state);
+        }
+        writeln!(state.file, "-{}", escape(key))?;
+        state.lines += 1;
+        Ok(())
+    }
+
+    // compact replaces the journal by a new one holding only the pending keys.
+    fn compact(&self, state: &mut JournalState) -> std::io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        let mut lines = 0;
+        for (key, n) in &state.pending {
+            for _ in 0..*n {
+                writeln!(file, "+{}", escape(key))?;
+                lines += 1;
+            }
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        state.file = OpenOptions::new().append(true).open(&self.path)?;
+        state.lines = lines;
+        Ok(())
+    }
+
+    fn pending(&self) -> Vec<String> {
+        let mut keys: Vec<_> = self.state.lock().unwrap().pending.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+}
+
+fn escape(key: &str) -> String {
+    key.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => break,
+        }
+    }
+    out
+}
+
+impl Client {
+    // with_invalidation_journal journals the invalidations of tag_as_deleted and update
+    // to the file at path, synced before they run, for deployments where a crash between
+    // a database write and its invalidation must not leave a stale value behind.
+    // Invalidations that didn't complete are run again by replay_invalidation_journal,
+    // which should be called on startup.
+    pub fn with_invalidation_journal(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let journal = Journal::open(path.as_ref()).map_err(new_io_error)?;
+        self.journal = Some(Arc::new(journal));
+        Ok(self)
+    }
+
+    // replay_invalidation_journal tag deletes the keys of the journal whose invalidation
+    // didn't complete, in one round trip, returning their number. It stops at the first error.
+    pub async fn replay_invalidation_journal(&self) -> Result<u64> {
+        let Some(journal) = &self.journal else {
+            return Ok(0);
+        };
+        let keys = journal.pending();
+        let calls = keys
+            .iter()
+            .map(|key| self.delete_call(key.clone()))
+            .collect();
+        let mut replayed = 0;
+        for (key, reply) in keys.iter().zip(self.call_lua_pipeline(calls).await) {
+            reply?;
+            self.executor
+                .block_in_place(|| journal.done(key))
+                .map_err(new_io_error)?;
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+
+    // update runs f, e.g. the database write changing the value of key, and tag deletes
+    // key afterwards, even if f failed. With a journal, the invalidation is journaled
+    // before f runs, so it is replayed if the process crashes before it is done.
+    pub async fn update<F, Fut, T>(&self, key: impl AsRef<str>, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if self.options().disable_cache_delete {
+            return f().await;
+        }
+        let key = self.prefixed_key(key.as_ref());
+        self.journal_begin(&key)?;
+        let result = f().await;
+        let invalidated = self.invalidate(key).await;
+        let value = result?;
+        invalidated?;
+        Ok(value)
+    }
+
+    // journal_begin journals the start of the invalidation of key. The file is written
+    // and synced with Runtime::block_in_place, not to stall the other tasks of the worker.
+    pub(crate) fn journal_begin(&self, key: &str) -> Result<()> {
+        match &self.journal {
+            Some(journal) => self
+                .executor
+                .block_in_place(|| journal.begin(key))
+                .map_err(new_io_error),
+            None => Ok(()),
+        }
+    }
+
+    // journal_done records that the invalidation of key completed, like journal_begin
+    // with block_in_place as it may compact the journal. A failure only leaves the key
+    // to be invalidated once more on replay, so it is ignored.
+    pub(crate) fn journal_done(&self, key: &str) {
+        if let Some(journal) = &self.journal {
+            _ = self.executor.block_in_place(|| journal.done(key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::BoxFuture, test_util::FakeBackend, Error, Options, Runtime};
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+
+    fn journal_path() -> PathBuf {
+        std::env::temp_dir().join(format!("rdcache-journal-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_escape() {
+        for key in ["plain", "a\nb", "c\\nd\\", ""] {
+            assert_eq!(unescape(&escape(key)), key);
+            assert!(!escape(key).contains('\n'));
+        }
+    }
+
+    #[test]
+    fn test_journal_compacts() {
+        let path = journal_path();
+        let journal = Journal::open(&path).unwrap();
+        journal.begin("kept").unwrap();
+        for i in 0..JOURNAL_COMPACT_LINES {
+            journal.begin(&format!("k{}", i)).unwrap();
+            journal.done(&format!("k{}", i)).unwrap();
+        }
+        assert!(fs::read_to_string(&path).unwrap().lines().count() < JOURNAL_COMPACT_LINES);
+        drop(journal);
+        assert_eq!(Journal::open(&path).unwrap().pending(), vec!["kept"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_journal_replayed_after_crash() {
+        let path = journal_path();
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default())
+            .with_invalidation_journal(&path)
+            .unwrap();
+        client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        client.tag_as_deleted("done").await.unwrap();
+        let failing = fake.clone();
+        let updated = client
+            .update("k", || async move {
+                failing.fail_next(1);
+                Ok("written")
+            })
+            .await;
+        assert!(updated.as_ref().is_err_and(Error::is_redis_error));
+        // the process crashes before the retry runs
+        drop(client);
+        assert_eq!(fake.hget("k", "lockUntil"), None);
+
+        let client = Client::with_backend(fake.clone(), Options::default())
+            .with_invalidation_journal(&path)
+            .unwrap();
+        assert_eq!(client.replay_invalidation_journal().await.unwrap(), 1);
+        assert_eq!(fake.hget("k", "lockUntil"), Some(b"0".to_vec()));
+        assert_eq!(client.replay_invalidation_journal().await.unwrap(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_journal_io_blocks_in_place() {
+        struct Blocking(Arc<AtomicU64>);
+
+        impl Runtime for Blocking {
+            fn spawn(&self, task: BoxFuture<'static, ()>) {
+                tokio::spawn(task);
+            }
+
+            fn sleep(&self, d: Duration) -> BoxFuture<'static, ()> {
+                Box::pin(tokio::time::sleep(d))
+            }
+
+            fn block_in_place(&self, f: &mut dyn FnMut()) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                tokio::task::block_in_place(f)
+            }
+        }
+
+        let path = journal_path();
+        let blocked = Arc::new(AtomicU64::new(0));
+        let client = Client::with_backend(FakeBackend::new(), Options::default())
+            .with_runtime(Blocking(blocked.clone()))
+            .with_invalidation_journal(&path)
+            .unwrap();
+        client.tag_as_deleted("k").await.unwrap();
+        // the begin and the done of the invalidation
+        assert_eq!(blocked.load(Ordering::SeqCst), 2);
+        client.update("k", || async { Ok(()) }).await.unwrap();
+        assert_eq!(blocked.load(Ordering::SeqCst), 4);
+        fs::remove_file(&path).unwrap();
+    }
+}
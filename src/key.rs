@@ -0,0 +1,233 @@
+use std::fmt::{Display, Write};
+
+pub use rdcache_macros::{cached, CacheKey};
+
+// CacheKey turns a value into the redis key it is cached under.
+// Derive it with `#[derive(CacheKey)]` so the fetch side and the invalidate side
+// always build the same key string.
+pub trait CacheKey {
+    fn cache_key(&self) -> String;
+}
+
+impl CacheKey for str {
+    fn cache_key(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl CacheKey for String {
+    fn cache_key(&self) -> String {
+        self.clone()
+    }
+}
+
+impl<T: CacheKey + ?Sized> CacheKey for &T {
+    fn cache_key(&self) -> String {
+        (**self).cache_key()
+    }
+}
+
+// KeyBuilder composes a key from a namespace, an entity, an id and a version, as
+// namespace:entity:id:v<version>, escaping the separators in each part. It is passed to
+// fetch and tag_as_deleted as is, so a function returning the KeyBuilder of an entity
+// builds its key the same way at the fetch site and at the invalidation site.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeyBuilder {
+    key: String,
+}
+
+impl KeyBuilder {
+    // new starts a key in namespace, e.g. the name of the service.
+    pub fn new(namespace: &str) -> Self {
+        Self {
+            key: escape_key_part(namespace),
+        }
+    }
+
+    // entity appends the kind of the cached value, e.g. user.
+    pub fn entity(self, entity: &str) -> Self {
+        self.part(entity)
+    }
+
+    // id appends the id of the cached value.
+    pub fn id(self, id: impl Display) -> Self {
+        self.part(&id.to_string())
+    }
+
+    // version appends the version of the layout of the cached value, bumped to stop
+    // reading the values cached before a change of the type.
+    pub fn version(self, version: u32) -> Self {
+        self.part(&format!("v{}", version))
+    }
+
+    fn part(mut self, part: &str) -> Self {
+        self.key.push(':');
+        self.key.push_str(&escape_key_part(part));
+        self
+    }
+}
+
+impl CacheKey for KeyBuilder {
+    fn cache_key(&self) -> String {
+        self.key.clone()
+    }
+}
+
+impl AsRef<str> for KeyBuilder {
+    fn as_ref(&self) -> &str {
+        &self.key
+    }
+}
+
+impl From<KeyBuilder> for String {
+    fn from(key: KeyBuilder) -> String {
+        key.key
+    }
+}
+
+impl Display for KeyBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.key)
+    }
+}
+
+// push_key_part appends `:name=value` to key, escaping the separators in value.
+// It is used by the code generated by `#[derive(CacheKey)]`.
+#[doc(hidden)]
+pub fn push_key_part(key: &mut String, name: &str, value: &dyn Display) {
+    key.push(':');
+    key.push_str(name);
+    key.push('=');
+    let start = key.len();
+    write!(key, "{}", value).expect("writing to a String never fails");
+    if key[start..].contains(['\\', ':', '=']) {
+        let escaped = escape_key_part(&key[start..]);
+        key.truncate(start);
+        key.push_str(&escaped);
+    }
+}
+
+// escape_key_part escapes `\`, `:` and `=` with a backslash.
+pub fn escape_key_part(part: &str) -> String {
+    let mut out = String::with_capacity(part.len());
+    for c in part.chars() {
+        if matches!(c, '\\' | ':' | '=') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+
+    #[derive(CacheKey)]
+    struct UserOrder {
+        user_id: u64,
+        order_id: String,
+    }
+
+    #[derive(CacheKey)]
+    #[cache_key(prefix = "prof")]
+    struct Profile<'a> {
+        #[cache_key(rename = "id")]
+        user_id: &'a str,
+        #[cache_key(skip)]
+        #[allow(dead_code)]
+        cached_name: String,
+    }
+
+    #[test]
+    fn test_derive_cache_key() {
+        let key = UserOrder {
+            user_id: 7,
+            order_id: "a1".to_string(),
+        };
+        assert_eq!(key.cache_key(), "user_order:order_id=a1:user_id=7");
+    }
+
+    #[test]
+    fn test_derive_cache_key_attributes() {
+        let key = Profile {
+            user_id: "x:y=z",
+            cached_name: "ignored".to_string(),
+        };
+        assert_eq!(key.cache_key(), "prof:id=x\\:y\\=z");
+    }
+
+    fn user_key(id: &str) -> KeyBuilder {
+        KeyBuilder::new("app").entity("user").id(id).version(2)
+    }
+
+    #[tokio::test]
+    async fn test_key_builder() {
+        assert_eq!(user_key("7").cache_key(), "app:user:7:v2");
+        assert_eq!(user_key("a:b").to_string(), "app:user:a\\:b:v2");
+
+        let fake = FakeBackend::new();
+        let client = crate::Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        let v = client.fetch(user_key("7"), expire, || async { Ok(Some(1)) });
+        assert_eq!(v.await.unwrap(), Some(1));
+        assert!(fake.hget("app:user:7:v2", "value").is_some());
+        client.tag_as_deleted(user_key("7")).await.unwrap();
+        let v = client.fetch(user_key("7"), expire, || async { Ok(Some(2)) });
+        assert_eq!(v.await.unwrap(), Some(2));
+    }
+
+    #[cached(key = "user:{id}", ttl = "600s")]
+    async fn load_user(
+        client: &crate::Client,
+        id: u64,
+        calls: &AtomicU64,
+    ) -> crate::Result<Option<String>> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(format!("user {}", id)))
+    }
+
+    struct Repo {
+        cache: crate::Client,
+    }
+
+    impl Repo {
+        #[cached(key = "order:{id}", ttl = "1m", client = "self.cache")]
+        async fn order(&self, id: &str) -> crate::Result<Option<String>> {
+            if id.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(id.to_uppercase()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached() {
+        let fake = FakeBackend::new();
+        let client = crate::Client::with_backend(fake.clone(), Options::default());
+        let calls = AtomicU64::new(0);
+        for _ in 0..2 {
+            let user = load_user(&client, 7, &calls).await.unwrap();
+            assert_eq!(user, Some("user 7".to_string()));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(fake.hget("user:7", "value").is_some());
+
+        let repo = Repo { cache: client };
+        assert_eq!(repo.order("a1").await.unwrap(), Some("A1".to_string()));
+        assert_eq!(repo.order("").await.unwrap(), None);
+        let ttl = fake.pttl("order:a1").unwrap();
+        assert!(ttl > Duration::from_secs(44) && ttl <= Duration::from_secs(50));
+    }
+
+    #[test]
+    fn test_escape_key_part() {
+        assert_eq!(escape_key_part("plain"), "plain");
+        assert_eq!(escape_key_part("a\\b:c"), "a\\\\b\\:c");
+    }
+}
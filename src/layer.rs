@@ -0,0 +1,209 @@
+use crate::{
+    error::{new_cache_miss_error, new_unexpected_reply_error},
+    Client, Error, Reply,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::{self, Debug},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tower_layer::Layer;
+use tower_service::Service;
+
+// CacheLayer caches the responses of the services it wraps with Client::fetch, under
+// the key its key function returns for a request. Requests without a key go straight
+// to the inner service. Failed responses are not cached.
+pub struct CacheLayer<K> {
+    client: Client,
+    expire: Duration,
+    key: Arc<K>,
+}
+
+impl<K> CacheLayer<K> {
+    // new caches responses for expire, under the key returned by key.
+    pub fn new(client: Client, expire: Duration, key: K) -> Self {
+        Self {
+            client,
+            expire,
+            key: Arc::new(key),
+        }
+    }
+}
+
+impl<K> Clone for CacheLayer<K> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            expire: self.expire,
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<S, K> Layer<S> for CacheLayer<K> {
+    type Service = CacheService<S, K>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CacheService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+// CacheService is the service built by CacheLayer.
+pub struct CacheService<S, K> {
+    inner: S,
+    layer: CacheLayer<K>,
+}
+
+impl<S: Clone, K> Clone for CacheService<S, K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+// CacheServiceError is the error of a CacheService: the error of the inner service,
+// or of the cache.
+#[derive(Debug)]
+pub enum CacheServiceError<E> {
+    Inner(E),
+    Cache(Error),
+}
+
+impl<E: fmt::Display> fmt::Display for CacheServiceError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inner(e) => e.fmt(f),
+            Self::Cache(e) => write!(f, "cache error: {}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for CacheServiceError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Inner(e) => Some(e),
+            Self::Cache(e) => Some(e),
+        }
+    }
+}
+
+impl<S, K, Req> Service<Req> for CacheService<S, K>
+where
+    S: Service<Req> + Clone + Send + 'static,
+    S::Response: DeserializeOwned + Serialize + Debug + Send + 'static,
+    S::Error: Send + 'static,
+    S::Future: Send + 'static,
+    K: Fn(&Req) -> Option<String> + Send + Sync + 'static,
+    Req: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = CacheServiceError<S::Error>;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(CacheServiceError::Inner)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        // the inner service is ready, not its clone: take the ready one.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let Some(key) = (self.layer.key)(&req) else {
+            return Box::pin(
+                async move { inner.call(req).await.map_err(CacheServiceError::Inner) },
+            );
+        };
+        let client = self.layer.client.clone();
+        let miss = new_cache_miss_error(&key);
+        let expire = self.layer.expire;
+        Box::pin(async move {
+            // the error of the inner service is kept aside, fetch only sees that the
+            // load failed.
+            let failed = Mutex::new(None);
+            let fetched = client
+                .fetch(key, expire, || async {
+                    match inner.call(req).await {
+                        Ok(response) => Ok(Some(response)),
+                        Err(e) => {
+                            *failed.lock().unwrap() = Some(e);
+                            Err(new_unexpected_reply_error(Reply::Nil))
+                        }
+                    }
+                })
+                .await;
+            if let Some(e) = failed.lock().unwrap().take() {
+                return Err(CacheServiceError::Inner(e));
+            }
+            match fetched {
+                Ok(Some(response)) => Ok(response),
+                Ok(None) => Err(CacheServiceError::Cache(miss)),
+                Err(e) => Err(CacheServiceError::Cache(e)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use futures::future::{self, Ready};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Clone, Default)]
+    struct Echo {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Service<String> for Echo {
+        type Response = String;
+        type Error = String;
+        type Future = Ready<Result<String, String>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), String>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: String) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match req.as_str() {
+                "fail" => future::ready(Err("failed".to_string())),
+                _ => future::ready(Ok(format!("echo {}", req))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_layer() {
+        let client = Client::with_backend(FakeBackend::new(), Options::default());
+        let echo = Echo::default();
+        let layer = CacheLayer::new(client, Duration::from_secs(600), |req: &String| {
+            (req != "nocache").then(|| format!("echo:{}", req))
+        });
+        let mut service = layer.layer(echo.clone());
+
+        for _ in 0..2 {
+            assert_eq!(service.call("a".to_string()).await.unwrap(), "echo a");
+            assert_eq!(
+                service.call("nocache".to_string()).await.unwrap(),
+                "echo nocache"
+            );
+        }
+        assert_eq!(echo.calls.load(Ordering::SeqCst), 3);
+
+        for _ in 0..2 {
+            let err = service.call("fail".to_string()).await.unwrap_err();
+            assert!(matches!(err, CacheServiceError::Inner(e) if e == "failed"));
+        }
+        assert_eq!(echo.calls.load(Ordering::SeqCst), 5);
+    }
+}
@@ -1,8 +1,164 @@
+extern crate self as rdcache;
+
+pub mod backend;
+
 pub mod client;
 
+pub mod clock;
+
+pub mod codec;
+
+#[cfg(feature = "zstd")]
+pub mod compress;
+
 pub mod error;
 
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(feature = "graphql")]
+pub mod graphql;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "axum")]
+pub mod handler;
+
+pub mod key;
+
+#[cfg(feature = "tower")]
+pub mod layer;
+
+pub mod migrate;
+
+#[cfg(feature = "http")]
+pub mod response;
+
+pub mod runtime;
+
+pub mod warm;
+
+#[cfg(feature = "rustis")]
+pub use backend::RustisBackend;
+pub use backend::{CacheBackend, Reply, ScriptCall};
+pub use breaker::{CircuitState, Health};
 pub use client::*;
+pub use clock::{Clock, ManualClock, SkewedClock, SystemClock};
+#[cfg(feature = "bincode")]
+pub use codec::Bincode;
+#[cfg(feature = "json")]
+pub use codec::Json;
+pub use codec::{Codec, MessagePack};
+#[cfg(feature = "zstd")]
+pub use compress::ZstdDictionary;
+pub use compression::Compression;
+#[cfg(feature = "aes-gcm")]
+pub use encrypt::AesGcmEncryptor;
+pub use encrypt::Encryptor;
 pub use error::{Error, Result};
+pub use executor::BackgroundStats;
+pub use flush::FlushSchedule;
+#[cfg(feature = "graphql")]
+pub use graphql::CachedLoader;
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcCache;
+#[cfg(feature = "axum")]
+pub use handler::{CacheExt, Cached};
+pub use hot_keys::HotKey;
+pub use jitter::seeded_jitter;
+pub use key::{cached, CacheKey, KeyBuilder};
+#[cfg(feature = "tower")]
+pub use layer::CacheLayer;
+pub use metrics::CacheMetrics;
+#[cfg(feature = "otel")]
+pub use metrics::OtelMetrics;
+#[cfg(feature = "prometheus")]
+pub use metrics::PrometheusMetrics;
+pub use migrate::{MigrateOptions, MigrateReport};
+pub use namespace::Namespace;
+pub use negative::NegativeCachePolicy;
+pub use payload::Payload;
+pub use region::CacheRegion;
+#[cfg(feature = "http")]
+pub use response::{http_cache_key, is_cacheable, CachedHttpResponse};
+pub use retry::{is_transient, RetryPolicy};
+#[cfg(feature = "async-std-runtime")]
+pub use runtime::AsyncStdRuntime;
+pub use runtime::Runtime;
+#[cfg(feature = "tokio-runtime")]
+pub use runtime::TokioRuntime;
+pub use script::Script;
+pub use stats::CacheStats;
+pub use try_fetch::FetchOutcome;
+pub use wait::LockWaitStrategy;
+pub use warm::{WarmOptions, WarmReport};
+
+mod batch;
+
+mod breaker;
+
+mod coalesce;
+
+mod compression;
+
+mod config;
+
+mod encrypt;
+
+mod executor;
+
+mod flush;
+
+mod hot_keys;
+
+mod janitor;
+
+mod jitter;
+
+mod journal;
+
+mod limit;
+
+#[cfg(feature = "local-cache")]
+mod local;
+
+mod metrics;
+
+mod namespace;
+
+mod negative;
+
+mod payload;
+
+mod pool;
+
+mod region;
+
+mod retry;
+
+mod schedule;
 
 mod script;
+
+mod shard;
+
+mod slot;
+
+mod stats;
+
+mod tags;
+
+mod touch;
+
+mod try_fetch;
+
+mod wait;
+
+mod write_retry;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -0,0 +1,100 @@
+use crate::Options;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// LoadLimits bounds the loaders running at once in a client and the clients detached
+// from it, see Options::max_concurrent_loads.
+#[derive(Debug, Default)]
+pub(crate) struct LoadLimits {
+    all: Option<Semaphore>,
+    // prefixes are sorted by decreasing length, the longest prefix of a key wins.
+    prefixes: Vec<(String, Semaphore)>,
+}
+
+// LoadPermits are the permits held by a running loader.
+pub(crate) type LoadPermits<'a> = (Option<SemaphorePermit<'a>>, Option<SemaphorePermit<'a>>);
+
+impl LoadLimits {
+    pub(crate) fn new(options: &Options) -> Self {
+        let all = (options.max_concurrent_loads > 0)
+            .then(|| Semaphore::new(options.max_concurrent_loads));
+        let mut prefixes: Vec<(String, Semaphore)> = options
+            .max_concurrent_loads_by_prefix
+            .iter()
+            .map(|(prefix, max)| (prefix.clone(), Semaphore::new(*max)))
+            .collect();
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self { all, prefixes }
+    }
+
+    // acquire waits for the permits of a loader of key, without Options::common_prefix:
+    // the one of its prefix, if any, then one of max_concurrent_loads.
+    pub(crate) async fn acquire(&self, key: &str) -> LoadPermits<'_> {
+        let prefix = self
+            .prefixes
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()));
+        let by_prefix = match prefix {
+            Some((_, semaphore)) => semaphore.acquire().await.ok(),
+            None => None,
+        };
+        let all = match &self.all {
+            Some(semaphore) => semaphore.acquire().await.ok(),
+            None => None,
+        };
+        (by_prefix, all)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::FakeBackend, Client, Options};
+    use futures::future::join_all;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_concurrent_loads() {
+        let options = Options {
+            common_prefix: "app:".to_string(),
+            max_concurrent_loads: 3,
+            max_concurrent_loads_by_prefix: vec![("report:".to_string(), 1)],
+            lock_renewal: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(FakeBackend::new(), options);
+        let expire = Duration::from_secs(600);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let fetch = |key: String| {
+            let (client, running, max_running) = (&client, running.clone(), max_running.clone());
+            async move {
+                client
+                    .fetch(key, expire, || async move {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        Ok(Some(1))
+                    })
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let started = tokio::time::Instant::now();
+        join_all((0..9).map(|i| fetch(format!("user:{}", i)))).await;
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+
+        max_running.store(0, Ordering::SeqCst);
+        let started = tokio::time::Instant::now();
+        join_all((0..3).map(|i| fetch(format!("report:{}", i)))).await;
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,403 @@
+use crate::{shard::Sharded, Client, Result};
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+// ENTRY_OVERHEAD is the approximate size in bytes of an entry besides its key and
+// value: the map slots, the lru slot and the allocation headers.
+const ENTRY_OVERHEAD: usize = 96;
+
+// SHARD_CAPACITY is the smallest number of entries of a shard.
+const SHARD_CAPACITY: usize = 64;
+
+// SUBSCRIBE_RETRY is how long the invalidation subscriber waits to subscribe again
+// after its subscription failed or was lost.
+const SUBSCRIBE_RETRY: Duration = Duration::from_secs(1);
+
+// MAX_SHARDS bounds the shards, so that the budget of a shard fits large values.
+const MAX_SHARDS: usize = 16;
+
+// LocalCache is the in-process tier of Options::local_ttl in front of redis. It holds
+// the encoded values of the keys fetched lately for up to local_ttl, and evicts the
+// least recently used ones once it holds more than Options::local_capacity entries or
+// Options::local_memory_budget bytes, weighing an entry by the size of its key and
+// value. A value larger than the budget of a shard is never cached, so a few huge
+// values can't take the memory of the process.
+// It is sharded by key like HotKeySketch, the limits being split between the shards.
+pub(crate) struct LocalCache {
+    ttl: Duration,
+    // capacity and budget are the limits of each shard.
+    capacity: usize,
+    budget: usize,
+    shards: Sharded<Shard>,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<String, Entry>,
+    // lru orders the keys by their last use.
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+    // generation counts the invalidations of the keys of the shard, see
+    // LocalCache::generation.
+    generation: u64,
+}
+
+struct Entry {
+    value: Arc<[u8]>,
+    expires_at: SystemTime,
+    tick: u64,
+}
+
+fn weight(key: &str, value: &[u8]) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
+
+impl Shard {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.bytes -= weight(key, &entry.value);
+        }
+    }
+}
+
+impl LocalCache {
+    pub(crate) fn new(ttl: Duration, capacity: usize, budget: usize) -> Self {
+        let shards = Sharded::new((capacity / SHARD_CAPACITY).min(MAX_SHARDS));
+        Self {
+            ttl,
+            capacity: capacity.div_ceil(shards.len()),
+            budget: budget / shards.len(),
+            shards,
+        }
+    }
+
+    // get returns the value of key if it has not expired at now.
+    pub(crate) fn get(&self, key: &str, now: SystemTime) -> Option<Arc<[u8]>> {
+        let mut shard = self.shards.shard(key);
+        let shard = &mut *shard;
+        let entry = shard.entries.get_mut(key)?;
+        if entry.expires_at <= now {
+            shard.remove(key);
+            return None;
+        }
+        shard.tick += 1;
+        shard.lru.remove(&entry.tick);
+        entry.tick = shard.tick;
+        shard.lru.insert(entry.tick, key.to_string());
+        Some(entry.value.clone())
+    }
+
+    // generation returns the invalidation generation of key, bumped by remove and clear.
+    // It is shared by the keys of a shard, so an invalidation of another key of the
+    // shard also changes it.
+    pub(crate) fn generation(&self, key: &str) -> u64 {
+        self.shards.shard(key).generation
+    }
+
+    // insert caches value for key until now plus the ttl, evicting the least recently
+    // used entries over the limits. With a generation, it does nothing if the generation
+    // of key is no longer it: the value was read or written before an invalidation of key.
+    pub(crate) fn insert(&self, key: &str, value: &[u8], now: SystemTime, generation: Option<u64>) {
+        let mut shard = self.shards.shard(key);
+        if generation.is_some_and(|generation| generation != shard.generation) {
+            return;
+        }
+        shard.remove(key);
+        let size = weight(key, value);
+        if size > self.budget || self.capacity == 0 {
+            return;
+        }
+        while shard.entries.len() >= self.capacity || shard.bytes + size > self.budget {
+            let Some((_, oldest)) = shard.lru.pop_first() else {
+                break;
+            };
+            shard.remove(&oldest);
+        }
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.lru.insert(tick, key.to_string());
+        shard.bytes += size;
+        shard.entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.into(),
+                expires_at: now + self.ttl,
+                tick,
+            },
+        );
+    }
+
+    pub(crate) fn remove(&self, key: &str) {
+        let mut shard = self.shards.shard(key);
+        shard.generation += 1;
+        shard.remove(key);
+    }
+
+    fn clear(&self) {
+        for mut shard in self.shards.iter() {
+            *shard = Shard {
+                generation: shard.generation + 1,
+                ..Default::default()
+            };
+        }
+    }
+
+    // bytes is the weight of the entries held.
+    #[cfg(test)]
+    fn bytes(&self) -> usize {
+        self.shards.iter().map(|shard| shard.bytes).sum()
+    }
+}
+
+impl Client {
+    // local_get returns the value of key from the local tier, None if it is not there.
+    pub(crate) fn local_get<V: DeserializeOwned>(&self, key: &str) -> Result<Option<Option<V>>> {
+        let Some(local) = &self.local else {
+            return Ok(None);
+        };
+        let Some(s) = local.get(key, self.clock.now()) else {
+            return Ok(None);
+        };
+        let value = self.decode_value(key, &s)?;
+        self.stats.hit();
+        Ok(Some(value))
+    }
+
+    // local_generation returns the invalidation generation of key in the local tier,
+    // taken before reading or writing the value passed to local_insert.
+    pub(crate) fn local_generation(&self, key: &str) -> u64 {
+        self.local.as_ref().map_or(0, |local| local.generation(key))
+    }
+
+    // local_insert caches the encoded value of key in the local tier, unless key was
+    // invalidated since generation was taken, the value possibly predating it, or it is
+    // not fetched often enough for Options::local_min_fetches.
+    pub(crate) fn local_insert(&self, key: &str, s: &[u8], generation: u64) {
+        let Some(local) = &self.local else {
+            return;
+        };
+        let min_fetches = self.options().local_min_fetches;
+        if min_fetches > 0 && self.hot_keys.count(key) < u64::from(min_fetches) {
+            return;
+        }
+        local.insert(key, s, self.clock.now(), Some(generation));
+    }
+
+    // local_remove evicts key from the local tier of this client.
+    pub(crate) fn local_remove(&self, key: &str) {
+        if let Some(local) = &self.local {
+            local.remove(key);
+        }
+    }
+
+    // start_invalidation_subscriber evicts from the local tier the keys tag deleted by
+    // the clients of other instances, received on Options::invalidation_channel, until
+    // stop_invalidation_subscriber is called, or the client is shut down or dropped.
+    // The local tier is cleared each time the subscription starts, since invalidations
+    // may have been missed while it was down, and a lost subscription is retried every
+    // SUBSCRIBE_RETRY. It does nothing without a channel or a local tier, and is not
+    // started after shutdown. It must be called within a tokio runtime.
+    pub fn start_invalidation_subscriber(&self) {
+        if self.options().invalidation_channel.is_empty() || self.local.is_none() {
+            return;
+        }
+        let client = self.detach();
+        let subscriber = self.executor.spawn_service(async move {
+            let channel = &client.options().invalidation_channel;
+            loop {
+                if let Ok(mut keys) = client.backend.subscribe(channel).await {
+                    if let Some(local) = &client.local {
+                        local.clear();
+                    }
+                    while let Some(key) = keys.next().await {
+                        client.local_remove(&key);
+                    }
+                }
+                client.executor.sleep(SUBSCRIBE_RETRY).await;
+            }
+        });
+        if let Some(subscriber) = subscriber {
+            self.subscriber.set(subscriber);
+        }
+    }
+
+    // stop_invalidation_subscriber stops the subscriber, returning false if none was
+    // running.
+    pub fn stop_invalidation_subscriber(&self) -> bool {
+        self.subscriber.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, ManualClock, Options};
+
+    #[test]
+    fn test_local_cache_eviction() {
+        let now = SystemTime::UNIX_EPOCH;
+        let local = LocalCache::new(Duration::from_secs(10), 3, 1 << 20);
+        for key in ["a", "b", "c"] {
+            local.insert(key, b"v", now, None);
+        }
+        local.get("a", now).unwrap();
+        local.insert("d", b"v", now, None);
+        assert!(local.get("b", now).is_none());
+        assert!(local.get("a", now).is_some());
+        assert!(local.get("d", now + Duration::from_secs(10)).is_none());
+
+        // the budget is in bytes: a large value evicts several small ones, a value
+        // larger than the budget is not cached.
+        let local = LocalCache::new(Duration::from_secs(10), 100, 1000);
+        for key in ["a", "b", "c", "d"] {
+            local.insert(key, &[0; 100], now, None);
+        }
+        local.insert("large", &[0; 500], now, None);
+        assert!(local.get("large", now).is_some());
+        assert!(local.get("a", now).is_none() && local.get("b", now).is_none());
+        assert!(local.get("d", now).is_some());
+        assert!(local.bytes() <= 1000);
+        local.insert("huge", &[0; 1000], now, None);
+        assert!(local.get("huge", now).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_local_cache() {
+        let clock = ManualClock::new(SystemTime::now());
+        let fake = FakeBackend::with_clock(clock.clone());
+        let options = Options {
+            local_ttl: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options).with_clock(clock.clone());
+        let expire = Duration::from_secs(600);
+        let fetch = |v: u64| client.fetch("k", expire, move || async move { Ok(Some(v)) });
+
+        assert_eq!(fetch(1).await.unwrap(), Some(1));
+        let gets = fake.calls("get");
+        assert_eq!(fetch(2).await.unwrap(), Some(1));
+        assert_eq!(fake.calls("get"), gets);
+
+        // redis changed behind the back of the client, the local value is served until
+        // it expires.
+        fake.hset("k", "value", rmp_serde::to_vec(&Some(3)).unwrap());
+        assert_eq!(fetch(2).await.unwrap(), Some(1));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(fetch(2).await.unwrap(), Some(3));
+
+        client.tag_as_deleted("k").await.unwrap();
+        assert_eq!(fetch(4).await.unwrap(), Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_local_min_fetches() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            local_ttl: Duration::from_secs(600),
+            local_min_fetches: 3,
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+        let options = Options {
+            hot_key_capacity: 16,
+            ..options
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let expire = Duration::from_secs(600);
+        let fetch = |key| client.fetch(key, expire, || async { Ok(Some(1)) });
+
+        // the two first fetches of hot are not enough to promote it, the third one is.
+        for gets in 1..=3 {
+            assert_eq!(fetch("hot").await.unwrap(), Some(1));
+            assert_eq!(fake.calls("get"), gets);
+        }
+        assert_eq!(fetch("hot").await.unwrap(), Some(1));
+        assert_eq!(fake.calls("get"), 3);
+        assert_eq!(fetch("cold").await.unwrap(), Some(1));
+        assert_eq!(fetch("cold").await.unwrap(), Some(1));
+        assert_eq!(fake.calls("get"), 5);
+    }
+
+    #[tokio::test]
+    async fn test_local_cache_invalidated_during_load() {
+        for detached_write in [false, true] {
+            let options = Options {
+                local_ttl: Duration::from_secs(600),
+                detached_write,
+                ..Default::default()
+            };
+            let client = Client::with_backend(FakeBackend::new(), options);
+            let expire = Duration::from_secs(600);
+            // the key is deleted between the load and the SET, which doesn't write: the
+            // loaded value must not be cached locally either.
+            let fetched = client.fetch("k", expire, || async {
+                client.tag_as_deleted("k").await?;
+                Ok(Some(1))
+            });
+            assert_eq!(fetched.await.unwrap(), Some(1));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let fetched = client.fetch("k", expire, || async { Ok(Some(2)) });
+            assert_eq!(fetched.await.unwrap(), Some(2));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_local_cache_invalidated_during_get() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            local_ttl: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let expire = Duration::from_secs(600);
+        let fetch = |v: u64| client.fetch("k", expire, move || async move { Ok(Some(v)) });
+        fake.hset("k", "value", rmp_serde::to_vec(&Some(1)).unwrap());
+
+        // the GET reads 1 before the key is deleted and returns after it: 1 is served but
+        // not cached locally.
+        fake.set_latency(Duration::from_millis(10));
+        let delete = async {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            client.tag_as_deleted("k").await.unwrap();
+        };
+        let (fetched, ()) = tokio::join!(fetch(2), delete);
+        assert_eq!(fetched.unwrap(), Some(1));
+        assert_eq!(fetch(3).await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_invalidation_subscriber() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            local_ttl: Duration::from_secs(600),
+            invalidation_channel: "rdcache:invalidations".to_string(),
+            ..Default::default()
+        };
+        let a = Client::with_backend(fake.clone(), options.clone());
+        let b = Client::with_backend(fake.clone(), options);
+        b.start_invalidation_subscriber();
+        async fn fetch(client: &Client, v: u64) -> Option<u64> {
+            let expire = Duration::from_secs(600);
+            let fetched = client.fetch("k", expire, || async move { Ok(Some(v)) });
+            fetched.await.unwrap()
+        }
+        assert_eq!(fetch(&b, 1).await, Some(1));
+
+        a.tag_as_deleted("k").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(fetch(&b, 2).await, Some(2));
+        assert_eq!(fetch(&a, 3).await, Some(2));
+
+        assert!(b.stop_invalidation_subscriber());
+        a.tag_as_deleted("k").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(fetch(&b, 4).await, Some(2));
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{CircuitState, Client, Error};
+use std::{sync::Arc, time::Duration};
+
+// CacheMetrics receives the events of the fetches of a client, set with
+// Client::with_metrics, e.g. to export the hit ratio and the lock waits of stampedes
+// to a metrics backend. Every method does nothing by default. They are called inline,
+// so they must not block.
+pub trait CacheMetrics: Send + Sync + 'static {
+    // on_hit is called for each value served from the cache.
+    fn on_hit(&self) {}
+
+    // on_miss is called for each value the loader is called for.
+    fn on_miss(&self) {}
+
+    // on_lock_wait is called with how long a fetch waited for the lock of another fetch,
+    // once it stopped waiting, also when it timed out.
+    fn on_lock_wait(&self, _waited: Duration) {}
+
+    // on_loader is called with how long a loader ran and its result, refreshes included.
+    fn on_loader(&self, _elapsed: Duration, _result: std::result::Result<(), &Error>) {}
+
+    // on_redis_error is called for each redis call that failed.
+    fn on_redis_error(&self) {}
+
+    // on_compress is called with the size of each value compressed with
+    // Options::compression and the size it was compressed to, which is stored only if it
+    // is smaller.
+    fn on_compress(&self, _encoded: usize, _compressed: usize) {}
+
+    // on_payload is called with the size of each value written to redis, as stored:
+    // encoded, compressed and encrypted.
+    fn on_payload(&self, _size: usize) {}
+
+    // on_circuit_state is called when the circuit breaker of
+    // Options::circuit_breaker_threshold changes state.
+    fn on_circuit_state(&self, _state: CircuitState) {}
+}
+
+impl Client {
+    // with_metrics reports the events of the fetches of the client to metrics. It must
+    // be called before the client is used, the stats of the client start over.
+    pub fn with_metrics(mut self, metrics: impl CacheMetrics) -> Self {
+        self.stats = Arc::new(crate::stats::StatsCounters::with_metrics(Arc::new(metrics)));
+        self
+    }
+}
+
+// PrometheusMetrics exports the events as prometheus metrics: the counters
+// rdcache_hits_total, rdcache_misses_total and rdcache_redis_errors_total, and the
+// histograms rdcache_lock_wait_seconds and rdcache_loader_seconds, the latter by
+// result, "ok" or "error", and rdcache_compression_ratio, the compressed size of the
+// values over their encoded size, and rdcache_payload_bytes, the size of the values
+// written, and the gauge rdcache_circuit_state, 0 closed, 1 open and 2 half open.
+#[cfg(feature = "prometheus")]
+#[derive(Debug, Clone)]
+pub struct PrometheusMetrics {
+    hits: prometheus::IntCounter,
+    misses: prometheus::IntCounter,
+    redis_errors: prometheus::IntCounter,
+    lock_wait: prometheus::Histogram,
+    loader: prometheus::HistogramVec,
+    compression_ratio: prometheus::Histogram,
+    payload: prometheus::Histogram,
+    circuit_state: prometheus::IntGauge,
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusMetrics {
+    // new registers the metrics in registry.
+    pub fn new(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        use prometheus::{Histogram, HistogramOpts, HistogramVec, IntCounter, IntGauge};
+        let metrics = Self {
+            hits: IntCounter::new("rdcache_hits_total", "Values served from the cache.")?,
+            misses: IntCounter::new("rdcache_misses_total", "Values loaded on a miss.")?,
+            redis_errors: IntCounter::new("rdcache_redis_errors_total", "Failed redis calls.")?,
+            lock_wait: Histogram::with_opts(HistogramOpts::new(
+                "rdcache_lock_wait_seconds",
+                "Time fetches waited for the lock of another fetch.",
+            ))?,
+            loader: HistogramVec::new(
+                HistogramOpts::new("rdcache_loader_seconds", "Time the loaders ran."),
+                &["result"],
+            )?,
+            compression_ratio: Histogram::with_opts(
+                HistogramOpts::new(
+                    "rdcache_compression_ratio",
+                    "Compressed size of the values over their encoded size.",
+                )
+                .buckets(prometheus::linear_buckets(0.1, 0.1, 10)?),
+            )?,
+            payload: Histogram::with_opts(
+                HistogramOpts::new("rdcache_payload_bytes", "Size of the values written.")
+                    .buckets(prometheus::exponential_buckets(64.0, 4.0, 10)?),
+            )?,
+            circuit_state: IntGauge::new(
+                "rdcache_circuit_state",
+                "State of the circuit breaker: 0 closed, 1 open, 2 half open.",
+            )?,
+        };
+        registry.register(Box::new(metrics.hits.clone()))?;
+        registry.register(Box::new(metrics.misses.clone()))?;
+        registry.register(Box::new(metrics.redis_errors.clone()))?;
+        registry.register(Box::new(metrics.lock_wait.clone()))?;
+        registry.register(Box::new(metrics.loader.clone()))?;
+        registry.register(Box::new(metrics.compression_ratio.clone()))?;
+        registry.register(Box::new(metrics.payload.clone()))?;
+        registry.register(Box::new(metrics.circuit_state.clone()))?;
+        Ok(metrics)
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl CacheMetrics for PrometheusMetrics {
+    fn on_hit(&self) {
+        self.hits.inc();
+    }
+
+    fn on_miss(&self) {
+        self.misses.inc();
+    }
+
+    fn on_lock_wait(&self, waited: Duration) {
+        self.lock_wait.observe(waited.as_secs_f64());
+    }
+
+    fn on_loader(&self, elapsed: Duration, result: std::result::Result<(), &Error>) {
+        let result = if result.is_ok() { "ok" } else { "error" };
+        self.loader
+            .with_label_values(&[result])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    fn on_redis_error(&self) {
+        self.redis_errors.inc();
+    }
+
+    fn on_compress(&self, encoded: usize, compressed: usize) {
+        self.compression_ratio
+            .observe(compressed as f64 / encoded as f64);
+    }
+
+    fn on_payload(&self, size: usize) {
+        self.payload.observe(size as f64);
+    }
+
+    fn on_circuit_state(&self, state: CircuitState) {
+        self.circuit_state.set(match state {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        });
+    }
+}
+
+// OtelMetrics records the events with an opentelemetry Meter: the counters rdcache.hits,
+// rdcache.misses and rdcache.redis_errors, the histograms rdcache.lock_wait and
+// rdcache.loader in seconds, the latter with a result attribute, "ok" or "error",
+// rdcache.compression_ratio and rdcache.payload in bytes, and the gauge
+// rdcache.circuit_state, 0 closed, 1 open and 2 half open.
+#[cfg(feature = "otel")]
+#[derive(Debug, Clone)]
+pub struct OtelMetrics {
+    hits: opentelemetry::metrics::Counter<u64>,
+    misses: opentelemetry::metrics::Counter<u64>,
+    redis_errors: opentelemetry::metrics::Counter<u64>,
+    lock_wait: opentelemetry::metrics::Histogram<f64>,
+    loader: opentelemetry::metrics::Histogram<f64>,
+    compression_ratio: opentelemetry::metrics::Histogram<f64>,
+    payload: opentelemetry::metrics::Histogram<u64>,
+    circuit_state: opentelemetry::metrics::Gauge<i64>,
+}
+
+#[cfg(feature = "otel")]
+impl OtelMetrics {
+    // new creates the instruments with meter, e.g. opentelemetry::global::meter("rdcache").
+    pub fn new(meter: &opentelemetry::metrics::Meter) -> Self {
+        Self {
+            hits: meter
+                .u64_counter("rdcache.hits")
+                .with_description("Values served from the cache.")
+                .build(),
+            misses: meter
+                .u64_counter("rdcache.misses")
+                .with_description("Values loaded on a miss.")
+                .build(),
+            redis_errors: meter
+                .u64_counter("rdcache.redis_errors")
+                .with_description("Failed redis calls.")
+                .build(),
+            lock_wait: meter
+                .f64_histogram("rdcache.lock_wait")
+                .with_description("Time fetches waited for the lock of another fetch.")
+                .with_unit("s")
+                .build(),
+            loader: meter
+                .f64_histogram("rdcache.loader")
+                .with_description("Time the loaders ran.")
+                .with_unit("s")
+                .build(),
+            compression_ratio: meter
+                .f64_histogram("rdcache.compression_ratio")
+                .with_description("Compressed size of the values over their encoded size.")
+                .build(),
+            payload: meter
+                .u64_histogram("rdcache.payload")
+                .with_description("Size of the values written.")
+                .with_unit("By")
+                .build(),
+            circuit_state: meter
+                .i64_gauge("rdcache.circuit_state")
+                .with_description("State of the circuit breaker: 0 closed, 1 open, 2 half open.")
+                .build(),
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+impl CacheMetrics for OtelMetrics {
+    fn on_hit(&self) {
+        self.hits.add(1, &[]);
+    }
+
+    fn on_miss(&self) {
+        self.misses.add(1, &[]);
+    }
+
+    fn on_lock_wait(&self, waited: Duration) {
+        self.lock_wait.record(waited.as_secs_f64(), &[]);
+    }
+
+    fn on_loader(&self, elapsed: Duration, result: std::result::Result<(), &Error>) {
+        let result = if result.is_ok() { "ok" } else { "error" };
+        self.loader.record(
+            elapsed.as_secs_f64(),
+            &[opentelemetry::KeyValue::new("result", result)],
+        );
+    }
+
+    fn on_redis_error(&self) {
+        self.redis_errors.add(1, &[]);
+    }
+
+    fn on_compress(&self, encoded: usize, compressed: usize) {
+        self.compression_ratio
+            .record(compressed as f64 / encoded as f64, &[]);
+    }
+
+    fn on_payload(&self, size: usize) {
+        self.payload.record(size as u64, &[]);
+    }
+
+    fn on_circuit_state(&self, state: CircuitState) {
+        let state = match state {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        };
+        self.circuit_state.record(state, &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::new_config_error, test_util::FakeBackend, Options};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl CacheMetrics for Arc<Recorder> {
+        fn on_hit(&self) {
+            self.events.lock().unwrap().push("hit".to_string());
+        }
+
+        fn on_miss(&self) {
+            self.events.lock().unwrap().push("miss".to_string());
+        }
+
+        fn on_lock_wait(&self, _waited: Duration) {
+            self.events.lock().unwrap().push("lock_wait".to_string());
+        }
+
+        fn on_loader(&self, _elapsed: Duration, result: std::result::Result<(), &Error>) {
+            let event = format!("loader ok={}", result.is_ok());
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let options = Options {
+            lock_sleep: Duration::from_millis(1),
+            lock_wait_max_attempts: 2,
+            ..Default::default()
+        };
+        let recorder = Arc::new(Recorder::default());
+        let client =
+            Client::with_backend(FakeBackend::new(), options).with_metrics(recorder.clone());
+        let expire = Duration::from_secs(600);
+        for _ in 0..2 {
+            client
+                .fetch("k", expire, || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+        }
+        let failed = client
+            .fetch("e", expire, || async {
+                Err::<Option<u64>, _>(new_config_error("down".to_string()))
+            })
+            .await;
+        assert!(failed.is_err());
+
+        client.lua_get("locked", "holder").await.unwrap();
+        let waited = client
+            .fetch("locked", expire, || async { Ok(Some(1)) })
+            .await;
+        assert!(matches!(waited, Err(Error::LockWaitTimeout(_))));
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec![
+                "miss",
+                "loader ok=true",
+                "hit",
+                "miss",
+                "loader ok=false",
+                "lock_wait",
+            ]
+        );
+        assert_eq!(client.stats().load_errors, 1);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_prometheus_metrics() {
+        let registry = prometheus::Registry::new();
+        let metrics = PrometheusMetrics::new(&registry).unwrap();
+        let client = Client::with_backend(FakeBackend::new(), Options::default())
+            .with_metrics(metrics.clone());
+        for _ in 0..3 {
+            client
+                .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+        }
+        assert_eq!(metrics.hits.get(), 2);
+        assert_eq!(metrics.misses.get(), 1);
+        assert_eq!(
+            metrics.loader.with_label_values(&["ok"]).get_sample_count(),
+            1
+        );
+        assert_eq!(metrics.payload.get_sample_count(), 1);
+        assert_eq!(metrics.payload.get_sample_sum(), 1.0);
+        assert!(PrometheusMetrics::new(&registry).is_err());
+    }
+}
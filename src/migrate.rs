@@ -0,0 +1,204 @@
+use crate::{
+    backend::{Args, Reply},
+    script::{COPY_SCRIPT, READ_SCRIPT},
+    Client, Result,
+};
+
+type Transform = Box<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+type Progress = Box<dyn Fn(&MigrateReport) + Send + Sync>;
+
+pub struct MigrateOptions {
+    // ScanCount is the COUNT hint passed to every SCAN call. default is 100
+    pub scan_count: usize,
+    // Overwrite replaces values already present under the new prefix. default is false
+    pub overwrite: bool,
+    // Transform re-encodes every copied value, e.g. when switching to a new codec.
+    pub transform: Option<Transform>,
+    // Progress is called with the running totals after every SCAN batch.
+    pub progress: Option<Progress>,
+}
+
+impl Default for MigrateOptions {
+    fn default() -> Self {
+        Self {
+            scan_count: 100,
+            overwrite: false,
+            transform: None,
+            progress: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateReport {
+    // Scanned is the number of keys found under the old prefix.
+    pub scanned: u64,
+    // Copied is the number of values written under the new prefix.
+    pub copied: u64,
+    // Skipped counts keys without a fresh value, and existing targets when not overwriting.
+    pub skipped: u64,
+}
+
+impl Client {
+    // migrate_namespace copies every fresh value stored under old_prefix to the same
+    // key under new_prefix, keeping the remaining ttl. The prefixes are full redis
+    // key prefixes, common_prefix is not applied to them.
+    // Tag deleted and locked entries are not copied, so the new namespace never
+    // resurrects a value that has been invalidated.
+    pub async fn migrate_namespace(
+        &self,
+        old_prefix: &str,
+        new_prefix: &str,
+        options: MigrateOptions,
+    ) -> Result<MigrateReport> {
+        let mut report = MigrateReport::default();
+        let pattern = format!("{}*", escape_glob(old_prefix));
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = self
+                .backend
+                .scan(cursor, &pattern, options.scan_count)
+                .await?;
+            for key in keys {
+                report.scanned += 1;
+                if self
+                    .copy_key(&key, old_prefix, new_prefix, &options)
+                    .await?
+                {
+                    report.copied += 1;
+                } else {
+                    report.skipped += 1;
+                }
+            }
+            if let Some(progress) = &options.progress {
+                progress(&report);
+            }
+            if next == 0 {
+                return Ok(report);
+            }
+            cursor = next;
+        }
+    }
+
+    async fn copy_key(
+        &self,
+        key: &str,
+        old_prefix: &str,
+        new_prefix: &str,
+        options: &MigrateOptions,
+    ) -> Result<bool> {
+        let reply = self
+            .call_lua(&READ_SCRIPT, vec![key.to_string()], Vec::new())
+            .await?;
+        let mut items = reply.into_array()?.into_iter();
+        let (Some(Reply::Bulk(value)), Some(Reply::Nil), Some(pttl)) =
+            (items.next(), items.next(), items.next())
+        else {
+            return Ok(false);
+        };
+        let pttl = pttl.as_int()?;
+        let new_key = rename_key(key, old_prefix, new_prefix);
+        // an encrypted value is bound to its key, see Encryptor.
+        let value = self.reencrypt(key, &new_key, value)?;
+        let value = match &options.transform {
+            Some(transform) => transform(&value)?,
+            None => value,
+        };
+        let copied = self
+            .call_lua(
+                &COPY_SCRIPT,
+                vec![new_key],
+                Args::default()
+                    .arg(value)
+                    .arg(pttl.max(0))
+                    .arg(if options.overwrite { "1" } else { "0" })
+                    .build(),
+            )
+            .await?;
+        Ok(copied.as_int()? == 1)
+    }
+}
+
+fn rename_key(key: &str, old_prefix: &str, new_prefix: &str) -> String {
+    format!(
+        "{}{}",
+        new_prefix,
+        key.strip_prefix(old_prefix).unwrap_or(key)
+    )
+}
+
+pub(crate) fn escape_glob(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "rustis")]
+    use crate::Options;
+    #[cfg(feature = "rustis")]
+    use rustis::client::Client as RustisClient;
+    #[cfg(feature = "rustis")]
+    use std::time::Duration;
+
+    #[test]
+    fn test_rename_key() {
+        assert_eq!(rename_key("old:user:1", "old:", "new:"), "new:user:1");
+    }
+
+    #[test]
+    fn test_escape_glob() {
+        assert_eq!(escape_glob("svc[1]*?"), "svc\\[1\\]\\*\\?");
+        assert_eq!(escape_glob("svc:"), "svc:");
+    }
+
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
+    async fn test_migrate_namespace() {
+        let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
+        let client = Client::new(
+            rdb,
+            Options {
+                common_prefix: "test_migrate_old:".to_string(),
+                ..Default::default()
+            },
+        );
+        client.tag_as_deleted("a").await.unwrap();
+        let f = async { Ok(Some("test".to_string())) };
+        client
+            .fetch("a", Duration::from_secs(600), || f)
+            .await
+            .unwrap();
+
+        let report = client
+            .migrate_namespace(
+                "test_migrate_old:",
+                "test_migrate_new:",
+                MigrateOptions {
+                    overwrite: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(report.copied >= 1);
+
+        let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
+        let client = Client::new(
+            rdb,
+            Options {
+                common_prefix: "test_migrate_new:".to_string(),
+                ..Default::default()
+            },
+        );
+        assert!(client.exists("a").await.unwrap());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{
+    script::{BUMP_VERSION_SCRIPT, VERSION_SCRIPT},
+    Client, Result,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::{Debug, Display},
+    future::Future,
+    time::Duration,
+};
+
+// NAMESPACE_VERSION_PREFIX prefixes the version counters of the namespaces, before
+// Options::common_prefix and the name. They are skipped by flush_namespace.
+pub(crate) const NAMESPACE_VERSION_PREFIX: &str = "rdcache:namespace:";
+
+// Namespace is a view of the keys of client under a name and a version, from
+// Client::namespaced. Ids are cached under `<name>:v<version>:<id>`, so bump_version
+// invalidates the whole namespace in O(1): the keys of the old version are not read
+// anymore and expire on their own.
+//
+//     let orders = client.namespaced("orders");
+//     let order = orders.fetch(id, Duration::from_secs(600), || load_order(id)).await?;
+//     orders.bump_version().await?;
+//
+// The version is read from redis by each call, one more round trip, so that a bump is
+// seen right away by every instance.
+pub struct Namespace<'a> {
+    client: &'a Client,
+    name: String,
+}
+
+impl Client {
+    // namespaced returns the namespace called name of the keys of the client.
+    pub fn namespaced(&self, name: impl Into<String>) -> Namespace<'_> {
+        Namespace {
+            client: self,
+            name: name.into(),
+        }
+    }
+}
+
+impl Namespace<'_> {
+    // version returns the current version of the namespace, 0 until the first bump.
+    pub async fn version(&self) -> Result<u64> {
+        let version = self
+            .client
+            .call_lua(&VERSION_SCRIPT, vec![self.version_key()], Vec::new())
+            .await?;
+        Ok(version.as_int()? as u64)
+    }
+
+    // bump_version moves the namespace to a new version, returning it. The values of
+    // the previous versions are not read anymore.
+    pub async fn bump_version(&self) -> Result<u64> {
+        let version = self
+            .client
+            .call_lua(&BUMP_VERSION_SCRIPT, vec![self.version_key()], Vec::new())
+            .await?;
+        Ok(version.as_int()? as u64)
+    }
+
+    // key is the key the value of id is cached under at the current version, without
+    // Options::common_prefix.
+    pub async fn key(&self, id: impl Display) -> Result<String> {
+        Ok(format!("{}:v{}:{}", self.name, self.version().await?, id))
+    }
+
+    // fetch is Client::fetch of the value of id at the current version.
+    pub async fn fetch<F, Fut, V>(
+        &self,
+        id: impl Display,
+        expire: Duration,
+        f: F,
+    ) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        self.client.fetch(self.key(id).await?, expire, f).await
+    }
+
+    // tag_as_deleted is Client::tag_as_deleted of the value of id at the current version.
+    pub async fn tag_as_deleted(&self, id: impl Display) -> Result<()> {
+        self.client.tag_as_deleted(self.key(id).await?).await
+    }
+
+    fn version_key(&self) -> String {
+        format!(
+            "{}{}{}",
+            NAMESPACE_VERSION_PREFIX,
+            self.client.options().common_prefix,
+            self.name
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+
+    #[tokio::test]
+    async fn test_namespace_version() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            common_prefix: "app:".to_string(),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let orders = client.namespaced("orders");
+        let expire = Duration::from_secs(600);
+        assert_eq!(orders.version().await.unwrap(), 0);
+        assert_eq!(orders.key(1).await.unwrap(), "orders:v0:1");
+
+        let v = orders.fetch(1, expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+        assert!(fake.hget("app:orders:v0:1", "value").is_some());
+        let v = orders.fetch(1, expire, || async { Ok(Some(2)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+
+        assert_eq!(orders.bump_version().await.unwrap(), 1);
+        assert_eq!(client.namespaced("orders").version().await.unwrap(), 1);
+        let v = orders.fetch(1, expire, || async { Ok(Some(3)) }).await;
+        assert_eq!(v.unwrap(), Some(3));
+        assert_eq!(client.namespaced("users").version().await.unwrap(), 0);
+
+        assert_eq!(client.flush_namespace("").await.unwrap(), 2);
+        assert_eq!(orders.version().await.unwrap(), 1);
+    }
+}
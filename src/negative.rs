@@ -0,0 +1,133 @@
+use crate::{
+    backend::Args,
+    script::{MARKED_EMPTY_SCRIPT, MARK_EMPTY_SCRIPT},
+    Client, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// NegativeCachePolicy is how the empty results of loaders are cached, see
+// Options::negative_cache.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NegativeCachePolicy {
+    // Expire caches them in their key like values, for Options::empty_expire, not at
+    // all if it is 0.
+    #[default]
+    Expire,
+    // Never doesn't cache them, every fetch of a key without a value calls the loader.
+    Never,
+    // Suffix caches them for Options::empty_expire under a separate key, the key with
+    // the suffix appended, so that they can be told apart from values, e.g. to be
+    // scanned or evicted on their own. It costs one more round trip on each miss.
+    // fetch_batch doesn't cache them.
+    Suffix(String),
+}
+
+impl Client {
+    // empty_expire is the expire of the empty results written in their key, 0 if they
+    // are not.
+    pub(crate) fn empty_expire(&self) -> Duration {
+        match self.options().negative_cache {
+            NegativeCachePolicy::Expire => self.options().empty_expire,
+            _ => Duration::ZERO,
+        }
+    }
+
+    // empty_marker is the key marking key as an empty result with
+    // NegativeCachePolicy::Suffix.
+    pub(crate) fn empty_marker(&self, key: &str) -> Option<String> {
+        match &self.options().negative_cache {
+            NegativeCachePolicy::Suffix(suffix) => Some(format!("{}{}", key, suffix)),
+            _ => None,
+        }
+    }
+
+    // mark_empty caches the empty result of key under its marker, if any.
+    pub(crate) async fn mark_empty(&self, key: &str) -> Result<()> {
+        let expire = self.options().empty_expire;
+        let Some(marker) = self.empty_marker(key).filter(|_| !expire.is_zero()) else {
+            return Ok(());
+        };
+        let args = Args::default().arg(expire.as_millis()).build();
+        self.call_lua(&MARK_EMPTY_SCRIPT, vec![marker], args)
+            .await?;
+        Ok(())
+    }
+
+    // hit_marked_empty reports whether an empty result of key is cached under its
+    // marker, for a fetch that took the lock of key for owner. If so, it releases the
+    // lock and counts a hit.
+    pub(crate) async fn hit_marked_empty(&self, key: &str, owner: &str) -> Result<bool> {
+        let Some(marker) = self.empty_marker(key) else {
+            return Ok(false);
+        };
+        let marked = self
+            .call_lua(&MARKED_EMPTY_SCRIPT, vec![marker], Vec::new())
+            .await?;
+        if marked.as_int()? != 1 {
+            return Ok(false);
+        }
+        _ = self.unlock_for_update(key, owner).await;
+        self.stats.hit();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, FetchOptions, Options};
+
+    #[tokio::test]
+    async fn test_negative_cache_policy() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            negative_cache: NegativeCachePolicy::Never,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let expire = Duration::from_secs(600);
+        let none = || async { Ok(None::<u64>) };
+        for _ in 0..2 {
+            let v = client.fetch("k", expire, none).await;
+            assert_eq!(v.unwrap(), None);
+        }
+        assert!(fake.hgetall("k").is_empty());
+        assert_eq!(client.stats().misses, 2);
+
+        let options = FetchOptions {
+            negative_cache: Some(NegativeCachePolicy::Expire),
+            ..Default::default()
+        };
+        let v = client.fetch_with("k", expire, options, none).await;
+        assert_eq!(v.unwrap(), None);
+        assert!(fake.hget("k", "value").is_some());
+
+        let options = Options {
+            negative_cache: NegativeCachePolicy::Suffix(":none".to_string()),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let v = client.fetch("m", expire, none).await;
+        assert_eq!(v.unwrap(), None);
+        assert!(fake.hgetall("m").is_empty());
+        assert!(fake.pttl("m:none").unwrap() <= client.options().empty_expire);
+        let v = client.fetch("m", expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), None, "the marker is a hit");
+        assert_eq!(fake.hget("m", "value"), None);
+        assert_eq!(fake.hget("m", "lockOwner"), None);
+        assert_eq!((client.stats().hits, client.stats().misses), (1, 1));
+
+        client.tag_as_deleted("m").await.unwrap();
+        assert_eq!(fake.pttl("m:none"), None);
+        let v = client.fetch("m", expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+
+        let options = Options {
+            negative_cache: NegativeCachePolicy::Suffix(String::new()),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+    }
+}
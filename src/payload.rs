@@ -0,0 +1,327 @@
+use crate::{
+    backend::Args,
+    error::{new_decode_error, new_unexpected_reply_error},
+    script::{PAYLOAD_SCRIPT, RAW_SET_SCRIPT, WRITE_SCRIPT},
+    Client, Error, Reply, Result, ScriptCall,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+
+// Payload is a cached value as stored in redis, for consumers reusing the exact bytes
+// rdcache wrote instead of decoding and encoding them again, e.g. a shared memory hot
+// cache or a wasm plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Payload {
+    // Bytes is the value as stored. It is encoded first, the MessagePack encoding of an
+    // Option<V> by rmp_serde with structs as arrays, nil being the empty result. With
+    // Options::go_compat the empty result is the empty string, with Client::with_codec
+    // values are encoded by the codec instead. The encoded value is then compressed
+    // with a dictionary of Client::with_dictionary, or with Options::compression over
+    // Options::compression_threshold, and last encrypted with Client::with_encryptor.
+    // The compressed and the encrypted values start with the byte 0xc1, which no
+    // MessagePack value starts with, decode doesn't read them but
+    // Client::decode_payload does.
+    pub bytes: Vec<u8>,
+    // Stale is true if the value was tag deleted or its lock is held, it is being reloaded.
+    pub stale: bool,
+    // Ttl is the remaining time to live, None if the key has no expire.
+    pub ttl: Option<Duration>,
+    // Metadata holds the metadata fields written with the value.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Payload {
+    // decode decodes the value like fetch does without a codec, a compression or an
+    // encryptor, see Client::decode_payload for the others.
+    pub fn decode<V: DeserializeOwned>(&self) -> Result<Option<V>> {
+        if self.bytes.is_empty() {
+            return Ok(None);
+        }
+        rmp_serde::from_slice(&self.bytes).map_err(new_decode_error)
+    }
+}
+
+impl Client {
+    // payload returns the value of key as stored, None if there is none. It neither
+    // takes the lock nor decodes the value.
+    pub async fn payload(&self, key: impl AsRef<str>) -> Result<Option<Payload>> {
+        let key = self.prefixed_key(key.as_ref());
+        let reply = self
+            .call_lua(&PAYLOAD_SCRIPT, vec![key], Vec::new())
+            .await?;
+        if reply == Reply::Nil {
+            return Ok(None);
+        }
+        let [bytes, stale, pttl, metadata] = <[Reply; 4]>::try_from(reply.into_array()?)
+            .map_err(|r| new_unexpected_reply_error(Reply::Array(r)))?;
+        let pttl = pttl.as_int()?;
+        let metadata = metadata
+            .into_array()?
+            .into_iter()
+            .map(|r| r.into_string().map(Option::unwrap_or_default))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Payload {
+            bytes: bytes.into_bytes()?.unwrap_or_default(),
+            stale: stale.as_int()? == 1,
+            ttl: (pttl >= 0).then(|| Duration::from_millis(pttl as u64)),
+            metadata: metadata
+                .chunks_exact(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect(),
+        }))
+    }
+
+    // decode_payload decodes the payload of key like fetch does, decrypting and
+    // decompressing it and decoding it with the codec of the client.
+    pub fn decode_payload<V: DeserializeOwned>(
+        &self,
+        key: impl AsRef<str>,
+        payload: &Payload,
+    ) -> Result<Option<V>> {
+        self.decode_value(&self.borrowed_key(key.as_ref()), &payload.bytes)
+    }
+
+    // raw_get returns the value field of key as stored, like RawGet of the Go rockscache
+    // client, None if there is none. It reads it with CacheBackend::hmget, ignoring the
+    // lock and the tag deleted state.
+    pub async fn raw_get(&self, key: impl AsRef<str>) -> Result<Option<Vec<u8>>> {
+        let key = self.borrowed_key(key.as_ref());
+        let fields = self
+            .with_redis_retry(|| self.backend.hmget(&key, &["value"]))
+            .await;
+        if fields.as_ref().is_err_and(Error::is_redis_error) {
+            self.stats.redis_error();
+        }
+        Ok(fields?.into_iter().next().flatten())
+    }
+
+    // get returns the value of key decoded like fetch does, None if there is none or it
+    // is a cached empty result. Like raw_get it reads the value field alone, ignoring the
+    // lock and the tag deleted state, and it never calls a loader.
+    pub async fn get<V: DeserializeOwned>(&self, key: impl AsRef<str>) -> Result<Option<V>> {
+        let key = key.as_ref();
+        let Some(s) = self.raw_get(key).await? else {
+            return Ok(None);
+        };
+        self.decode_value(&self.borrowed_key(key), &s)
+    }
+
+    // set writes value under key the way fetch does, encoded with the codec of the
+    // client, with Options::metadata and the expire of a fetch with expire, for services
+    // computing values ahead of the reads. A lock held on key is released, and the fetch
+    // holding it doesn't overwrite the value. Like tag_as_deleted, it publishes key on
+    // Options::invalidation_channel.
+    pub async fn set<V: Serialize>(
+        &self,
+        key: impl AsRef<str>,
+        value: &V,
+        expire: Duration,
+    ) -> Result<()> {
+        let key = self.prefixed_key(key.as_ref());
+        let ex = self.value_expire(expire)?;
+        let encoded = self.encode_value(&key, &Some(value))?;
+        #[cfg(feature = "local-cache")]
+        self.local_remove(&key);
+        let call = ScriptCall {
+            script: &WRITE_SCRIPT,
+            keys: vec![key.clone()],
+            args: self.set_args(encoded, "", ex, &[]),
+        };
+        if self.options().invalidation_channel.is_empty() {
+            self.call_lua(call.script, call.keys, call.args).await?;
+            return Ok(());
+        }
+        let replies = self
+            .call_lua_pipeline(vec![call, self.publish_call(&key)])
+            .await;
+        replies.into_iter().next().unwrap_or(Ok(Reply::Nil))?;
+        Ok(())
+    }
+
+    // raw_set writes value as the value field of key with expire, 0 for none, like
+    // RawSet of the Go rockscache client. The value is stored as is, encode it like the
+    // client does for fetch to read it, e.g. with Payload::bytes of another key. A lock
+    // held on key is left in place, and its holder overwrites the value.
+    pub async fn raw_set(
+        &self,
+        key: impl AsRef<str>,
+        value: &[u8],
+        expire: Duration,
+    ) -> Result<()> {
+        let key = self.prefixed_key(key.as_ref());
+        #[cfg(feature = "local-cache")]
+        self.local_remove(&key);
+        self.call_lua(
+            &RAW_SET_SCRIPT,
+            vec![key],
+            Args::default().arg(value).arg(expire.as_millis()).build(),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Encryptor, Options};
+
+    #[tokio::test]
+    async fn test_payload() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            metadata: vec![("version".to_string(), "1.2.0".to_string())],
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        assert_eq!(client.payload("k").await.unwrap(), None);
+
+        client
+            .fetch("k", Duration::from_secs(600), || async {
+                Ok(Some(vec![1, 2, 3]))
+            })
+            .await
+            .unwrap();
+        let payload = client.payload("k").await.unwrap().unwrap();
+        assert_eq!(payload.bytes, fake.hget("k", "value").unwrap());
+        assert!(!payload.stale);
+        assert!(payload.ttl.is_some());
+        assert_eq!(payload.metadata["version"], "1.2.0");
+        assert_eq!(payload.decode::<Vec<u8>>().unwrap(), Some(vec![1, 2, 3]));
+
+        client.tag_as_deleted("k").await.unwrap();
+        assert!(client.payload("k").await.unwrap().unwrap().stale);
+    }
+
+    // Not flips the bits of the values.
+    struct Not;
+
+    impl Encryptor for Not {
+        fn key_id(&self) -> &str {
+            "not"
+        }
+
+        fn encrypt(&self, plaintext: &[u8], _aad: &[u8]) -> Result<Vec<u8>> {
+            Ok(plaintext.iter().map(|b| !b).collect())
+        }
+
+        fn decrypt(&self, _key_id: &str, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+            self.encrypt(ciphertext, aad)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decode_payload() {
+        let options = Options {
+            common_prefix: "app:".to_string(),
+            ..Default::default()
+        };
+        let client = Client::with_backend(FakeBackend::new(), options).with_encryptor(Not);
+        client
+            .fetch("k", Duration::from_secs(600), || async {
+                Ok(Some(vec![1, 2, 3]))
+            })
+            .await
+            .unwrap();
+        let payload = client.payload("k").await.unwrap().unwrap();
+        assert_eq!(payload.bytes[0], 0xc1);
+        assert!(payload.decode::<Vec<u8>>().is_err());
+        assert_eq!(
+            client.decode_payload::<Vec<u8>>("k", &payload).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_raw_get_and_set() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            common_prefix: "app:".to_string(),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        assert_eq!(client.raw_get("k").await.unwrap(), None);
+
+        let value = rmp_serde::to_vec(&Some("warm")).unwrap();
+        client
+            .raw_set("k", &value, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(fake.hget("app:k", "value"), Some(value.clone()));
+        assert!(fake.pttl("app:k").unwrap() <= Duration::from_secs(60));
+        assert_eq!(client.raw_get("k").await.unwrap(), Some(value));
+        let fetched = client
+            .fetch("k", Duration::from_secs(600), || async {
+                Ok(Some("loaded".to_string()))
+            })
+            .await
+            .unwrap();
+        assert_eq!(fetched.as_deref(), Some("warm"));
+
+        client.raw_set("k", b"raw", Duration::ZERO).await.unwrap();
+        assert_eq!(fake.pttl("app:k"), None);
+        assert_eq!(client.raw_get("k").await.unwrap(), Some(b"raw".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            common_prefix: "app:".to_string(),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        assert_eq!(client.get::<String>("k").await.unwrap(), None);
+
+        let expire = Duration::from_secs(600);
+        client
+            .fetch("k", expire, || async { Ok(Some("v".to_string())) })
+            .await
+            .unwrap();
+        client
+            .fetch("empty", expire, || async { Ok(None::<String>) })
+            .await
+            .unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Some("v".to_string()));
+        assert_eq!(client.get::<String>("empty").await.unwrap(), None);
+        client.tag_as_deleted("k").await.unwrap();
+        assert_eq!(client.get("k").await.unwrap(), Some("v".to_string()));
+        assert!(client.get::<u64>("k").await.is_err());
+        assert_eq!(fake.calls("get"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_set() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            metadata: vec![("version".to_string(), "1".to_string())],
+            random_expire_adjustment: 0.0,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let expire = Duration::from_secs(600);
+        client.set("k", &"warm", expire).await.unwrap();
+        assert_eq!(fake.hget("k", "meta:version"), Some(b"1".to_vec()));
+        assert_eq!(fake.pttl("k"), Some(expire - client.options().delay));
+        let fetched = client
+            .fetch("k", expire, || async { Ok(Some("loaded".to_string())) })
+            .await
+            .unwrap();
+        assert_eq!(fetched.as_deref(), Some("warm"));
+
+        client.tag_as_deleted("k").await.unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let fetch = client.fetch("k", expire, || async move {
+            _ = rx.await;
+            Ok(Some("stale".to_string()))
+        });
+        let set = async {
+            client.set("k", &"fresh", expire).await.unwrap();
+            assert!(fake.hget("k", "lockOwner").is_none());
+            tx.send(()).unwrap();
+        };
+        let (fetched, ()) = tokio::join!(fetch, set);
+        assert_eq!(fetched.unwrap().as_deref(), Some("stale"));
+        assert_eq!(client.get("k").await.unwrap(), Some("fresh".to_string()));
+    }
+}
@@ -0,0 +1,59 @@
+use std::cell::RefCell;
+
+// MAX_BUFFERS is the number of buffers kept per thread.
+#[cfg_attr(not(feature = "rustis"), allow(dead_code))]
+const MAX_BUFFERS: usize = 64;
+
+// MAX_CAPACITY is the capacity above which a buffer is freed instead of kept, so that
+// a few large values don't stay allocated.
+#[cfg_attr(not(feature = "rustis"), allow(dead_code))]
+const MAX_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    static BUFFERS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+// take returns an empty buffer for encoding a value or a script argument, reusing one
+// recycled on this thread if there is one.
+pub(crate) fn take() -> Vec<u8> {
+    BUFFERS
+        .with(|buffers| buffers.borrow_mut().pop())
+        .unwrap_or_default()
+}
+
+// recycle keeps the buffers of sent script arguments for take.
+#[cfg_attr(not(feature = "rustis"), allow(dead_code))]
+pub(crate) fn recycle(args: Vec<Vec<u8>>) {
+    BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        for mut buf in args {
+            if buffers.len() >= MAX_BUFFERS {
+                break;
+            }
+            if buf.capacity() > 0 && buf.capacity() <= MAX_CAPACITY {
+                buf.clear();
+                buffers.push(buf);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool() {
+        let mut buf = take();
+        buf.extend_from_slice(b"value");
+        let ptr = buf.as_ptr();
+        recycle(vec![buf, Vec::new(), vec![0; MAX_CAPACITY + 1]]);
+        let buf = take();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(take().capacity(), 0);
+
+        recycle((0..MAX_BUFFERS + 1).map(|_| vec![0; 8]).collect());
+        assert_eq!(BUFFERS.with(|b| b.borrow().len()), MAX_BUFFERS);
+    }
+}
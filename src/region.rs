@@ -0,0 +1,132 @@
+use crate::{Client, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    future::Future,
+    hash::Hash,
+    marker::PhantomData,
+    time::Duration,
+};
+
+// CacheRegion is a typed view of the keys of client under a prefix, from
+// Client::region. Values are cached for the ttl of the region under `<prefix>:<id>`,
+// so that the fetch side and the invalidate side of an id always agree:
+//
+//     let users = client.region::<User>("user", Duration::from_secs(600));
+//     let user = users.get(id, || load_user(id)).await?;
+//     users.invalidate(id).await?;
+pub struct CacheRegion<'a, V> {
+    client: &'a Client,
+    prefix: String,
+    ttl: Duration,
+    value: PhantomData<fn() -> V>,
+}
+
+impl Client {
+    // region returns the region of the values of type V cached under prefix for ttl.
+    pub fn region<V>(&self, prefix: impl Into<String>, ttl: Duration) -> CacheRegion<'_, V> {
+        CacheRegion {
+            client: self,
+            prefix: prefix.into(),
+            ttl,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<V> CacheRegion<'_, V>
+where
+    V: DeserializeOwned + Serialize + Debug,
+{
+    // key is the key the value of id is cached under, without Options::common_prefix.
+    pub fn key(&self, id: impl Display) -> String {
+        format!("{}:{}", self.prefix, id)
+    }
+
+    // get is Client::fetch of the value of id.
+    pub async fn get<F, Fut>(&self, id: impl Display, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+    {
+        self.client.fetch(self.key(id), self.ttl, f).await
+    }
+
+    // invalidate is Client::tag_as_deleted of the value of id.
+    pub async fn invalidate(&self, id: impl Display) -> Result<()> {
+        self.client.tag_as_deleted(self.key(id)).await
+    }
+
+    // get_batch is Client::fetch_batch of the values of ids: f is called with the ids
+    // missing from the cache, and the ones it doesn't return are cached as empty
+    // results. It returns the values by id, without the empty results.
+    pub async fn get_batch<K, F, Fut>(&self, ids: &[K], f: F) -> Result<HashMap<K, V>>
+    where
+        K: Display + Clone + Eq + Hash,
+        F: Fn(Vec<K>) -> Fut,
+        Fut: Future<Output = Result<HashMap<K, V>>>,
+    {
+        let keys = ids.iter().map(|id| self.key(id)).collect();
+        let values = self
+            .client
+            .fetch_batch(keys, self.ttl, |idxs: Vec<usize>| {
+                let loading: Vec<K> = idxs.iter().map(|&i| ids[i].clone()).collect();
+                let loaded = f(loading);
+                async move {
+                    let mut loaded = loaded.await?;
+                    Ok(idxs
+                        .into_iter()
+                        .filter_map(|i| Some((i, loaded.remove(&ids[i])?)))
+                        .collect())
+                }
+            })
+            .await?;
+        Ok(values
+            .into_iter()
+            .map(|(i, value)| (ids[i].clone(), value))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_cache_region() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let users = client.region::<String>("user", Duration::from_secs(600));
+        assert_eq!(users.key(7), "user:7");
+
+        let user = users.get(7, || async { Ok(Some("ann".to_string())) });
+        assert_eq!(user.await.unwrap().as_deref(), Some("ann"));
+        assert!(fake.hget("user:7", "value").is_some());
+        let user = users.get(7, || async { Ok(Some("bob".to_string())) });
+        assert_eq!(user.await.unwrap().as_deref(), Some("ann"));
+        users.invalidate(7).await.unwrap();
+        let user = users.get(7, || async { Ok(Some("bob".to_string())) });
+        assert_eq!(user.await.unwrap().as_deref(), Some("bob"));
+
+        let loads = Arc::new(Mutex::new(Vec::new()));
+        let load = |ids: Vec<u64>| {
+            loads.lock().unwrap().push(ids.clone());
+            let values = ids
+                .into_iter()
+                .filter(|&id| id != 0)
+                .map(|id| (id, format!("user {}", id)))
+                .collect();
+            async move { Ok(values) }
+        };
+        let values = users.get_batch(&[7, 8, 0], load).await.unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[&7], "bob");
+        assert_eq!(values[&8], "user 8");
+        let values = users.get_batch(&[8, 9], load).await.unwrap();
+        assert_eq!(values[&9], "user 9");
+        assert_eq!(*loads.lock().unwrap(), vec![vec![8, 0], vec![9]]);
+    }
+}
@@ -0,0 +1,241 @@
+use http::{header, HeaderName, HeaderValue, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+// CachedHttpResponse is an http response as cached, for using rdcache as an
+// application level http cache:
+//
+//     let key = http_cache_key(&request, &[header::ACCEPT_LANGUAGE]);
+//     let cached = client.fetch(key, expire, || async {
+//         let response = render(&request).await?;
+//         Ok(Some(CachedHttpResponse::new(&response, &[header::CONTENT_TYPE])))
+//     }).await?;
+//
+// Check is_cacheable before caching responses from an upstream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedHttpResponse {
+    pub status: u16,
+    // Headers are the headers kept from the response, in order.
+    pub headers: Vec<(String, ByteBuf)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+impl CachedHttpResponse {
+    // new keeps the status, the body and the headers of allowlist of response. Headers
+    // specific to one client, like Set-Cookie, must not be allowed.
+    pub fn new<B: AsRef<[u8]>>(response: &Response<B>, allowlist: &[HeaderName]) -> Self {
+        let headers = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| allowlist.contains(name))
+            .map(|(name, value)| {
+                (
+                    name.as_str().to_string(),
+                    ByteBuf::from(value.as_bytes().to_vec()),
+                )
+            })
+            .collect();
+        Self {
+            status: response.status().as_u16(),
+            headers,
+            body: response.body().as_ref().to_vec(),
+        }
+    }
+
+    // json is a 200 OK response with value as a json body.
+    pub fn json(value: &serde_json::Value) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16(),
+            headers: vec![(
+                header::CONTENT_TYPE.as_str().to_string(),
+                ByteBuf::from(b"application/json".to_vec()),
+            )],
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    // json_body parses the body as json, None if it is not.
+    pub fn json_body(&self) -> Option<serde_json::Value> {
+        serde_json::from_slice(&self.body).ok()
+    }
+
+    // into_response restores the response, skipping the headers that are not valid
+    // anymore.
+    pub fn into_response(self) -> Response<Vec<u8>> {
+        let mut response = Response::new(self.body);
+        *response.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        for (name, value) in self.headers {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::try_from(name), HeaderValue::from_bytes(&value))
+            {
+                response.headers_mut().append(name, value);
+            }
+        }
+        response
+    }
+}
+
+// is_cacheable reports whether response can be shared between clients: a 2xx status,
+// no Cache-Control no-store or private, no Vary: * and no Set-Cookie.
+pub fn is_cacheable<B>(response: &Response<B>) -> bool {
+    let headers = response.headers();
+    let mut directives = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase());
+    response.status().is_success()
+        && !directives.any(|d| d == "no-store" || d == "private" || d.starts_with("private="))
+        && !headers.get_all(header::VARY).iter().any(|v| {
+            v.as_bytes()
+                .split(|b| *b == b',')
+                .any(|v| v.trim_ascii() == b"*")
+        })
+        && !headers.contains_key(header::SET_COOKIE)
+}
+
+// http_cache_key builds the cache key of request from its method, its host, its path
+// and query, and the values of its vary headers, the request headers the response
+// depends on like with the Vary response header. Requests differing in any of them get
+// different keys. The host is the authority of the uri, or the Host header of an
+// origin-form uri like the ones servers receive.
+pub fn http_cache_key<B>(request: &Request<B>, vary: &[HeaderName]) -> String {
+    let uri = request.uri();
+    let host = match uri.authority() {
+        Some(authority) => authority.as_str().to_ascii_lowercase(),
+        None => request
+            .headers()
+            .get(header::HOST)
+            .map(|host| String::from_utf8_lossy(host.as_bytes()).to_ascii_lowercase())
+            .unwrap_or_default(),
+    };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let key = format!("http:{}:{}{}", request.method(), host, path);
+    if vary.is_empty() {
+        return key;
+    }
+    let mut vary = vary.to_vec();
+    vary.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    vary.dedup();
+    let mut hasher = Sha1::new();
+    for name in &vary {
+        hasher.update(name.as_str());
+        for value in request.headers().get_all(name) {
+            hasher.update([0]);
+            hasher.update(value.as_bytes());
+        }
+        hasher.update([1]);
+    }
+    format!("{}:{:x}", key, hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Client, Options};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cached_http_response() {
+        let client = Client::with_backend(FakeBackend::new(), Options::default());
+        let response = Response::builder()
+            .status(StatusCode::CREATED)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::DATE, "Mon, 01 Jan 2024 00:00:00 GMT")
+            .body(b"hello".to_vec())
+            .unwrap();
+        assert!(is_cacheable(&response));
+        let cached = CachedHttpResponse::new(&response, &[header::CONTENT_TYPE]);
+        let fetched = client
+            .fetch("page", Duration::from_secs(600), || async {
+                Ok(Some(cached.clone()))
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetched, cached);
+        let restored = fetched.into_response();
+        assert_eq!(restored.status(), StatusCode::CREATED);
+        assert_eq!(restored.headers().len(), 1);
+        assert_eq!(restored.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(restored.body(), b"hello");
+
+        let json = CachedHttpResponse::json(&serde_json::json!({"id": 1}));
+        assert_eq!(json.json_body(), Some(serde_json::json!({"id": 1})));
+    }
+
+    #[test]
+    fn test_is_cacheable() {
+        let with = |name, value| Response::builder().header(name, value).body(()).unwrap();
+        assert!(is_cacheable(&with(
+            header::CACHE_CONTROL,
+            "public, max-age=60"
+        )));
+        assert!(!is_cacheable(&with(
+            header::CACHE_CONTROL,
+            "max-age=60, Private"
+        )));
+        assert!(!is_cacheable(&with(header::CACHE_CONTROL, "no-store")));
+        assert!(!is_cacheable(&with(header::VARY, "Accept, *")));
+        assert!(!is_cacheable(&with(header::SET_COOKIE, "id=1")));
+        let mut error = with(header::VARY, "Accept");
+        *error.status_mut() = StatusCode::BAD_GATEWAY;
+        assert!(!is_cacheable(&error));
+    }
+
+    #[test]
+    fn test_http_cache_key() {
+        let request = |lang: &str| {
+            Request::get("/users?page=1")
+                .header(header::ACCEPT_LANGUAGE, lang)
+                .header(header::USER_AGENT, "curl")
+                .body(())
+                .unwrap()
+        };
+        assert_eq!(
+            http_cache_key(&request("en"), &[]),
+            "http:GET:/users?page=1"
+        );
+        let vary = [header::ACCEPT_LANGUAGE];
+        assert_eq!(
+            http_cache_key(&request("en"), &vary),
+            http_cache_key(&request("en"), &vary)
+        );
+        assert_ne!(
+            http_cache_key(&request("en"), &vary),
+            http_cache_key(&request("fr"), &vary)
+        );
+        assert_eq!(
+            http_cache_key(&request("en"), &[header::ACCEPT_LANGUAGE, header::ACCEPT]),
+            http_cache_key(&request("en"), &[header::ACCEPT, header::ACCEPT_LANGUAGE])
+        );
+    }
+
+    #[test]
+    fn test_http_cache_key_host() {
+        let request = |host: &str| {
+            Request::get("/users?page=1")
+                .header(header::HOST, host)
+                .body(())
+                .unwrap()
+        };
+        assert_eq!(
+            http_cache_key(&request("a.example.com"), &[]),
+            "http:GET:a.example.com/users?page=1"
+        );
+        assert_ne!(
+            http_cache_key(&request("a.example.com"), &[]),
+            http_cache_key(&request("b.example.com"), &[])
+        );
+        let absolute = Request::get("https://A.example.com/users?page=1")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            http_cache_key(&absolute, &[]),
+            "http:GET:a.example.com/users?page=1"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{Client, Error, Result};
+#[cfg(feature = "rustis")]
+use rustis::RedisErrorKind;
+use serde::{Deserialize, Serialize};
+use std::{future::Future, time::Duration};
+
+// RetryPolicy is how the redis calls failing with a transient error are retried, see
+// Options::redis_retry.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    // Attempts is the number of times a call is made at most, 1 never retries.
+    // default is 1
+    pub attempts: u32,
+    // Backoff is the wait before the first retry, doubled before each next one up to
+    // MaxBackoff. default is 50ms
+    #[serde(with = "crate::config::duration")]
+    pub backoff: Duration,
+    // MaxBackoff caps the wait before a retry. default is 1s
+    #[serde(with = "crate::config::duration")]
+    pub max_backoff: Duration,
+    // Retryable tells whether a call that failed with an error is worth retrying.
+    // default is is_transient, it is not serialized.
+    #[serde(skip)]
+    pub retryable: fn(&Error) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            retryable: is_transient,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // attempts returns the policy retrying up to attempts times in all, with the
+    // default backoff and classifier.
+    pub fn attempts(attempts: u32) -> Self {
+        Self {
+            attempts,
+            ..Default::default()
+        }
+    }
+}
+
+// is_transient reports whether err is likely gone on a retry: a dropped connection, a
+// timeout, or a redis failover or cluster resharding in progress. The errors of other
+// backends are transient if they are io errors.
+pub fn is_transient(err: &Error) -> bool {
+    match err {
+        #[cfg(feature = "rustis")]
+        Error::RedisError(err) => match err {
+            rustis::Error::IO(_) | rustis::Error::Timeout(_) | rustis::Error::EOF => true,
+            rustis::Error::Redis(e) => matches!(
+                e.kind,
+                RedisErrorKind::TryAgain
+                    | RedisErrorKind::MasterDown
+                    | RedisErrorKind::Readonly
+                    | RedisErrorKind::ClusterDown
+                    | RedisErrorKind::IoErr
+            ),
+            _ => false,
+        },
+        Error::BackendError(e) => e.is::<std::io::Error>(),
+        _ => false,
+    }
+}
+
+impl Client {
+    // with_redis_retry runs call until it succeeds, fails with an error that
+    // Options::redis_retry doesn't retry, or runs out of attempts.
+    pub(crate) async fn with_redis_retry<T, Fut>(&self, mut call: impl FnMut() -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = call().await;
+            if !self.should_retry(&result, attempt) {
+                return result;
+            }
+            self.retry_sleep(attempt).await;
+            attempt += 1;
+        }
+    }
+
+    // should_retry reports whether a call of the attempt-th attempt, from 1, is retried.
+    pub(crate) fn should_retry<T>(&self, result: &Result<T>, attempt: u32) -> bool {
+        let policy = &self.options().redis_retry;
+        match result {
+            Err(e) if e.is_redis_error() => attempt < policy.attempts && (policy.retryable)(e),
+            _ => false,
+        }
+    }
+
+    // retry_sleep waits before the retry of the attempt-th attempt, from 1.
+    pub(crate) async fn retry_sleep(&self, attempt: u32) {
+        let policy = &self.options().redis_retry;
+        let factor = 1u32 << attempt.saturating_sub(1).min(16);
+        let backoff = policy
+            .backoff
+            .saturating_mul(factor)
+            .min(policy.max_backoff);
+        self.executor.sleep(backoff).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_redis_retry() {
+        let fake = FakeBackend::new();
+        let expire = Duration::from_secs(600);
+        let client = Client::with_backend(fake.clone(), Options::default());
+        fake.fail_next(1);
+        let v = client.fetch("k", expire, || async { Ok(Some(1)) }).await;
+        assert!(v.is_err());
+
+        let options = Options {
+            redis_retry: RetryPolicy {
+                retryable: |_| true,
+                ..RetryPolicy::attempts(3)
+            },
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        fake.fail_next(2);
+        let started = tokio::time::Instant::now();
+        let v = client.fetch("k", expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+        assert_eq!(started.elapsed(), Duration::from_millis(50 + 100));
+        assert_eq!(client.stats().redis_errors, 2);
+
+        let options = Options {
+            redis_retry: RetryPolicy::attempts(3),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        fake.fail_next(1);
+        let v = client.fetch("k", expire, || async { Ok(Some(2)) }).await;
+        assert!(v.is_err(), "client errors are not transient");
+        #[cfg(feature = "rustis")]
+        assert!(is_transient(&crate::error::new_redis_error(
+            rustis::Error::Timeout("read".to_string())
+        )));
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(is_transient(&crate::error::new_backend_error(reset)));
+    }
+}
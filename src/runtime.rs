@@ -0,0 +1,173 @@
+use crate::backend::BoxFuture;
+use futures::future::{AbortHandle, Abortable};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// Runtime is the async runtime the client runs its timers and background tasks on.
+// TokioRuntime is the default, AsyncStdRuntime is available behind the
+// async-std-runtime feature, other runtimes can implement it.
+pub trait Runtime: Send + Sync + 'static {
+    // spawn runs task in the background, detached.
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    // sleep completes after d.
+    fn sleep(&self, d: Duration) -> BoxFuture<'static, ()>;
+
+    // now returns the current instant of the runtime timers.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    // block_in_place runs f, a cpu bound piece of work like decoding a large value,
+    // without stalling the other tasks of the current worker thread if the runtime
+    // supports it. The default runs f inline.
+    fn block_in_place(&self, f: &mut dyn FnMut()) {
+        f()
+    }
+}
+
+// TokioRuntime runs on the tokio runtime of the caller, its methods must be called
+// within one.
+#[cfg(any(feature = "tokio-runtime", test))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+#[cfg(any(feature = "tokio-runtime", test))]
+impl Runtime for TokioRuntime {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        tokio::spawn(task);
+    }
+
+    fn sleep(&self, d: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(d))
+    }
+
+    // now follows the tokio clock, so that it stops with it in tests that pause time.
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    // block_in_place hands the other tasks of the worker to another one while f runs.
+    // The current thread runtime has no other worker, f runs inline there.
+    fn block_in_place(&self, f: &mut dyn FnMut()) {
+        match tokio::runtime::Handle::try_current().map(|h| h.runtime_flavor()) {
+            Ok(tokio::runtime::RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+            _ => f(),
+        }
+    }
+}
+
+// AsyncStdRuntime runs on the global async-std executor.
+#[cfg(feature = "async-std-runtime")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std-runtime")]
+impl Runtime for AsyncStdRuntime {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        async_std::task::spawn(task);
+    }
+
+    fn sleep(&self, d: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async_std::task::sleep(d))
+    }
+}
+
+// default_runtime is the runtime of a new client. The unit tests run on tokio with
+// any features, pausing its clock.
+#[cfg(any(feature = "tokio-runtime", test))]
+pub(crate) fn default_runtime() -> Arc<dyn Runtime> {
+    Arc::new(TokioRuntime)
+}
+
+#[cfg(all(
+    not(any(feature = "tokio-runtime", test)),
+    feature = "async-std-runtime"
+))]
+pub(crate) fn default_runtime() -> Arc<dyn Runtime> {
+    Arc::new(AsyncStdRuntime)
+}
+
+#[cfg(not(any(feature = "tokio-runtime", feature = "async-std-runtime")))]
+compile_error!("rdcache needs one of the tokio-runtime or async-std-runtime features");
+
+// Task is a background task that can be stopped, on any runtime.
+#[derive(Debug)]
+pub(crate) struct Task {
+    abort: AbortHandle,
+    finished: Arc<AtomicBool>,
+}
+
+impl Task {
+    pub(crate) fn spawn(
+        runtime: &dyn Runtime,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) -> Self {
+        let (abort, registration) = AbortHandle::new_pair();
+        let finished = Arc::new(AtomicBool::new(false));
+        let done = finished.clone();
+        runtime.spawn(Box::pin(async move {
+            _ = Abortable::new(task, registration).await;
+            done.store(true, Ordering::SeqCst);
+        }));
+        Self { abort, finished }
+    }
+
+    // abort stops the task at its next await point.
+    pub(crate) fn abort(&self) {
+        self.abort.abort();
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst) || self.abort.is_aborted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_task() {
+        let runtime = TokioRuntime;
+        let task = Task::spawn(&runtime, async {});
+        runtime.sleep(Duration::from_millis(1)).await;
+        assert!(task.is_finished());
+
+        let start = runtime.now();
+        let task = Task::spawn(&runtime, std::future::pending());
+        runtime.sleep(Duration::from_secs(1)).await;
+        assert_eq!(runtime.now() - start, Duration::from_secs(1));
+        assert!(!task.is_finished());
+        task.abort();
+        assert!(task.is_finished());
+    }
+
+    #[cfg(feature = "async-std-runtime")]
+    #[test]
+    fn test_async_std_runtime() {
+        use crate::{test_util::FakeBackend, Client, Options};
+
+        async_std::task::block_on(async {
+            let fake = FakeBackend::new();
+            let options = Options {
+                detached_write: true,
+                ..Default::default()
+            };
+            let client = Client::with_backend(fake.clone(), options).with_runtime(AsyncStdRuntime);
+            let v = client
+                .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+            assert_eq!(v, Some(1));
+            client.shutdown().await;
+            assert!(fake.hget("k", "value").is_some());
+        });
+    }
+}
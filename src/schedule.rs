@@ -0,0 +1,265 @@
+use crate::runtime::Task;
+use crate::{Client, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use uuid::Uuid;
+
+// REFRESH_JITTER is the fraction of the interval a scheduled refresh may run early,
+// so that keys scheduled together don't hit the source at the same time.
+const REFRESH_JITTER: f64 = 0.1;
+
+// RefreshRegistry holds the tasks of the keys refreshed by schedule_refresh, or of
+// the prefixes flushed by schedule_flush, they are stopped when the client is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct RefreshRegistry {
+    pub(crate) tasks: Mutex<HashMap<String, Arc<Task>>>,
+}
+
+impl Drop for RefreshRegistry {
+    fn drop(&mut self) {
+        for (_, task) in self.tasks.get_mut().unwrap().drain() {
+            task.abort();
+        }
+    }
+}
+
+// TaskSlot holds a single background task, stopped when replaced or dropped.
+#[derive(Debug, Default)]
+pub(crate) struct TaskSlot {
+    task: Mutex<Option<Arc<Task>>>,
+}
+
+impl TaskSlot {
+    pub(crate) fn set(&self, task: Arc<Task>) {
+        if let Some(old) = self.task.lock().unwrap().replace(task) {
+            old.abort();
+        }
+    }
+
+    // stop aborts the task, returning false if there was none.
+    pub(crate) fn stop(&self) -> bool {
+        match self.task.lock().unwrap().take() {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for TaskSlot {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Client {
+    // schedule_refresh keeps key warm by reloading it every interval, regardless of
+    // traffic, for config or reference data that must never miss.
+    // Values are written with an expire of three intervals (at least 1s), so they
+    // survive two failed reloads in a row. A failed reload is retried after
+    // lock_sleep, doubling up to interval while it keeps failing.
+    // Scheduling a key again replaces its previous schedule, nothing is scheduled
+    // after shutdown. It must be called within a tokio runtime.
+    pub fn schedule_refresh<F, Fut, V>(&self, key: impl AsRef<str>, interval: Duration, f: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Option<V>>> + Send + 'static,
+        V: DeserializeOwned + Serialize + Debug + Send + 'static,
+    {
+        let key = self.prefixed_key(key.as_ref());
+        let client = self.detach();
+        let task_key = key.clone();
+        let Some(task) = self.executor.spawn_service(async move {
+            let expire = (interval * 3).max(Duration::from_secs(1));
+            let mut failures = 0;
+            loop {
+                let refreshed = client
+                    .executor
+                    .run(client.refresh_scheduled(&task_key, expire, &f))
+                    .await;
+                let delay = match refreshed {
+                    Ok(()) => {
+                        failures = 0;
+                        interval.mul_f64(1.0 - REFRESH_JITTER * random_fraction())
+                    }
+                    Err(_) => {
+                        failures += 1;
+                        retry_delay(client.options().lock_sleep, interval, failures)
+                    }
+                };
+                client.executor.sleep(delay).await;
+            }
+        }) else {
+            return;
+        };
+        if let Some(old) = self.refreshes.tasks.lock().unwrap().insert(key, task) {
+            old.abort();
+        }
+    }
+
+    // cancel_refresh stops the scheduled refresh of key, returning false if there was none.
+    pub fn cancel_refresh(&self, key: impl AsRef<str>) -> bool {
+        let key = self.prefixed_key(key.as_ref());
+        match self.refreshes.tasks.lock().unwrap().remove(&key) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // scheduled_refreshes returns the keys with a scheduled refresh, sorted.
+    pub fn scheduled_refreshes(&self) -> Vec<String> {
+        let mut keys: Vec<_> = self
+            .refreshes
+            .tasks
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // refresh_scheduled reloads key without blocking its readers, or loads it through
+    // the lock protocol if there is no fresh value to refresh.
+    async fn refresh_scheduled<F, Fut, V>(&self, key: &str, expire: Duration, f: &F) -> Result<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        if self.refresh(key, expire, f).await? {
+            return Ok(());
+        }
+        self.strong_fetch::<_, _, V>(key, expire, &[], f).await?;
+        Ok(())
+    }
+}
+
+fn retry_delay(base: Duration, interval: Duration, failures: u32) -> Duration {
+    base.saturating_mul(1 << (failures - 1).min(16))
+        .min(interval)
+}
+
+// random_fraction returns a random number in [0, 1).
+fn random_fraction() -> f64 {
+    (Uuid::new_v4().as_u128() >> 75) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::new_decode_error, test_util::FakeBackend, Options};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_retry_delay() {
+        let base = Duration::from_millis(100);
+        let interval = Duration::from_secs(1);
+        assert_eq!(retry_delay(base, interval, 1), base);
+        assert_eq!(retry_delay(base, interval, 3), Duration::from_millis(400));
+        assert_eq!(retry_delay(base, interval, 10), interval);
+        assert_eq!(retry_delay(base, interval, 100), interval);
+    }
+
+    #[test]
+    fn test_random_fraction() {
+        for _ in 0..100 {
+            let f = random_fraction();
+            assert!((0.0..1.0).contains(&f));
+        }
+    }
+
+    async fn wait_for(cond: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if cond() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn test_schedule_refresh() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let version = Arc::new(AtomicU64::new(0));
+        let loaded = version.clone();
+        client.schedule_refresh("config", Duration::from_millis(5), move || {
+            let version = loaded.clone();
+            async move { Ok(Some(version.fetch_add(1, Ordering::SeqCst) + 1)) }
+        });
+        assert_eq!(client.scheduled_refreshes(), vec!["config".to_string()]);
+
+        wait_for(|| version.load(Ordering::SeqCst) >= 3).await;
+        let v = rmp_serde::from_slice::<Option<u64>>(&fake.hget("config", "value").unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(v >= 2);
+        assert_eq!(fake.pttl("config"), Some(Duration::from_secs(1)));
+
+        assert!(client.cancel_refresh("config"));
+        assert!(!client.cancel_refresh("config"));
+        assert!(client.scheduled_refreshes().is_empty());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let stopped = version.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(version.load(Ordering::SeqCst), stopped);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_refresh_retries_failures() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            lock_sleep: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let calls = Arc::new(AtomicU64::new(0));
+        let counted = calls.clone();
+        client.schedule_refresh("config", Duration::from_secs(60), move || {
+            let calls = counted.clone();
+            async move {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(new_decode_error(rmp_serde::decode::Error::Uncategorized(
+                        "source down".to_string(),
+                    ))),
+                    _ => Ok(Some("warm".to_string())),
+                }
+            }
+        });
+        wait_for(|| fake.hget("config", "value").is_some()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(fake.hget("config", "lockOwner"), None);
+    }
+
+    #[tokio::test]
+    async fn test_dropping_client_stops_refreshes() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let calls = Arc::new(AtomicU64::new(0));
+        let counted = calls.clone();
+        client.schedule_refresh("config", Duration::from_millis(1), move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            async { Ok(Some(1)) }
+        });
+        wait_for(|| calls.load(Ordering::SeqCst) >= 1).await;
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let stopped = calls.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), stopped);
+    }
+}
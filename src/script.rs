@@ -1,82 +1,574 @@
 use sha1::{Digest, Sha1};
 use std::sync::LazyLock;
 
-pub(crate) struct Script {
-    pub src: &'static str,
-    pub hash: String,
+// Script is one of the lua scripts implementing the cache protocol.
+// Backends identify it by name, and run it by hash or source.
+#[derive(Debug)]
+pub struct Script {
+    name: &'static str,
+    src: &'static str,
+    hash: String,
 }
 
 impl Script {
-    pub fn new(src: &'static str) -> Self {
+    pub fn new(name: &'static str, src: &'static str) -> Self {
         let mut hasher = Sha1::new();
 
         hasher.update(src.as_bytes());
 
         let result = hasher.finalize();
         Self {
+            name,
             src,
             hash: format!("{:x}", result),
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn src(&self) -> &'static str {
+        self.src
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+// server_now! defines server_now(unit), the time of the redis server in units of unit
+// milliseconds, which the lock timestamps are in: 1, or 1000 for the seconds of the Go
+// client. Writes after TIME need the effects replication of redis 5 and later.
+macro_rules! server_now {
+    () => {
+        r#"
+local function server_now(unit)
+    local t = redis.call('TIME')
+    return math.floor((t[1] * 1000 + math.floor(t[2] / 1000)) / unit)
+end"#
+    };
 }
 
 pub(crate) static DELETE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
     Script::new(
+        "delete",
         r#"
 redis.call('HSET', KEYS[1], 'lockUntil', 0)
 redis.call('HDEL', KEYS[1], 'lockOwner')
-redis.call('EXPIRE', KEYS[1], ARGV[1])"#,
+redis.call('PEXPIRE', KEYS[1], ARGV[1])"#,
     )
 });
 
 pub(crate) static GET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
     Script::new(
-        r#"
+        "get",
+        concat!(
+            server_now!(),
+            r#"
+local now = server_now(tonumber(ARGV[3]))
 local v = redis.call('HGET', KEYS[1], 'value')
 local lu = redis.call('HGET', KEYS[1], 'lockUntil')
-if lu ~= false and tonumber(lu) < tonumber(ARGV[1]) or lu == false and v == false then
-    redis.call('HSET', KEYS[1], 'lockUntil', ARGV[2])
-    redis.call('HSET', KEYS[1], 'lockOwner', ARGV[3])
+if lu ~= false and tonumber(lu) < now or lu == false and v == false then
+    redis.call('HSET', KEYS[1], 'lockUntil', string.format('%.0f', now + tonumber(ARGV[1])))
+    redis.call('HSET', KEYS[1], 'lockOwner', ARGV[2])
     return { v, 'LOCKED' }
 end
-return {v, lu}"#,
+return {v, lu, redis.call('PTTL', KEYS[1])}"#
+        ),
+    )
+});
+
+// EXTEND_SCRIPT moves the lockUntil of a lock still owned by ARGV[2] to ARGV[1] after
+// the server time in units of ARGV[3] milliseconds, returning 0 if the lock is lost.
+pub(crate) static EXTEND_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "extend",
+        concat!(
+            server_now!(),
+            r#"
+if redis.call('HGET', KEYS[1], 'lockOwner') ~= ARGV[2] then
+    return 0
+end
+local now = server_now(tonumber(ARGV[3]))
+redis.call('HSET', KEYS[1], 'lockUntil', string.format('%.0f', now + tonumber(ARGV[1])))
+return 1"#
+        ),
     )
 });
 
 pub(crate) static SET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
     Script::new(
+        "set",
         r#"
 local o = redis.call('HGET', KEYS[1], 'lockOwner')
 if o ~= ARGV[2] then
-		return
+		return 0
 end
 redis.call('HSET', KEYS[1], 'value', ARGV[1])
 redis.call('HDEL', KEYS[1], 'lockUntil')
 redis.call('HDEL', KEYS[1], 'lockOwner')
-redis.call('EXPIRE', KEYS[1], ARGV[3])"#,
+redis.call('HDEL', KEYS[1], 'refreshUntil', 'refreshOwner')
+for _, f in ipairs(redis.call('HKEYS', KEYS[1])) do
+    if string.sub(f, 1, 5) == 'meta:' then
+        redis.call('HDEL', KEYS[1], f)
+    end
+end
+for i = 4, #ARGV, 2 do
+    redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('PEXPIRE', KEYS[1], ARGV[3])
+return 1"#,
     )
 });
 
 pub(crate) static UNLOCK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
     Script::new(
+        "unlock",
         r#"
 local lo = redis.call('HGET', KEYS[1], 'lockOwner')
 if lo == ARGV[1] then
 	redis.call('HSET', KEYS[1], 'lockUntil', 0)
 	redis.call('HDEL', KEYS[1], 'lockOwner')
-	redis.call('EXPIRE', KEYS[1], ARGV[2])
+	redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end"#,
+    )
+});
+
+pub(crate) static INSPECT_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "inspect",
+        concat!(
+            server_now!(),
+            r#"
+local lu = redis.call('HGET', KEYS[1], 'lockUntil')
+local lo = redis.call('HGET', KEYS[1], 'lockOwner')
+local locked = 0
+if lu ~= false and tonumber(lu) >= server_now(tonumber(ARGV[1])) then
+    locked = 1
+end
+local meta = {}
+for _, f in ipairs(redis.call('HKEYS', KEYS[1])) do
+    if string.sub(f, 1, 5) == 'meta:' then
+        meta[#meta + 1] = string.sub(f, 6)
+        meta[#meta + 1] = redis.call('HGET', KEYS[1], f)
+    end
+end
+return {redis.call('PTTL', KEYS[1]), lu, lo, redis.call('HEXISTS', KEYS[1], 'value'), locked, meta}"#
+        ),
+    )
+});
+
+pub(crate) static TTL_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "ttl",
+        r#"
+return redis.call('PTTL', KEYS[1])"#,
+    )
+});
+
+pub(crate) static EXISTS_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "exists",
+        r#"
+local v = redis.call('HGET', KEYS[1], 'value')
+if v == false or v == ARGV[1] or redis.call('HEXISTS', KEYS[1], 'lockUntil') == 1 then
+    return 0
+end
+return 1"#,
+    )
+});
+
+pub(crate) static READ_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "read",
+        r#"
+return {
+    redis.call('HGET', KEYS[1], 'value'),
+    redis.call('HGET', KEYS[1], 'lockUntil'),
+    redis.call('PTTL', KEYS[1])
+}"#,
+    )
+});
+
+pub(crate) static COPY_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "copy",
+        r#"
+if ARGV[3] ~= '1' and redis.call('HEXISTS', KEYS[1], 'value') == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'value', ARGV[1])
+redis.call('HDEL', KEYS[1], 'lockUntil', 'lockOwner')
+if tonumber(ARGV[2]) > 0 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+else
+    redis.call('PERSIST', KEYS[1])
+end
+return 1"#,
+    )
+});
+
+pub(crate) static REFRESH_LOCK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "refresh_lock",
+        concat!(
+            server_now!(),
+            r#"
+if redis.call('HEXISTS', KEYS[1], 'lockUntil') == 1 or redis.call('HEXISTS', KEYS[1], 'value') == 0 then
+    return 0
+end
+local now = server_now(tonumber(ARGV[3]))
+local ru = redis.call('HGET', KEYS[1], 'refreshUntil')
+if ru ~= false and tonumber(ru) >= now then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'refreshUntil', string.format('%.0f', now + tonumber(ARGV[1])))
+redis.call('HSET', KEYS[1], 'refreshOwner', ARGV[2])
+return 1"#
+        ),
+    )
+});
+
+pub(crate) static REFRESH_SET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "refresh_set",
+        r#"
+if redis.call('HGET', KEYS[1], 'refreshOwner') ~= ARGV[2] then
+    return 0
+end
+redis.call('HDEL', KEYS[1], 'refreshUntil', 'refreshOwner')
+if redis.call('HEXISTS', KEYS[1], 'lockUntil') == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'value', ARGV[1])
+for _, f in ipairs(redis.call('HKEYS', KEYS[1])) do
+    if string.sub(f, 1, 5) == 'meta:' then
+        redis.call('HDEL', KEYS[1], f)
+    end
+end
+for i = 4, #ARGV, 2 do
+    redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('PEXPIRE', KEYS[1], ARGV[3])
+return 1"#,
+    )
+});
+
+pub(crate) static REFRESH_UNLOCK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "refresh_unlock",
+        r#"
+if redis.call('HGET', KEYS[1], 'refreshOwner') == ARGV[1] then
+    redis.call('HDEL', KEYS[1], 'refreshUntil', 'refreshOwner')
+end"#,
+    )
+});
+
+// WRITE_SCRIPT is SET without the owner check, ARGV[2] is ignored: it overwrites the
+// value whoever holds the lock, releasing it, so the SET of that fetch is a no-op.
+pub(crate) static WRITE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "write",
+        r#"
+redis.call('HSET', KEYS[1], 'value', ARGV[1])
+redis.call('HDEL', KEYS[1], 'lockUntil', 'lockOwner', 'refreshUntil', 'refreshOwner')
+for _, f in ipairs(redis.call('HKEYS', KEYS[1])) do
+    if string.sub(f, 1, 5) == 'meta:' then
+        redis.call('HDEL', KEYS[1], f)
+    end
+end
+for i = 4, #ARGV, 2 do
+    redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('PEXPIRE', KEYS[1], ARGV[3])"#,
+    )
+});
+
+// TAG_SCRIPT adds the key ARGV[1] to the tag index KEYS[1], a sorted set scored by the
+// unix time in milliseconds the membership ends at, now plus ARGV[2]. It prunes the
+// ended memberships and keeps the index alive as long as its last one.
+pub(crate) static TAG_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "tag",
+        concat!(
+            server_now!(),
+            r#"
+local now = server_now(1)
+local until_ms = now + tonumber(ARGV[2])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now)
+local score = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if score == false or tonumber(score) < until_ms then
+    redis.call('ZADD', KEYS[1], string.format('%.0f', until_ms), ARGV[1])
+end
+if redis.call('PTTL', KEYS[1]) < tonumber(ARGV[2]) then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end"#
+        ),
+    )
+});
+
+// TAG_MEMBERS_SCRIPT removes the tag index KEYS[1], returning its current members.
+pub(crate) static TAG_MEMBERS_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "tag_members",
+        concat!(
+            server_now!(),
+            r#"
+local members = redis.call('ZRANGEBYSCORE', KEYS[1], server_now(1), '+inf')
+redis.call('DEL', KEYS[1])
+return members"#
+        ),
+    )
+});
+
+// VERSION_SCRIPT returns the version counter KEYS[1] of a namespace, 0 if unset.
+pub(crate) static VERSION_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "version",
+        r#"
+local v = redis.call('GET', KEYS[1])
+if v == false then
+    return 0
+end
+return tonumber(v)"#,
+    )
+});
+
+pub(crate) static BUMP_VERSION_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "bump_version",
+        r#"
+return redis.call('INCR', KEYS[1])"#,
+    )
+});
+
+// MARK_EMPTY_SCRIPT caches an empty result under its marker KEYS[1] for ARGV[1]
+// milliseconds, see NegativeCachePolicy::Suffix.
+pub(crate) static MARK_EMPTY_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "mark_empty",
+        r#"
+redis.call('SET', KEYS[1], '', 'PX', ARGV[1])"#,
+    )
+});
+
+pub(crate) static MARKED_EMPTY_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "marked_empty",
+        r#"
+return redis.call('EXISTS', KEYS[1])"#,
+    )
+});
+
+pub(crate) static WARM_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "warm",
+        r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'value', ARGV[1])
+for i = 3, #ARGV, 2 do
+    redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return 1"#,
+    )
+});
+
+pub(crate) static CLEAN_LOCK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "clean_lock",
+        concat!(
+            server_now!(),
+            r#"
+if redis.call('TYPE', KEYS[1]).ok ~= 'hash' or redis.call('HEXISTS', KEYS[1], 'value') == 1 then
+    return 0
+end
+local lu = redis.call('HGET', KEYS[1], 'lockUntil')
+if lu == false or tonumber(lu) >= server_now(tonumber(ARGV[2])) - tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('HDEL', KEYS[1], 'lockUntil', 'lockOwner')
+return 1"#
+        ),
+    )
+});
+
+pub(crate) static TOUCH_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "touch",
+        r#"
+local n = 0
+for i = 1, #KEYS do
+    if redis.call('HEXISTS', KEYS[i], 'value') == 1 and redis.call('HEXISTS', KEYS[i], 'lockUntil') == 0
+        and redis.call('PTTL', KEYS[i]) < tonumber(ARGV[i]) then
+        redis.call('PEXPIRE', KEYS[i], ARGV[i])
+        n = n + 1
+    end
+end
+return n"#,
+    )
+});
+
+pub(crate) static PAYLOAD_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "payload",
+        r#"
+local v = redis.call('HGET', KEYS[1], 'value')
+if v == false then
+    return false
+end
+local meta = {}
+for _, f in ipairs(redis.call('HKEYS', KEYS[1])) do
+    if string.sub(f, 1, 5) == 'meta:' then
+        meta[#meta + 1] = string.sub(f, 6)
+        meta[#meta + 1] = redis.call('HGET', KEYS[1], f)
+    end
+end
+return {v, redis.call('HEXISTS', KEYS[1], 'lockUntil'), redis.call('PTTL', KEYS[1]), meta}"#,
+    )
+});
+
+// GET_BATCH_SCRIPT is GET over KEYS, without the ttl left. It is a multi key script,
+// so on a cluster all keys must be in one slot, see Options::cluster.
+pub(crate) static GET_BATCH_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "get_batch",
+        concat!(
+            server_now!(),
+            r#"
+local now = server_now(tonumber(ARGV[3]))
+local lock_until = string.format('%.0f', now + tonumber(ARGV[1]))
+local rets = {}
+for i, key in ipairs(KEYS) do
+    local v = redis.call('HGET', key, 'value')
+    local lu = redis.call('HGET', key, 'lockUntil')
+    if lu ~= false and tonumber(lu) < now or lu == false and v == false then
+        redis.call('HSET', key, 'lockUntil', lock_until)
+        redis.call('HSET', key, 'lockOwner', ARGV[2])
+        rets[i] = { v, 'LOCKED' }
+    else
+        rets[i] = { v, lu }
+    end
+end
+return rets"#
+        ),
+    )
+});
+
+// SET_BATCH_SCRIPT is SET over KEYS, skipping the keys whose lock is not owned by
+// ARGV[1]. ARGV[2..n+1] are the values, ARGV[n+2..2n+1] the expire times in
+// milliseconds and the rest the metadata pairs of every key.
+pub(crate) static SET_BATCH_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "set_batch",
+        r#"
+local n = #KEYS
+for i, key in ipairs(KEYS) do
+    if redis.call('HGET', key, 'lockOwner') == ARGV[1] then
+        redis.call('HSET', key, 'value', ARGV[i + 1])
+        redis.call('HDEL', key, 'lockUntil', 'lockOwner', 'refreshUntil', 'refreshOwner')
+        for _, f in ipairs(redis.call('HKEYS', key)) do
+            if string.sub(f, 1, 5) == 'meta:' then
+                redis.call('HDEL', key, f)
+            end
+        end
+        for j = 2 * n + 2, #ARGV, 2 do
+            redis.call('HSET', key, ARGV[j], ARGV[j + 1])
+        end
+        redis.call('PEXPIRE', key, ARGV[i + 1 + n])
+    end
 end"#,
     )
 });
 
+// RAW_SET_SCRIPT is the RawSet of the Go rockscache client: it writes the value and
+// the expire time in milliseconds, 0 for none, leaving a lock in place.
+pub(crate) static RAW_SET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "raw_set",
+        r#"
+redis.call('HSET', KEYS[1], 'value', ARGV[1])
+if ARGV[2] == '0' then
+    redis.call('PERSIST', KEYS[1])
+else
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end"#,
+    )
+});
+
+// PUBLISH_SCRIPT is PUBLISH, so that it can be pipelined with the other scripts.
+pub(crate) static PUBLISH_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "publish",
+        r#"
+return redis.call('PUBLISH', ARGV[1], ARGV[2])"#,
+    )
+});
+
+// HMGET_SCRIPT is HMGET, for the backends without a read only command path.
+pub(crate) static HMGET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "hmget",
+        r#"
+return redis.call('HMGET', KEYS[1], unpack(ARGV))"#,
+    )
+});
+
+// all_scripts returns every rdcache script.
+pub(crate) fn all_scripts() -> [&'static Script; 29] {
+    [
+        &*DELETE_SCRIPT,
+        &*GET_SCRIPT,
+        &*SET_SCRIPT,
+        &*EXTEND_SCRIPT,
+        &*UNLOCK_SCRIPT,
+        &*INSPECT_SCRIPT,
+        &*TTL_SCRIPT,
+        &*EXISTS_SCRIPT,
+        &*READ_SCRIPT,
+        &*COPY_SCRIPT,
+        &*REFRESH_LOCK_SCRIPT,
+        &*REFRESH_SET_SCRIPT,
+        &*REFRESH_UNLOCK_SCRIPT,
+        &*WRITE_SCRIPT,
+        &*TAG_SCRIPT,
+        &*TAG_MEMBERS_SCRIPT,
+        &*VERSION_SCRIPT,
+        &*BUMP_VERSION_SCRIPT,
+        &*MARK_EMPTY_SCRIPT,
+        &*MARKED_EMPTY_SCRIPT,
+        &*WARM_SCRIPT,
+        &*CLEAN_LOCK_SCRIPT,
+        &*TOUCH_SCRIPT,
+        &*PAYLOAD_SCRIPT,
+        &*GET_BATCH_SCRIPT,
+        &*SET_BATCH_SCRIPT,
+        &*RAW_SET_SCRIPT,
+        &*PUBLISH_SCRIPT,
+        &*HMGET_SCRIPT,
+    ]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_script_new() {
-        let script = Script::new("return 1");
-        assert_eq!(script.hash, "e0e1f9fabfc9d4800c877a703b823ac0578ff8db");
-        assert_eq!(script.src, "return 1");
+        let script = Script::new("one", "return 1");
+        assert_eq!(script.hash(), "e0e1f9fabfc9d4800c877a703b823ac0578ff8db");
+        assert_eq!(script.src(), "return 1");
+        assert_eq!(script.name(), "one");
+    }
+
+    #[test]
+    fn test_all_scripts() {
+        let mut names: Vec<&str> = all_scripts().iter().map(|s| s.name()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), all_scripts().len());
     }
 }
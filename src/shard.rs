@@ -0,0 +1,72 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+};
+
+// SHARDS is the default number of shards, enough for fetches of different keys on
+// 64 and more threads to rarely wait for each other.
+const SHARDS: usize = 16;
+
+// Sharded is per key state split into shards guarded by their own mutex, a key always
+// going to the same shard.
+#[derive(Debug)]
+pub(crate) struct Sharded<T> {
+    shards: Box<[Mutex<T>]>,
+}
+
+impl<T: Default> Sharded<T> {
+    // new creates n shards, rounded up to a power of two.
+    pub(crate) fn new(n: usize) -> Self {
+        let shards = (0..n.max(1).next_power_of_two())
+            .map(|_| Mutex::default())
+            .collect();
+        Self { shards }
+    }
+}
+
+impl<T: Default> Default for Sharded<T> {
+    fn default() -> Self {
+        Self::new(SHARDS)
+    }
+}
+
+impl<T> Sharded<T> {
+    // shard locks the shard of key.
+    pub(crate) fn shard(&self, key: &str) -> MutexGuard<'_, T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let i = hasher.finish() as usize & (self.shards.len() - 1);
+        self.shards[i].lock().unwrap()
+    }
+
+    // iter locks the shards one after the other.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = MutexGuard<'_, T>> {
+        self.shards.iter().map(|shard| shard.lock().unwrap())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_sharded() {
+        let sharded: Sharded<HashSet<String>> = Sharded::new(5);
+        assert_eq!(sharded.len(), 8);
+        for i in 0..100 {
+            let key = format!("k{}", i);
+            sharded.shard(&key).insert(key.clone());
+            assert!(sharded.shard(&key).contains(&key));
+        }
+        let sizes: Vec<_> = sharded.iter().map(|shard| shard.len()).collect();
+        assert_eq!(sizes.iter().sum::<usize>(), 100);
+        assert!(sizes.iter().all(|n| *n > 0));
+        assert_eq!(Sharded::<HashSet<String>>::new(0).len(), 1);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{backend::Reply, Client, Result, Script};
+use futures::future::join_all;
+use std::collections::BTreeMap;
+
+// SLOTS is the number of hash slots of a redis cluster.
+const SLOTS: u16 = 16384;
+
+// key_slot is the hash slot of key on a redis cluster: the crc16 of its hash tag, the
+// part between the first `{` and the next `}` if it is not empty, or of the whole key.
+pub(crate) fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    let tag = key
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|start| {
+            let len = key[start + 1..].iter().position(|&b| b == b'}')?;
+            (len > 0).then(|| &key[start + 1..start + 1 + len])
+        })
+        .unwrap_or(key);
+    crc16(tag) % SLOTS
+}
+
+// crc16 is the CRC16-CCITT (XMODEM) of redis cluster.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+impl Client {
+    // slot_groups groups the indexes of keys by hash slot with Options::cluster, so that
+    // the keys of a group can be passed to one multi key command. Without it all the
+    // keys are one group.
+    pub(crate) fn slot_groups(&self, keys: &[String]) -> Vec<Vec<usize>> {
+        if !self.options().cluster {
+            return vec![(0..keys.len()).collect()];
+        }
+        let mut groups: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            groups.entry(key_slot(key)).or_default().push(i);
+        }
+        groups.into_values().collect()
+    }
+
+    // call_lua_by_slot runs the multi key script once for each group of slot_groups of
+    // keys, concurrently, with the ARGV that args returns for the indexes of the group.
+    // It returns the reply of each group.
+    pub(crate) async fn call_lua_by_slot(
+        &self,
+        script: &Script,
+        keys: &[String],
+        args: impl Fn(&[usize]) -> Vec<Vec<u8>>,
+    ) -> Result<Vec<(Vec<usize>, Reply)>> {
+        let groups = self.slot_groups(keys);
+        let replies = join_all(groups.iter().map(|group| {
+            let group_keys = group.iter().map(|&i| keys[i].clone()).collect();
+            self.call_lua(script, group_keys, args(group))
+        }))
+        .await;
+        groups
+            .into_iter()
+            .zip(replies)
+            .map(|(group, reply)| Ok((group, reply?)))
+            .collect()
+    }
+
+    // del_by_slot deletes keys with one DEL per group of slot_groups.
+    pub(crate) async fn del_by_slot(&self, keys: Vec<String>) -> Result<u64> {
+        let groups = self.slot_groups(&keys);
+        let deleted = join_all(groups.iter().map(|group| {
+            let group_keys: Vec<String> = group.iter().map(|&i| keys[i].clone()).collect();
+            self.with_redis_retry(move || self.backend.del(group_keys.clone()))
+        }))
+        .await;
+        deleted.into_iter().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+        assert_eq!(key_slot("a{}b"), crc16(b"a{}b") % SLOTS);
+        assert_eq!(key_slot("{}{user}"), crc16(b"{}{user}") % SLOTS);
+        assert_eq!(key_slot("{user"), crc16(b"{user") % SLOTS);
+    }
+
+    #[test]
+    fn test_slot_groups() {
+        let keys: Vec<String> = ["{a}1", "b", "{a}2", "c"].map(String::from).to_vec();
+        let client = Client::with_backend(FakeBackend::new(), Options::default());
+        assert_eq!(client.slot_groups(&keys), vec![vec![0, 1, 2, 3]]);
+
+        let options = Options {
+            cluster: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(FakeBackend::new(), options);
+        let mut groups = client.slot_groups(&keys);
+        groups.sort();
+        assert_eq!(groups, vec![vec![0, 2], vec![1], vec![3]]);
+    }
+}
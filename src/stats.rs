@@ -0,0 +1,296 @@
+use crate::{
+    error::new_loader_timeout_error, BackgroundStats, CacheMetrics, CircuitState, Client, Error,
+    Result,
+};
+use futures::future::{self, Either};
+use std::{
+    fmt,
+    future::Future,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+// StatsCounters counts the fetches of a client and the clients detached from it, and
+// reports them to the metrics of Client::with_metrics.
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    load_errors: AtomicU64,
+    redis_errors: AtomicU64,
+    metrics: Option<Arc<dyn CacheMetrics>>,
+}
+
+impl StatsCounters {
+    pub(crate) fn with_metrics(metrics: Arc<dyn CacheMetrics>) -> Self {
+        Self {
+            metrics: Some(metrics),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.on_hit();
+        }
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.on_miss();
+        }
+    }
+
+    // loader counts a loader call that ran for elapsed.
+    pub(crate) fn loader(&self, elapsed: Duration, result: std::result::Result<(), &Error>) {
+        if result.is_err() {
+            self.load_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.on_loader(elapsed, result);
+        }
+    }
+
+    pub(crate) fn lock_wait(&self, waited: Duration) {
+        if let Some(metrics) = &self.metrics {
+            metrics.on_lock_wait(waited);
+        }
+    }
+
+    // compressed reports a value encoded in encoded bytes compressed to compressed bytes.
+    pub(crate) fn compressed(&self, encoded: usize, compressed: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.on_compress(encoded, compressed);
+        }
+    }
+
+    // payload reports a value stored in size bytes.
+    pub(crate) fn payload(&self, size: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.on_payload(size);
+        }
+    }
+
+    pub(crate) fn circuit_state(&self, state: CircuitState) {
+        if let Some(metrics) = &self.metrics {
+            metrics.on_circuit_state(state);
+        }
+    }
+
+    pub(crate) fn redis_error(&self) {
+        self.redis_errors.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.on_redis_error();
+        }
+    }
+}
+
+// CacheStats is a snapshot of the counters and state of a client, counted since it was created.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    // Hits is the number of fetches served from the cache.
+    pub hits: u64,
+    // Misses is the number of fetches that ran the loader.
+    pub misses: u64,
+    // LoadErrors is the number of loader calls that returned an error, refreshes included.
+    pub load_errors: u64,
+    // RedisErrors is the number of redis calls that failed.
+    pub redis_errors: u64,
+    // ReadDisabled and DeleteDisabled mirror Options::disable_cache_read and
+    // Options::disable_cache_delete, true while the cache is downgraded.
+    pub read_disabled: bool,
+    pub delete_disabled: bool,
+    // PendingInvalidations is the number of failed invalidations waiting for a retry.
+    pub pending_invalidations: usize,
+    // Background is the state of the background work.
+    pub background: BackgroundStats,
+}
+
+impl CacheStats {
+    // hit_ratio is hits / (hits + misses), 0 if nothing was fetched.
+    pub fn hit_ratio(&self) -> f64 {
+        let fetches = self.hits + self.misses;
+        if fetches == 0 {
+            0.0
+        } else {
+            self.hits as f64 / fetches as f64
+        }
+    }
+}
+
+// CacheStats formats as a single log line, e.g.
+// `hits=90 misses=10 hit_ratio=0.900 load_errors=0 redis_errors=0 degraded=none pending_invalidations=0 background_running=0 background_queued=0 services=1`
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let degraded = match (self.read_disabled, self.delete_disabled) {
+            (false, false) => "none",
+            (true, false) => "read",
+            (false, true) => "delete",
+            (true, true) => "read,delete",
+        };
+        write!(
+            f,
+            "hits={} misses={} hit_ratio={:.3} load_errors={} redis_errors={} degraded={} pending_invalidations={} background_running={} background_queued={} services={}",
+            self.hits,
+            self.misses,
+            self.hit_ratio(),
+            self.load_errors,
+            self.redis_errors,
+            degraded,
+            self.pending_invalidations,
+            self.background.running,
+            self.background.queued,
+            self.background.services,
+        )
+    }
+}
+
+impl Client {
+    // load awaits the future of a loader, counting it in the stats. It gives up after
+    // Options::loader_timeout with Error::LoaderTimeout of key, the first key of a batch.
+    // It first waits for the permits of Options::max_concurrent_loads, the wait is not
+    // part of the timeout.
+    pub(crate) async fn load<T>(
+        &self,
+        key: &str,
+        load: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let unprefixed = key
+            .strip_prefix(self.options().common_prefix.as_str())
+            .unwrap_or(key);
+        let _permits = self.load_limits.acquire(unprefixed).await;
+        let started = self.executor.now();
+        let timeout = self.options().loader_timeout;
+        let result = if timeout.is_zero() {
+            load.await
+        } else {
+            match future::select(pin!(load), self.executor.sleep(timeout)).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(new_loader_timeout_error(key)),
+            }
+        };
+        let elapsed = self.executor.now().duration_since(started);
+        self.stats.loader(elapsed, result.as_ref().map(|_| ()));
+        result
+    }
+
+    // stats returns the counters and state of the client.
+    pub fn stats(&self) -> CacheStats {
+        let counters = &self.stats;
+        CacheStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            load_errors: counters.load_errors.load(Ordering::Relaxed),
+            redis_errors: counters.redis_errors.load(Ordering::Relaxed),
+            read_disabled: self.options().disable_cache_read,
+            delete_disabled: self.options().disable_cache_delete,
+            pending_invalidations: self.pending_invalidations(),
+            background: self.background_stats(),
+        }
+    }
+
+    // start_stats_report calls report with the stats every interval, e.g. to log them
+    // where there is no metrics backend, until stop_stats_report is called or the client
+    // is dropped. It replaces a running report, and is not started after shutdown.
+    // It must be called within a tokio runtime.
+    pub fn start_stats_report(
+        &self,
+        interval: Duration,
+        report: impl Fn(&CacheStats) + Send + Sync + 'static,
+    ) {
+        let mut client = self.detach();
+        client.write_retry = self.write_retry.clone();
+        let task = self.executor.spawn_service(async move {
+            loop {
+                client.executor.sleep(interval).await;
+                report(&client.stats());
+            }
+        });
+        if let Some(task) = task {
+            self.stats_report.set(task);
+        }
+    }
+
+    // stop_stats_report stops the stats report, returning false if none was running.
+    pub fn stop_stats_report(&self) -> bool {
+        self.stats_report.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_stats() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        for _ in 0..3 {
+            client
+                .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+                .await
+                .unwrap();
+        }
+        fake.fail_next(1);
+        assert!(client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .is_err());
+        let failed: crate::Result<Option<u64>> = client
+            .fetch("other", Duration::from_secs(600), || async {
+                Err(crate::error::new_decode_error(
+                    rmp_serde::decode::Error::OutOfRange,
+                ))
+            })
+            .await;
+        assert!(failed.is_err());
+
+        let stats = client.stats();
+        assert_eq!(
+            (
+                stats.hits,
+                stats.misses,
+                stats.load_errors,
+                stats.redis_errors
+            ),
+            (2, 2, 1, 1)
+        );
+        assert_eq!(stats.hit_ratio(), 0.5);
+        assert!(stats.to_string().starts_with(
+            "hits=2 misses=2 hit_ratio=0.500 load_errors=1 redis_errors=1 degraded=none"
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stats_report() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake, Options::default());
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reported = reports.clone();
+        client.start_stats_report(Duration::from_secs(10), move |stats| {
+            reported.lock().unwrap().push(stats.hits)
+        });
+        client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        client
+            .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(*reports.lock().unwrap(), vec![1, 1]);
+
+        assert!(client.stop_stats_report());
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(reports.lock().unwrap().len(), 2);
+    }
+}
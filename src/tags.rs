@@ -0,0 +1,127 @@
+use crate::{
+    backend::Args,
+    script::{TAG_MEMBERS_SCRIPT, TAG_SCRIPT},
+    Client, Result,
+};
+use futures::future::join_all;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, future::Future, time::Duration};
+
+// TAG_INDEX_PREFIX prefixes the tag indexes, before Options::common_prefix and the tag.
+// They are sorted sets of keys, skipped by flush_namespace.
+pub(crate) const TAG_INDEX_PREFIX: &str = "rdcache:tag:";
+
+impl Client {
+    // fetch_tagged is fetch, also recording key as a member of each of tags, so that
+    // invalidate_tag of any of them tag deletes it. The membership is recorded before
+    // the value is loaded, so an invalidate_tag racing the fetch is not missed, and it
+    // ends with the value, after expire plus Options::lock_expire.
+    pub async fn fetch_tagged<F, Fut, V>(
+        &self,
+        key: impl AsRef<str>,
+        tags: &[impl AsRef<str>],
+        expire: Duration,
+        f: F,
+    ) -> Result<Option<V>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>>>,
+        V: DeserializeOwned + Serialize + Debug,
+    {
+        let key = key.as_ref();
+        let member = self.prefixed_key(key);
+        let span = (expire + self.options().lock_expire).as_millis();
+        let tagged = join_all(tags.iter().map(|tag| {
+            self.call_lua(
+                &TAG_SCRIPT,
+                vec![self.tag_index(tag.as_ref())],
+                Args::default().arg(member.as_str()).arg(span).build(),
+            )
+        }))
+        .await;
+        for result in tagged {
+            result?;
+        }
+        self.fetch(key, expire, f).await
+    }
+
+    // invalidate_tag tag deletes every key fetched with tag by fetch_tagged and still
+    // cached, like tag_as_deleted, and removes the index of tag. It returns the number
+    // of keys invalidated, and does nothing with Options::disable_cache_delete.
+    pub async fn invalidate_tag(&self, tag: &str) -> Result<u64> {
+        if self.options().disable_cache_delete {
+            return Ok(0);
+        }
+        let members = self
+            .call_lua(&TAG_MEMBERS_SCRIPT, vec![self.tag_index(tag)], Vec::new())
+            .await?
+            .into_array()?;
+        let mut keys = Vec::with_capacity(members.len());
+        for member in members {
+            if let Some(key) = member.into_string()? {
+                self.journal_begin(&key)?;
+                keys.push(key);
+            }
+        }
+        let invalidated = join_all(keys.into_iter().map(|key| self.invalidate(key))).await;
+        let mut count = 0;
+        for result in invalidated {
+            result?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn tag_index(&self, tag: &str) -> String {
+        format!(
+            "{}{}{}",
+            TAG_INDEX_PREFIX,
+            self.options().common_prefix,
+            tag
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::ManualClock, test_util::FakeBackend, Options};
+
+    #[tokio::test]
+    async fn test_invalidate_tag() {
+        let clock = ManualClock::default();
+        let fake = FakeBackend::with_clock(clock.clone());
+        let options = Options {
+            common_prefix: "app:".to_string(),
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let fetch = |key: &'static str, tags: &'static [&'static str], expire, v| {
+            let client = &client;
+            async move {
+                client
+                    .fetch_tagged(key, tags, expire, || async move { Ok(Some(v)) })
+                    .await
+                    .unwrap()
+            }
+        };
+        let long = Duration::from_secs(600);
+        fetch("post:1", &["user:42", "feed"], long, 1).await;
+        fetch("post:2", &["user:42"], long, 2).await;
+        fetch("post:3", &["user:7"], long, 3).await;
+        fetch("short", &["user:42"], Duration::from_secs(20), 4).await;
+        assert!(fake.pttl("rdcache:tag:app:user:42").unwrap() >= long);
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(client.invalidate_tag("user:42").await.unwrap(), 2);
+        assert!(fake.keys().iter().all(|k| k != "rdcache:tag:app:user:42"));
+        assert_eq!(fetch("post:1", &[], long, 10).await, Some(10));
+        assert_eq!(fetch("post:2", &[], long, 20).await, Some(20));
+        assert_eq!(fetch("post:3", &[], long, 30).await, Some(3));
+        assert_eq!(client.invalidate_tag("user:42").await.unwrap(), 0);
+        assert_eq!(client.invalidate_tag("feed").await.unwrap(), 1);
+
+        assert_eq!(client.flush_namespace("").await.unwrap(), 3);
+        assert!(fake.keys().contains(&"rdcache:tag:app:user:7".to_string()));
+    }
+}
@@ -0,0 +1,1017 @@
+use crate::{
+    backend::{BoxFuture, CacheBackend, Reply},
+    clock::{unix_millis, ManualClock},
+    error::new_backend_error,
+    pool,
+    slot::key_slot,
+    Client, Options, Result, Script,
+};
+use futures::{channel::mpsc, stream::BoxStream, StreamExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+mod go;
+mod loader;
+mod protocol;
+mod skew;
+
+pub use go::GoClient;
+pub use loader::{CountingLoader, RecordingLoader};
+pub use protocol::{ProtocolCheck, ProtocolReport};
+pub use skew::SkewCheck;
+
+#[derive(Debug, Default)]
+struct Entry {
+    fields: HashMap<String, Vec<u8>>,
+    // ExpireAt is the unix time in milliseconds the entry expires at.
+    expire_at: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    // Clock is the server time, used for the key expiration.
+    clock: ManualClock,
+    // Failures is the number of upcoming script calls to fail.
+    failures: usize,
+    // Latency is how long the script replies take to arrive.
+    latency: Duration,
+    // Calls counts the script calls by script name.
+    calls: HashMap<&'static str, usize>,
+    // Subscribers are the subscriptions by channel.
+    subscribers: HashMap<String, Vec<mpsc::UnboundedSender<String>>>,
+    // Cluster rejects the multi key calls over several hash slots.
+    cluster: bool,
+}
+
+// FakeBackend is an in-memory CacheBackend implementing the rdcache scripts in rust,
+// with the same hash layout, lock and ttl semantics as the lua versions, which
+// test_scripts_differential checks against redis.
+// It is cheap to clone, clones share the same data so a test can keep one to
+// control time and look at the stored fields while a Client owns another.
+#[derive(Debug, Clone, Default)]
+pub struct FakeBackend {
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with_clock creates a backend whose server time is clock, so a test can share
+    // it with the Client to move both forward together.
+    pub fn with_clock(clock: ManualClock) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                clock,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // cluster creates a backend rejecting the multi key calls over several hash slots
+    // with CROSSSLOT, like a redis cluster.
+    pub fn cluster() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                cluster: true,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // advance moves the server clock forward, expiring keys whose ttl has passed.
+    pub fn advance(&self, d: Duration) {
+        self.state.lock().unwrap().clock.advance(d);
+    }
+
+    // now returns the server time as unix milliseconds.
+    pub fn now(&self) -> u64 {
+        self.state.lock().unwrap().now()
+    }
+
+    // fail_next makes the next n script calls fail with a redis error, to simulate an outage.
+    pub fn fail_next(&self, n: usize) {
+        self.state.lock().unwrap().failures = n;
+    }
+
+    // set_latency delays the script replies by d, the scripts still run when called.
+    // It needs a tokio runtime.
+    pub fn set_latency(&self, d: Duration) {
+        self.state.lock().unwrap().latency = d;
+    }
+
+    // calls returns the number of calls of the script named name.
+    pub fn calls(&self, name: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.calls.get(name).copied().unwrap_or_default()
+    }
+
+    // hget returns a field of the hash stored at key.
+    pub fn hget(&self, key: &str, field: &str) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.entry(key)?.fields.get(field).cloned()
+    }
+
+    // hgetall returns every field of the hash stored at key, sorted by name.
+    pub fn hgetall(&self, key: &str) -> BTreeMap<String, Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        match state.entry(key) {
+            Some(entry) => entry.fields.clone().into_iter().collect(),
+            None => BTreeMap::new(),
+        }
+    }
+
+    // hset writes a field of the hash stored at key, creating the key without ttl.
+    pub fn hset(&self, key: &str, field: &str, value: impl Into<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        state.entry_mut(key).insert(field.to_string(), value.into());
+    }
+
+    // pttl returns the remaining ttl of key, None if it doesn't exist or has no expire.
+    pub fn pttl(&self, key: &str) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        match state.pttl(key) {
+            ms if ms >= 0 => Some(Duration::from_millis(ms as u64)),
+            _ => None,
+        }
+    }
+
+    // keys returns every live key, sorted.
+    pub fn keys(&self) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let now = state.now();
+        state.purge(now);
+        let mut keys: Vec<_> = state.entries.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+}
+
+impl State {
+    fn now(&self) -> u64 {
+        unix_millis(&self.clock)
+    }
+
+    // check_slots fails like a redis cluster if keys are in several hash slots.
+    fn check_slots(&self, keys: &[String]) -> Result<()> {
+        let mut slots = keys.iter().map(|key| key_slot(key));
+        let first = slots.next();
+        if self.cluster && slots.any(|slot| Some(slot) != first) {
+            return Err(new_backend_error(
+                "CROSSSLOT Keys in request don't hash to the same slot",
+            ));
+        }
+        Ok(())
+    }
+
+    fn purge(&mut self, now: u64) {
+        self.entries
+            .retain(|_, e| e.expire_at.is_none_or(|at| at > now));
+    }
+
+    fn entry(&mut self, key: &str) -> Option<&mut Entry> {
+        let now = self.now();
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|e| e.expire_at.is_some_and(|at| at <= now))
+        {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn entry_mut(&mut self, key: &str) -> &mut HashMap<String, Vec<u8>> {
+        self.entry(key);
+        &mut self.entries.entry(key.to_string()).or_default().fields
+    }
+
+    fn hget(&mut self, key: &str, field: &str) -> Option<Vec<u8>> {
+        self.entry(key)?.fields.get(field).cloned()
+    }
+
+    fn hdel(&mut self, key: &str, field: &str) {
+        if let Some(entry) = self.entry(key) {
+            entry.fields.remove(field);
+            if entry.fields.is_empty() {
+                self.entries.remove(key);
+            }
+        }
+    }
+
+    fn pexpire(&mut self, key: &str, ms: i64) {
+        let now = self.now();
+        if ms <= 0 {
+            self.entries.remove(key);
+        } else if let Some(entry) = self.entry(key) {
+            entry.expire_at = Some(now + ms as u64);
+        }
+    }
+
+    fn pttl(&mut self, key: &str) -> i64 {
+        let now = self.now();
+        match self.entry(key) {
+            None => -2,
+            Some(Entry {
+                expire_at: None, ..
+            }) => -1,
+            Some(Entry {
+                expire_at: Some(at),
+                ..
+            }) => (*at - now) as i64,
+        }
+    }
+
+    // set_value writes ARGV value, expire millis and metadata pairs the way SET does.
+    fn set_value(&mut self, key: &str, args: &[Vec<u8>]) {
+        let fields = self.entry_mut(key);
+        fields.insert("value".to_string(), args[0].clone());
+        fields.retain(|f, _| !f.starts_with("meta:"));
+        for pair in args[3.min(args.len())..].chunks_exact(2) {
+            let name = String::from_utf8_lossy(&pair[0]).into_owned();
+            fields.insert(name, pair[1].clone());
+        }
+        self.pexpire(key, parse_num(&args[2]));
+    }
+
+    // server_now is the server time in units of unit milliseconds, like the server_now
+    // of the scripts.
+    fn server_now(&self, unit: i64) -> i64 {
+        self.now() as i64 / unit.max(1)
+    }
+
+    // lock takes the lock of key for owner until lock_until if it is free at now, the
+    // way GET does.
+    fn lock(&mut self, key: &str, now: i64, lock_until: i64, owner: Vec<u8>) -> Result<Reply> {
+        let v = self.hget(key, "value");
+        let lu = self.hget(key, "lockUntil");
+        let expired = lu.as_ref().is_some_and(|lu| parse_num(lu) < now);
+        if expired || (lu.is_none() && v.is_none()) {
+            let fields = self.entry_mut(key);
+            fields.insert("lockUntil".to_string(), lock_until.to_string().into());
+            fields.insert("lockOwner".to_string(), owner);
+            return Ok(Reply::Array(vec![bulk_or_nil(v), bulk(b"LOCKED")]));
+        }
+        let pttl = self.pttl(key);
+        Ok(Reply::Array(vec![
+            bulk_or_nil(v),
+            bulk_or_nil(lu),
+            Reply::Int(pttl),
+        ]))
+    }
+
+    // meta returns the metadata fields of key as name, value pairs without the prefix.
+    fn meta(&mut self, key: &str) -> Reply {
+        let mut meta = Vec::new();
+        if let Some(entry) = self.entry(key) {
+            let mut names: Vec<_> = entry
+                .fields
+                .keys()
+                .filter(|f| f.starts_with("meta:"))
+                .collect();
+            names.sort();
+            for name in names {
+                meta.push(bulk(&name.as_bytes()[5..]));
+                meta.push(bulk(&entry.fields[name]));
+            }
+        }
+        Reply::Array(meta)
+    }
+
+    fn eval(&mut self, script: &str, key: &str, args: &[Vec<u8>]) -> Result<Reply> {
+        let arg = |i: usize| args.get(i).cloned().unwrap_or_default();
+        let num = |i: usize| parse_num(&arg(i));
+        match script {
+            "delete" => {
+                self.entry_mut(key)
+                    .insert("lockUntil".to_string(), b"0".to_vec());
+                self.hdel(key, "lockOwner");
+                self.pexpire(key, num(0));
+                Ok(Reply::Nil)
+            }
+            "get" => {
+                let now = self.server_now(num(2));
+                self.lock(key, now, now + num(0), arg(1))
+            }
+            "extend" => {
+                if self.hget(key, "lockOwner") != Some(arg(1)) {
+                    return Ok(Reply::Int(0));
+                }
+                let lock_until = self.server_now(num(2)) + num(0);
+                self.entry_mut(key)
+                    .insert("lockUntil".to_string(), lock_until.to_string().into());
+                Ok(Reply::Int(1))
+            }
+            "set" => {
+                if self.hget(key, "lockOwner") != Some(arg(1)) {
+                    return Ok(Reply::Int(0));
+                }
+                let fields = self.entry_mut(key);
+                fields.remove("lockUntil");
+                fields.remove("lockOwner");
+                fields.remove("refreshUntil");
+                fields.remove("refreshOwner");
+                self.set_value(key, args);
+                Ok(Reply::Int(1))
+            }
+            "write" => {
+                let fields = self.entry_mut(key);
+                fields.remove("lockUntil");
+                fields.remove("lockOwner");
+                fields.remove("refreshUntil");
+                fields.remove("refreshOwner");
+                self.set_value(key, args);
+                Ok(Reply::Nil)
+            }
+            // The scripts of the Go rockscache client, see GoClient. Its GET doesn't
+            // return the ttl, and its SET leaves the refresh and meta fields alone.
+            "go_get" => {
+                let mut reply = self.lock(key, num(0), num(1), arg(2))?.into_array()?;
+                reply.truncate(2);
+                Ok(Reply::Array(reply))
+            }
+            "go_set" => {
+                if self.hget(key, "lockOwner") != Some(arg(1)) {
+                    return Ok(Reply::Nil);
+                }
+                let fields = self.entry_mut(key);
+                fields.insert("value".to_string(), arg(0));
+                fields.remove("lockUntil");
+                fields.remove("lockOwner");
+                self.pexpire(key, num(2) * 1000);
+                Ok(Reply::Nil)
+            }
+            // the Go scripts expire in seconds.
+            "go_delete" => self.eval("delete", key, &[(num(0) * 1000).to_string().into()]),
+            "go_unlock" => self.eval("unlock", key, &[arg(0), (num(1) * 1000).to_string().into()]),
+            "unlock" => {
+                if self.hget(key, "lockOwner") == Some(arg(0)) {
+                    self.entry_mut(key)
+                        .insert("lockUntil".to_string(), b"0".to_vec());
+                    self.hdel(key, "lockOwner");
+                    self.pexpire(key, num(1));
+                }
+                Ok(Reply::Nil)
+            }
+            "ttl" => Ok(Reply::Int(self.pttl(key))),
+            "inspect" => {
+                let lu = self.hget(key, "lockUntil");
+                let lo = self.hget(key, "lockOwner");
+                let now = self.server_now(num(0));
+                let locked = lu.as_ref().is_some_and(|lu| parse_num(lu) >= now);
+                let has_value = self.hget(key, "value").is_some();
+                Ok(Reply::Array(vec![
+                    Reply::Int(self.pttl(key)),
+                    bulk_or_nil(lu),
+                    bulk_or_nil(lo),
+                    Reply::Int(has_value as i64),
+                    Reply::Int(locked as i64),
+                    self.meta(key),
+                ]))
+            }
+            "raw_set" => {
+                self.entry_mut(key).insert("value".to_string(), arg(0));
+                if num(1) > 0 {
+                    self.pexpire(key, num(1));
+                } else if let Some(entry) = self.entry(key) {
+                    entry.expire_at = None;
+                }
+                Ok(Reply::Nil)
+            }
+            "publish" => {
+                let channel = String::from_utf8_lossy(&arg(0)).into_owned();
+                let message = String::from_utf8_lossy(&arg(1)).into_owned();
+                let subscribers = self.subscribers.entry(channel).or_default();
+                subscribers.retain(|tx| tx.unbounded_send(message.clone()).is_ok());
+                Ok(Reply::Int(subscribers.len() as i64))
+            }
+            "hmget" => Ok(Reply::Array(
+                args.iter()
+                    .map(|f| bulk_or_nil(self.hget(key, &String::from_utf8_lossy(f))))
+                    .collect(),
+            )),
+            "payload" => {
+                let Some(v) = self.hget(key, "value") else {
+                    return Ok(Reply::Nil);
+                };
+                let stale = self.hget(key, "lockUntil").is_some();
+                Ok(Reply::Array(vec![
+                    bulk(&v),
+                    Reply::Int(stale as i64),
+                    Reply::Int(self.pttl(key)),
+                    self.meta(key),
+                ]))
+            }
+            "exists" => {
+                let v = self.hget(key, "value");
+                let locked = self.hget(key, "lockUntil").is_some();
+                let exists = v.is_some_and(|v| v != arg(0)) && !locked;
+                Ok(Reply::Int(exists as i64))
+            }
+            "read" => Ok(Reply::Array(vec![
+                bulk_or_nil(self.hget(key, "value")),
+                bulk_or_nil(self.hget(key, "lockUntil")),
+                Reply::Int(self.pttl(key)),
+            ])),
+            "copy" => {
+                if arg(2) != b"1" && self.hget(key, "value").is_some() {
+                    return Ok(Reply::Int(0));
+                }
+                let fields = self.entry_mut(key);
+                fields.insert("value".to_string(), arg(0));
+                fields.remove("lockUntil");
+                fields.remove("lockOwner");
+                if num(1) > 0 {
+                    self.pexpire(key, num(1));
+                } else if let Some(entry) = self.entry(key) {
+                    entry.expire_at = None;
+                }
+                Ok(Reply::Int(1))
+            }
+            "refresh_lock" => {
+                let fields = self.entry(key).map(|e| &e.fields);
+                if fields.is_none_or(|f| f.contains_key("lockUntil") || !f.contains_key("value")) {
+                    return Ok(Reply::Int(0));
+                }
+                let now = self.server_now(num(2));
+                let ru = self.hget(key, "refreshUntil");
+                if ru.is_some_and(|ru| parse_num(&ru) >= now) {
+                    return Ok(Reply::Int(0));
+                }
+                let fields = self.entry_mut(key);
+                fields.insert(
+                    "refreshUntil".to_string(),
+                    (now + num(0)).to_string().into(),
+                );
+                fields.insert("refreshOwner".to_string(), arg(1));
+                Ok(Reply::Int(1))
+            }
+            "refresh_set" => {
+                if self.hget(key, "refreshOwner") != Some(arg(1)) {
+                    return Ok(Reply::Int(0));
+                }
+                self.hdel(key, "refreshUntil");
+                self.hdel(key, "refreshOwner");
+                if self.hget(key, "lockUntil").is_some() {
+                    return Ok(Reply::Int(0));
+                }
+                self.set_value(key, args);
+                Ok(Reply::Int(1))
+            }
+            "refresh_unlock" => {
+                if self.hget(key, "refreshOwner") == Some(arg(0)) {
+                    self.hdel(key, "refreshUntil");
+                    self.hdel(key, "refreshOwner");
+                }
+                Ok(Reply::Nil)
+            }
+            "warm" => {
+                if self.entry(key).is_some() {
+                    return Ok(Reply::Int(0));
+                }
+                let fields = self.entry_mut(key);
+                fields.insert("value".to_string(), arg(0));
+                for pair in args[2.min(args.len())..].chunks_exact(2) {
+                    let name = String::from_utf8_lossy(&pair[0]).into_owned();
+                    fields.insert(name, pair[1].clone());
+                }
+                self.pexpire(key, num(1));
+                Ok(Reply::Int(1))
+            }
+            "clean_lock" => {
+                let expired_before = self.server_now(num(1)) - num(0);
+                let lu = self.hget(key, "lockUntil");
+                if self.hget(key, "value").is_some()
+                    || lu.is_none_or(|lu| parse_num(&lu) >= expired_before)
+                {
+                    return Ok(Reply::Int(0));
+                }
+                self.hdel(key, "lockUntil");
+                self.hdel(key, "lockOwner");
+                Ok(Reply::Int(1))
+            }
+            // empty result markers are a hash with a "marker" field here, not strings.
+            "mark_empty" => {
+                self.entry_mut(key).insert("marker".to_string(), Vec::new());
+                self.pexpire(key, num(0));
+                Ok(Reply::Nil)
+            }
+            "marked_empty" => Ok(Reply::Int(self.entry(key).is_some() as i64)),
+            // version counters are the "counter" field of a hash here, not strings.
+            "version" => Ok(Reply::Int(
+                self.hget(key, "counter").map_or(0, |v| parse_num(&v)),
+            )),
+            "bump_version" => {
+                let counter = self.hget(key, "counter").map_or(0, |v| parse_num(&v)) + 1;
+                let fields = self.entry_mut(key);
+                fields.insert("counter".to_string(), counter.to_string().into_bytes());
+                Ok(Reply::Int(counter))
+            }
+            // tag indexes are hashes of member to score here, not sorted sets.
+            "tag" => {
+                let now = self.server_now(1);
+                let until = now + num(1);
+                let fields = self.entry_mut(key);
+                fields.retain(|_, score| parse_num(score) > now);
+                let score = fields.entry(String::from_utf8_lossy(&arg(0)).into_owned());
+                let score = score.or_default();
+                if parse_num(score) < until {
+                    *score = until.to_string().into_bytes();
+                }
+                if self.pttl(key) < num(1) {
+                    self.pexpire(key, num(1));
+                }
+                Ok(Reply::Nil)
+            }
+            "tag_members" => {
+                let now = self.server_now(1);
+                self.entry(key);
+                let Some(entry) = self.entries.remove(key) else {
+                    return Ok(Reply::Array(Vec::new()));
+                };
+                let mut members: Vec<_> = entry
+                    .fields
+                    .into_iter()
+                    .filter(|(_, score)| parse_num(score) >= now)
+                    .map(|(member, _)| member)
+                    .collect();
+                members.sort();
+                let members = members.into_iter().map(|m| Reply::Bulk(m.into_bytes()));
+                Ok(Reply::Array(members.collect()))
+            }
+            "touch" => {
+                let fields = self.entry(key).map(|e| &e.fields);
+                let valued =
+                    fields.is_some_and(|f| f.contains_key("value") && !f.contains_key("lockUntil"));
+                if !valued || self.pttl(key) >= num(0) {
+                    return Ok(Reply::Int(0));
+                }
+                self.pexpire(key, num(0));
+                Ok(Reply::Int(1))
+            }
+            name => Err(new_backend_error(format!(
+                "FakeBackend doesn't implement script {}",
+                name
+            ))),
+        }
+    }
+}
+
+impl Client {
+    // in_memory creates a client on a new FakeBackend, for unit tests of code using
+    // rdcache without a redis server.
+    pub fn in_memory(options: Options) -> Self {
+        Client::with_backend(FakeBackend::new(), options)
+    }
+
+    // in_memory_with_clock creates a client on a new FakeBackend, both reading the
+    // time from clock, so a test keeping a clone of it can move the ttls, the delay of
+    // tag_as_deleted and the lock expiry forward without sleeping.
+    pub fn in_memory_with_clock(options: Options, clock: ManualClock) -> Self {
+        Client::with_backend(FakeBackend::with_clock(clock.clone()), options).with_clock(clock)
+    }
+}
+
+impl CacheBackend for FakeBackend {
+    fn eval<'a>(
+        &'a self,
+        script: &'a Script,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+    ) -> BoxFuture<'a, Result<Reply>> {
+        let (result, latency) = {
+            let mut state = self.state.lock().unwrap();
+            *state.calls.entry(script.name()).or_default() += 1;
+            let key = keys.first().map(String::as_str).unwrap_or_default();
+            let result = if state.failures > 0 {
+                state.failures -= 1;
+                Err(new_backend_error("FakeBackend injected failure"))
+            } else if let Err(e) = state.check_slots(&keys) {
+                Err(e)
+            } else if script.name() == "touch" {
+                keys.iter()
+                    .zip(&args)
+                    .map(|(key, ms)| state.eval("touch", key, std::slice::from_ref(ms))?.as_int())
+                    .sum::<Result<i64>>()
+                    .map(Reply::Int)
+            } else if script.name() == "get_batch" {
+                keys.iter()
+                    .map(|key| {
+                        let mut reply = state.eval("get", key, &args)?.into_array()?;
+                        reply.truncate(2);
+                        Ok(Reply::Array(reply))
+                    })
+                    .collect::<Result<Vec<_>>>()
+                    .map(Reply::Array)
+            } else if script.name() == "set_batch" {
+                let n = keys.len();
+                let meta = args.get(2 * n + 1..).unwrap_or_default();
+                keys.iter()
+                    .enumerate()
+                    .try_for_each(|(i, key)| {
+                        let mut set = vec![args[i + 1].clone(), args[0].clone()];
+                        set.push(args[i + 1 + n].clone());
+                        set.extend_from_slice(meta);
+                        state.eval("set", key, &set).map(drop)
+                    })
+                    .map(|()| Reply::Nil)
+            } else {
+                state.eval(script.name(), key, &args)
+            };
+            (result, state.latency)
+        };
+        // like RustisBackend, so that the benchmarks see the same allocations
+        pool::recycle(args);
+        Box::pin(async move {
+            if !latency.is_zero() {
+                tokio::time::sleep(latency).await;
+            }
+            result
+        })
+    }
+
+    fn subscribe<'a>(
+        &'a self,
+        channel: &'a str,
+    ) -> BoxFuture<'a, Result<BoxStream<'static, String>>> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut state = self.state.lock().unwrap();
+        state
+            .subscribers
+            .entry(channel.to_string())
+            .or_default()
+            .push(tx);
+        Box::pin(async move { Ok(rx.boxed()) })
+    }
+
+    fn del(&self, keys: Vec<String>) -> BoxFuture<'_, Result<u64>> {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = state.check_slots(&keys) {
+            return Box::pin(async move { Err(e) });
+        }
+        let mut n = 0;
+        for key in keys {
+            if state.entry(&key).is_some() {
+                state.entries.remove(&key);
+                n += 1;
+            }
+        }
+        Box::pin(async move { Ok(n) })
+    }
+
+    fn scan<'a>(
+        &'a self,
+        _cursor: u64,
+        pattern: &'a str,
+        _count: usize,
+    ) -> BoxFuture<'a, Result<(u64, Vec<String>)>> {
+        let keys = self
+            .keys()
+            .into_iter()
+            .filter(|k| glob_match(pattern.as_bytes(), k.as_bytes()))
+            .collect();
+        Box::pin(async move { Ok((0, keys)) })
+    }
+}
+
+fn parse_num(b: &[u8]) -> i64 {
+    std::str::from_utf8(b)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .unwrap_or_default() as i64
+}
+
+fn bulk(b: &[u8]) -> Reply {
+    Reply::Bulk(b.to_vec())
+}
+
+fn bulk_or_nil(b: Option<Vec<u8>>) -> Reply {
+    b.map(Reply::Bulk).unwrap_or(Reply::Nil)
+}
+
+// glob_match supports the `*`, `?` and `\` escapes of the redis MATCH patterns.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            s.first() == Some(&rest[0]) && glob_match(&rest[1..], &s[1..])
+        }
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"u?er", b"user"));
+    }
+
+    #[tokio::test]
+    async fn test_fake_fetch_and_tag_as_deleted() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+
+        let v = client
+            .fetch("k", expire, || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+        let v = client
+            .fetch("k", expire, || async { Ok(Some(2)) })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+        assert!(fake.pttl("k").unwrap() <= Duration::from_secs(600));
+
+        client.tag_as_deleted("k").await.unwrap();
+        assert_eq!(fake.hget("k", "lockUntil").unwrap(), b"0");
+        let v = client
+            .fetch("k", expire, || async { Ok(Some(3)) })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_fake_expire() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        client.tag_as_deleted("k").await.unwrap();
+        assert!(client.inspect("k").await.unwrap().exists);
+        fake.advance(Duration::from_secs(11));
+        assert!(!client.inspect("k").await.unwrap().exists);
+        assert!(fake.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_with_clock() {
+        let clock = ManualClock::default();
+        let client = Client::in_memory_with_clock(
+            Options {
+                random_expire_adjustment: 0.0,
+                ..Default::default()
+            },
+            clock.clone(),
+        );
+        let expire = Duration::from_secs(600);
+        let v = client.fetch("k", expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+        clock.advance(Duration::from_secs(589));
+        let v = client.fetch("k", expire, || async { Ok(Some(2)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+        clock.advance(Duration::from_secs(1));
+        let v = client.fetch("k", expire, || async { Ok(Some(3)) }).await;
+        assert_eq!(v.unwrap(), Some(3));
+        assert_eq!(
+            client.ttl("k").await.unwrap(),
+            Some(Duration::from_secs(590))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fake_loader_error_unlocks() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let r = client
+            .fetch::<_, _, String>("k", Duration::from_secs(600), || async {
+                Err(crate::Error::UnexpectedReply(Reply::Nil))
+            })
+            .await;
+        assert!(r.is_err());
+        let info = client.inspect("k").await.unwrap();
+        assert!(!info.locked);
+        assert_eq!(info.lock_until, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_fake_migrate_namespace() {
+        let fake = FakeBackend::new();
+        let old = Client::with_backend(
+            fake.clone(),
+            Options {
+                common_prefix: "old:".to_string(),
+                ..Default::default()
+            },
+        );
+        old.fetch("a", Duration::from_secs(600), || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        old.tag_as_deleted("b").await.unwrap();
+        let report = old
+            .migrate_namespace("old:", "new:", Default::default())
+            .await
+            .unwrap();
+        assert_eq!((report.scanned, report.copied, report.skipped), (2, 1, 1));
+        assert!(fake.hget("new:a", "value").is_some());
+        assert!(fake.pttl("new:a").is_some());
+    }
+
+    #[cfg(feature = "rustis")]
+    // close compares the replies of the two backends, the timestamps and ttls, over 10s,
+    // within a second: the server clocks are not the same.
+    fn close(a: &Reply, b: &Reply) -> bool {
+        let near = |a: i64, b: i64| a == b || (a.abs() > 10_000 && (a - b).abs() <= 1000);
+        let num = |s: &[u8]| std::str::from_utf8(s).ok()?.parse::<i64>().ok();
+        match (a, b) {
+            (Reply::Int(a), Reply::Int(b)) => near(*a, *b),
+            (Reply::Bulk(a), Reply::Bulk(b)) => match (num(a), num(b)) {
+                (Some(a), Some(b)) => near(a, b),
+                _ => a == b,
+            },
+            (Reply::Array(a), Reply::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| close(a, b))
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    // test_scripts_differential runs every script on redis and on FakeBackend, which
+    // implements them in rust, checking that they reply the same and leave the same hash
+    // and ttl behind.
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
+    async fn test_scripts_differential() {
+        use crate::{backend::RustisBackend, script::*, test_util::go::*};
+        use std::{collections::HashSet, time::SystemTime};
+
+        let rdb = rustis::client::Client::connect("127.0.0.1:6379")
+            .await
+            .unwrap();
+        let redis = RustisBackend::new(rdb);
+        let fake = FakeBackend::with_clock(ManualClock::new(SystemTime::now()));
+        let hgetall = Script::new("hgetall", "return redis.call('HGETALL', KEYS[1])");
+        let prefix = format!("test_differential:{}:", uuid::Uuid::new_v4().simple());
+
+        // the steps are a script, its keys, its args and whether its keys are hashes.
+        let steps: &[(&Script, &[&str], &[&str], bool)] = &[
+            (&GET_SCRIPT, &["a"], &["3000", "o1", "1"], true),
+            (&GET_SCRIPT, &["a"], &["3000", "o2", "1"], true),
+            (&EXTEND_SCRIPT, &["a"], &["3000", "o1", "1"], true),
+            (&EXTEND_SCRIPT, &["a"], &["3000", "o2", "1"], true),
+            (&SET_SCRIPT, &["a"], &["v1", "o2", "60000"], true),
+            (
+                &SET_SCRIPT,
+                &["a"],
+                &["v1", "o1", "60000", "meta:v", "1"],
+                true,
+            ),
+            (&INSPECT_SCRIPT, &["a"], &["1"], true),
+            (&PAYLOAD_SCRIPT, &["a"], &[], true),
+            (&EXISTS_SCRIPT, &["a"], &["x"], true),
+            (&EXISTS_SCRIPT, &["a"], &["v1"], true),
+            (&TTL_SCRIPT, &["a"], &[], true),
+            (&READ_SCRIPT, &["a"], &[], true),
+            (
+                &HMGET_SCRIPT,
+                &["a"],
+                &["value", "lockUntil", "meta:v"],
+                true,
+            ),
+            (&TOUCH_SCRIPT, &["a"], &["120000"], true),
+            (&REFRESH_LOCK_SCRIPT, &["a"], &["3000", "r1", "1"], true),
+            (&REFRESH_LOCK_SCRIPT, &["a"], &["3000", "r2", "1"], true),
+            (&REFRESH_SET_SCRIPT, &["a"], &["v2", "r2", "60000"], true),
+            (
+                &REFRESH_SET_SCRIPT,
+                &["a"],
+                &["v2", "r1", "60000", "meta:w", "2"],
+                true,
+            ),
+            (&REFRESH_LOCK_SCRIPT, &["a"], &["3000", "r3", "1"], true),
+            (&REFRESH_UNLOCK_SCRIPT, &["a"], &["r3"], true),
+            (
+                &WRITE_SCRIPT,
+                &["a"],
+                &["v3", "", "60000", "meta:x", "3"],
+                true,
+            ),
+            (&DELETE_SCRIPT, &["a"], &["5000"], true),
+            (&REFRESH_LOCK_SCRIPT, &["a"], &["3000", "r4", "1"], true),
+            (&EXISTS_SCRIPT, &["a"], &["x"], true),
+            (&TOUCH_SCRIPT, &["a"], &["120000"], true),
+            (&GET_SCRIPT, &["a"], &["3000", "o3", "1"], true),
+            (&UNLOCK_SCRIPT, &["a"], &["o4", "5000"], true),
+            (&UNLOCK_SCRIPT, &["a"], &["o3", "5000"], true),
+            (&CLEAN_LOCK_SCRIPT, &["a"], &["0", "1"], true),
+            (&GET_SCRIPT, &["b"], &["3000", "o5", "1"], true),
+            (&CLEAN_LOCK_SCRIPT, &["b"], &["0", "1"], true),
+            (&COPY_SCRIPT, &["c"], &["v", "60000", "0"], true),
+            (&COPY_SCRIPT, &["c"], &["w", "0", "0"], true),
+            (&COPY_SCRIPT, &["c"], &["w", "0", "1"], true),
+            (&RAW_SET_SCRIPT, &["c"], &["x", "60000"], true),
+            (&RAW_SET_SCRIPT, &["c"], &["y", "0"], true),
+            (&WARM_SCRIPT, &["d"], &["v", "60000", "meta:a", "1"], true),
+            (&WARM_SCRIPT, &["d"], &["w", "60000"], true),
+            (
+                &GET_BATCH_SCRIPT,
+                &["e", "f", "d"],
+                &["3000", "o6", "1"],
+                true,
+            ),
+            (
+                &SET_BATCH_SCRIPT,
+                &["e", "f", "d"],
+                &["o6", "ve", "vf", "vd", "60000", "0", "60000", "meta:b", "1"],
+                true,
+            ),
+            (&GET_BATCH_SCRIPT, &["e", "f"], &["3000", "o7", "1"], true),
+            (&GO_GET_SCRIPT, &["g"], &["100", "200", "o8"], true),
+            (&GO_GET_SCRIPT, &["g"], &["150", "250", "o9"], true),
+            (&GO_SET_SCRIPT, &["g"], &["v", "o9", "60"], true),
+            (&GO_SET_SCRIPT, &["g"], &["v", "o8", "60"], true),
+            (&GO_DELETE_SCRIPT, &["g"], &["10"], true),
+            (&GO_GET_SCRIPT, &["g"], &["300", "400", "o10"], true),
+            (&GO_UNLOCK_SCRIPT, &["g"], &["o10", "10"], true),
+            (&TAG_SCRIPT, &["t"], &["a", "60000"], false),
+            (&TAG_SCRIPT, &["t"], &["b", "30000"], false),
+            (&TAG_MEMBERS_SCRIPT, &["t"], &[], false),
+            (&TAG_MEMBERS_SCRIPT, &["t"], &[], false),
+            (&VERSION_SCRIPT, &["n"], &[], false),
+            (&BUMP_VERSION_SCRIPT, &["n"], &[], false),
+            (&VERSION_SCRIPT, &["n"], &[], false),
+            (&MARKED_EMPTY_SCRIPT, &["m"], &[], false),
+            (&MARK_EMPTY_SCRIPT, &["m"], &["60000"], false),
+            (&MARKED_EMPTY_SCRIPT, &["m"], &[], false),
+            (&PUBLISH_SCRIPT, &[], &["test_differential", "a"], false),
+        ];
+        let covered: HashSet<&str> = steps.iter().map(|(s, ..)| s.name()).collect();
+        for script in all_scripts() {
+            assert!(
+                covered.contains(script.name()),
+                "{} is not run",
+                script.name()
+            );
+        }
+
+        for (i, (script, keys, args, hashes)) in steps.iter().enumerate() {
+            let keys: Vec<String> = keys.iter().map(|k| format!("{}{}", prefix, k)).collect();
+            let args: Vec<Vec<u8>> = args.iter().map(|a| a.as_bytes().to_vec()).collect();
+            let step = format!("step {} {} {:?}", i, script.name(), args);
+            let want = redis
+                .eval(script, keys.clone(), args.clone())
+                .await
+                .unwrap();
+            let got = fake.eval(script, keys.clone(), args).await.unwrap();
+            assert!(
+                close(&want, &got),
+                "{}: redis {:?}, fake {:?}",
+                step,
+                want,
+                got
+            );
+            if !hashes {
+                continue;
+            }
+            for key in keys {
+                let fields = redis.eval(&hgetall, vec![key.clone()], Vec::new()).await;
+                let fields: Vec<Reply> = fields.unwrap().into_array().unwrap();
+                let want = Reply::Array(fields);
+                let fields = fake.hgetall(&key).into_iter();
+                let fields =
+                    fields.flat_map(|(f, v)| [Reply::Bulk(f.into_bytes()), Reply::Bulk(v)]);
+                let got = Reply::Array(fields.collect());
+                let sorted = |reply: Reply| {
+                    let items = reply.into_array().unwrap();
+                    let mut pairs: Vec<_> = items.chunks(2).map(|p| p.to_vec()).collect();
+                    pairs.sort_by_key(|p| format!("{:?}", p[0]));
+                    Reply::Array(pairs.concat())
+                };
+                let (want, got) = (sorted(want), sorted(got));
+                assert!(
+                    close(&want, &got),
+                    "{} {}: redis {:?}, fake {:?}",
+                    step,
+                    key,
+                    want,
+                    got
+                );
+                let want = redis.eval(&TTL_SCRIPT, vec![key.clone()], Vec::new()).await;
+                let got = fake.eval(&TTL_SCRIPT, vec![key.clone()], Vec::new()).await;
+                let (want, got) = (want.unwrap(), got.unwrap());
+                assert!(
+                    close(&want, &got),
+                    "{} {} ttl: redis {:?}, fake {:?}",
+                    step,
+                    key,
+                    want,
+                    got
+                );
+            }
+        }
+    }
+}
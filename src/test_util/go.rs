@@ -0,0 +1,305 @@
+use crate::{
+    backend::{Args, CacheBackend},
+    clock::{unix_secs, Clock, SystemClock},
+    Options, Result, Script,
+};
+use std::{
+    future::Future,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use uuid::Uuid;
+
+// The lua scripts of the Go rockscache client, verbatim.
+pub(super) static GO_GET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "go_get",
+        r#"
+local v = redis.call('HGET', KEYS[1], 'value')
+local lu = redis.call('HGET', KEYS[1], 'lockUntil')
+if lu ~= false and tonumber(lu) < tonumber(ARGV[1]) or lu == false and v == false then
+	redis.call('HSET', KEYS[1], 'lockUntil', ARGV[2])
+	redis.call('HSET', KEYS[1], 'lockOwner', ARGV[3])
+	return { v, 'LOCKED' }
+end
+return {v, lu}"#,
+    )
+});
+
+pub(super) static GO_SET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "go_set",
+        r#"
+local o = redis.call('HGET', KEYS[1], 'lockOwner')
+if o ~= ARGV[2] then
+		return
+end
+redis.call('HSET', KEYS[1], 'value', ARGV[1])
+redis.call('HDEL', KEYS[1], 'lockUntil')
+redis.call('HDEL', KEYS[1], 'lockOwner')
+redis.call('EXPIRE', KEYS[1], ARGV[3])"#,
+    )
+});
+
+pub(super) static GO_DELETE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "go_delete",
+        r#"
+redis.call('HSET', KEYS[1], 'lockUntil', 0)
+redis.call('HDEL', KEYS[1], 'lockOwner')
+redis.call('EXPIRE', KEYS[1], ARGV[1])"#,
+    )
+});
+
+pub(super) static GO_UNLOCK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        "go_unlock",
+        r#"
+local lo = redis.call('HGET', KEYS[1], 'lockOwner')
+if lo == ARGV[1] then
+	redis.call('HSET', KEYS[1], 'lockUntil', 0)
+	redis.call('HDEL', KEYS[1], 'lockOwner')
+	redis.call('EXPIRE', KEYS[1], ARGV[2])
+end"#,
+    )
+});
+
+// GoClient is a port of the strong consistency Fetch and TagAsDeleted of the Go
+// rockscache client, running its scripts verbatim, to check that a Client with
+// Options::go_compat can share keys with Go services. Values are raw bytes like the
+// Go strings, the empty one being the empty result. The random expire adjustment
+// is not applied.
+pub struct GoClient {
+    backend: Arc<dyn CacheBackend>,
+    options: Options,
+    clock: Arc<dyn Clock>,
+}
+
+impl GoClient {
+    // new creates a Go client using the delay, empty_expire, lock_expire and
+    // lock_sleep of options.
+    pub fn new(backend: impl CacheBackend, options: Options) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            options,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub async fn fetch<F, Fut>(&self, key: &str, expire: Duration, f: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<u8>>>,
+    {
+        let owner = Uuid::new_v4().simple().to_string();
+        let (mut value, mut lock_until) = self.lua_get(key, &owner).await?;
+        while value.is_none() && lock_until.as_deref() != Some("LOCKED") {
+            tokio::time::sleep(self.options.lock_sleep).await;
+            (value, lock_until) = self.lua_get(key, &owner).await?;
+        }
+        if lock_until.as_deref() != Some("LOCKED") {
+            return Ok(value.unwrap_or_default());
+        }
+        let result = match f().await {
+            Ok(result) => result,
+            Err(e) => {
+                let args = Args::default()
+                    .arg(owner)
+                    .arg(self.options.lock_expire.as_secs())
+                    .build();
+                _ = self
+                    .backend
+                    .eval(&GO_UNLOCK_SCRIPT, vec![key.to_string()], args)
+                    .await;
+                return Err(e);
+            }
+        };
+        let mut expire = expire.saturating_sub(self.options.delay);
+        if result.is_empty() {
+            if self.options.empty_expire.is_zero() {
+                self.backend.del(vec![key.to_string()]).await?;
+                return Ok(result);
+            }
+            expire = self.options.empty_expire;
+        }
+        let args = Args::default()
+            .arg(result.clone())
+            .arg(owner)
+            .arg(expire.as_secs())
+            .build();
+        self.backend
+            .eval(&GO_SET_SCRIPT, vec![key.to_string()], args)
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn tag_as_deleted(&self, key: &str) -> Result<()> {
+        let args = Args::default().arg(self.options.delay.as_secs()).build();
+        self.backend
+            .eval(&GO_DELETE_SCRIPT, vec![key.to_string()], args)
+            .await?;
+        Ok(())
+    }
+
+    async fn lua_get(&self, key: &str, owner: &str) -> Result<(Option<Vec<u8>>, Option<String>)> {
+        let now = unix_secs(self.clock.as_ref());
+        let args = Args::default()
+            .arg(now)
+            .arg(now + self.options.lock_expire.as_secs())
+            .arg(owner)
+            .build();
+        let reply = self
+            .backend
+            .eval(&GO_GET_SCRIPT, vec![key.to_string()], args)
+            .await?;
+        let mut items = reply.into_array()?.into_iter();
+        let value = items.next().unwrap_or(crate::Reply::Nil).into_bytes()?;
+        let lock_until = items.next().unwrap_or(crate::Reply::Nil).into_string()?;
+        Ok((value, lock_until))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Client, ManualClock};
+    #[cfg(feature = "rustis")]
+    use rustis::client::Client as RustisClient;
+
+    fn encode(v: Option<&str>) -> Vec<u8> {
+        rmp_serde::to_vec(&v).unwrap()
+    }
+
+    // check_shared_keys runs a Rust and a Go client against the same keys.
+    async fn check_shared_keys(
+        rust: Client,
+        go: GoClient,
+        prefix: &str,
+        fake: Option<&FakeBackend>,
+    ) {
+        let expire = Duration::from_secs(600);
+        let key = format!("{}k", prefix);
+
+        // a Go load holds the lock, the Rust fetch waits for its value
+        let go_load = go.fetch(&key, expire, || async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok(encode(Some("go")))
+        });
+        let rust_fetch = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            rust.fetch(&key, expire, || async { Ok(Some("rust".to_string())) })
+                .await
+        };
+        let (loaded, fetched) = futures::join!(go_load, rust_fetch);
+        assert_eq!(loaded.unwrap(), encode(Some("go")));
+        assert_eq!(fetched.unwrap().as_deref(), Some("go"));
+
+        // and the other way around
+        rust.tag_as_deleted(&key).await.unwrap();
+        let rust_load = rust.fetch(&key, expire, || async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok(Some("rust".to_string()))
+        });
+        let go_fetch = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // Go serves the old value while the key is locked
+            let stale = go
+                .fetch(&key, expire, || async { Ok(encode(Some("go2"))) })
+                .await;
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let fresh = go
+                .fetch(&key, expire, || async { Ok(encode(Some("go2"))) })
+                .await;
+            (stale, fresh)
+        };
+        let (loaded, (stale, fresh)) = futures::join!(rust_load, go_fetch);
+        assert_eq!(loaded.unwrap().as_deref(), Some("rust"));
+        assert_eq!(stale.unwrap(), encode(Some("go")));
+        assert_eq!(fresh.unwrap(), encode(Some("rust")));
+
+        // a Go invalidation makes the Rust client reload
+        go.tag_as_deleted(&key).await.unwrap();
+        let reloaded = rust
+            .fetch(&key, expire, || async { Ok(Some("rust2".to_string())) })
+            .await
+            .unwrap();
+        assert_eq!(reloaded.as_deref(), Some("rust2"));
+        if let Some(fake) = fake {
+            assert_eq!(fake.hget(&key, "meta:version"), None);
+        }
+
+        // a refresh doesn't leave refresh fields behind for the Go SET
+        assert!(rust
+            .refresh(&key, expire, || async { Ok(Some("rust3".to_string())) })
+            .await
+            .unwrap());
+        go.tag_as_deleted(&key).await.unwrap();
+        let v = go
+            .fetch(&key, expire, || async { Ok(encode(Some("go3"))) })
+            .await
+            .unwrap();
+        assert_eq!(v, encode(Some("go3")));
+        if let Some(fake) = fake {
+            assert_eq!(fake.hget(&key, "refreshOwner"), None);
+        }
+
+        // both store the empty result as the empty string
+        let empty = format!("{}empty", prefix);
+        assert_eq!(
+            go.fetch(&empty, expire, || async { Ok(Vec::new()) })
+                .await
+                .unwrap(),
+            Vec::<u8>::new()
+        );
+        let v: Option<String> = rust
+            .fetch(&empty, expire, || async { Ok(Some("x".to_string())) })
+            .await
+            .unwrap();
+        assert_eq!(v, None);
+        let rust_empty = format!("{}rust_empty", prefix);
+        rust.fetch::<_, _, String>(&rust_empty, expire, || async { Ok(None) })
+            .await
+            .unwrap();
+        assert_eq!(
+            go.fetch(&rust_empty, expire, || async { Ok(b"x".to_vec()) })
+                .await
+                .unwrap(),
+            Vec::<u8>::new()
+        );
+        assert!(!rust.exists(&rust_empty).await.unwrap());
+    }
+
+    fn options() -> Options {
+        Options {
+            random_expire_adjustment: 0.0,
+            go_compat: true,
+            metadata: vec![("version".to_string(), "1".to_string())],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_go_compat() {
+        let clock = ManualClock::default();
+        let fake = FakeBackend::with_clock(clock.clone());
+        let rust = Client::with_backend(fake.clone(), options()).with_clock(clock.clone());
+        let go = GoClient::new(fake.clone(), options()).with_clock(clock);
+        check_shared_keys(rust, go, "", Some(&fake)).await;
+    }
+
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
+    async fn test_go_compat_on_redis() {
+        let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
+        let prefix = format!("test_go_compat:{}:", Uuid::new_v4().simple());
+        let rust = Client::new(rdb.clone(), options());
+        let go = GoClient::new(crate::RustisBackend::new(rdb), options());
+        check_shared_keys(rust, go, &prefix, None).await;
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+// CountingLoader wraps a loader and counts how many times it actually runs,
+// to check that the cache prevents duplicate source loads.
+pub struct CountingLoader<F> {
+    f: F,
+    calls: Arc<AtomicUsize>,
+}
+
+impl<F, Fut> CountingLoader<F>
+where
+    F: Fn() -> Fut,
+{
+    pub fn new(f: F) -> Self {
+        Self {
+            f,
+            calls: Arc::default(),
+        }
+    }
+
+    // loader returns a closure to pass to fetch, counting a call when fetch invokes it.
+    pub fn loader(&self) -> impl FnOnce() -> Fut + '_ {
+        move || {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            (self.f)()
+        }
+    }
+
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    #[track_caller]
+    pub fn assert_calls(&self, n: usize) {
+        assert_eq!(self.calls(), n, "loader was called {} times", self.calls());
+    }
+
+    // assert_called_once checks that a stampede of fetches only loaded the source once.
+    #[track_caller]
+    pub fn assert_called_once(&self) {
+        self.assert_calls(1);
+    }
+
+    // assert_not_called checks that the fetches were served from the cache.
+    #[track_caller]
+    pub fn assert_not_called(&self) {
+        self.assert_calls(0);
+    }
+}
+
+// RecordingLoader records which keys were loaded, in order, for tests that fetch
+// many keys through one loader function.
+pub struct RecordingLoader<F> {
+    f: F,
+    loaded: Arc<Mutex<Vec<String>>>,
+}
+
+impl<F, Fut> RecordingLoader<F>
+where
+    F: Fn(&str) -> Fut,
+{
+    pub fn new(f: F) -> Self {
+        Self {
+            f,
+            loaded: Arc::default(),
+        }
+    }
+
+    // loader returns a closure to pass to fetch for key, recording key when invoked.
+    pub fn loader<'a>(&'a self, key: &'a str) -> impl FnOnce() -> Fut + 'a {
+        move || {
+            self.loaded.lock().unwrap().push(key.to_string());
+            (self.f)(key)
+        }
+    }
+
+    // loaded returns the loaded keys in invocation order.
+    pub fn loaded(&self) -> Vec<String> {
+        self.loaded.lock().unwrap().clone()
+    }
+
+    pub fn load_count(&self, key: &str) -> usize {
+        self.loaded
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|k| *k == key)
+            .count()
+    }
+
+    #[track_caller]
+    pub fn assert_loaded_once(&self, key: &str) {
+        assert_eq!(
+            self.load_count(key),
+            1,
+            "{} was loaded {} times",
+            key,
+            self.load_count(key)
+        );
+    }
+
+    #[track_caller]
+    pub fn assert_never_loaded(&self, key: &str) {
+        assert_eq!(
+            self.load_count(key),
+            0,
+            "{} was loaded {} times",
+            key,
+            self.load_count(key)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Client, Options};
+    use futures::future::join_all;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_counting_loader_stampede() {
+        let client = Client::with_backend(
+            FakeBackend::new(),
+            Options {
+                lock_sleep: Duration::from_millis(5),
+                ..Default::default()
+            },
+        );
+        let loader = CountingLoader::new(|| async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            Ok(Some(1))
+        });
+        let fetches = (0..20).map(|_| client.fetch("k", Duration::from_secs(600), loader.loader()));
+        for v in join_all(fetches).await {
+            assert_eq!(v.unwrap(), Some(1));
+        }
+        loader.assert_called_once();
+
+        client
+            .fetch("k", Duration::from_secs(600), loader.loader())
+            .await
+            .unwrap();
+        loader.assert_called_once();
+    }
+
+    #[tokio::test]
+    async fn test_recording_loader() {
+        let client = Client::with_backend(FakeBackend::new(), Options::default());
+        let loader = RecordingLoader::new(|key: &str| {
+            let v = key.len();
+            async move { Ok(Some(v)) }
+        });
+        for key in ["a", "bb", "a"] {
+            client
+                .fetch(key, Duration::from_secs(600), loader.loader(key))
+                .await
+                .unwrap();
+        }
+        assert_eq!(loader.loaded(), vec!["a", "bb"]);
+        loader.assert_loaded_once("a");
+        loader.assert_never_loaded("c");
+    }
+}
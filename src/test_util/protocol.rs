@@ -0,0 +1,337 @@
+use crate::{clock::SkewedClock, Client, ManualClock};
+use futures::future::join_all;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// ProtocolCheck runs a randomized workload of fetches, updates and crashing loaders
+// from several simulated clients against one backend, and checks the invariants of
+// the GET/SET/UNLOCK/DELETE scripts:
+// - a lock is never granted while another owner still loads under a valid lock,
+//   unless an invalidation revoked it in between
+// - a fetch started after an invalidation completed never returns the older value
+// - once the writers are done, every key converges to the latest source value
+pub struct ProtocolCheck {
+    // Clients is the number of simulated application instances. default is 8
+    pub clients: usize,
+    // Keys is the number of keys the clients share. default is 3
+    pub keys: usize,
+    // Operations is the number of fetches or updates issued by every client. default is 40
+    pub operations: usize,
+    // WriteRatio is the probability that an operation updates the source. default is 0.2
+    pub write_ratio: f64,
+    // CrashRatio is the probability that a fetch is dropped while its loader runs. default is 0.1
+    pub crash_ratio: f64,
+    // MaxSkew bounds the clock offset of every client from Clock. default is 500ms
+    pub max_skew: Duration,
+    // TimeScale is the simulated time passing every real millisecond. default is 50ms
+    pub time_scale: Duration,
+    // Seed makes the workload reproducible.
+    pub seed: u64,
+    // Clock is the simulated time the client clocks are skewed from.
+    // Share it with the backend, e.g. through FakeBackend::with_clock.
+    pub clock: ManualClock,
+}
+
+impl Default for ProtocolCheck {
+    fn default() -> Self {
+        Self {
+            clients: 8,
+            keys: 3,
+            operations: 40,
+            write_ratio: 0.2,
+            crash_ratio: 0.1,
+            max_skew: Duration::from_millis(500),
+            time_scale: Duration::from_millis(50),
+            seed: 1,
+            clock: ManualClock::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ProtocolReport {
+    pub fetches: usize,
+    pub updates: usize,
+    pub crashes: usize,
+    // MaxConcurrentLoaders is the most loaders seen running at once for a key,
+    // including loaders whose lock was revoked by an invalidation.
+    pub max_concurrent_loaders: usize,
+    pub violations: Vec<String>,
+}
+
+impl ProtocolReport {
+    #[track_caller]
+    pub fn assert_ok(&self) {
+        assert!(
+            self.violations.is_empty(),
+            "lock protocol violations: {:#?}",
+            self.violations
+        );
+    }
+}
+
+struct ActiveLoad {
+    id: u64,
+    // DeletesDone is the number of invalidations completed before the owner sent GET.
+    deletes_done: u64,
+}
+
+#[derive(Default)]
+struct KeyState {
+    source: u64,
+    committed: u64,
+    deletes_started: u64,
+    deletes_done: u64,
+    active: Vec<ActiveLoad>,
+}
+
+#[derive(Default)]
+struct Shared {
+    keys: Vec<Mutex<KeyState>>,
+    report: Mutex<ProtocolReport>,
+    next_load: AtomicU64,
+}
+
+impl Shared {
+    fn violation(&self, msg: String) {
+        self.report.lock().unwrap().violations.push(msg);
+    }
+}
+
+// LoadGuard tracks a running loader, including loaders dropped mid-load.
+struct LoadGuard<'a> {
+    shared: &'a Shared,
+    key: usize,
+    id: u64,
+}
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.shared.keys[self.key].lock().unwrap();
+        state.active.retain(|l| l.id != self.id);
+    }
+}
+
+impl ProtocolCheck {
+    // run drives the workload with the clients returned by new_client, which
+    // must all share one backend and use the given clock.
+    pub async fn run(&self, new_client: impl Fn(SkewedClock) -> Client) -> ProtocolReport {
+        let shared = Shared {
+            keys: (0..self.keys).map(|_| Mutex::default()).collect(),
+            ..Default::default()
+        };
+        let clock = self.clock.clone();
+        let scale = self.time_scale;
+        let ticker = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                clock.advance(scale);
+            }
+        });
+
+        let mut rng = Rng(self.seed);
+        let max = self.max_skew.as_millis() as i64;
+        let clients: Vec<_> = (0..self.clients)
+            .map(|i| {
+                let skew = rng.below(2 * max as u64 + 1) as i64 - max;
+                let ids = AtomicU64::new(0);
+                new_client(SkewedClock::new(self.clock.clone(), skew)).with_owner_id(move || {
+                    format!("client{}-{}", i, ids.fetch_add(1, Ordering::SeqCst))
+                })
+            })
+            .collect();
+        join_all(
+            clients.iter().enumerate().map(|(i, client)| {
+                self.drive(client, self.seed.wrapping_add(i as u64 + 1), &shared)
+            }),
+        )
+        .await;
+
+        for k in 0..self.keys {
+            let key = key_name(k);
+            let expected = shared.keys[k].lock().unwrap().source;
+            if let Err(e) = clients[0].tag_as_deleted(key.as_str()).await {
+                shared.violation(format!("final invalidation of {} failed: {:?}", key, e));
+                continue;
+            }
+            let v = clients[0]
+                .fetch(key.as_str(), Duration::from_secs(600), || async {
+                    Ok(Some(expected))
+                })
+                .await;
+            match v {
+                Ok(Some(v)) if v == expected => {}
+                v => shared.violation(format!(
+                    "lost update on {}: expected {}, got {:?}",
+                    key, expected, v
+                )),
+            }
+        }
+        ticker.abort();
+        shared.report.into_inner().unwrap()
+    }
+
+    async fn drive(&self, client: &Client, seed: u64, shared: &Shared) {
+        let mut rng = Rng(seed);
+        for _ in 0..self.operations {
+            let k = rng.below(self.keys as u64) as usize;
+            let key = key_name(k);
+            if rng.chance(self.write_ratio) {
+                let version = {
+                    let mut state = shared.keys[k].lock().unwrap();
+                    state.source += 1;
+                    state.deletes_started += 1;
+                    state.source
+                };
+                if let Err(e) = client.tag_as_deleted(key.as_str()).await {
+                    shared.violation(format!("invalidation of {} failed: {:?}", key, e));
+                }
+                let mut state = shared.keys[k].lock().unwrap();
+                state.deletes_done += 1;
+                state.committed = state.committed.max(version);
+                shared.report.lock().unwrap().updates += 1;
+                continue;
+            }
+
+            let (min_version, done) = {
+                let state = shared.keys[k].lock().unwrap();
+                (state.committed, state.deletes_done)
+            };
+            let crash = rng.chance(self.crash_ratio);
+            let load_time = Duration::from_millis(rng.below(5));
+            let loaded = AtomicUsize::new(0);
+            let loader = || async {
+                let _guard = self.start_load(shared, k, done);
+                loaded.fetch_add(1, Ordering::SeqCst);
+                let v = shared.keys[k].lock().unwrap().source;
+                tokio::time::sleep(load_time).await;
+                if crash {
+                    std::future::pending::<()>().await;
+                }
+                Ok(Some(v))
+            };
+            let fetch = client.fetch(key.as_str(), Duration::from_secs(600), loader);
+            if crash {
+                tokio::select! {
+                    _ = fetch => {}
+                    _ = tokio::time::sleep(load_time + Duration::from_millis(2)) => {}
+                }
+                if loaded.load(Ordering::SeqCst) > 0 {
+                    shared.report.lock().unwrap().crashes += 1;
+                }
+                continue;
+            }
+            match fetch.await {
+                Ok(Some(v)) if v >= min_version => {}
+                v => shared.violation(format!(
+                    "stale read on {}: started after version {} was invalidated, got {:?}",
+                    key, min_version, v
+                )),
+            }
+            shared.report.lock().unwrap().fetches += 1;
+        }
+    }
+
+    fn start_load<'a>(&self, shared: &'a Shared, k: usize, deletes_done: u64) -> LoadGuard<'a> {
+        let id = shared.next_load.fetch_add(1, Ordering::SeqCst);
+        let mut state = shared.keys[k].lock().unwrap();
+        for other in &state.active {
+            if state.deletes_started == other.deletes_done {
+                shared.violation(format!(
+                    "two owners loading {} without an invalidation in between",
+                    key_name(k)
+                ));
+            }
+        }
+        state.active.push(ActiveLoad { id, deletes_done });
+        let mut report = shared.report.lock().unwrap();
+        report.max_concurrent_loaders = report.max_concurrent_loaders.max(state.active.len());
+        LoadGuard { shared, key: k, id }
+    }
+}
+
+fn key_name(k: usize) -> String {
+    format!("protocol:{}", k)
+}
+
+// Rng is splitmix64, good enough to make the workload reproducible from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next() % n
+        }
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    #[cfg(feature = "rustis")]
+    use rustis::client::Client as RustisClient;
+    #[cfg(feature = "rustis")]
+    use uuid::Uuid;
+
+    fn options() -> Options {
+        Options {
+            lock_sleep: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocol_on_fake_backend() {
+        for seed in 1..=3 {
+            let check = ProtocolCheck {
+                seed,
+                ..Default::default()
+            };
+            let fake = FakeBackend::with_clock(check.clock.clone());
+            let report = check
+                .run(|clock| Client::with_backend(fake.clone(), options()).with_clock(clock))
+                .await;
+            report.assert_ok();
+            assert!(report.fetches > 0 && report.updates > 0);
+        }
+    }
+
+    #[cfg(feature = "rustis")]
+    #[tokio::test]
+    #[ignore = "needs redis at 127.0.0.1:6379"]
+    async fn test_protocol_on_redis() {
+        let rdb = RustisClient::connect("127.0.0.1:6379").await.unwrap();
+        let prefix = format!("test_protocol:{}:", Uuid::new_v4().simple());
+        let check = ProtocolCheck::default();
+        let report = check
+            .run(|clock| {
+                let options = Options {
+                    common_prefix: prefix.clone(),
+                    ..options()
+                };
+                Client::new(rdb.clone(), options).with_clock(clock)
+            })
+            .await;
+        report.assert_ok();
+    }
+}
@@ -0,0 +1,103 @@
+use super::FakeBackend;
+use crate::{Client, ManualClock, Options, Result, SkewedClock};
+use std::time::{Duration, UNIX_EPOCH};
+
+// SkewCheck replays a lock handoff between two application servers whose clocks
+// differ: the holder takes the lock and loads for LoadTime, while the contender,
+// skewed from the holder, polls the same key on the fake server.
+// Lock timestamps come from the server time, so the skew must not matter: the lock is
+// only taken over once LockExpire has passed on the server.
+#[derive(Debug, Clone)]
+pub struct SkewCheck {
+    // LockExpire is Options::lock_expire of both servers. default is 3s
+    pub lock_expire: Duration,
+    // LoadTime is how long the holder keeps the lock. default is 1s
+    pub load_time: Duration,
+    // PollInterval is the simulated time between two lock attempts of the contender. default is 100ms
+    pub poll_interval: Duration,
+}
+
+impl Default for SkewCheck {
+    fn default() -> Self {
+        Self {
+            lock_expire: Duration::from_secs(3),
+            load_time: Duration::from_secs(1),
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+impl SkewCheck {
+    // lock_stolen reports whether a contender whose clock is skew_millis ahead of the
+    // holder (behind if negative) takes the lock while the holder is still loading.
+    // start_millis is the sub-second phase of the holder clock when it takes the lock.
+    pub async fn lock_stolen(&self, skew_millis: i64, start_millis: u64) -> Result<bool> {
+        let clock =
+            ManualClock::new(UNIX_EPOCH + Duration::from_millis(1_000_000_000 + start_millis));
+        let backend = FakeBackend::with_clock(clock.clone());
+        let new_client = |offset| {
+            let options = Options {
+                lock_expire: self.lock_expire,
+                ..Default::default()
+            };
+            Client::with_backend(backend.clone(), options)
+                .with_clock(SkewedClock::new(clock.clone(), offset))
+        };
+        let holder = new_client(0);
+        let contender = new_client(skew_millis);
+
+        let (_, lock, _) = holder.lua_get("skew", "holder").await?;
+        assert_eq!(lock.as_deref(), Some("LOCKED"), "holder must get the lock");
+        let mut elapsed = Duration::ZERO;
+        while elapsed + self.poll_interval <= self.load_time {
+            clock.advance(self.poll_interval);
+            elapsed += self.poll_interval;
+            let (_, lock, _) = contender.lua_get("skew", "contender").await?;
+            if lock.as_deref() == Some("LOCKED") {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // assert_skew_ignored checks that no skew of the contender, up to a minute either
+    // way, lets it take the lock while the holder loads for less than LockExpire, for
+    // several clock phases.
+    pub async fn assert_skew_ignored(&self) {
+        for start in [0, 1, 250, 500, 999] {
+            for skew in [-60_000, -5_000, -1_000, -1, 0, 1, 1_000, 5_000, 60_000] {
+                assert!(
+                    !self.lock_stolen(skew, start).await.unwrap(),
+                    "lock taken over with {}ms skew (start {}ms)",
+                    skew,
+                    start
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_skew_ignored() {
+        SkewCheck::default().assert_skew_ignored().await;
+        let check = SkewCheck {
+            load_time: Duration::from_millis(2900),
+            ..Default::default()
+        };
+        check.assert_skew_ignored().await;
+    }
+
+    #[tokio::test]
+    async fn test_lock_expires_on_server_time() {
+        let check = SkewCheck {
+            load_time: Duration::from_millis(3500),
+            ..Default::default()
+        };
+        assert!(check.lock_stolen(-60_000, 0).await.unwrap());
+        assert!(check.lock_stolen(60_000, 0).await.unwrap());
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{Client, Options};
+use std::ops::Deref;
+use testcontainers_modules::{
+    redis::{Redis, REDIS_PORT},
+    testcontainers::{runners::AsyncRunner, ContainerAsync},
+};
+use uuid::Uuid;
+
+// TestRedis is a Client connected to a throwaway redis container.
+// The container is stopped and removed when TestRedis is dropped.
+pub struct TestRedis {
+    client: Client,
+    url: String,
+    _container: ContainerAsync<Redis>,
+}
+
+impl TestRedis {
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    // url is the address of the container, for connecting more clients to it.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+impl Deref for TestRedis {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+// redis starts a redis container and returns a Client with default options
+// connected to it. It panics if docker is not available.
+pub async fn redis() -> TestRedis {
+    redis_with_options(Options::default()).await
+}
+
+// redis_with_options is redis, appending a random segment to options.common_prefix
+// so that tests sharing the container never see each other's keys.
+pub async fn redis_with_options(mut options: Options) -> TestRedis {
+    let container = Redis::default()
+        .start()
+        .await
+        .expect("failed to start the redis container");
+    let host = container
+        .get_host()
+        .await
+        .expect("failed to get the redis container host");
+    let port = container
+        .get_host_port_ipv4(REDIS_PORT)
+        .await
+        .expect("failed to get the redis container port");
+    let url = format!("redis://{}:{}", host, port);
+    let rdb = rustis::client::Client::connect(url.as_str())
+        .await
+        .expect("failed to connect to the redis container");
+    options.common_prefix = isolated_prefix(&options.common_prefix);
+    TestRedis {
+        client: Client::new(rdb, options),
+        url,
+        _container: container,
+    }
+}
+
+fn isolated_prefix(prefix: &str) -> String {
+    format!("{}test:{}:", prefix, Uuid::new_v4().simple())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_isolated_prefix() {
+        let a = isolated_prefix("svc:");
+        let b = isolated_prefix("svc:");
+        assert!(a.starts_with("svc:test:") && a.ends_with(':'));
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{backend::Args, runtime::Task, script::TOUCH_SCRIPT, shard::Sharded, Client, Result};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+// TOUCH_BATCH_SIZE is the number of keys extended by one TOUCH call.
+const TOUCH_BATCH_SIZE: usize = 256;
+
+#[derive(Default)]
+struct Touched {
+    // keys maps the touched keys to the ttl in milliseconds they are extended to.
+    keys: Sharded<HashMap<String, u64>>,
+    notify: Notify,
+}
+
+impl Touched {
+    fn take(&self) -> Vec<(String, u64)> {
+        self.keys
+            .iter()
+            .flat_map(|mut keys| keys.drain().collect::<Vec<_>>())
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.iter().all(|keys| keys.is_empty())
+    }
+}
+
+// TouchBatch collects the keys read with Options::sliding_expiration, their ttl is
+// extended by a background task every Options::touch_flush_interval, so that a hot
+// key costs one TOUCH per interval instead of one EXPIRE per read.
+// The task is stopped when the client is dropped.
+#[derive(Default)]
+pub(crate) struct TouchBatch {
+    touched: Arc<Touched>,
+    task: Mutex<Option<Arc<Task>>>,
+}
+
+impl Drop for TouchBatch {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get_mut().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+impl Client {
+    // touch extends the ttl of key to expire after a hit, batched unless
+    // Options::touch_flush_interval is 0 or the client has been shut down.
+    pub(crate) async fn touch(&self, key: &str, expire: Duration) {
+        let ms = (expire.as_millis() as u64).max(1);
+        let interval = self.options().touch_flush_interval;
+        if interval.is_zero() || self.executor.is_closed() {
+            _ = self.touch_keys(vec![(key.to_string(), ms)]).await;
+            return;
+        }
+        let batch = &self.touches;
+        let mut task = batch.task.lock().unwrap();
+        if task.is_none() {
+            *task = self.executor.spawn_service(run_touches(
+                self.detach(),
+                batch.touched.clone(),
+                interval,
+            ));
+        }
+        let mut keys = batch.touched.keys.shard(key);
+        let ttl = keys.entry(key.to_string()).or_default();
+        *ttl = (*ttl).max(ms);
+        batch.touched.notify.notify_one();
+    }
+
+    // flush_touches extends the ttl of the keys touched since the last batch.
+    pub(crate) async fn flush_touches(&self) {
+        _ = self.touch_keys(self.touches.touched.take()).await;
+    }
+
+    // touch_keys runs TOUCH over keys, TOUCH_BATCH_SIZE keys at a time.
+    // It is a multi key script, so on a cluster the keys of a call are split by slot
+    // with Options::cluster.
+    async fn touch_keys(&self, keys: Vec<(String, u64)>) -> Result<()> {
+        for batch in keys.chunks(TOUCH_BATCH_SIZE) {
+            let (keys, ms): (Vec<String>, Vec<u64>) = batch.iter().cloned().unzip();
+            self.call_lua_by_slot(&TOUCH_SCRIPT, &keys, |group| {
+                let mut args = Args::default();
+                for &i in group {
+                    args.push(ms[i]);
+                }
+                args.build()
+            })
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+async fn run_touches(client: Client, touched: Arc<Touched>, interval: Duration) {
+    loop {
+        if touched.is_empty() {
+            touched.notify.notified().await;
+        }
+        client.executor.sleep(interval).await;
+        let keys = touched.take();
+        _ = client.executor.run(client.touch_keys(keys)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::FakeBackend, Client, Options};
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sliding_expiration() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            delay: Duration::ZERO,
+            random_expire_adjustment: 0.0,
+            sliding_expiration: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let expire = Duration::from_secs(60);
+        client
+            .fetch("k", expire, || async { Ok(Some(1)) })
+            .await
+            .unwrap();
+        client
+            .fetch("empty", expire, || async { Ok(None::<u64>) })
+            .await
+            .unwrap();
+        fake.advance(Duration::from_secs(30));
+
+        for key in ["k", "k", "empty"] {
+            client
+                .fetch(key, expire, || async { Ok(Some(2)) })
+                .await
+                .unwrap();
+        }
+        assert_eq!(fake.pttl("k"), Some(Duration::from_secs(30)));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(fake.pttl("k"), Some(expire));
+        assert_eq!(fake.pttl("empty"), Some(Duration::from_secs(30)));
+
+        client.shutdown().await;
+        fake.advance(Duration::from_secs(10));
+        client
+            .fetch("k", expire, || async { Ok(Some(2)) })
+            .await
+            .unwrap();
+        assert_eq!(fake.pttl("k"), Some(expire));
+    }
+}
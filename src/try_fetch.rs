@@ -0,0 +1,101 @@
+use crate::{error::new_unexpected_reply_error, Client, Error, Reply, Result};
+use serde::de::DeserializeOwned;
+
+// FetchOutcome is what Client::try_fetch found under a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchOutcome<V> {
+    // Hit is a fresh value, None being a cached empty result.
+    Hit(Option<V>),
+    // Stale is a value that was tag deleted or whose lock is held, it is being reloaded.
+    Stale(Option<V>),
+    // Locked is a key without a value whose lock is held by a fetch loading it.
+    Locked,
+    // Miss is a key with neither a value nor a lock.
+    Miss,
+}
+
+impl Client {
+    // try_fetch reads the value of key without ever waiting: it neither takes the lock
+    // nor calls a loader, so that latency critical callers can fall back to a default
+    // on anything but a hit instead of sleeping in the lock loop. A lock left by a fetch
+    // that died is reported as Locked until the key expires or another fetch takes it.
+    // With Options::disable_cache_read it is always a Miss.
+    pub async fn try_fetch<V: DeserializeOwned>(
+        &self,
+        key: impl AsRef<str>,
+    ) -> Result<FetchOutcome<V>> {
+        if self.cache_bypassed() {
+            return Ok(FetchOutcome::Miss);
+        }
+        let key = self.prefixed_key(key.as_ref());
+        #[cfg(feature = "local-cache")]
+        if let Some(value) = self.local_get(&key)? {
+            return Ok(FetchOutcome::Hit(value));
+        }
+        let fields = self
+            .with_redis_retry(|| self.backend.hmget(&key, &["value", "lockUntil"]))
+            .await;
+        if fields.as_ref().is_err_and(Error::is_redis_error) {
+            self.stats.redis_error();
+        }
+        let [value, lock_until] = <[Option<Vec<u8>>; 2]>::try_from(fields?)
+            .map_err(|_| new_unexpected_reply_error(Reply::Nil))?;
+        Ok(match (value, lock_until) {
+            (Some(s), None) => {
+                let value = self.decode_value(&key, &s)?;
+                self.stats.hit();
+                FetchOutcome::Hit(value)
+            }
+            (Some(s), Some(_)) => FetchOutcome::Stale(self.decode_value(&key, &s)?),
+            (None, Some(_)) => FetchOutcome::Locked,
+            (None, None) => FetchOutcome::Miss,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_try_fetch() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        let expire = Duration::from_secs(600);
+        let outcome = client.try_fetch::<u64>("k").await.unwrap();
+        assert_eq!(outcome, FetchOutcome::Miss);
+
+        client.lua_get("k", "holder").await.unwrap();
+        assert_eq!(
+            client.try_fetch::<u64>("k").await.unwrap(),
+            FetchOutcome::Locked
+        );
+        client.unlock_for_update("k", "holder").await.unwrap();
+
+        let v = client.fetch("k", expire, || async { Ok(Some(1)) }).await;
+        assert_eq!(v.unwrap(), Some(1));
+        assert_eq!(
+            client.try_fetch("k").await.unwrap(),
+            FetchOutcome::Hit(Some(1))
+        );
+        assert_eq!(client.stats().hits, 1);
+
+        client.tag_as_deleted("k").await.unwrap();
+        assert_eq!(
+            client.try_fetch("k").await.unwrap(),
+            FetchOutcome::Stale(Some(1))
+        );
+
+        client
+            .fetch("empty", expire, || async { Ok(None::<u64>) })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.try_fetch::<u64>("empty").await.unwrap(),
+            FetchOutcome::Hit(None)
+        );
+        assert_eq!(fake.calls("get"), 3, "try_fetch never takes the lock");
+    }
+}
@@ -0,0 +1,424 @@
+use crate::{
+    backend::{Args, Reply},
+    error::{new_lock_wait_timeout_error, new_unexpected_reply_error},
+    runtime::Task,
+    script::GET_SCRIPT,
+    Client, Options, Result, ScriptCall,
+};
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+// LockWaitStrategy is how often a fetch polls a lock held by another fetch, see
+// Options::lock_wait_strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockWaitStrategy {
+    // Fixed polls every Options::lock_sleep.
+    #[default]
+    Fixed,
+    // Exponential polls after Options::lock_sleep, doubling the sleep up to max. Each
+    // sleep is shortened by a random fraction of up to jitter, so that the fetches
+    // waiting for one lock spread out.
+    Exponential {
+        #[serde(with = "crate::config::duration")]
+        max: Duration,
+        jitter: f64,
+    },
+}
+
+impl LockWaitStrategy {
+    // sleep is the sleep before the attempt-th poll, from 1, without the jitter.
+    fn sleep(&self, lock_sleep: Duration, attempt: u32) -> Duration {
+        match *self {
+            LockWaitStrategy::Fixed => lock_sleep,
+            LockWaitStrategy::Exponential { max, .. } => {
+                let factor = 1u32 << attempt.saturating_sub(1).min(16);
+                lock_sleep.saturating_mul(factor).min(max)
+            }
+        }
+    }
+
+    fn jitter(&self) -> f64 {
+        match *self {
+            LockWaitStrategy::Fixed => 0.0,
+            LockWaitStrategy::Exponential { jitter, .. } => jitter.clamp(0.0, 1.0),
+        }
+    }
+}
+
+// LockWait is the state of a fetch waiting for a lock.
+pub(crate) struct LockWait {
+    attempts: u32,
+    started: Instant,
+}
+
+// Waiter is a fetch waiting for the lock of key to be released.
+struct Waiter {
+    key: String,
+    owner: String,
+    reply: oneshot::Sender<Result<Reply>>,
+}
+
+#[derive(Default)]
+struct Waiting {
+    waiters: Mutex<Vec<Waiter>>,
+    notify: Notify,
+}
+
+// LockWaits polls the keys of the fetches waiting for a lock held by another fetch,
+// with Options::coalesce_lock_waits. Instead of one timer and one GET per waiting
+// fetch, a background task wakes up every Options::lock_sleep and runs the GET of all
+// of them in one round trip.
+// The task reads lock_sleep from the options of the client owning the waits on each
+// tick, so it follows update_options, and the fetches with FetchOptions sharing the
+// waits don't fix it to their own options.
+// The task is stopped when the client is dropped.
+pub(crate) struct LockWaits {
+    options: Arc<RwLock<Arc<Options>>>,
+    waiting: Arc<Waiting>,
+    task: Mutex<Option<Arc<Task>>>,
+}
+
+impl LockWaits {
+    pub(crate) fn new(options: Arc<RwLock<Arc<Options>>>) -> Self {
+        Self {
+            options,
+            waiting: Arc::default(),
+            task: Mutex::default(),
+        }
+    }
+}
+
+impl Drop for LockWaits {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get_mut().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+// GetReply is the value, the lock state and the ttl left returned by GET.
+pub(crate) type GetReply = (Option<Vec<u8>>, Option<String>, Option<Duration>);
+
+impl Client {
+    // lock_wait starts waiting for a lock.
+    pub(crate) fn lock_wait(&self) -> LockWait {
+        LockWait {
+            attempts: 0,
+            started: self.executor.now(),
+        }
+    }
+
+    // wait_get runs GET again after the next sleep of Options::lock_wait_strategy, or on
+    // the shared tick of Options::lock_sleep with Options::coalesce_lock_waits unless the
+    // client has been shut down. It fails with Error::LockWaitTimeout once the wait is
+    // over Options::lock_wait_max_attempts or Options::lock_wait_timeout, which also
+    // apply to coalesced waits.
+    pub(crate) async fn wait_get(
+        &self,
+        key: &str,
+        owner: &str,
+        wait: &mut LockWait,
+    ) -> Result<GetReply> {
+        let sleep = self.next_lock_sleep(key, wait)?;
+        if !self.options().coalesce_lock_waits || self.executor.is_closed() {
+            self.executor.sleep(sleep).await;
+            return self.lua_get(key, owner).await;
+        }
+        let (tx, rx) = oneshot::channel();
+        {
+            let waits = &self.lock_waits;
+            let mut task = waits.task.lock().unwrap();
+            if task.is_none() {
+                let client = Client {
+                    options: waits.options.clone(),
+                    ..self.detach()
+                };
+                *task = self
+                    .executor
+                    .spawn_service(run_lock_waits(client, waits.waiting.clone()));
+            }
+            waits.waiting.waiters.lock().unwrap().push(Waiter {
+                key: key.to_string(),
+                owner: owner.to_string(),
+                reply: tx,
+            });
+            waits.waiting.notify.notify_one();
+        }
+        // the sender is dropped if the task was stopped
+        let reply = rx
+            .await
+            .map_err(|_| new_unexpected_reply_error(Reply::Nil))??;
+        parse_get(reply)
+    }
+
+    // end_lock_wait counts the time waited by wait in the stats, if it did wait.
+    pub(crate) fn end_lock_wait(&self, wait: &LockWait) {
+        if wait.attempts > 0 {
+            let waited = self.executor.now().duration_since(wait.started);
+            self.stats.lock_wait(waited);
+        }
+    }
+
+    // next_lock_sleep counts an attempt of wait, returning how long to sleep before it.
+    fn next_lock_sleep(&self, key: &str, wait: &mut LockWait) -> Result<Duration> {
+        let options = self.options();
+        wait.attempts += 1;
+        let waited = self.executor.now().duration_since(wait.started);
+        let timeout = options.lock_wait_timeout;
+        if options.lock_wait_max_attempts > 0 && wait.attempts > options.lock_wait_max_attempts
+            || !timeout.is_zero() && waited >= timeout
+        {
+            self.stats.lock_wait(waited);
+            return Err(new_lock_wait_timeout_error(key));
+        }
+        let strategy = options.lock_wait_strategy;
+        let mut sleep = strategy
+            .sleep(options.lock_sleep, wait.attempts)
+            .mul_f64(1.0 - strategy.jitter() * (self.jitter)());
+        if !timeout.is_zero() {
+            sleep = sleep.min(timeout - waited);
+        }
+        Ok(sleep)
+    }
+
+    // lua_get runs GET, returning the value, the lock state and the ttl left.
+    pub(crate) async fn lua_get(&self, key: &str, owner: &str) -> Result<GetReply> {
+        let call = self.get_call(key.to_string(), owner);
+        let reply = self.call_lua(call.script, call.keys, call.args).await?;
+        parse_get(reply)
+    }
+
+    // get_call is the GET script call taking the lock of key for owner if it is free.
+    fn get_call(&self, key: String, owner: &str) -> ScriptCall<'static> {
+        ScriptCall {
+            script: &GET_SCRIPT,
+            keys: vec![key],
+            args: Args::default()
+                .arg(self.lock_span(self.options().lock_expire))
+                .arg(owner)
+                .arg(self.lock_unit())
+                .build(),
+        }
+    }
+}
+
+pub(crate) fn parse_get(reply: Reply) -> Result<GetReply> {
+    let mut items = reply.into_array()?.into_iter();
+    let value = items.next().unwrap_or(Reply::Nil).into_bytes()?;
+    let lock_until = items.next().unwrap_or(Reply::Nil).into_string()?;
+    let pttl = match items.next() {
+        Some(pttl) => pttl.as_int()?,
+        None => -1,
+    };
+    Ok((
+        value,
+        lock_until,
+        (pttl >= 0).then(|| Duration::from_millis(pttl as u64)),
+    ))
+}
+
+async fn run_lock_waits(client: Client, waiting: Arc<Waiting>) {
+    loop {
+        if waiting.waiters.lock().unwrap().is_empty() {
+            waiting.notify.notified().await;
+        }
+        client.executor.sleep(client.options().lock_sleep).await;
+        let waiters = std::mem::take(&mut *waiting.waiters.lock().unwrap());
+        let calls = waiters
+            .iter()
+            .map(|w| client.get_call(w.key.clone(), &w.owner))
+            .collect();
+        let replies = client.executor.run(client.call_lua_pipeline(calls)).await;
+        for (waiter, reply) in waiters.into_iter().zip(replies) {
+            _ = waiter.reply.send(reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backend::{BoxFuture, CacheBackend, Reply, ScriptCall},
+        test_util::FakeBackend,
+        Client, Error, FetchOptions, LockWaitStrategy, Options, Result, Script,
+    };
+    use futures::future::join_all;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    // Pipelines counts the pipelines run on a FakeBackend.
+    struct Pipelines {
+        fake: FakeBackend,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl CacheBackend for Pipelines {
+        fn eval<'a>(
+            &'a self,
+            script: &'a Script,
+            keys: Vec<String>,
+            args: Vec<Vec<u8>>,
+        ) -> BoxFuture<'a, Result<Reply>> {
+            self.fake.eval(script, keys, args)
+        }
+
+        fn eval_pipeline<'a>(
+            &'a self,
+            calls: Vec<ScriptCall<'a>>,
+        ) -> BoxFuture<'a, Vec<Result<Reply>>> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            self.fake.eval_pipeline(calls)
+        }
+
+        fn del(&self, keys: Vec<String>) -> BoxFuture<'_, Result<u64>> {
+            self.fake.del(keys)
+        }
+
+        fn scan<'a>(
+            &'a self,
+            cursor: u64,
+            pattern: &'a str,
+            count: usize,
+        ) -> BoxFuture<'a, Result<(u64, Vec<String>)>> {
+            self.fake.scan(cursor, pattern, count)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce_lock_waits() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let backend = Pipelines {
+            fake: FakeBackend::new(),
+            count: count.clone(),
+        };
+        let options = Options {
+            coalesce_lock_waits: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(backend, options);
+        let expire = Duration::from_secs(600);
+        let holder = client.fetch("k", expire, || async {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            Ok(Some(1))
+        });
+        let waiters = (0..10).map(|i| {
+            let key = ["k", "other"][i % 2];
+            client.fetch(key, expire, || async {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                Ok(Some(2))
+            })
+        });
+        let (held, waited) = futures::join!(holder, join_all(waiters));
+        assert_eq!(held.unwrap(), Some(1));
+        for (i, value) in waited.into_iter().enumerate() {
+            assert_eq!(value.unwrap(), Some([1, 2][i % 2]));
+        }
+        // 9 fetches waited 3 ticks of 100ms for the loaders of k and other
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce_lock_waits_options() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let fake = FakeBackend::new();
+        fake.hset("k", "lockUntil", u64::MAX.to_string());
+        fake.hset("k", "lockOwner", "other");
+        let backend = Pipelines {
+            fake,
+            count: count.clone(),
+        };
+        let options = Options {
+            coalesce_lock_waits: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(backend, options);
+        // the first waiter has overridden options, which must not fix the tick
+        let waiter = client.clone();
+        let fetch = tokio::spawn(async move {
+            let options = FetchOptions {
+                loader_timeout: Some(Duration::from_secs(5)),
+                ..Default::default()
+            };
+            waiter
+                .fetch_with("k", Duration::from_secs(600), options, || async {
+                    Ok(Some(1))
+                })
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        client
+            .update_options(|o| o.lock_sleep = Duration::from_secs(1))
+            .unwrap();
+        // the tick sleeping at the update still ends at 300ms, the next one at 1300ms
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        fetch.abort();
+    }
+
+    #[test]
+    fn test_lock_wait_strategy_sleep() {
+        let lock_sleep = Duration::from_millis(100);
+        assert_eq!(LockWaitStrategy::Fixed.sleep(lock_sleep, 5), lock_sleep);
+        let strategy = LockWaitStrategy::Exponential {
+            max: Duration::from_millis(500),
+            jitter: 0.0,
+        };
+        let sleeps: Vec<u128> = (1..=5)
+            .map(|n| strategy.sleep(lock_sleep, n).as_millis())
+            .collect();
+        assert_eq!(sleeps, vec![100, 200, 400, 500, 500]);
+        assert_eq!(strategy.sleep(lock_sleep, 100), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lock_wait_limits() {
+        let fake = FakeBackend::new();
+        fake.hset("k", "lockUntil", u64::MAX.to_string());
+        fake.hset("k", "lockOwner", "other");
+        let fetch = |options| {
+            let client = Client::with_backend(fake.clone(), options);
+            async move {
+                client
+                    .fetch("k", Duration::from_secs(600), || async { Ok(Some(1)) })
+                    .await
+            }
+        };
+
+        let options = Options {
+            lock_wait_max_attempts: 3,
+            ..Default::default()
+        };
+        let result = fetch(options).await;
+        assert!(matches!(result, Err(Error::LockWaitTimeout(key)) if key == "k"));
+        assert_eq!(fake.calls("get"), 4);
+
+        let started = tokio::time::Instant::now();
+        let options = Options {
+            lock_wait_strategy: LockWaitStrategy::Exponential {
+                max: Duration::from_secs(1),
+                jitter: 0.5,
+            },
+            lock_wait_timeout: Duration::from_millis(1500),
+            ..Default::default()
+        };
+        assert!(matches!(
+            fetch(options).await,
+            Err(Error::LockWaitTimeout(_))
+        ));
+        assert_eq!(started.elapsed(), Duration::from_millis(1500));
+    }
+}
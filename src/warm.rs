@@ -0,0 +1,133 @@
+use crate::{backend::Args, script::WARM_SCRIPT, Client, Result};
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::time::Duration;
+
+pub struct WarmOptions {
+    // Concurrency is the number of values written to redis at the same time. default is 16
+    pub concurrency: usize,
+}
+
+impl Default for WarmOptions {
+    fn default() -> Self {
+        Self { concurrency: 16 }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WarmReport {
+    // Written is the number of values written to the cache.
+    pub written: u64,
+    // Skipped counts keys that already had an entry, which is at least as fresh as the snapshot.
+    pub skipped: u64,
+}
+
+impl Client {
+    // warm_from fills the cache from a stream of (key, value, ttl), e.g. produced from a
+    // database snapshot so new instances come up warm after redis has been flushed.
+    // A key is only written if it has no entry at all: cached values, running loads and
+    // tag deleted values are all left alone, as they may be fresher than the snapshot.
+    // It stops at the first error.
+    pub async fn warm_from<S, K, V>(&self, stream: S, options: WarmOptions) -> Result<WarmReport>
+    where
+        S: Stream<Item = (K, V, Duration)>,
+        K: Into<String>,
+        V: Serialize,
+    {
+        stream
+            .map(|(key, value, ttl)| self.warm_key(self.prefixed_key(key), value, ttl))
+            .buffer_unordered(options.concurrency.max(1))
+            .try_fold(WarmReport::default(), |mut report, written| async move {
+                if written {
+                    report.written += 1;
+                } else {
+                    report.skipped += 1;
+                }
+                Ok(report)
+            })
+            .await
+    }
+
+    async fn warm_key<V: Serialize>(&self, key: String, value: V, ttl: Duration) -> Result<bool> {
+        let value = self.encode_value(&key, &Some(value))?;
+        let mut args = Args::default()
+            .arg(value)
+            .arg((ttl.as_millis() as u64).max(1));
+        if !self.options().go_compat {
+            for (name, value) in &self.options().metadata {
+                args.push(format!("meta:{}", name));
+                args.push(value);
+            }
+        }
+        let written = self.call_lua(&WARM_SCRIPT, vec![key], args.build()).await?;
+        Ok(written.as_int()? == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+    use futures::stream;
+
+    #[tokio::test]
+    async fn test_warm_from() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(
+            fake.clone(),
+            Options {
+                common_prefix: "app:".to_string(),
+                ..Default::default()
+            },
+        );
+        client
+            .fetch("cached", Duration::from_secs(600), || async {
+                Ok(Some("live".to_string()))
+            })
+            .await
+            .unwrap();
+        client.tag_as_deleted("deleted").await.unwrap();
+
+        let snapshot = stream::iter(
+            ["cached", "deleted", "a", "b"]
+                .map(|k| (k, format!("snapshot {}", k), Duration::from_secs(60))),
+        );
+        let report = client
+            .warm_from(snapshot, WarmOptions { concurrency: 2 })
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            WarmReport {
+                written: 2,
+                skipped: 2
+            }
+        );
+        assert_eq!(fake.pttl("app:a"), Some(Duration::from_secs(60)));
+
+        let v: Option<String> = client
+            .fetch("a", Duration::from_secs(600), || async { unreachable!() })
+            .await
+            .unwrap();
+        assert_eq!(v, Some("snapshot a".to_string()));
+        let v: Option<String> = client
+            .fetch("cached", Duration::from_secs(600), || async {
+                unreachable!()
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, Some("live".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_warm_from_stops_on_error() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        fake.fail_next(1);
+        let snapshot = stream::iter([("a", 1, Duration::from_secs(60))]);
+        assert!(client
+            .warm_from(snapshot, WarmOptions::default())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{backend::ScriptCall, runtime::Task, script::SET_SCRIPT, Client, Result};
+use futures::future;
+use std::{
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+// RETRY_BASE and RETRY_MAX bound the backoff between two attempts of a failed write.
+const RETRY_BASE: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(10);
+
+pub(crate) type DroppedFn = dyn Fn(&str) + Send + Sync;
+
+// Write is a cache write retried in the background.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Write {
+    // Delete is a tag_as_deleted.
+    Delete,
+    // Set is a SET script call with its ARGV, written after the fetch returned.
+    Set(Vec<Vec<u8>>),
+}
+
+struct Pending {
+    key: String,
+    write: Write,
+    first_failed: Instant,
+    attempts: u32,
+    next_at: Instant,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: Mutex<Vec<Pending>>,
+    notify: Notify,
+}
+
+impl Queue {
+    fn push(&self, key: String, write: Write, first_failed: Instant, attempts: u32, now: Instant) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.iter().any(|p| p.key == key && p.write == write) {
+            return;
+        }
+        pending.push(Pending {
+            key,
+            write,
+            first_failed,
+            attempts,
+            next_at: now + retry_delay(attempts),
+        });
+        self.notify.notify_one();
+    }
+}
+
+// WriteRetry buffers the invalidations and detached writes that failed on a redis
+// error, and retries them in the background until they succeed or get too old.
+// The retry task is stopped when the client is dropped.
+#[derive(Default)]
+pub(crate) struct WriteRetry {
+    queue: Arc<Queue>,
+    task: Mutex<Option<Arc<Task>>>,
+}
+
+impl WriteRetry {
+    // push queues write of key, starting the retry task on a detached copy of client.
+    pub(crate) fn push(&self, client: &Client, key: String, write: Write) {
+        let mut task = self.task.lock().unwrap();
+        if task.is_none() {
+            let queue = self.queue.clone();
+            *task = client
+                .executor
+                .spawn_service(run_retries(client.detach(), queue));
+        }
+        let now = client.executor.now();
+        self.queue.push(key, write, now, 1, now);
+    }
+}
+
+impl Drop for WriteRetry {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get_mut().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+impl Client {
+    // with_invalidation_dropped sets a callback called with the key of an invalidation
+    // given up after Options::invalidation_retry_max_age, so it can be alerted on or
+    // repaired out of band.
+    pub fn with_invalidation_dropped(mut self, f: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_invalidation_dropped = Some(Arc::new(f));
+        self
+    }
+
+    // pending_invalidations returns the number of failed invalidations waiting for a retry.
+    pub fn pending_invalidations(&self) -> usize {
+        let pending = self.write_retry.queue.pending.lock().unwrap();
+        pending.iter().filter(|p| p.write == Write::Delete).count()
+    }
+
+    // retry_write queues write of key to be done again in the background.
+    pub(crate) fn retry_write(&self, key: String, write: Write) {
+        self.write_retry.push(self, key, write);
+    }
+
+    // flush_write_retries makes a last attempt of the queued writes, dropping them if it fails.
+    pub(crate) async fn flush_write_retries(&self) {
+        let pending: Vec<_> = self
+            .write_retry
+            .queue
+            .pending
+            .lock()
+            .unwrap()
+            .drain(..)
+            .collect();
+        let results = self.apply_writes(&pending).await;
+        for (p, result) in pending.iter().zip(results) {
+            if result.is_err() {
+                self.dropped_write(p);
+            }
+        }
+    }
+
+    fn dropped_write(&self, p: &Pending) {
+        if let (Write::Delete, Some(on_dropped)) = (&p.write, &self.on_invalidation_dropped) {
+            on_dropped(&p.key);
+        }
+    }
+
+    // apply_writes runs the writes in one round trip, returning their results in order.
+    async fn apply_writes(&self, pending: &[Pending]) -> Vec<Result<()>> {
+        let calls = pending
+            .iter()
+            .map(|p| match &p.write {
+                Write::Delete => self.delete_call(p.key.clone()),
+                Write::Set(args) => ScriptCall {
+                    script: &SET_SCRIPT,
+                    keys: vec![p.key.clone()],
+                    args: args.clone(),
+                },
+            })
+            .collect();
+        let replies = self.call_lua_pipeline(calls).await;
+        pending
+            .iter()
+            .zip(replies)
+            .map(|(p, reply)| {
+                reply?;
+                if p.write == Write::Delete {
+                    self.journal_done(&p.key);
+                }
+                Ok(())
+            })
+            .collect()
+    }
+}
+
+async fn run_retries(client: Client, queue: Arc<Queue>) {
+    let max_age = client.options().invalidation_retry_max_age;
+    loop {
+        let next_at = queue
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.next_at)
+            .min();
+        match next_at {
+            Some(at) => {
+                let delay = at.saturating_duration_since(client.executor.now());
+                let sleep = client.executor.sleep(delay);
+                future::select(sleep, pin!(queue.notify.notified())).await;
+            }
+            None => queue.notify.notified().await,
+        }
+
+        let now = client.executor.now();
+        let due: Vec<_> = {
+            let mut pending = queue.pending.lock().unwrap();
+            let (due, later) = pending.drain(..).partition(|p| p.next_at <= now);
+            *pending = later;
+            due
+        };
+        let results = client.executor.run(client.apply_writes(&due)).await;
+        for (p, result) in due.into_iter().zip(results) {
+            match result {
+                Ok(()) => {}
+                Err(e) if e.is_redis_error() && now - p.first_failed < max_age => {
+                    let now = client.executor.now();
+                    queue.push(p.key, p.write, p.first_failed, p.attempts + 1, now);
+                }
+                Err(_) => client.dropped_write(&p),
+            }
+        }
+    }
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_BASE
+        .saturating_mul(1 << (attempts - 1).min(16))
+        .min(RETRY_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::FakeBackend, Options};
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), RETRY_BASE);
+        assert_eq!(retry_delay(3), RETRY_BASE * 4);
+        assert_eq!(retry_delay(40), RETRY_MAX);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_invalidation_is_retried() {
+        let fake = FakeBackend::new();
+        let client = Client::with_backend(fake.clone(), Options::default());
+        fake.fail_next(2);
+        assert!(client
+            .tag_as_deleted("k")
+            .await
+            .is_err_and(|e| e.is_redis_error()));
+        assert_eq!(client.pending_invalidations(), 1);
+        assert_eq!(fake.hget("k", "lockUntil"), None);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(client.pending_invalidations(), 0);
+        assert_eq!(fake.hget("k", "lockUntil"), Some(b"0".to_vec()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_invalidation_dropped_after_max_age() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            invalidation_retry_max_age: Duration::from_millis(150),
+            ..Default::default()
+        };
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let recorded = dropped.clone();
+        let client = Client::with_backend(fake.clone(), options)
+            .with_invalidation_dropped(move |key| recorded.lock().unwrap().push(key.to_string()));
+        fake.fail_next(100);
+        _ = client.tag_as_deleted("k").await;
+        _ = client.tag_as_deleted("k").await;
+        assert_eq!(client.pending_invalidations(), 1);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(client.pending_invalidations(), 0);
+        assert_eq!(*dropped.lock().unwrap(), vec!["k".to_string()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_detached_write() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            detached_write: true,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        let failing = fake.clone();
+        let v = client
+            .fetch("k", Duration::from_secs(600), || async move {
+                failing.fail_next(1);
+                Ok(Some(1))
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, Some(1));
+        assert_eq!(fake.hget("k", "value"), None);
+        assert!(fake.hget("k", "lockOwner").is_some());
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            fake.hget("k", "value"),
+            Some(rmp_serde::to_vec(&Some(1)).unwrap())
+        );
+        assert_eq!(fake.hget("k", "lockOwner"), None);
+        assert_eq!(client.pending_invalidations(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalidation_retry_disabled() {
+        let fake = FakeBackend::new();
+        let options = Options {
+            invalidation_retry_max_age: Duration::ZERO,
+            ..Default::default()
+        };
+        let client = Client::with_backend(fake.clone(), options);
+        fake.fail_next(1);
+        assert!(client.tag_as_deleted("k").await.is_err());
+        assert_eq!(client.pending_invalidations(), 0);
+    }
+}